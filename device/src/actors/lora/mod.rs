@@ -1,8 +1,14 @@
 use crate::{
-    kernel::actor::{Actor, Address},
+    kernel::{
+        actor::{Actor, ActorContext, ActorHandle, Address},
+        signal::SignalSlot,
+        util::ImmediateFuture,
+    },
     traits::lora::*,
 };
 use core::{future::Future, pin::Pin};
+#[cfg(feature = "time")]
+use embassy::time::{Duration, Timer};
 
 /// Messages handled by lora actor
 pub enum LoraRequest<'m> {
@@ -10,6 +16,53 @@ pub enum LoraRequest<'m> {
     Join(ConnectMode),
     Send(QoS, Port, &'m [u8]),
     SendRecv(QoS, Port, &'m [u8], &'m mut [u8]),
+    /// Deliver downlinks received outside of an uplink to the downlink handler, without waiting.
+    Poll,
+    LinkCheck,
+    Status,
+    Session,
+    Restore(&'m LoraSession),
+}
+
+/// Responses returned by lora actor
+#[derive(Debug, Clone, Copy)]
+pub enum LoraResponse {
+    Ok,
+    Received(Port, usize),
+    LinkCheck(LinkCheck),
+    Status(LoraStatus),
+    Session(LoraSession),
+}
+
+fn unexpected<T>(response: LoraResponse) -> Result<T, LoraError> {
+    error!("Unexpected response: {:?}", response);
+    Err(LoraError::OtherError)
+}
+
+/// Conversion of downlinks into messages of the actor handling them.
+pub trait FromLoraDownlink<M> {
+    fn from(downlink: LoraDownlink) -> Option<M>
+    where
+        Self: Sized;
+}
+
+/// Receiver of the downlinks delivered by the lora actor.
+pub trait LoraDownlinkHandler {
+    /// Handle a downlink, or give it back if it cannot be handled yet, to be delivered again later.
+    fn on_downlink(&'static self, downlink: LoraDownlink) -> Result<(), LoraDownlink>;
+}
+
+impl<A, const QUEUE_SIZE: usize> LoraDownlinkHandler for ActorContext<'static, A, QUEUE_SIZE>
+where
+    A: Actor + FromLoraDownlink<A::Message<'static>> + 'static,
+    [SignalSlot<<A as Actor>::Response>; QUEUE_SIZE]: Default,
+{
+    fn on_downlink(&'static self, downlink: LoraDownlink) -> Result<(), LoraDownlink> {
+        match A::from(downlink) {
+            Some(message) => self.notify(message).map_err(|_| downlink),
+            None => Ok(()),
+        }
+    }
 }
 
 impl<'a, D> LoraDriver for Address<'a, LoraActor<D>>
@@ -59,11 +112,65 @@ where
         rx: &'m mut [u8],
     ) -> Self::SendRecvFuture<'m> {
         async move {
-            self.request(LoraRequest::SendRecv(qos, port, data, rx))
+            match self
+                .request(LoraRequest::SendRecv(qos, port, data, rx))
+                .unwrap()
+                .await?
+            {
+                LoraResponse::Received(_, len) => Ok(len),
+                r => unexpected(r),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkCheckFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkCheck, LoraError>> + 'm;
+    fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+        async move {
+            match self.request(LoraRequest::LinkCheck).unwrap().await? {
+                LoraResponse::LinkCheck(link_check) => Ok(link_check),
+                r => unexpected(r),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type StatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraStatus, LoraError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        async move {
+            match self.request(LoraRequest::Status).unwrap().await? {
+                LoraResponse::Status(status) => Ok(status),
+                r => unexpected(r),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type SessionFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraSession, LoraError>> + 'm;
+    fn session<'m>(&'m mut self) -> Self::SessionFuture<'m> {
+        async move {
+            match self.request(LoraRequest::Session).unwrap().await? {
+                LoraResponse::Session(session) => Ok(session),
+                r => unexpected(r),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type RestoreFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn restore<'m>(&'m mut self, session: &'m LoraSession) -> Self::RestoreFuture<'m> {
+        async move {
+            self.request(LoraRequest::Restore(session))
                 .unwrap()
                 .await
+                .map(|_| ())
         }
     }
+
+    /// Downlinks are delivered to the downlink handler of the actor instead.
+    fn take_downlink(&mut self) -> Option<LoraDownlink> {
+        None
+    }
 }
 
 pub struct LoraActor<D>
@@ -71,6 +178,9 @@ where
     D: LoraDriver + 'static,
 {
     driver: D,
+    handler: Option<&'static dyn LoraDownlinkHandler>,
+    /// Downlink the handler was not able to handle yet.
+    pending: Option<LoraDownlink>,
 }
 
 impl<D> LoraActor<D>
@@ -78,7 +188,29 @@ where
     D: LoraDriver + 'static,
 {
     pub fn new(driver: D) -> Self {
-        Self { driver }
+        Self {
+            driver,
+            handler: None,
+            pending: None,
+        }
+    }
+
+    /// Deliver the downlinks taken from the driver to the handler, in the order received. Once the
+    /// handler is unable to keep up, the remaining downlinks are delivered by a later request.
+    fn deliver_downlinks(&mut self) {
+        loop {
+            let downlink = match self.pending.take().or_else(|| self.driver.take_downlink()) {
+                Some(downlink) => downlink,
+                None => return,
+            };
+            if let Some(handler) = self.handler {
+                if let Err(downlink) = handler.on_downlink(downlink) {
+                    trace!("Downlink handler busy, retrying on next request");
+                    self.pending.replace(downlink);
+                    return;
+                }
+            }
+        }
     }
 }
 
@@ -86,11 +218,16 @@ impl<D> Actor for LoraActor<D>
 where
     D: LoraDriver + 'static,
 {
-    type Configuration = ();
+    /// Handler receiving every downlink, whichever request it was received by.
+    type Configuration = Option<&'static dyn LoraDownlinkHandler>;
 
     #[rustfmt::skip]
     type Message<'m> where D: 'm = LoraRequest<'m>;
-    type Response = Result<usize, LoraError>;
+    type Response = Result<LoraResponse, LoraError>;
+
+    fn on_mount(&mut self, _: Address<'static, Self>, config: Self::Configuration) {
+        self.handler = config;
+    }
 
     #[rustfmt::skip]
     type OnStartFuture<'m> where D: 'm = impl Future<Output = ()> + 'm;
@@ -107,14 +244,88 @@ where
         async move {
             let this = unsafe { self.get_unchecked_mut() };
             let driver = &mut this.driver;
-            match message {
-                LoraRequest::Configure(config) => driver.configure(config).await.map(|_| 0),
-                LoraRequest::Join(mode) => driver.join(mode).await.map(|_| 0),
-                LoraRequest::Send(qos, port, buf) => driver.send(qos, port, buf).await.map(|_| 0),
-                LoraRequest::SendRecv(qos, port, buf, rx) => {
-                    driver.send_recv(qos, port, buf, rx).await
+            let result = match message {
+                LoraRequest::Configure(config) => {
+                    driver.configure(config).await.map(|_| LoraResponse::Ok)
+                }
+                LoraRequest::Join(mode) => driver.join(mode).await.map(|_| LoraResponse::Ok),
+                LoraRequest::Send(qos, port, buf) => {
+                    driver.send(qos, port, buf).await.map(|_| LoraResponse::Ok)
+                }
+                LoraRequest::SendRecv(qos, port, buf, rx) => driver
+                    .send_recv(qos, port, buf, rx)
+                    .await
+                    .map(|len| LoraResponse::Received(port, len)),
+                LoraRequest::Poll => Ok(LoraResponse::Ok),
+                LoraRequest::LinkCheck => driver.link_check().await.map(LoraResponse::LinkCheck),
+                LoraRequest::Status => driver.status().await.map(LoraResponse::Status),
+                LoraRequest::Session => driver.session().await.map(LoraResponse::Session),
+                LoraRequest::Restore(session) => {
+                    driver.restore(session).await.map(|_| LoraResponse::Ok)
+                }
+            };
+            this.deliver_downlinks();
+            result
+        }
+    }
+}
+
+/// Polls a `LoraActor` at a fixed interval, so that downlinks received outside of an uplink,
+/// as by a Class C device, reach the downlink handler without waiting for the next request.
+#[cfg(feature = "time")]
+pub struct LoraPoller<D>
+where
+    D: LoraDriver + 'static,
+{
+    interval: Duration,
+    actor: Option<Address<'static, LoraActor<D>>>,
+}
+
+#[cfg(feature = "time")]
+impl<D> LoraPoller<D>
+where
+    D: LoraDriver + 'static,
+{
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            actor: None,
+        }
+    }
+}
+
+#[cfg(feature = "time")]
+impl<D> Unpin for LoraPoller<D> where D: LoraDriver + 'static {}
+
+#[cfg(feature = "time")]
+impl<D> Actor for LoraPoller<D>
+where
+    D: LoraDriver + 'static,
+{
+    type Configuration = Address<'static, LoraActor<D>>;
+
+    fn on_mount(&mut self, _: Address<'static, Self>, config: Self::Configuration) {
+        self.actor.replace(config);
+    }
+
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where D: 'm = impl Future<Output = ()> + 'm;
+    fn on_start(self: Pin<&'_ mut Self>) -> Self::OnStartFuture<'_> {
+        async move {
+            let actor = self.actor.unwrap();
+            loop {
+                Timer::after(self.interval).await;
+                // A full queue means requests are pending, which deliver downlinks as well
+                if let Ok(response) = actor.request(LoraRequest::Poll) {
+                    let _ = response.await;
                 }
             }
         }
     }
+
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where D: 'm = ImmediateFuture;
+    fn on_message<'m>(self: Pin<&'m mut Self>, _: Self::Message<'m>) -> Self::OnMessageFuture<'m> {
+        ImmediateFuture::new()
+    }
 }
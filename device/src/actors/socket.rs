@@ -4,19 +4,32 @@ use crate::{
     traits::{
         ip::{IpProtocol, SocketAddress},
         tcp::{TcpError, TcpSocket, TcpStack},
+        udp::{UdpError, UdpSocket},
     },
 };
 
 use core::future::Future;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SocketState {
+    Opened,
+    Connected,
+    Closed,
+}
+
 /// A Socket type for connecting to a network endpoint + sending and receiving data.
-#[derive(Clone, Copy)]
+///
+/// The socket owns its adapter handle. Once closed, reads and writes return
+/// `TcpError::SocketClosed` until the socket is connected again, which opens a new handle.
+/// Dropping a socket that was not closed queues a close request to the adapter actor.
 pub struct Socket<'a, A>
 where
     A: Adapter + 'static,
 {
     address: Address<'a, AdapterActor<A>>,
     handle: A::SocketHandle,
+    state: SocketState,
 }
 
 impl<'a, A> Socket<'a, A>
@@ -24,7 +37,34 @@ where
     A: Adapter + 'static,
 {
     pub fn new(address: Address<'a, AdapterActor<A>>, handle: A::SocketHandle) -> Socket<'a, A> {
-        Self { address, handle }
+        Self {
+            address,
+            handle,
+            state: SocketState::Opened,
+        }
+    }
+
+    /// Wrap a handle returned by `TcpServer::accept`, which is already connected.
+    pub fn accepted(
+        address: Address<'a, AdapterActor<A>>,
+        handle: A::SocketHandle,
+    ) -> Socket<'a, A> {
+        Self {
+            address,
+            handle,
+            state: SocketState::Connected,
+        }
+    }
+
+    pub fn state(&self) -> SocketState {
+        self.state
+    }
+
+    fn ensure_open(&self) -> Result<(), TcpError> {
+        match self.state {
+            SocketState::Closed => Err(TcpError::SocketClosed),
+            _ => Ok(()),
+        }
     }
 }
 
@@ -35,25 +75,90 @@ where
     #[rustfmt::skip]
     type ConnectFuture<'m> where 'a: 'm, A: 'm =  impl Future<Output = Result<(), TcpError>> + 'm;
     fn connect<'m>(&'m mut self, proto: IpProtocol, dst: SocketAddress) -> Self::ConnectFuture<'m> {
-        async move { self.address.connect(self.handle, proto, dst).await }
+        async move {
+            if self.state == SocketState::Closed {
+                self.handle = self.address.open().await?;
+                self.state = SocketState::Opened;
+            }
+            self.address.connect(self.handle, proto, dst).await?;
+            self.state = SocketState::Connected;
+            Ok(())
+        }
     }
 
     #[rustfmt::skip]
     type WriteFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
     fn write<'m>(&'m mut self, buf: &'m [u8]) -> Self::WriteFuture<'m> {
-        async move { self.address.write(self.handle, buf).await }
+        async move {
+            self.ensure_open()?;
+            self.address.write(self.handle, buf).await
+        }
     }
 
     #[rustfmt::skip]
     type ReadFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<usize, TcpError>> + 'm;
     fn read<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::ReadFuture<'m> {
-        async move { self.address.read(self.handle, buf).await }
+        async move {
+            self.ensure_open()?;
+            self.address.read(self.handle, buf).await
+        }
     }
 
     #[rustfmt::skip]
     type CloseFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = ()> + 'm;
     fn close<'m>(&'m mut self) -> Self::CloseFuture<'m> {
-        async move { self.address.close(self.handle).await }
+        async move {
+            if self.state != SocketState::Closed {
+                self.state = SocketState::Closed;
+                self.address.close(self.handle).await
+            }
+        }
+    }
+}
+
+/// Datagram access to a socket connected with `IpProtocol::Udp`.
+impl<'a, A> UdpSocket for Socket<'a, A>
+where
+    A: Adapter + 'static,
+{
+    #[rustfmt::skip]
+    type SendFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<(), UdpError>> + 'm;
+    fn send<'m>(&'m mut self, buf: &'m [u8]) -> Self::SendFuture<'m> {
+        async move {
+            self.ensure_open().map_err(|_| UdpError::SocketClosed)?;
+            match self.address.write(self.handle, buf).await {
+                Ok(_) => Ok(()),
+                Err(TcpError::SocketClosed) => Err(UdpError::SocketClosed),
+                Err(_) => Err(UdpError::SendError),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type RecvFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<usize, UdpError>> + 'm;
+    fn recv<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::RecvFuture<'m> {
+        async move {
+            self.ensure_open().map_err(|_| UdpError::SocketClosed)?;
+            match self.address.read(self.handle, buf).await {
+                Ok(len) => Ok(len),
+                Err(TcpError::SocketClosed) => Err(UdpError::SocketClosed),
+                Err(_) => Err(UdpError::RecvError),
+            }
+        }
+    }
+}
+
+impl<'a, A> Drop for Socket<'a, A>
+where
+    A: Adapter + 'static,
+{
+    fn drop(&mut self) {
+        if self.state != SocketState::Closed {
+            self.state = SocketState::Closed;
+            if let Err(e) = self.address.notify(AdapterRequest::Close(self.handle)) {
+                warn!("Unable to queue close of dropped socket: {:?}", e);
+            }
+        }
     }
 }
 
@@ -111,6 +216,34 @@ mod tls {
         Connected(TlsConnection<'a, RNG, S, CipherSuite>),
     }
 
+    /// Configuration for a `TlsSocket`.
+    ///
+    /// Only the server name is configurable. The TLS backend currently pinned neither
+    /// validates server certificates against a trust anchor, nor checks them against the
+    /// server name, nor supports client certificates, so none of these are offered here.
+    /// Once the backend supports them, they belong in this configuration, with failed
+    /// verification reported as a distinct `TcpError`.
+    #[derive(Clone, Copy, Default)]
+    pub struct TlsConfig<'a> {
+        server_name: Option<&'a str>,
+    }
+
+    impl<'a> TlsConfig<'a> {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Server name sent using SNI.
+        pub fn with_server_name(mut self, server_name: &'a str) -> Self {
+            self.server_name.replace(server_name);
+            self
+        }
+    }
+
+    /// A `TcpSocket` encrypting the traffic of another with TLS.
+    ///
+    /// The server certificate is not verified, so the connection is protected against
+    /// eavesdropping but not against an active attacker impersonating the server.
     pub struct TlsSocket<'a, S, RNG, CipherSuite>
     where
         S: TcpSocket + AsyncWrite + AsyncRead + 'static,
@@ -127,6 +260,19 @@ mod tls {
         CipherSuite: TlsCipherSuite + 'static,
     {
         pub fn wrap(socket: S, context: TlsContext<'a, CipherSuite, RNG>) -> Self {
+            Self::wrap_with_config(socket, context, TlsConfig::new())
+        }
+
+        /// Wrap a socket, applying the server name in `config`.
+        pub fn wrap_with_config(
+            socket: S,
+            context: TlsContext<'a, CipherSuite, RNG>,
+            config: TlsConfig<'a>,
+        ) -> Self {
+            let context = match config.server_name {
+                Some(server_name) => context.with_server_name(server_name),
+                None => context,
+            };
             Self {
                 state: Some(State::New(context, socket)),
             }
@@ -235,13 +381,18 @@ mod tls {
             async move {
                 match self.state.take() {
                     Some(State::Connected(session)) => match session.close().await {
-                        Ok((_, mut socket)) => {
+                        Ok((context, mut socket)) => {
                             socket.close().await;
+                            // Keep the context and socket so that the connection can be reopened
+                            self.state.replace(State::New(context, socket));
+                        }
+                        Err(e) => {
+                            info!("Error closing TLS connection: {:?}", e);
                         }
-                        _ => {}
                     },
-                    Some(State::New(_, mut socket)) => {
+                    Some(State::New(context, mut socket)) => {
                         socket.close().await;
+                        self.state.replace(State::New(context, socket));
                     }
                     None => {}
                 }
@@ -1,8 +1,9 @@
-use super::AdapterActor;
+use super::{AdapterActor, AdapterResponse};
 use crate::drivers::wifi::esp8266::*;
 use crate::kernel::{
     actor::{Actor, ActorContext, ActorSpawner, Address},
     package::*,
+    signal::SignalSlot,
 };
 use core::{
     cell::{RefCell, UnsafeCell},
@@ -22,23 +23,35 @@ where
     Initialized,
 }
 
-pub struct Esp8266Wifi<UART, ENABLE, RESET>
+/// Package for an ESP8266 adapter, with `SOCKETS` sockets (at most 5) and an adapter actor queue
+/// of `QUEUE` requests.
+#[rustfmt::skip]
+pub struct Esp8266Wifi<
+    UART,
+    ENABLE,
+    RESET,
+    const SOCKETS: usize = 5,
+    const QUEUE: usize = 4,
+>
 where
     UART: AsyncBufReadExt + AsyncWriteExt + 'static,
     ENABLE: OutputPin + 'static,
     RESET: OutputPin + 'static,
+    [SignalSlot<AdapterResponse>; QUEUE]: Default,
 {
-    driver: UnsafeCell<Esp8266Driver>,
+    driver: UnsafeCell<Esp8266Driver<SOCKETS>>,
     state: RefCell<Option<State<UART, ENABLE, RESET>>>,
-    wifi: ActorContext<'static, AdapterActor<Esp8266Controller<'static>>, 4>,
+    wifi: ActorContext<'static, AdapterActor<Esp8266Controller<'static, SOCKETS>>, QUEUE>,
     modem: ActorContext<'static, ModemActor<'static, UART, ENABLE, RESET>>,
 }
 
-impl<UART, ENABLE, RESET> Esp8266Wifi<UART, ENABLE, RESET>
+impl<UART, ENABLE, RESET, const SOCKETS: usize, const QUEUE: usize>
+    Esp8266Wifi<UART, ENABLE, RESET, SOCKETS, QUEUE>
 where
     UART: AsyncBufReadExt + AsyncWriteExt + 'static,
     ENABLE: OutputPin + 'static,
     RESET: OutputPin + 'static,
+    [SignalSlot<AdapterResponse>; QUEUE]: Default,
 {
     pub fn new(uart: UART, enable: ENABLE, reset: RESET) -> Self {
         Self {
@@ -48,15 +61,22 @@ where
             modem: ActorContext::new(ModemActor::new()),
         }
     }
+
+    /// The firmware detected on the modem, once initialized.
+    pub fn firmware(&self) -> Option<Firmware> {
+        unsafe { &*self.driver.get() }.firmware()
+    }
 }
 
-impl<UART, ENABLE, RESET> Package for Esp8266Wifi<UART, ENABLE, RESET>
+impl<UART, ENABLE, RESET, const SOCKETS: usize, const QUEUE: usize> Package
+    for Esp8266Wifi<UART, ENABLE, RESET, SOCKETS, QUEUE>
 where
     UART: AsyncBufReadExt + AsyncWriteExt + 'static,
     ENABLE: OutputPin + 'static,
     RESET: OutputPin + 'static,
+    [SignalSlot<AdapterResponse>; QUEUE]: Default,
 {
-    type Primary = AdapterActor<Esp8266Controller<'static>>;
+    type Primary = AdapterActor<Esp8266Controller<'static, SOCKETS>>;
 
     fn mount<S: ActorSpawner>(
         &'static self,
@@ -132,4 +152,8 @@ where
     }
 }
 
-impl<'a> super::Adapter for Esp8266Controller<'a> {}
+impl<'a, const SOCKETS: usize> super::Adapter for Esp8266Controller<'a, SOCKETS> {
+    type Server = super::AccessPointServer;
+}
+
+impl<'a, const SOCKETS: usize> super::ServerAdapter for Esp8266Controller<'a, SOCKETS> {}
@@ -0,0 +1,319 @@
+use super::{Adapter, AdapterActor};
+use crate::{
+    kernel::{
+        actor::{Actor, Address},
+        util::ImmediateFuture,
+    },
+    traits::wifi::{Join, JoinError, LinkState, WifiSupplicant},
+};
+use core::future::Future;
+use core::pin::Pin;
+use embassy::time::{Duration, Instant, Timer};
+use heapless::{consts, String};
+
+/// How often the link state is checked.
+const SUPERVISE_INTERVAL_MS: u64 = 1000;
+
+/// Delay before the first rejoin attempt, doubled after each failed attempt.
+const MIN_BACKOFF_SECS: u64 = 1;
+const MAX_BACKOFF_SECS: u64 = 64;
+
+/// Trait for actors that want to be notified about link state changes.
+pub trait FromLinkState<M> {
+    fn from(state: LinkState) -> Option<M>
+    where
+        Self: Sized;
+}
+
+/// Credentials used to join an access point.
+#[derive(Clone)]
+pub struct Credentials {
+    ssid: String<consts::U32>,
+    password: String<consts::U64>,
+}
+
+impl Credentials {
+    pub fn wpa(ssid: &str, password: &str) -> Result<Self, JoinError> {
+        let mut credentials = Self {
+            ssid: String::new(),
+            password: String::new(),
+        };
+        if ssid.is_empty() {
+            return Err(JoinError::InvalidSsid);
+        }
+        credentials
+            .ssid
+            .push_str(ssid)
+            .map_err(|_| JoinError::InvalidSsid)?;
+        credentials
+            .password
+            .push_str(password)
+            .map_err(|_| JoinError::InvalidPassword)?;
+        Ok(credentials)
+    }
+
+    pub fn ssid(&self) -> &str {
+        self.ssid.as_str()
+    }
+
+    pub fn password(&self) -> &str {
+        self.password.as_str()
+    }
+
+    fn join(&self) -> Join<'_> {
+        Join::Wpa {
+            ssid: self.ssid.as_str(),
+            password: self.password.as_str(),
+        }
+    }
+}
+
+pub enum ConnectionManagerRequest<'a, H>
+where
+    H: Actor + 'static,
+{
+    /// Check the link and rejoin if needed. Sent periodically by a `ConnectionSupervisor`.
+    Supervise,
+    /// Notify an actor of all future link state changes.
+    Subscribe(Address<'a, H>),
+    /// Replace the credentials and rejoin using them.
+    SetCredentials(Credentials),
+    /// Query the current link state.
+    LinkState,
+}
+
+/// Keeps an adapter joined to an access point.
+///
+/// The manager checks the link on each `Supervise` request, joining when it is down and
+/// backing off exponentially after failed attempts. The requests are sent every second by a
+/// `ConnectionSupervisor` mounted with the address of the manager, so that supervision never
+/// holds up other requests. Link state changes are published to subscribed actors. All
+/// requests respond with the current link state.
+#[rustfmt::skip]
+pub struct ConnectionManager<'a, A, H, const N: usize = 2>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    credentials: Option<Credentials>,
+    adapter: Option<Address<'a, AdapterActor<A>>>,
+    subscribers: [Option<Address<'a, H>>; N],
+    state: LinkState,
+    rejoin: bool,
+    backoff_secs: u64,
+    next_attempt: Option<Instant>,
+}
+
+impl<'a, A, H, const N: usize> ConnectionManager<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        Self {
+            credentials,
+            adapter: None,
+            subscribers: [None; N],
+            state: LinkState::Down,
+            rejoin: false,
+            backoff_secs: MIN_BACKOFF_SECS,
+            next_attempt: None,
+        }
+    }
+
+    async fn supervise(&mut self) {
+        let mut adapter = self.adapter.unwrap();
+        let mut state = adapter.link_state().await;
+
+        if state == LinkState::Down || self.rejoin {
+            let due = self
+                .next_attempt
+                .map(|at| Instant::now() >= at)
+                .unwrap_or(true);
+            if let (true, Some(credentials)) = (due, &self.credentials) {
+                info!("Joining {}", credentials.ssid());
+                match adapter.join(credentials.join()).await {
+                    Ok(ip) => {
+                        info!("Joined with IP {}", ip);
+                        self.backoff_secs = MIN_BACKOFF_SECS;
+                        self.next_attempt.take();
+                        self.rejoin = false;
+                        state = LinkState::Up;
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Error joining, retrying in {} seconds: {:?}",
+                            self.backoff_secs, e
+                        );
+                        self.next_attempt
+                            .replace(Instant::now() + Duration::from_secs(self.backoff_secs));
+                        self.backoff_secs = core::cmp::min(self.backoff_secs * 2, MAX_BACKOFF_SECS);
+                        state = adapter.link_state().await;
+                    }
+                }
+            }
+        }
+        self.publish(state);
+    }
+
+    fn publish(&mut self, state: LinkState) {
+        if state == self.state {
+            return;
+        }
+        info!("Link state changed to {:?}", state);
+        self.state = state;
+        for subscriber in self.subscribers.iter().flatten() {
+            if let Some(m) = H::from(state) {
+                let _ = subscriber.notify(m);
+            }
+        }
+    }
+}
+
+impl<'a, A, H, const N: usize> Unpin for ConnectionManager<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+}
+
+impl<'a, A, H, const N: usize> Actor for ConnectionManager<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    type Configuration = Address<'a, AdapterActor<A>>;
+    #[rustfmt::skip]
+    type Message<'m> where 'a: 'm = ConnectionManagerRequest<'a, H>;
+    type Response = LinkState;
+
+    fn on_mount(&mut self, _: Address<'a, Self>, config: Self::Configuration) {
+        self.adapter.replace(config);
+    }
+
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = ImmediateFuture;
+    fn on_start(self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        ImmediateFuture::new()
+    }
+
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = impl Future<Output = Self::Response> + 'm;
+    fn on_message<'m>(
+        self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move {
+            let this = self.get_mut();
+            match message {
+                ConnectionManagerRequest::Supervise => this.supervise().await,
+                ConnectionManagerRequest::Subscribe(address) => {
+                    match this.subscribers.iter_mut().find(|s| s.is_none()) {
+                        Some(slot) => {
+                            slot.replace(address);
+                        }
+                        None => warn!("No room for more link state subscribers"),
+                    }
+                }
+                ConnectionManagerRequest::SetCredentials(credentials) => {
+                    this.credentials.replace(credentials);
+                    this.rejoin = true;
+                    this.backoff_secs = MIN_BACKOFF_SECS;
+                    this.next_attempt.take();
+                }
+                ConnectionManagerRequest::LinkState => {}
+            }
+            this.state
+        }
+    }
+}
+
+/// Drives a `ConnectionManager`, requesting it to supervise the link every second.
+///
+/// Each supervision is awaited before waiting for the next, so the requests never pile up in
+/// the queue of the manager.
+#[rustfmt::skip]
+pub struct ConnectionSupervisor<'a, A, H, const N: usize = 2>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    manager: Option<Address<'a, ConnectionManager<'a, A, H, N>>>,
+}
+
+impl<'a, A, H, const N: usize> ConnectionSupervisor<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    pub fn new() -> Self {
+        Self { manager: None }
+    }
+}
+
+impl<'a, A, H, const N: usize> Unpin for ConnectionSupervisor<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+}
+
+impl<'a, A, H, const N: usize> Actor for ConnectionSupervisor<'a, A, H, N>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    type Configuration = Address<'a, ConnectionManager<'a, A, H, N>>;
+
+    fn on_mount(&mut self, _: Address<'a, Self>, config: Self::Configuration) {
+        self.manager.replace(config);
+    }
+
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn on_start(self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        async move {
+            let manager = self.manager.unwrap();
+            loop {
+                // A full queue means the manager is busy, so simply try again next time
+                if let Ok(response) = manager.request(ConnectionManagerRequest::Supervise) {
+                    response.await;
+                }
+                Timer::after(Duration::from_millis(SUPERVISE_INTERVAL_MS)).await;
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = ImmediateFuture;
+    fn on_message<'m>(self: Pin<&'m mut Self>, _: Self::Message<'m>) -> Self::OnMessageFuture<'m> {
+        ImmediateFuture::new()
+    }
+}
+
+impl<'a, A, H, const N: usize> Address<'a, ConnectionManager<'a, A, H, N>>
+where
+    A: Adapter + 'static,
+    H: Actor + FromLinkState<H::Message<'a>> + 'static,
+{
+    /// Wait until the link is up, e.g. before opening sockets.
+    pub async fn wait_link_up(&self) {
+        loop {
+            if let Ok(response) = self.request(ConnectionManagerRequest::LinkState) {
+                if response.await == LinkState::Up {
+                    return;
+                }
+            }
+            Timer::after(Duration::from_millis(SUPERVISE_INTERVAL_MS)).await;
+        }
+    }
+
+    pub async fn link_state(&self) -> LinkState {
+        loop {
+            if let Ok(response) = self.request(ConnectionManagerRequest::LinkState) {
+                return response.await;
+            }
+            Timer::after(Duration::from_millis(SUPERVISE_INTERVAL_MS)).await;
+        }
+    }
+}
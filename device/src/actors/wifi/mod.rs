@@ -2,20 +2,34 @@ use crate::{
     kernel::actor::{Actor, Address},
     traits::{
         ip::{IpAddress, IpProtocol, SocketAddress},
-        tcp::{TcpError, TcpStack},
-        wifi::{Join, JoinError, WifiSupplicant},
+        tcp::{TcpError, TcpServer, TcpStack},
+        wifi::{
+            AccessPoint, AccessPointConfig, AccessPointError, Join, JoinError, JoinOptions,
+            LinkInfo, LinkInfoError, LinkState, ScanError, WifiAccessPoint, WifiSupplicant,
+        },
     },
 };
 
 use core::future::Future;
 use core::pin::Pin;
+use futures::future::{ready, Ready};
 
 #[cfg(feature = "wifi+esp8266")]
 pub mod esp8266;
 
+#[cfg(feature = "time")]
+pub mod manager;
+
+#[cfg(feature = "time")]
+pub mod provisioning;
+
 /// Actor messages handled by network adapter actors
 pub enum AdapterRequest<'m> {
-    Join(Join<'m>),
+    Join(Join<'m>, JoinOptions),
+    LinkState,
+    LinkInfo,
+    Scan(&'m mut [AccessPoint]),
+    Server(ServerRequest<'m>),
     Open,
     Connect(u8, IpProtocol, SocketAddress),
     Write(u8, &'m [u8]),
@@ -23,17 +37,98 @@ pub enum AdapterRequest<'m> {
     Close(u8),
 }
 
+/// Access point and server requests, handled by the `ServerHandler` of the adapter.
+pub enum ServerRequest<'m> {
+    StartAccessPoint(AccessPointConfig<'m>),
+    StopAccessPoint,
+    Listen(u16),
+    Accept,
+    StopListening,
+}
+
 /// Actor responses returned by network adapter actors
 pub enum AdapterResponse {
     Join(Result<IpAddress, JoinError>),
-    Open(u8),
+    LinkState(LinkState),
+    LinkInfo(Result<LinkInfo, LinkInfoError>),
+    Scan(Result<usize, ScanError>),
+    StartAccessPoint(Result<IpAddress, AccessPointError>),
+    StopAccessPoint,
+    Open(Result<u8, TcpError>),
     Connect(Result<(), TcpError>),
     Write(Result<usize, TcpError>),
     Read(Result<usize, TcpError>),
     Close,
+    Listen(Result<(), TcpError>),
+    Accept(Result<Option<u8>, TcpError>),
+    StopListening,
+}
+
+pub trait Adapter: WifiSupplicant + TcpStack<SocketHandle = u8> {
+    /// Handler of access point and server requests. Adapters implementing `ServerAdapter` use
+    /// `AccessPointServer`, other adapters keep `NoServer`.
+    type Server: ServerHandler<Self> = NoServer;
+}
+
+/// Adapter able to run an access point and accept inbound connections.
+pub trait ServerAdapter: Adapter<Server = AccessPointServer> + WifiAccessPoint + TcpServer {}
+
+/// Handles the access point and server requests sent to an adapter `A`.
+pub trait ServerHandler<A: ?Sized> {
+    type ServeFuture<'m>: Future<Output = AdapterResponse>
+    where
+        A: 'm;
+    fn serve<'m>(adapter: &'m mut A, request: ServerRequest<'m>) -> Self::ServeFuture<'m>;
 }
 
-pub trait Adapter: WifiSupplicant + TcpStack<SocketHandle = u8> {}
+/// Server handler of adapters without access point and server support, failing every request.
+pub struct NoServer;
+
+impl<A: ?Sized> ServerHandler<A> for NoServer {
+    #[rustfmt::skip]
+    type ServeFuture<'m> where A: 'm = Ready<AdapterResponse>;
+    fn serve<'m>(_: &'m mut A, request: ServerRequest<'m>) -> Self::ServeFuture<'m> {
+        ready(match request {
+            ServerRequest::StartAccessPoint(_) => {
+                AdapterResponse::StartAccessPoint(Err(AccessPointError::Unknown))
+            }
+            ServerRequest::StopAccessPoint => AdapterResponse::StopAccessPoint,
+            ServerRequest::Listen(_) => AdapterResponse::Listen(Err(TcpError::IoError)),
+            ServerRequest::Accept => AdapterResponse::Accept(Err(TcpError::IoError)),
+            ServerRequest::StopListening => AdapterResponse::StopListening,
+        })
+    }
+}
+
+/// Server handler passing requests on to the access point and server traits of the adapter.
+pub struct AccessPointServer;
+
+impl<A> ServerHandler<A> for AccessPointServer
+where
+    A: WifiAccessPoint + TcpServer<SocketHandle = u8>,
+{
+    #[rustfmt::skip]
+    type ServeFuture<'m> where A: 'm = impl Future<Output = AdapterResponse> + 'm;
+    fn serve<'m>(adapter: &'m mut A, request: ServerRequest<'m>) -> Self::ServeFuture<'m> {
+        async move {
+            match request {
+                ServerRequest::StartAccessPoint(config) => {
+                    AdapterResponse::StartAccessPoint(adapter.start_access_point(config).await)
+                }
+                ServerRequest::StopAccessPoint => {
+                    adapter.stop_access_point().await;
+                    AdapterResponse::StopAccessPoint
+                }
+                ServerRequest::Listen(port) => AdapterResponse::Listen(adapter.listen(port).await),
+                ServerRequest::Accept => AdapterResponse::Accept(adapter.accept().await),
+                ServerRequest::StopListening => {
+                    adapter.stop_listening().await;
+                    AdapterResponse::StopListening
+                }
+            }
+        }
+    }
+}
 
 impl<'a, A> WifiSupplicant for Address<'a, AdapterActor<A>>
 where
@@ -41,14 +136,83 @@ where
 {
     #[rustfmt::skip]
     type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
-    fn join<'m>(&'m mut self, join: Join<'m>) -> Self::JoinFuture<'m> {
+    fn join_with_options<'m>(
+        &'m mut self,
+        join: Join<'m>,
+        options: JoinOptions,
+    ) -> Self::JoinFuture<'m> {
         async move {
-            self.request(AdapterRequest::Join(join))
+            self.request(AdapterRequest::Join(join, options))
                 .unwrap()
                 .await
                 .join()
         }
     }
+
+    #[rustfmt::skip]
+    type LinkStateFuture<'m> where 'a: 'm = impl Future<Output = LinkState> + 'm;
+    fn link_state<'m>(&'m mut self) -> Self::LinkStateFuture<'m> {
+        async move {
+            self.request(AdapterRequest::LinkState)
+                .unwrap()
+                .await
+                .link_state()
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkInfoFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkInfo, LinkInfoError>> + 'm;
+    fn link_info<'m>(&'m mut self) -> Self::LinkInfoFuture<'m> {
+        async move {
+            self.request(AdapterRequest::LinkInfo)
+                .unwrap()
+                .await
+                .link_info()
+        }
+    }
+
+    #[rustfmt::skip]
+    type ScanFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, ScanError>> + 'm;
+    fn scan<'m>(&'m mut self, results: &'m mut [AccessPoint]) -> Self::ScanFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Scan(results))
+                .unwrap()
+                .await
+                .scan()
+        }
+    }
+}
+
+impl<'a, A> WifiAccessPoint for Address<'a, AdapterActor<A>>
+where
+    A: ServerAdapter + 'static,
+{
+    #[rustfmt::skip]
+    type StartAccessPointFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, AccessPointError>> + 'm;
+    fn start_access_point<'m>(
+        &'m mut self,
+        config: AccessPointConfig<'m>,
+    ) -> Self::StartAccessPointFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Server(ServerRequest::StartAccessPoint(
+                config,
+            )))
+            .unwrap()
+            .await
+            .start_access_point()
+        }
+    }
+
+    #[rustfmt::skip]
+    type StopAccessPointFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn stop_access_point<'m>(&'m mut self) -> Self::StopAccessPointFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Server(ServerRequest::StopAccessPoint))
+                .unwrap()
+                .await
+                .stop_access_point()
+        }
+    }
 }
 
 impl<'a, A> TcpStack for Address<'a, AdapterActor<A>>
@@ -58,7 +222,7 @@ where
     type SocketHandle = A::SocketHandle;
 
     #[rustfmt::skip]
-    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
+    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Result<Self::SocketHandle, TcpError>> + 'm;
     fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
         async move { self.request(AdapterRequest::Open).unwrap().await.open() }
     }
@@ -114,10 +278,48 @@ where
     }
 }
 
+impl<'a, A> TcpServer for Address<'a, AdapterActor<A>>
+where
+    A: ServerAdapter + 'static,
+{
+    #[rustfmt::skip]
+    type ListenFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn listen<'m>(&'m mut self, port: u16) -> Self::ListenFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Server(ServerRequest::Listen(port)))
+                .unwrap()
+                .await
+                .listen()
+        }
+    }
+
+    #[rustfmt::skip]
+    type AcceptFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = Result<Option<Self::SocketHandle>, TcpError>> + 'm;
+    fn accept<'m>(&'m mut self) -> Self::AcceptFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Server(ServerRequest::Accept))
+                .unwrap()
+                .await
+                .accept()
+        }
+    }
+
+    #[rustfmt::skip]
+    type StopListeningFuture<'m> where 'a: 'm, A: 'm = impl Future<Output = ()> + 'm;
+    fn stop_listening<'m>(&'m mut self) -> Self::StopListeningFuture<'m> {
+        async move {
+            self.request(AdapterRequest::Server(ServerRequest::StopListening))
+                .unwrap()
+                .await
+                .stop_listening()
+        }
+    }
+}
+
 impl AdapterResponse {
-    fn open(self) -> u8 {
+    fn open(self) -> Result<u8, TcpError> {
         match self {
-            AdapterResponse::Open(handle) => handle,
+            AdapterResponse::Open(result) => result,
             _ => panic!("unexpected response type"),
         }
     }
@@ -129,6 +331,41 @@ impl AdapterResponse {
         }
     }
 
+    fn link_state(self) -> LinkState {
+        match self {
+            AdapterResponse::LinkState(state) => state,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn link_info(self) -> Result<LinkInfo, LinkInfoError> {
+        match self {
+            AdapterResponse::LinkInfo(result) => result,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn scan(self) -> Result<usize, ScanError> {
+        match self {
+            AdapterResponse::Scan(result) => result,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn start_access_point(self) -> Result<IpAddress, AccessPointError> {
+        match self {
+            AdapterResponse::StartAccessPoint(result) => result,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn stop_access_point(self) {
+        match self {
+            AdapterResponse::StopAccessPoint => (),
+            _ => panic!("unexpected response type"),
+        }
+    }
+
     fn connect(self) -> Result<(), TcpError> {
         match self {
             AdapterResponse::Connect(result) => result,
@@ -156,6 +393,27 @@ impl AdapterResponse {
             _ => panic!("unexpected response type"),
         }
     }
+
+    fn listen(self) -> Result<(), TcpError> {
+        match self {
+            AdapterResponse::Listen(result) => result,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn accept(self) -> Result<Option<u8>, TcpError> {
+        match self {
+            AdapterResponse::Accept(result) => result,
+            _ => panic!("unexpected response type"),
+        }
+    }
+
+    fn stop_listening(self) {
+        match self {
+            AdapterResponse::StopListening => (),
+            _ => panic!("unexpected response type"),
+        }
+    }
 }
 
 pub struct AdapterActor<N: Adapter> {
@@ -195,7 +453,15 @@ impl<N: Adapter> Actor for AdapterActor<N> {
             let this = unsafe { self.get_unchecked_mut() };
             let driver = this.driver.as_mut().unwrap();
             match message {
-                AdapterRequest::Join(join) => AdapterResponse::Join(driver.join(join).await),
+                AdapterRequest::Join(join, options) => {
+                    AdapterResponse::Join(driver.join_with_options(join, options).await)
+                }
+                AdapterRequest::LinkState => AdapterResponse::LinkState(driver.link_state().await),
+                AdapterRequest::LinkInfo => AdapterResponse::LinkInfo(driver.link_info().await),
+                AdapterRequest::Scan(results) => AdapterResponse::Scan(driver.scan(results).await),
+                AdapterRequest::Server(request) => {
+                    <N::Server as ServerHandler<N>>::serve(driver, request).await
+                }
                 AdapterRequest::Open => AdapterResponse::Open(driver.open().await),
                 AdapterRequest::Connect(handle, proto, addr) => {
                     AdapterResponse::Connect(driver.connect(handle, proto, addr).await)
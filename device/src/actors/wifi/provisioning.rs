@@ -0,0 +1,445 @@
+use super::{manager::Credentials, AdapterActor, ServerAdapter};
+use crate::{
+    actors::socket::Socket,
+    clients::http::{find, find_header},
+    kernel::{
+        actor::{Actor, ActorContext, ActorSpawner, Address},
+        package::Package,
+    },
+    traits::{
+        tcp::{TcpError, TcpServer, TcpSocket},
+        wifi::{AccessPoint, AccessPointConfig, AccessPointError, WifiAccessPoint, WifiSupplicant},
+    },
+};
+use core::fmt::Write;
+use core::future::Future;
+use core::pin::Pin;
+use embassy::time::{Duration, Timer};
+use heapless::{consts, String};
+
+/// Maximum number of scanned networks offered in the form.
+const MAX_NETWORKS: usize = 8;
+
+/// Maximum size of a request, including the submitted form.
+const REQUEST_LEN: usize = 1024;
+
+/// Largest write passed to the socket at once.
+const WRITE_LEN: usize = 128;
+
+/// Interval between checks for inbound connections.
+const ACCEPT_POLL_MS: u64 = 100;
+
+const READ_POLL_MS: u64 = 100;
+const READ_TIMEOUT_MS: u64 = 10_000;
+
+const FORM_HEAD: &str =
+    "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Wi-Fi setup</title></head><body><form method=\"post\" action=\"/\">\
+<p><label>Network <input name=\"ssid\" list=\"networks\" maxlength=\"32\" required></label></p>\
+<p><label>Password <input name=\"password\" type=\"password\" maxlength=\"64\"></label></p>\
+<p><button>Connect</button></p></form><datalist id=\"networks\">";
+const FORM_TAIL: &str = "</datalist></body></html>";
+const SAVED: &str = "<!DOCTYPE html><html><body><p>Saved, connecting.</p></body></html>";
+const INVALID: &str = "<!DOCTYPE html><html><body><p>Invalid network or password.</p>\
+<p><a href=\"/\">Back</a></p></body></html>";
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProvisioningError {
+    AccessPoint(AccessPointError),
+    Server(TcpError),
+}
+
+pub enum ProvisioningRequest {
+    /// Serve the form until credentials are submitted.
+    Provision,
+}
+
+/// Collects Wi-Fi credentials from the user.
+///
+/// On request, the provisioner scans for nearby networks, starts an access point and serves a
+/// form on it. Once credentials are submitted, the access point is stopped and the credentials
+/// are returned, e.g. to be passed on to a `ConnectionManager`.
+pub struct Provisioner<'a, A>
+where
+    A: ServerAdapter + 'static,
+{
+    ssid: &'a str,
+    password: &'a str,
+    channel: u8,
+    port: u16,
+    adapter: Option<Address<'a, AdapterActor<A>>>,
+}
+
+impl<'a, A> Provisioner<'a, A>
+where
+    A: ServerAdapter + 'static,
+{
+    /// Create a provisioner for an access point with the given SSID and password. An empty
+    /// password starts an open network.
+    pub fn new(ssid: &'a str, password: &'a str) -> Self {
+        Self {
+            ssid,
+            password,
+            channel: 1,
+            port: 80,
+            adapter: None,
+        }
+    }
+
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    async fn provision(&mut self) -> Result<Credentials, ProvisioningError> {
+        let mut adapter = self.adapter.unwrap();
+
+        let mut networks: [AccessPoint; MAX_NETWORKS] = Default::default();
+        let found = match adapter.scan(&mut networks[..]).await {
+            Ok(found) => found,
+            Err(e) => {
+                warn!("Unable to scan for networks: {:?}", e);
+                0
+            }
+        };
+
+        let ip = adapter
+            .start_access_point(AccessPointConfig {
+                ssid: self.ssid,
+                password: self.password,
+                channel: self.channel,
+            })
+            .await
+            .map_err(ProvisioningError::AccessPoint)?;
+        if let Err(e) = adapter.listen(self.port).await {
+            adapter.stop_access_point().await;
+            return Err(ProvisioningError::Server(e));
+        }
+        info!(
+            "Provisioning on network {} at {}:{}",
+            self.ssid, ip, self.port
+        );
+
+        let result = loop {
+            match adapter.accept().await {
+                Ok(None) => Timer::after(Duration::from_millis(ACCEPT_POLL_MS)).await,
+                Ok(Some(handle)) => {
+                    let mut socket = Socket::accepted(adapter, handle);
+                    let result = serve(&mut socket, &networks[..found]).await;
+                    socket.close().await;
+                    match result {
+                        Ok(Some(credentials)) => break Ok(credentials),
+                        Ok(None) => {}
+                        Err(e) => warn!("Error serving provisioning request: {:?}", e),
+                    }
+                }
+                Err(e) => break Err(ProvisioningError::Server(e)),
+            }
+        };
+
+        adapter.stop_listening().await;
+        adapter.stop_access_point().await;
+        result
+    }
+}
+
+impl<'a, A> Unpin for Provisioner<'a, A> where A: ServerAdapter + 'static {}
+
+impl<'a, A> Actor for Provisioner<'a, A>
+where
+    A: ServerAdapter + 'static,
+{
+    type Configuration = Address<'a, AdapterActor<A>>;
+    #[rustfmt::skip]
+    type Message<'m> where 'a: 'm = ProvisioningRequest;
+    type Response = Result<Credentials, ProvisioningError>;
+
+    fn on_mount(&mut self, _: Address<'static, Self>, config: Self::Configuration) {
+        self.adapter.replace(config);
+    }
+
+    #[rustfmt::skip]
+    type OnStartFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn on_start(self: Pin<&mut Self>) -> Self::OnStartFuture<'_> {
+        async move {}
+    }
+
+    #[rustfmt::skip]
+    type OnMessageFuture<'m> where 'a: 'm = impl Future<Output = Self::Response> + 'm;
+    fn on_message<'m>(
+        self: Pin<&'m mut Self>,
+        message: Self::Message<'m>,
+    ) -> Self::OnMessageFuture<'m> {
+        async move {
+            let this = self.get_mut();
+            match message {
+                ProvisioningRequest::Provision => this.provision().await,
+            }
+        }
+    }
+}
+
+impl<'a, A> Address<'a, Provisioner<'a, A>>
+where
+    A: ServerAdapter + 'static,
+{
+    /// Serve the provisioning form and wait for credentials to be submitted.
+    pub async fn provision(&self) -> Result<Credentials, ProvisioningError> {
+        self.request(ProvisioningRequest::Provision).unwrap().await
+    }
+}
+
+/// Package running a `Provisioner` on top of a network adapter.
+pub struct WifiProvisioning<A>
+where
+    A: ServerAdapter + 'static,
+{
+    provisioner: ActorContext<'static, Provisioner<'static, A>>,
+}
+
+impl<A> WifiProvisioning<A>
+where
+    A: ServerAdapter + 'static,
+{
+    pub fn new(provisioner: Provisioner<'static, A>) -> Self {
+        Self {
+            provisioner: ActorContext::new(provisioner),
+        }
+    }
+}
+
+impl<A> Package for WifiProvisioning<A>
+where
+    A: ServerAdapter + 'static,
+{
+    type Primary = Provisioner<'static, A>;
+    type Configuration = Address<'static, AdapterActor<A>>;
+
+    fn mount<S: ActorSpawner>(
+        &'static self,
+        config: Self::Configuration,
+        spawner: S,
+    ) -> Address<Self::Primary> {
+        self.provisioner.mount(config, spawner)
+    }
+}
+
+/// Handle one request, returning the credentials if they were submitted.
+async fn serve<S>(socket: &mut S, networks: &[AccessPoint]) -> Result<Option<Credentials>, TcpError>
+where
+    S: TcpSocket,
+{
+    let mut buf = [0; REQUEST_LEN];
+    let len = read_request(socket, &mut buf).await?;
+    let request = &buf[..len];
+
+    let line_end = find(request, b"\r\n").unwrap_or(len);
+    let mut request_line = core::str::from_utf8(&request[..line_end])
+        .unwrap_or("")
+        .split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", "/") => {
+            write_all(socket, b"HTTP/1.1 200 OK\r\n").await?;
+            write_all(
+                socket,
+                b"Content-Type: text/html\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+            write_all(socket, FORM_HEAD.as_bytes()).await?;
+            for network in networks.iter().filter(|n| !n.ssid.is_empty()) {
+                let mut option: String<consts::U256> = String::new();
+                let _ = option.push_str("<option value=\"");
+                escape(network.ssid.as_str(), &mut option);
+                let _ = write!(option, "\">{} dBm</option>", network.rssi);
+                write_all(socket, option.as_bytes()).await?;
+            }
+            write_all(socket, FORM_TAIL.as_bytes()).await?;
+            Ok(None)
+        }
+        ("POST", "/") => {
+            let body = match find(request, b"\r\n\r\n") {
+                Some(i) => &request[i + 4..],
+                None => &[],
+            };
+            match parse_form(body) {
+                Some(credentials) => {
+                    respond(socket, "200 OK", SAVED).await?;
+                    Ok(Some(credentials))
+                }
+                None => {
+                    respond(socket, "400 Bad Request", INVALID).await?;
+                    Ok(None)
+                }
+            }
+        }
+        _ => {
+            respond(socket, "404 Not Found", "").await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Read a complete request into `buf`, returning its length.
+async fn read_request<S>(socket: &mut S, buf: &mut [u8]) -> Result<usize, TcpError>
+where
+    S: TcpSocket,
+{
+    let mut pos = 0;
+    let mut waited = 0;
+    loop {
+        if let Some(len) = request_length(&buf[..pos]) {
+            if pos >= len {
+                return Ok(len);
+            }
+        }
+        if pos == buf.len() {
+            return Err(TcpError::ReadError);
+        }
+        match socket.read(&mut buf[pos..]).await? {
+            0 => {
+                if waited >= READ_TIMEOUT_MS {
+                    return Err(TcpError::ReadError);
+                }
+                Timer::after(Duration::from_millis(READ_POLL_MS)).await;
+                waited += READ_POLL_MS;
+            }
+            len => pos += len,
+        }
+    }
+}
+
+/// Length of a request including its body, once the head has been received.
+fn request_length(data: &[u8]) -> Option<usize> {
+    let head_len = find(data, b"\r\n\r\n")? + 4;
+    let head = core::str::from_utf8(&data[..head_len]).ok()?;
+    let body_len = find_header(head, "Content-Length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    Some(head_len + body_len)
+}
+
+async fn respond<S>(socket: &mut S, status: &str, body: &str) -> Result<(), TcpError>
+where
+    S: TcpSocket,
+{
+    let mut head: String<consts::U128> = String::new();
+    let _ = write!(
+        head,
+        "HTTP/1.1 {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    write_all(socket, head.as_bytes()).await?;
+    write_all(socket, body.as_bytes()).await
+}
+
+async fn write_all<S>(socket: &mut S, mut data: &[u8]) -> Result<(), TcpError>
+where
+    S: TcpSocket,
+{
+    while !data.is_empty() {
+        let len = core::cmp::min(data.len(), WRITE_LEN);
+        let written = socket.write(&data[..len]).await?;
+        if written == 0 {
+            return Err(TcpError::WriteError);
+        }
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+fn escape<N>(value: &str, out: &mut String<N>)
+where
+    N: heapless::ArrayLength<u8>,
+{
+    for c in value.chars() {
+        let _ = match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c).map_err(|_| ()),
+        };
+    }
+}
+
+/// Parse an `application/x-www-form-urlencoded` body with `ssid` and `password` fields.
+fn parse_form(body: &[u8]) -> Option<Credentials> {
+    let body = core::str::from_utf8(body).ok()?;
+    let mut ssid: String<consts::U32> = String::new();
+    let mut password: String<consts::U64> = String::new();
+    for field in body.split('&') {
+        let mut kv = field.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("ssid"), Some(value)) => url_decode(value, &mut ssid)?,
+            (Some("password"), Some(value)) => url_decode(value, &mut password)?,
+            _ => {}
+        }
+    }
+    Credentials::wpa(ssid.as_str(), password.as_str()).ok()
+}
+
+fn url_decode<N>(value: &str, out: &mut String<N>) -> Option<()>
+where
+    N: heapless::ArrayLength<u8>,
+{
+    let mut decoded = [0; 64];
+    let mut len = 0;
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+        let b = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hi = (bytes.next()? as char).to_digit(16)?;
+                let lo = (bytes.next()? as char).to_digit(16)?;
+                (hi * 16 + lo) as u8
+            }
+            b => b,
+        };
+        *decoded.get_mut(len)? = b;
+        len += 1;
+    }
+    out.push_str(core::str::from_utf8(&decoded[..len]).ok()?)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_length_includes_body() {
+        assert_eq!(None, request_length(b"GET / HTTP/1.1\r\nHost: a\r\n"));
+        assert_eq!(Some(18), request_length(b"GET / HTTP/1.1\r\n\r\n"));
+        assert_eq!(
+            Some(40),
+            request_length(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn parse_submitted_form() {
+        let credentials = parse_form(b"ssid=My+Network%21&password=s3cr%26t").unwrap();
+        assert_eq!("My Network!", credentials.ssid());
+        assert_eq!("s3cr&t", credentials.password());
+
+        assert!(parse_form(b"ssid=&password=secret").is_none());
+        assert!(parse_form(b"ssid=net%2").is_none());
+    }
+
+    #[test]
+    fn escape_ssid() {
+        let mut out: String<consts::U64> = String::new();
+        escape("<a&\"b\">", &mut out);
+        assert_eq!("&lt;a&amp;&quot;b&quot;&gt;", out.as_str());
+    }
+}
@@ -0,0 +1,1458 @@
+//! A CoAP (RFC 7252) client and server working over any `UdpSocket`.
+//!
+//! The client sends confirmable requests with exponential back-off, accepts both
+//! piggybacked and separate responses, follows block-wise transfers (RFC 7959) in
+//! both directions and supports observing resources (RFC 7641). The server answers
+//! requests through a `RequestHandler`, splitting large representations into blocks
+//! and de-duplicating retransmitted requests.
+
+use crate::traits::udp::{UdpError, UdpSocket};
+use core::cmp::min;
+use embassy::time::{Duration, Instant, Timer};
+
+/// How often to poll the socket for data while waiting for a message.
+const POLL_INTERVAL_MS: u64 = 50;
+
+/// Initial retransmission timeout for confirmable messages.
+const ACK_TIMEOUT_MS: u64 = 2000;
+
+/// Maximum number of retransmissions of a confirmable message.
+const MAX_RETRANSMIT: u8 = 4;
+
+/// How long to wait for a separate response after the request was acknowledged.
+const MAX_TRANSMIT_WAIT_MS: u64 = 93_000;
+
+/// Default block size exponent, giving blocks of 16 << 4 = 256 bytes.
+const DEFAULT_BLOCK_SZX: u8 = 4;
+
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+/// Option numbers understood by the client and server.
+pub mod options {
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const URI_QUERY: u16 = 15;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying socket reported an error.
+    Network(UdpError),
+    /// A malformed message was received.
+    Codec,
+    /// A message did not fit in the provided buffers.
+    BufferTooSmall,
+    /// Options were not added in ascending order, or a value was too long.
+    InvalidOption,
+    /// The server did not respond in time.
+    Timeout,
+    /// The server rejected the request with a reset message.
+    Reset,
+    /// No resource is being observed.
+    NotObserving,
+}
+
+impl From<UdpError> for Error {
+    fn from(e: UdpError) -> Self {
+        Error::Network(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+}
+
+/// A request method or response code, encoded as `class << 5 | detail`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Code(pub u8);
+
+impl Code {
+    pub const EMPTY: Code = Code::new(0, 0);
+    pub const GET: Code = Code::new(0, 1);
+    pub const POST: Code = Code::new(0, 2);
+    pub const PUT: Code = Code::new(0, 3);
+    pub const DELETE: Code = Code::new(0, 4);
+
+    pub const CREATED: Code = Code::new(2, 1);
+    pub const DELETED: Code = Code::new(2, 2);
+    pub const VALID: Code = Code::new(2, 3);
+    pub const CHANGED: Code = Code::new(2, 4);
+    pub const CONTENT: Code = Code::new(2, 5);
+    pub const CONTINUE: Code = Code::new(2, 31);
+
+    pub const BAD_REQUEST: Code = Code::new(4, 0);
+    pub const BAD_OPTION: Code = Code::new(4, 2);
+    pub const NOT_FOUND: Code = Code::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: Code = Code::new(4, 5);
+    pub const REQUEST_ENTITY_INCOMPLETE: Code = Code::new(4, 8);
+    pub const REQUEST_ENTITY_TOO_LARGE: Code = Code::new(4, 13);
+
+    pub const INTERNAL_SERVER_ERROR: Code = Code::new(5, 0);
+    pub const NOT_IMPLEMENTED: Code = Code::new(5, 1);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Self((class << 5) | (detail & 0x1F))
+    }
+
+    pub fn class(&self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(&self) -> u8 {
+        self.0 & 0x1F
+    }
+
+    pub fn is_request(&self) -> bool {
+        self.class() == 0 && self.0 != 0
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.class() >= 2
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.class() == 2
+    }
+}
+
+/// The value of a Block1 or Block2 option.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Size exponent, the block size is `16 << szx` bytes.
+    pub szx: u8,
+}
+
+impl Block {
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    fn decode(value: &[u8]) -> Option<Self> {
+        let value = decode_uint(value)?;
+        let szx = (value & 0x07) as u8;
+        if szx == 7 {
+            return None;
+        }
+        Some(Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+
+    fn value(&self) -> u32 {
+        (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32
+    }
+}
+
+/// A decoded CoAP message, borrowing from the datagram it was decoded from.
+#[derive(Debug)]
+pub struct Message<'m> {
+    pub mtype: MessageType,
+    pub code: Code,
+    pub message_id: u16,
+    pub token: &'m [u8],
+    pub payload: &'m [u8],
+    options: &'m [u8],
+}
+
+impl<'m> Message<'m> {
+    pub fn decode(data: &'m [u8]) -> Result<Self, Error> {
+        if data.len() < 4 || data[0] >> 6 != 1 {
+            return Err(Error::Codec);
+        }
+        let token_len = (data[0] & 0x0F) as usize;
+        if token_len > 8 || data.len() < 4 + token_len {
+            return Err(Error::Codec);
+        }
+        let code = Code(data[1]);
+        if code == Code::EMPTY && data.len() != 4 {
+            return Err(Error::Codec);
+        }
+
+        let start = 4 + token_len;
+        let mut pos = start;
+        let mut number = 0;
+        while pos < data.len() && data[pos] != PAYLOAD_MARKER {
+            let (n, _, used) = decode_option(&data[pos..], number)?;
+            number = n;
+            pos += used;
+        }
+        let options = &data[start..pos];
+        let payload = if pos < data.len() {
+            // A payload marker must be followed by a non-empty payload
+            if pos + 1 == data.len() {
+                return Err(Error::Codec);
+            }
+            &data[pos + 1..]
+        } else {
+            &[]
+        };
+
+        Ok(Self {
+            mtype: MessageType::from_bits(data[0] >> 4),
+            code,
+            message_id: u16::from_be_bytes([data[2], data[3]]),
+            token: &data[4..start],
+            payload,
+            options,
+        })
+    }
+
+    /// All options of the message as `(number, value)` pairs, in ascending order.
+    pub fn options(&self) -> Options<'m> {
+        Options {
+            data: self.options,
+            number: 0,
+        }
+    }
+
+    /// The value of the first option with the given number.
+    pub fn option(&self, number: u16) -> Option<&'m [u8]> {
+        self.options()
+            .find(|(n, _)| *n == number)
+            .map(|(_, value)| value)
+    }
+
+    /// The Uri-Path segments of a request.
+    pub fn path(&self) -> impl Iterator<Item = &'m str> {
+        self.options()
+            .filter(|(n, _)| *n == options::URI_PATH)
+            .filter_map(|(_, value)| core::str::from_utf8(value).ok())
+    }
+
+    /// The Uri-Query arguments of a request.
+    pub fn query(&self) -> impl Iterator<Item = &'m str> {
+        self.options()
+            .filter(|(n, _)| *n == options::URI_QUERY)
+            .filter_map(|(_, value)| core::str::from_utf8(value).ok())
+    }
+
+    pub fn content_format(&self) -> Option<u16> {
+        self.option(options::CONTENT_FORMAT)
+            .and_then(decode_uint)
+            .map(|v| v as u16)
+    }
+
+    pub fn observe(&self) -> Option<u32> {
+        self.option(options::OBSERVE).and_then(decode_uint)
+    }
+
+    pub fn block1(&self) -> Option<Block> {
+        self.option(options::BLOCK1).and_then(Block::decode)
+    }
+
+    pub fn block2(&self) -> Option<Block> {
+        self.option(options::BLOCK2).and_then(Block::decode)
+    }
+}
+
+/// Iterator over the options of a `Message`.
+pub struct Options<'m> {
+    data: &'m [u8],
+    number: u16,
+}
+
+impl<'m> Iterator for Options<'m> {
+    type Item = (u16, &'m [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        // Options were validated when the message was decoded
+        let (number, value, used) = decode_option(self.data, self.number).ok()?;
+        self.number = number;
+        self.data = &self.data[used..];
+        Some((number, value))
+    }
+}
+
+fn decode_option(data: &[u8], previous: u16) -> Result<(u16, &[u8], usize), Error> {
+    let header = data[0];
+    let mut pos = 1;
+    let delta = decode_extended(header >> 4, data, &mut pos)?;
+    let len = decode_extended(header & 0x0F, data, &mut pos)?;
+    let number = previous as usize + delta;
+    if number > u16::MAX as usize || data.len() < pos + len {
+        return Err(Error::Codec);
+    }
+    Ok((number as u16, &data[pos..pos + len], pos + len))
+}
+
+fn decode_extended(nibble: u8, data: &[u8], pos: &mut usize) -> Result<usize, Error> {
+    match nibble {
+        0..=12 => Ok(nibble as usize),
+        13 => {
+            let value = *data.get(*pos).ok_or(Error::Codec)?;
+            *pos += 1;
+            Ok(value as usize + 13)
+        }
+        14 => {
+            if data.len() < *pos + 2 {
+                return Err(Error::Codec);
+            }
+            let value = u16::from_be_bytes([data[*pos], data[*pos + 1]]);
+            *pos += 2;
+            Ok(value as usize + 269)
+        }
+        _ => Err(Error::Codec),
+    }
+}
+
+fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Writes a CoAP message into a buffer. Options must be added in ascending order.
+pub struct Encoder<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+    number: u16,
+}
+
+impl<'b> Encoder<'b> {
+    pub fn new(
+        buf: &'b mut [u8],
+        mtype: MessageType,
+        code: Code,
+        message_id: u16,
+        token: &[u8],
+    ) -> Result<Self, Error> {
+        if token.len() > 8 {
+            return Err(Error::Codec);
+        }
+        let pos = 4 + token.len();
+        if buf.len() < pos {
+            return Err(Error::BufferTooSmall);
+        }
+        buf[0] = (1 << 6) | ((mtype as u8) << 4) | token.len() as u8;
+        buf[1] = code.0;
+        buf[2..4].copy_from_slice(&message_id.to_be_bytes());
+        buf[4..pos].copy_from_slice(token);
+        Ok(Self {
+            buf,
+            pos,
+            number: 0,
+        })
+    }
+
+    pub fn option(&mut self, number: u16, value: &[u8]) -> Result<(), Error> {
+        if number < self.number {
+            return Err(Error::InvalidOption);
+        }
+        let mut extended = [0; 4];
+        let mut extended_len = 0;
+        let delta = encode_extended(
+            (number - self.number) as usize,
+            &mut extended,
+            &mut extended_len,
+        )?;
+        let len = encode_extended(value.len(), &mut extended, &mut extended_len)?;
+
+        let total = 1 + extended_len + value.len();
+        if self.buf.len() < self.pos + total {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buf[self.pos] = (delta << 4) | len;
+        self.buf[self.pos + 1..self.pos + 1 + extended_len]
+            .copy_from_slice(&extended[..extended_len]);
+        self.buf[self.pos + 1 + extended_len..self.pos + total].copy_from_slice(value);
+        self.pos += total;
+        self.number = number;
+        Ok(())
+    }
+
+    /// Add an option with an unsigned integer value, using the shortest encoding.
+    pub fn uint_option(&mut self, number: u16, value: u32) -> Result<(), Error> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.option(number, &bytes[skip..])
+    }
+
+    /// Add a Uri-Path option for each segment of `path`.
+    pub fn uri_path(&mut self, path: &str) -> Result<(), Error> {
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            self.option(options::URI_PATH, segment.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Add a Uri-Query option for each `&`-separated argument of `query`.
+    pub fn uri_query(&mut self, query: &str) -> Result<(), Error> {
+        for argument in query.split('&').filter(|s| !s.is_empty()) {
+            self.option(options::URI_QUERY, argument.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Append the payload, returning the length of the encoded message.
+    pub fn payload(self, payload: &[u8]) -> Result<usize, Error> {
+        if payload.is_empty() {
+            return Ok(self.pos);
+        }
+        let end = self.pos + 1 + payload.len();
+        if self.buf.len() < end {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buf[self.pos] = PAYLOAD_MARKER;
+        self.buf[self.pos + 1..end].copy_from_slice(payload);
+        Ok(end)
+    }
+
+    /// Finish a message without payload, returning its length.
+    pub fn finish(self) -> usize {
+        self.pos
+    }
+}
+
+fn encode_extended(value: usize, extended: &mut [u8; 4], len: &mut usize) -> Result<u8, Error> {
+    if value < 13 {
+        Ok(value as u8)
+    } else if value < 269 {
+        extended[*len] = (value - 13) as u8;
+        *len += 1;
+        Ok(13)
+    } else if value < 269 + 65536 {
+        extended[*len..*len + 2].copy_from_slice(&((value - 269) as u16).to_be_bytes());
+        *len += 2;
+        Ok(14)
+    } else {
+        Err(Error::InvalidOption)
+    }
+}
+
+fn encode_empty(mtype: MessageType, message_id: u16) -> [u8; 4] {
+    let id = message_id.to_be_bytes();
+    [(1 << 6) | ((mtype as u8) << 4), 0, id[0], id[1]]
+}
+
+/// Retransmission timeout after `retransmissions` attempts, doubling each time.
+fn backoff(initial_ms: u64, retransmissions: u8) -> u64 {
+    initial_ms << retransmissions
+}
+
+/// A response received by the client. The payload is reassembled from all blocks
+/// when the server used a block-wise transfer.
+#[derive(Debug)]
+pub struct Response<'m> {
+    code: Code,
+    content_format: Option<u16>,
+    observe: Option<u32>,
+    payload: &'m [u8],
+}
+
+impl<'m> Response<'m> {
+    pub fn code(&self) -> Code {
+        self.code
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.code.is_success()
+    }
+
+    pub fn content_format(&self) -> Option<u16> {
+        self.content_format
+    }
+
+    /// The Observe sequence number, present when the resource is being observed.
+    pub fn observe(&self) -> Option<u32> {
+        self.observe
+    }
+
+    pub fn payload(&self) -> &'m [u8] {
+        self.payload
+    }
+}
+
+/// A request about to be sent by the client.
+struct Outgoing<'p> {
+    method: Code,
+    path: &'p str,
+    observe: Option<u32>,
+    content_format: Option<u16>,
+    block2: Option<Block>,
+    block1: Option<Block>,
+    payload: &'p [u8],
+}
+
+impl<'p> Outgoing<'p> {
+    fn encode(&self, buf: &mut [u8], message_id: u16, token: &[u8]) -> Result<usize, Error> {
+        let mut encoder = Encoder::new(
+            buf,
+            MessageType::Confirmable,
+            self.method,
+            message_id,
+            token,
+        )?;
+        let (path, query) = match self.path.find('?') {
+            Some(i) => (&self.path[..i], &self.path[i + 1..]),
+            None => (self.path, ""),
+        };
+        if let Some(observe) = self.observe {
+            encoder.uint_option(options::OBSERVE, observe)?;
+        }
+        encoder.uri_path(path)?;
+        if let Some(format) = self.content_format {
+            encoder.uint_option(options::CONTENT_FORMAT, format as u32)?;
+        }
+        encoder.uri_query(query)?;
+        if let Some(block) = self.block2 {
+            encoder.uint_option(options::BLOCK2, block.value())?;
+        }
+        if let Some(block) = self.block1 {
+            encoder.uint_option(options::BLOCK1, block.value())?;
+        }
+        encoder.payload(self.payload)
+    }
+}
+
+/// What a received message means for an outstanding confirmable request.
+enum Outcome {
+    Ignore,
+    EmptyAck,
+    Reset,
+    /// The response, with the message id to acknowledge if it was confirmable.
+    Response(Option<u16>),
+}
+
+fn classify(message: &Message<'_>, message_id: u16, token: &[u8]) -> Outcome {
+    match message.mtype {
+        MessageType::Acknowledgement if message.message_id == message_id => {
+            if message.code == Code::EMPTY {
+                Outcome::EmptyAck
+            } else if message.token == token {
+                Outcome::Response(None)
+            } else {
+                Outcome::Ignore
+            }
+        }
+        MessageType::Reset if message.message_id == message_id => Outcome::Reset,
+        MessageType::Confirmable if message.token == token && message.code.is_response() => {
+            Outcome::Response(Some(message.message_id))
+        }
+        MessageType::NonConfirmable if message.token == token && message.code.is_response() => {
+            Outcome::Response(None)
+        }
+        _ => Outcome::Ignore,
+    }
+}
+
+#[rustfmt::skip]
+pub struct CoapClient<'a, S, const N: usize = 512>
+where
+    S: UdpSocket + 'static,
+{
+    socket: &'a mut S,
+    get_random: fn() -> u32,
+    message_id: u16,
+    token: u32,
+    block_szx: u8,
+    observation: Option<[u8; 4]>,
+    tx_buf: [u8; N],
+    rx_buf: [u8; N],
+}
+
+impl<'a, S, const N: usize> CoapClient<'a, S, N>
+where
+    S: UdpSocket + 'static,
+{
+    /// Create a client for a socket connected to the server. `get_random` seeds
+    /// message ids and tokens, and randomizes retransmission timeouts.
+    pub fn new(socket: &'a mut S, get_random: fn() -> u32) -> Self {
+        let seed = get_random();
+        Self {
+            socket,
+            get_random,
+            message_id: seed as u16,
+            token: get_random(),
+            block_szx: DEFAULT_BLOCK_SZX,
+            observation: None,
+            tx_buf: [0; N],
+            rx_buf: [0; N],
+        }
+    }
+
+    /// Block size used when uploading large payloads, as `16 << szx` bytes.
+    pub fn block_size(mut self, szx: u8) -> Self {
+        self.block_szx = min(szx, 6);
+        self
+    }
+
+    pub async fn get<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Code::GET, path, None, rx_buf).await
+    }
+
+    pub async fn post<'m>(
+        &mut self,
+        path: &str,
+        content_format: u16,
+        payload: &[u8],
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Code::POST, path, Some((content_format, payload)), rx_buf)
+            .await
+    }
+
+    pub async fn put<'m>(
+        &mut self,
+        path: &str,
+        content_format: u16,
+        payload: &[u8],
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Code::PUT, path, Some((content_format, payload)), rx_buf)
+            .await
+    }
+
+    pub async fn delete<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Code::DELETE, path, None, rx_buf).await
+    }
+
+    /// Send a confirmable request and wait for the response. `path` may contain a
+    /// `?`-separated query. A payload is given together with its content format.
+    pub async fn request<'m>(
+        &mut self,
+        method: Code,
+        path: &str,
+        payload: Option<(u16, &[u8])>,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        let token = self.next_token();
+        self.request_with(token, method, path, None, payload, rx_buf)
+            .await
+    }
+
+    /// Register as an observer of a resource. If the response carries an Observe
+    /// option, later notifications can be received with `notification`.
+    pub async fn observe<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        let token = self.next_token();
+        let response = self
+            .request_with(token, Code::GET, path, Some(0), None, rx_buf)
+            .await?;
+        if response.observe.is_some() {
+            self.observation.replace(token);
+        } else {
+            self.observation.take();
+        }
+        Ok(response)
+    }
+
+    /// Wait for the next notification of the observed resource. Only the first
+    /// block of a block-wise notification is returned.
+    pub async fn notification<'m>(&mut self, rx_buf: &'m mut [u8]) -> Result<Response<'m>, Error> {
+        let token = self.observation.ok_or(Error::NotObserving)?;
+        loop {
+            let len = self.socket.recv(&mut self.rx_buf).await?;
+            if len == 0 {
+                Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+                continue;
+            }
+
+            let (ack, code, content_format, observe, payload_len) = {
+                let message = match Message::decode(&self.rx_buf[..len]) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                if message.token != &token[..] || !message.code.is_response() {
+                    continue;
+                }
+                if message.payload.len() > rx_buf.len() {
+                    return Err(Error::BufferTooSmall);
+                }
+                rx_buf[..message.payload.len()].copy_from_slice(message.payload);
+                let ack = if message.mtype == MessageType::Confirmable {
+                    Some(message.message_id)
+                } else {
+                    None
+                };
+                (
+                    ack,
+                    message.code,
+                    message.content_format(),
+                    message.observe(),
+                    message.payload.len(),
+                )
+            };
+
+            if let Some(message_id) = ack {
+                self.send_empty(MessageType::Acknowledgement, message_id)
+                    .await?;
+            }
+            // A notification without Observe option ends the observation
+            if observe.is_none() {
+                self.observation.take();
+            }
+            return Ok(Response {
+                code,
+                content_format,
+                observe,
+                payload: &rx_buf[..payload_len],
+            });
+        }
+    }
+
+    /// Deregister from the observed resource.
+    pub async fn cancel_observation<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        let token = self.observation.take().ok_or(Error::NotObserving)?;
+        self.request_with(token, Code::GET, path, Some(1), None, rx_buf)
+            .await
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.observation.is_some()
+    }
+
+    async fn request_with<'m>(
+        &mut self,
+        token: [u8; 4],
+        method: Code,
+        path: &str,
+        observe: Option<u32>,
+        payload: Option<(u16, &[u8])>,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        let block_size = 16 << self.block_szx;
+
+        // Upload the payload, using Block1 when it does not fit in a single block
+        let mut num = 0;
+        let mut rx_len;
+        loop {
+            let (content_format, data) = match payload {
+                Some((format, data)) => (Some(format), data),
+                None => (None, &[][..]),
+            };
+            let (block1, chunk) = if data.len() > block_size {
+                let start = num * block_size;
+                let end = min(start + block_size, data.len());
+                let block = Block {
+                    num: num as u32,
+                    more: end < data.len(),
+                    szx: self.block_szx,
+                };
+                (Some(block), &data[start..end])
+            } else {
+                (None, data)
+            };
+            let request = Outgoing {
+                method,
+                path,
+                observe,
+                content_format,
+                block2: None,
+                block1,
+                payload: chunk,
+            };
+            rx_len = self.exchange(&token, &request).await?;
+            match block1 {
+                Some(block) if block.more => {
+                    if Message::decode(&self.rx_buf[..rx_len])?.code != Code::CONTINUE {
+                        break;
+                    }
+                    num += 1;
+                }
+                _ => break,
+            }
+        }
+
+        // Collect the response, following Block2 until the last block
+        let mut total = 0;
+        loop {
+            let (code, content_format, observe, block2) = {
+                let message = Message::decode(&self.rx_buf[..rx_len])?;
+                let block2 = message.block2();
+                let offset = block2.map(|b| b.num as usize * b.size()).unwrap_or(0);
+                if offset != total {
+                    return Err(Error::Codec);
+                }
+                let end = offset + message.payload.len();
+                if end > rx_buf.len() {
+                    return Err(Error::BufferTooSmall);
+                }
+                rx_buf[offset..end].copy_from_slice(message.payload);
+                total = end;
+                (
+                    message.code,
+                    message.content_format(),
+                    message.observe(),
+                    block2,
+                )
+            };
+
+            match block2 {
+                Some(block) if block.more && code.is_success() => {
+                    let request = Outgoing {
+                        method,
+                        path,
+                        observe: None,
+                        content_format: None,
+                        block2: Some(Block {
+                            num: block.num + 1,
+                            more: false,
+                            szx: block.szx,
+                        }),
+                        block1: None,
+                        payload: &[],
+                    };
+                    rx_len = self.exchange(&token, &request).await?;
+                }
+                _ => {
+                    return Ok(Response {
+                        code,
+                        content_format,
+                        observe,
+                        payload: &rx_buf[..total],
+                    })
+                }
+            }
+        }
+    }
+
+    /// Send a request as a confirmable message, retransmitting it until it is
+    /// acknowledged, and wait for the response. Returns the length of the response
+    /// in `rx_buf`.
+    async fn exchange(&mut self, token: &[u8], request: &Outgoing<'_>) -> Result<usize, Error> {
+        let message_id = self.next_message_id();
+        let len = request.encode(&mut self.tx_buf, message_id, token)?;
+
+        let initial = ACK_TIMEOUT_MS + (self.get_random)() as u64 % (ACK_TIMEOUT_MS / 2);
+        let mut timeout = initial;
+        let mut retransmissions = 0;
+        let mut acknowledged = false;
+        let mut deadline: Option<Instant> = None;
+
+        self.socket.send(&self.tx_buf[..len]).await?;
+        loop {
+            let rx_len = self.socket.recv(&mut self.rx_buf).await?;
+            if rx_len == 0 {
+                let now = Instant::now();
+                match deadline {
+                    None => {
+                        deadline.replace(now + Duration::from_millis(timeout));
+                    }
+                    Some(d) if now >= d => {
+                        if acknowledged || retransmissions >= MAX_RETRANSMIT {
+                            return Err(Error::Timeout);
+                        }
+                        retransmissions += 1;
+                        timeout = backoff(initial, retransmissions);
+                        trace!("Retransmitting CoAP message {}", message_id);
+                        self.socket.send(&self.tx_buf[..len]).await?;
+                        deadline.replace(now + Duration::from_millis(timeout));
+                    }
+                    _ => {}
+                }
+                Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+                continue;
+            }
+
+            let outcome = match Message::decode(&self.rx_buf[..rx_len]) {
+                Ok(message) => classify(&message, message_id, token),
+                Err(_) => Outcome::Ignore,
+            };
+            match outcome {
+                Outcome::Ignore => {}
+                Outcome::EmptyAck => {
+                    // The response will follow in a separate message
+                    acknowledged = true;
+                    timeout = MAX_TRANSMIT_WAIT_MS;
+                    deadline.take();
+                }
+                Outcome::Reset => return Err(Error::Reset),
+                Outcome::Response(ack) => {
+                    if let Some(id) = ack {
+                        self.send_empty(MessageType::Acknowledgement, id).await?;
+                    }
+                    return Ok(rx_len);
+                }
+            }
+        }
+    }
+
+    async fn send_empty(&mut self, mtype: MessageType, message_id: u16) -> Result<(), Error> {
+        let message = encode_empty(mtype, message_id);
+        self.socket.send(&message).await?;
+        Ok(())
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_token(&mut self) -> [u8; 4] {
+        self.token = self.token.wrapping_add(1);
+        self.token.to_be_bytes()
+    }
+}
+
+/// The outcome of handling a request on the server.
+#[derive(Debug, Clone, Copy)]
+pub struct Reply {
+    pub code: Code,
+    pub content_format: Option<u16>,
+    /// Observe sequence number, set when the request registered an observer.
+    pub observe: Option<u32>,
+    /// Length of the payload written by the handler.
+    pub len: usize,
+}
+
+impl Reply {
+    pub fn new(code: Code) -> Self {
+        Self {
+            code,
+            content_format: None,
+            observe: None,
+            len: 0,
+        }
+    }
+
+    /// A 2.05 Content reply with `len` bytes of payload.
+    pub fn content(content_format: u16, len: usize) -> Self {
+        Self {
+            code: Code::CONTENT,
+            content_format: Some(content_format),
+            observe: None,
+            len,
+        }
+    }
+
+    pub fn observe(mut self, sequence: u32) -> Self {
+        self.observe.replace(sequence);
+        self
+    }
+}
+
+/// Trait for handling requests received by a `CoapServer`.
+pub trait RequestHandler {
+    /// Handle a request, writing the complete response payload into `payload`.
+    /// Block-wise responses are split up by the server.
+    fn handle(&mut self, request: &Message<'_>, payload: &mut [u8]) -> Reply;
+}
+
+#[rustfmt::skip]
+pub struct CoapServer<const N: usize = 512> {
+    message_id: u16,
+    last_request: Option<u16>,
+    response_len: usize,
+    payload: [u8; N],
+    response: [u8; N],
+}
+
+impl<const N: usize> CoapServer<N> {
+    pub fn new() -> Self {
+        Self {
+            message_id: 0,
+            last_request: None,
+            response_len: 0,
+            payload: [0; N],
+            response: [0; N],
+        }
+    }
+
+    /// Handle a single datagram, returning the message to send back, if any.
+    pub fn handle<H: RequestHandler>(
+        &mut self,
+        datagram: &[u8],
+        handler: &mut H,
+    ) -> Result<Option<&[u8]>, Error> {
+        let request = Message::decode(datagram)?;
+        match request.mtype {
+            MessageType::Acknowledgement | MessageType::Reset => return Ok(None),
+            _ => {}
+        }
+
+        let confirmable = request.mtype == MessageType::Confirmable;
+        if request.code == Code::EMPTY {
+            // A confirmable empty message is a ping, answered with a reset
+            if !confirmable {
+                return Ok(None);
+            }
+            let reset = encode_empty(MessageType::Reset, request.message_id);
+            self.response[..4].copy_from_slice(&reset);
+            self.last_request.take();
+            return Ok(Some(&self.response[..4]));
+        }
+        if confirmable && self.last_request == Some(request.message_id) {
+            trace!(
+                "Resending response to duplicate request {}",
+                request.message_id
+            );
+            return Ok(Some(&self.response[..self.response_len]));
+        }
+        if !request.code.is_request() {
+            return Ok(None);
+        }
+
+        let reply = handler.handle(&request, &mut self.payload);
+        let len = min(reply.len, N);
+        let mut code = reply.code;
+        let mut content_format = reply.content_format;
+
+        let requested = request.block2();
+        let szx = requested
+            .map(|b| min(b.szx, DEFAULT_BLOCK_SZX))
+            .unwrap_or(DEFAULT_BLOCK_SZX);
+        let size = 16 << szx;
+        let mut block2 = None;
+        let mut range = 0..len;
+        if requested.is_some() || len > size {
+            let num = requested
+                .map(|b| b.num as usize * b.size() / size)
+                .unwrap_or(0);
+            let start = num * size;
+            if start > len || (start == len && len > 0) {
+                code = Code::BAD_OPTION;
+                content_format = None;
+                range = 0..0;
+            } else {
+                let end = min(start + size, len);
+                block2.replace(Block {
+                    num: num as u32,
+                    more: end < len,
+                    szx,
+                });
+                range = start..end;
+            }
+        }
+
+        let (mtype, message_id) = if confirmable {
+            (MessageType::Acknowledgement, request.message_id)
+        } else {
+            self.message_id = self.message_id.wrapping_add(1);
+            (MessageType::NonConfirmable, self.message_id)
+        };
+        let mut encoder = Encoder::new(&mut self.response, mtype, code, message_id, request.token)?;
+        if let Some(sequence) = reply.observe {
+            encoder.uint_option(options::OBSERVE, sequence & 0x00FF_FFFF)?;
+        }
+        if let Some(format) = content_format {
+            encoder.uint_option(options::CONTENT_FORMAT, format as u32)?;
+        }
+        if let Some(block) = block2 {
+            encoder.uint_option(options::BLOCK2, block.value())?;
+        }
+        if let Some(block) = request.block1() {
+            encoder.uint_option(options::BLOCK1, block.value())?;
+        }
+        let response_len = encoder.payload(&self.payload[range])?;
+
+        if confirmable {
+            self.last_request.replace(request.message_id);
+            self.response_len = response_len;
+        } else {
+            self.last_request.take();
+        }
+        Ok(Some(&self.response[..response_len]))
+    }
+
+    /// Build a non-confirmable notification for an observer registered with `token`.
+    pub fn notification(
+        &mut self,
+        token: &[u8],
+        sequence: u32,
+        content_format: u16,
+        payload: &[u8],
+    ) -> Result<&[u8], Error> {
+        self.message_id = self.message_id.wrapping_add(1);
+        let mut encoder = Encoder::new(
+            &mut self.response,
+            MessageType::NonConfirmable,
+            Code::CONTENT,
+            self.message_id,
+            token,
+        )?;
+        encoder.uint_option(options::OBSERVE, sequence & 0x00FF_FFFF)?;
+        encoder.uint_option(options::CONTENT_FORMAT, content_format as u32)?;
+        let len = encoder.payload(payload)?;
+        // The cached response is no longer available for duplicate detection
+        self.last_request.take();
+        Ok(&self.response[..len])
+    }
+
+    /// Receive and handle at most one request. Returns `false` if no datagram was
+    /// available.
+    pub async fn process<S, H>(&mut self, socket: &mut S, handler: &mut H) -> Result<bool, Error>
+    where
+        S: UdpSocket,
+        H: RequestHandler,
+    {
+        let mut datagram = [0; N];
+        let len = socket.recv(&mut datagram).await?;
+        if len == 0 {
+            return Ok(false);
+        }
+        match self.handle(&datagram[..len], handler) {
+            Ok(Some(reply)) => socket.send(reply).await?,
+            Ok(None) => {}
+            Err(e) => warn!("Discarding CoAP message: {:?}", e),
+        }
+        Ok(true)
+    }
+
+    /// Serve requests until the socket fails.
+    pub async fn run<S, H>(&mut self, socket: &mut S, handler: &mut H) -> Result<(), Error>
+    where
+        S: UdpSocket,
+        H: RequestHandler,
+    {
+        loop {
+            if !self.process(socket, handler).await? {
+                Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::future::Future;
+    use futures::executor::block_on;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    const TEXT: u16 = 0;
+    const OCTETS: u16 = 42;
+
+    struct Resources {
+        large: [u8; 600],
+        stored: Vec<u8>,
+        observer: Option<Vec<u8>>,
+        calls: usize,
+    }
+
+    impl Resources {
+        fn new() -> Self {
+            let mut large = [0; 600];
+            for (i, b) in large.iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            Self {
+                large,
+                stored: Vec::new(),
+                observer: None,
+                calls: 0,
+            }
+        }
+    }
+
+    impl RequestHandler for Resources {
+        fn handle(&mut self, request: &Message<'_>, payload: &mut [u8]) -> Reply {
+            self.calls += 1;
+            let mut path = request.path();
+            match (request.code, path.next(), path.next()) {
+                (Code::GET, Some("hello"), None) => {
+                    payload[..5].copy_from_slice(b"world");
+                    let reply = Reply::content(TEXT, 5);
+                    match request.observe() {
+                        Some(0) => {
+                            self.observer.replace(request.token.to_vec());
+                            reply.observe(1)
+                        }
+                        _ => reply,
+                    }
+                }
+                (Code::GET, Some("large"), None) => {
+                    payload[..600].copy_from_slice(&self.large);
+                    Reply::content(OCTETS, 600)
+                }
+                (Code::PUT, Some("store"), None) => {
+                    self.stored.extend_from_slice(request.payload);
+                    match request.block1() {
+                        Some(block) if block.more => Reply::new(Code::CONTINUE),
+                        _ => Reply::new(Code::CHANGED),
+                    }
+                }
+                _ => Reply::new(Code::NOT_FOUND),
+            }
+        }
+    }
+
+    /// An in-memory transport delivering datagrams straight to a server.
+    struct Loopback {
+        server: CoapServer<1024>,
+        resources: Resources,
+        inbox: VecDeque<Vec<u8>>,
+        sent: usize,
+    }
+
+    impl Loopback {
+        fn new() -> Self {
+            Self {
+                server: CoapServer::new(),
+                resources: Resources::new(),
+                inbox: VecDeque::new(),
+                sent: 0,
+            }
+        }
+    }
+
+    impl UdpSocket for Loopback {
+        type SendFuture<'m> = impl Future<Output = Result<(), UdpError>> + 'm;
+        fn send<'m>(&'m mut self, buf: &'m [u8]) -> Self::SendFuture<'m> {
+            async move {
+                self.sent += 1;
+                if let Some(reply) = self.server.handle(buf, &mut self.resources).unwrap() {
+                    self.inbox.push_back(reply.to_vec());
+                }
+                if let Some(token) = self.resources.observer.take() {
+                    let notification = self
+                        .server
+                        .notification(&token, 2, TEXT, b"changed")
+                        .unwrap();
+                    self.inbox.push_back(notification.to_vec());
+                }
+                Ok(())
+            }
+        }
+
+        type RecvFuture<'m> = impl Future<Output = Result<usize, UdpError>> + 'm;
+        fn recv<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::RecvFuture<'m> {
+            async move {
+                match self.inbox.pop_front() {
+                    Some(datagram) => {
+                        buf[..datagram.len()].copy_from_slice(&datagram);
+                        Ok(datagram.len())
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
+    }
+
+    fn random() -> u32 {
+        0x1234_5678
+    }
+
+    #[test]
+    fn encode_decode_options() {
+        let mut buf = [0; 64];
+        let mut encoder = Encoder::new(
+            &mut buf,
+            MessageType::Confirmable,
+            Code::GET,
+            0x1234,
+            &[1, 2],
+        )
+        .unwrap();
+        encoder.uri_path("/sensors/temp").unwrap();
+        encoder.uint_option(options::CONTENT_FORMAT, 50).unwrap();
+        encoder.uri_query("unit=c").unwrap();
+        encoder.option(300, b"x").unwrap();
+        assert!(matches!(
+            encoder.option(11, b"y"),
+            Err(Error::InvalidOption)
+        ));
+        let len = encoder.payload(b"hi").unwrap();
+        assert_eq!(&[0x42, 0x01, 0x12, 0x34, 1, 2, 0xB7], &buf[..7]);
+
+        let message = Message::decode(&buf[..len]).unwrap();
+        assert_eq!(MessageType::Confirmable, message.mtype);
+        assert_eq!(Code::GET, message.code);
+        assert_eq!(0x1234, message.message_id);
+        assert_eq!(&[1, 2], message.token);
+        assert_eq!(vec!["sensors", "temp"], message.path().collect::<Vec<_>>());
+        assert_eq!(vec!["unit=c"], message.query().collect::<Vec<_>>());
+        assert_eq!(Some(50), message.content_format());
+        assert_eq!(Some(&b"x"[..]), message.option(300));
+        assert_eq!(b"hi", message.payload);
+    }
+
+    #[test]
+    fn decode_invalid() {
+        // Wrong version
+        assert!(Message::decode(&[0x00, 0x01, 0, 0]).is_err());
+        // Token longer than the message
+        assert!(Message::decode(&[0x44, 0x01, 0, 0, 1]).is_err());
+        // Payload marker without payload
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xFF]).is_err());
+        // Reserved option delta
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xF1, 0]).is_err());
+        // Truncated option value
+        assert!(Message::decode(&[0x40, 0x01, 0, 0, 0xB3, b'a']).is_err());
+    }
+
+    #[test]
+    fn block_values() {
+        let block = Block {
+            num: 5,
+            more: true,
+            szx: 6,
+        };
+        assert_eq!(0x5E, block.value());
+        assert_eq!(1024, block.size());
+        assert_eq!(Some(block), Block::decode(&[0x5E]));
+        assert_eq!(None, Block::decode(&[0x07]));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        assert_eq!(2500, backoff(2500, 0));
+        assert_eq!(5000, backoff(2500, 1));
+        assert_eq!(40000, backoff(2500, 4));
+    }
+
+    #[test]
+    fn server_deduplicates_confirmable_requests() {
+        let mut server: CoapServer = CoapServer::new();
+        let mut resources = Resources::new();
+        let mut request = [0; 32];
+        let mut encoder =
+            Encoder::new(&mut request, MessageType::Confirmable, Code::GET, 7, &[9]).unwrap();
+        encoder.uri_path("hello").unwrap();
+        let len = encoder.finish();
+
+        let first = server
+            .handle(&request[..len], &mut resources)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        let second = server
+            .handle(&request[..len], &mut resources)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        assert_eq!(first, second);
+        assert_eq!(1, resources.calls);
+
+        let response = Message::decode(&first).unwrap();
+        assert_eq!(MessageType::Acknowledgement, response.mtype);
+        assert_eq!(7, response.message_id);
+        assert_eq!(&[9], response.token);
+        assert_eq!(Code::CONTENT, response.code);
+        assert_eq!(b"world", response.payload);
+    }
+
+    #[test]
+    fn server_answers_ping_with_reset() {
+        let mut server: CoapServer = CoapServer::new();
+        let mut resources = Resources::new();
+        let ping = encode_empty(MessageType::Confirmable, 3);
+        let reply = server.handle(&ping, &mut resources).unwrap().unwrap();
+        assert_eq!(&encode_empty(MessageType::Reset, 3), reply);
+    }
+
+    #[test]
+    fn loopback_get() {
+        let mut transport = Loopback::new();
+        let mut client: CoapClient<'_, _> = CoapClient::new(&mut transport, random);
+        let mut rx_buf = [0; 64];
+        block_on(async {
+            let response = client.get("/hello", &mut rx_buf).await.unwrap();
+            assert_eq!(Code::CONTENT, response.code());
+            assert_eq!(Some(TEXT), response.content_format());
+            assert_eq!(b"world", response.payload());
+        });
+        block_on(async {
+            let response = client.get("/missing", &mut rx_buf).await.unwrap();
+            assert_eq!(Code::NOT_FOUND, response.code());
+            assert!(response.payload().is_empty());
+        });
+    }
+
+    #[test]
+    fn loopback_block2_download() {
+        let mut transport = Loopback::new();
+        let mut rx_buf = [0; 1024];
+        {
+            let mut client: CoapClient<'_, _> = CoapClient::new(&mut transport, random);
+            block_on(async {
+                let response = client.get("/large", &mut rx_buf).await.unwrap();
+                assert!(response.is_success());
+                assert_eq!(600, response.payload().len());
+                assert_eq!(&Resources::new().large[..], response.payload());
+            });
+        }
+        // One request per 256 byte block
+        assert_eq!(3, transport.sent);
+    }
+
+    #[test]
+    fn loopback_block1_upload() {
+        let mut transport = Loopback::new();
+        let payload: Vec<u8> = (0..700).map(|i| i as u8).collect();
+        {
+            let mut client: CoapClient<'_, _, 1024> =
+                CoapClient::new(&mut transport, random).block_size(5);
+            let mut rx_buf = [0; 64];
+            block_on(async {
+                let response = client
+                    .put("/store", OCTETS, &payload, &mut rx_buf)
+                    .await
+                    .unwrap();
+                assert_eq!(Code::CHANGED, response.code());
+            });
+        }
+        assert_eq!(2, transport.sent);
+        assert_eq!(payload, transport.resources.stored);
+    }
+
+    #[test]
+    fn loopback_observe() {
+        let mut transport = Loopback::new();
+        let mut client: CoapClient<'_, _> = CoapClient::new(&mut transport, random);
+        let mut rx_buf = [0; 64];
+        block_on(async {
+            let response = client.observe("/hello", &mut rx_buf).await.unwrap();
+            assert_eq!(Some(1), response.observe());
+            assert!(client.is_observing());
+
+            let notification = client.notification(&mut rx_buf).await.unwrap();
+            assert_eq!(Some(2), notification.observe());
+            assert_eq!(b"changed", notification.payload());
+
+            client
+                .cancel_observation("/hello", &mut rx_buf)
+                .await
+                .unwrap();
+            assert!(!client.is_observing());
+            assert!(matches!(
+                client.notification(&mut rx_buf).await,
+                Err(Error::NotObserving)
+            ));
+        });
+    }
+}
@@ -1,10 +1,148 @@
 use crate::traits::{
     ip::{IpAddress, IpProtocol, SocketAddress},
-    tcp::TcpSocket,
+    tcp::{TcpError, TcpSocket},
 };
 use core::fmt::Write;
+#[cfg(feature = "time")]
+use embassy::time::{Duration, Instant, Timer};
 use heapless::{consts, String};
 
+/// Size of the buffer used to coalesce request writes to the socket.
+const WRITE_BUFFER_LEN: usize = 256;
+
+/// How often to poll the socket for data while waiting for a response.
+#[cfg(feature = "time")]
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait for the complete response to a request.
+#[cfg(feature = "time")]
+const RESPONSE_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    GET,
+    PUT,
+    POST,
+    DELETE,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::PUT => "PUT",
+            Method::POST => "POST",
+            Method::DELETE => "DELETE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying socket reported an error.
+    Network(TcpError),
+    /// The response could not be parsed.
+    Codec,
+    /// The response did not fit in the provided receive buffer.
+    BufferTooSmall,
+    /// The connection was closed before a complete response was received.
+    ConnectionClosed,
+    /// No complete response was received in time. Only reported with the `time` feature.
+    Timeout,
+}
+
+impl From<TcpError> for Error {
+    fn from(e: TcpError) -> Self {
+        Error::Network(e)
+    }
+}
+
+/// An HTTP request to be sent with `HttpClient::request`.
+pub struct Request<'a> {
+    method: Method,
+    path: &'a str,
+    headers: &'a [(&'a str, &'a str)],
+    payload: Option<(&'a str, &'a [u8])>,
+}
+
+impl<'a> Request<'a> {
+    pub fn new(method: Method, path: &'a str) -> Self {
+        Self {
+            method,
+            path,
+            headers: &[],
+            payload: None,
+        }
+    }
+
+    pub fn get(path: &'a str) -> Self {
+        Self::new(Method::GET, path)
+    }
+
+    pub fn put(path: &'a str) -> Self {
+        Self::new(Method::PUT, path)
+    }
+
+    pub fn post(path: &'a str) -> Self {
+        Self::new(Method::POST, path)
+    }
+
+    pub fn delete(path: &'a str) -> Self {
+        Self::new(Method::DELETE, path)
+    }
+
+    /// Additional headers to send with the request.
+    pub fn headers(mut self, headers: &'a [(&'a str, &'a str)]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Request body and its content type.
+    pub fn payload(mut self, content_type: &'a str, payload: &'a [u8]) -> Self {
+        self.payload.replace((content_type, payload));
+        self
+    }
+}
+
+/// A parsed HTTP response. The headers and payload borrow from the receive buffer
+/// passed to the request.
+pub struct Response<'m> {
+    status: u16,
+    reason: &'m str,
+    headers: &'m str,
+    payload: &'m [u8],
+}
+
+impl<'m> Response<'m> {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &'m str {
+        self.reason
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// Look up the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&'m str> {
+        find_header(self.headers, name)
+    }
+
+    /// Iterate over all response headers as name and value pairs.
+    pub fn headers(&self) -> impl Iterator<Item = (&'m str, &'m str)> {
+        header_lines(self.headers)
+    }
+
+    pub fn payload(&self) -> &'m [u8] {
+        self.payload
+    }
+}
+
 pub struct HttpClient<'a, S>
 where
     S: TcpSocket + 'static,
@@ -12,89 +150,646 @@ where
     socket: &'a mut S,
     ip: IpAddress,
     port: u16,
-    username: &'a str,
-    password: &'a str,
+    host: Option<&'a str>,
+    credentials: Option<(&'a str, &'a str)>,
+    connected: bool,
 }
 
 impl<'a, S> HttpClient<'a, S>
 where
     S: TcpSocket + 'static,
 {
-    pub fn new(
-        socket: &'a mut S,
-        ip: IpAddress,
-        port: u16,
-        username: &'a str,
-        password: &'a str,
-    ) -> Self {
+    pub fn new(socket: &'a mut S, ip: IpAddress, port: u16) -> Self {
         Self {
             socket,
             ip,
             port,
-            username,
-            password,
+            host: None,
+            credentials: None,
+            connected: false,
         }
     }
 
-    pub async fn post(
+    /// Host name to send in the `Host` header. Defaults to the IP address.
+    pub fn host(mut self, host: &'a str) -> Self {
+        self.host.replace(host);
+        self
+    }
+
+    /// Authenticate all requests using HTTP Basic authentication.
+    pub fn basic_auth(mut self, username: &'a str, password: &'a str) -> Self {
+        self.credentials.replace((username, password));
+        self
+    }
+
+    pub async fn get<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Request::get(path), rx_buf).await
+    }
+
+    pub async fn post<'m>(
         &mut self,
         path: &str,
         payload: &[u8],
         content_type: &str,
-        rx_buf: &mut [u8],
-    ) -> Result<usize, ()> {
-        match self
-            .socket
-            .connect(IpProtocol::Tcp, SocketAddress::new(self.ip, self.port))
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Request::post(path).payload(content_type, payload), rx_buf)
             .await
-        {
-            Ok(_) => {
-                info!("Connected to {}:{}", self.ip, self.port);
-                let mut combined: String<consts::U128> = String::new();
-                write!(combined, "{}:{}", self.username, self.password).unwrap();
-                let mut authz = [0; 256];
-                let authz_len =
-                    base64::encode_config_slice(combined.as_bytes(), base64::STANDARD, &mut authz);
-                let mut request: String<consts::U1024> = String::new();
-                write!(request, "POST {} HTTP/1.1\r\n", path).unwrap();
-                write!(request, "Authorization: Basic {}\r\n", unsafe {
-                    core::str::from_utf8_unchecked(&authz[..authz_len])
-                })
-                .unwrap();
-                write!(request, "Content-Type: {}\r\n", content_type).unwrap();
-                write!(request, "Content-Length: {}\r\n\r\n", payload.len()).unwrap();
-                let result = self
-                    .socket
-                    .write(&request.as_bytes()[..request.len()])
-                    .await;
-                match result {
-                    Ok(_) => {
-                        let result = self.socket.write(payload).await;
-                        match result {
-                            Ok(_) => {
-                                info!("Request sent");
-                                let response = self
-                                    .socket
-                                    .read(&mut rx_buf[..])
-                                    .await
-                                    .expect("error reading response");
-                                info!("Got {} bytes in response", response);
-                                return Ok(response);
-                            }
-                            Err(e) => {
-                                warn!("Error sending data: {:?}", e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Error sending headers: {:?}", e);
-                    }
+    }
+
+    pub async fn put<'m>(
+        &mut self,
+        path: &str,
+        payload: &[u8],
+        content_type: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Request::put(path).payload(content_type, payload), rx_buf)
+            .await
+    }
+
+    pub async fn delete<'m>(
+        &mut self,
+        path: &str,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.request(Request::delete(path), rx_buf).await
+    }
+
+    /// Send a request and read the full response into `rx_buf`.
+    ///
+    /// The connection is kept open for subsequent requests unless the server asks
+    /// for it to be closed.
+    pub async fn request<'m>(
+        &mut self,
+        request: Request<'_>,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        let reused = self.connected;
+        if let Err(e) = self.send(&request).await {
+            if !reused {
+                return Err(e);
+            }
+            // The server may have dropped an idle keep-alive connection, retry once.
+            warn!("Error reusing connection, reconnecting: {:?}", e);
+            self.close().await;
+            self.send(&request).await?;
+        }
+
+        match self.receive(rx_buf).await {
+            Ok((response, keep_alive)) => {
+                if !keep_alive {
+                    self.close().await;
                 }
+                Ok(response)
             }
             Err(e) => {
-                warn!("Error connecting to {}:{}: {:?}", self.ip, self.port, e);
+                // Data of the failed response may still arrive on the socket
+                self.close().await;
+                Err(e)
             }
         }
-        Err(())
+    }
+
+    /// Send a request asking the server to switch protocols and read the response
+    /// head, leaving the connection to the new protocol.
+    ///
+    /// Any bytes received after the head already belong to the new protocol and are
+    /// returned as the response payload. The client must not be used for further
+    /// requests after a successful upgrade.
+    pub async fn upgrade<'m>(
+        &mut self,
+        request: Request<'_>,
+        rx_buf: &'m mut [u8],
+    ) -> Result<Response<'m>, Error> {
+        self.send(&request).await?;
+        let (header_len, pos) = match self.receive_head(rx_buf, 0, Deadline::response()).await {
+            Ok(r) => r,
+            Err(e) => {
+                self.connected = false;
+                return Err(e);
+            }
+        };
+
+        let (head_buf, rest) = rx_buf.split_at(header_len);
+        let head = parse_head(head_buf)?;
+        info!("Got upgrade response with status {}", head.status);
+        Ok(Response {
+            status: head.status,
+            reason: head.reason,
+            headers: head.headers,
+            payload: &rest[..pos - header_len],
+        })
+    }
+
+    /// Give up the client, returning the underlying socket.
+    pub fn into_socket(self) -> &'a mut S {
+        self.socket
+    }
+
+    /// Close the underlying connection.
+    pub async fn close(&mut self) {
+        self.connected = false;
+        self.socket.close().await;
+    }
+
+    async fn connect(&mut self) -> Result<(), Error> {
+        if !self.connected {
+            match self
+                .socket
+                .connect(IpProtocol::Tcp, SocketAddress::new(self.ip, self.port))
+                .await
+            {
+                Ok(_) => {
+                    info!("Connected to {}:{}", self.ip, self.port);
+                    self.connected = true;
+                }
+                Err(e) => {
+                    warn!("Error connecting to {}:{}: {:?}", self.ip, self.port, e);
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, request: &Request<'_>) -> Result<(), Error> {
+        self.connect().await?;
+        let ip = self.ip;
+        let port = self.port;
+        let host = self.host;
+        let credentials = self.credentials;
+        let mut writer = RequestWriter::new(self.socket);
+
+        writer.write(request.method.as_str().as_bytes()).await?;
+        writer.write(b" ").await?;
+        writer.write(request.path.as_bytes()).await?;
+        writer.write(b" HTTP/1.1\r\nHost: ").await?;
+        if let Some(host) = host {
+            writer.write(host.as_bytes()).await?;
+        } else {
+            let mut s: String<consts::U32> = String::new();
+            write!(s, "{}:{}", ip, port).unwrap();
+            writer.write(s.as_bytes()).await?;
+        }
+        writer.write(b"\r\n").await?;
+
+        if let Some((username, password)) = credentials {
+            let mut combined: String<consts::U128> = String::new();
+            write!(combined, "{}:{}", username, password).map_err(|_| Error::Codec)?;
+            let mut authz = [0; 256];
+            let authz_len =
+                base64::encode_config_slice(combined.as_bytes(), base64::STANDARD, &mut authz);
+            writer.write(b"Authorization: Basic ").await?;
+            writer.write(&authz[..authz_len]).await?;
+            writer.write(b"\r\n").await?;
+        }
+
+        for (name, value) in request.headers.iter() {
+            writer.write(name.as_bytes()).await?;
+            writer.write(b": ").await?;
+            writer.write(value.as_bytes()).await?;
+            writer.write(b"\r\n").await?;
+        }
+
+        if let Some((content_type, payload)) = request.payload {
+            let mut len: String<consts::U16> = String::new();
+            write!(len, "{}", payload.len()).unwrap();
+            writer.write(b"Content-Type: ").await?;
+            writer.write(content_type.as_bytes()).await?;
+            writer.write(b"\r\nContent-Length: ").await?;
+            writer.write(len.as_bytes()).await?;
+            writer.write(b"\r\n\r\n").await?;
+            writer.write(payload).await?;
+        } else {
+            writer.write(b"\r\n").await?;
+        }
+        writer.flush().await?;
+        info!("Request sent");
+        Ok(())
+    }
+
+    /// Read until the end of the response head, with `pos` bytes already in `rx_buf`.
+    /// Returns the length of the head and the total number of bytes read.
+    async fn receive_head(
+        &mut self,
+        rx_buf: &mut [u8],
+        mut pos: usize,
+        deadline: Deadline,
+    ) -> Result<(usize, usize), Error> {
+        loop {
+            if let Some(end) = find(&rx_buf[..pos], b"\r\n\r\n") {
+                return Ok((end + 4, pos));
+            }
+            pos += self.read_more(rx_buf, pos, deadline).await?;
+        }
+    }
+
+    async fn receive<'m>(&mut self, rx_buf: &'m mut [u8]) -> Result<(Response<'m>, bool), Error> {
+        let deadline = Deadline::response();
+        let mut pos = 0;
+        let (header_len, status, chunked, content_length, mut keep_alive) = loop {
+            let (header_len, read) = self.receive_head(rx_buf, pos, deadline).await?;
+            let head = parse_head(&rx_buf[..header_len])?;
+            if head.status / 100 == 1 && head.status != 101 {
+                // Interim response such as 100 Continue, the final response follows
+                debug!("Skipping interim response with status {}", head.status);
+                rx_buf.copy_within(header_len..read, 0);
+                pos = read - header_len;
+                continue;
+            }
+            pos = read;
+            break (
+                header_len,
+                head.status,
+                head.chunked,
+                head.content_length,
+                head.keep_alive,
+            );
+        };
+
+        let body_len = if status == 101 || status == 204 || status == 304 {
+            0
+        } else if chunked {
+            loop {
+                if let Some(raw_len) = scan_chunked(&rx_buf[header_len..pos])? {
+                    break decode_chunked(&mut rx_buf[header_len..header_len + raw_len]);
+                }
+                pos += self.read_more(rx_buf, pos, deadline).await?;
+            }
+        } else if let Some(content_length) = content_length {
+            let len = header_len.checked_add(content_length).ok_or(Error::Codec)?;
+            if len > rx_buf.len() {
+                return Err(Error::BufferTooSmall);
+            }
+            while pos < len {
+                pos += self.read_more(rx_buf, pos, deadline).await?;
+            }
+            content_length
+        } else {
+            // Body is delimited by the server closing the connection
+            keep_alive = false;
+            loop {
+                match self.read_more(rx_buf, pos, deadline).await {
+                    Ok(len) => pos += len,
+                    Err(Error::ConnectionClosed) => break pos - header_len,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        info!("Got response with status {}", status);
+
+        let (head_buf, body_buf) = rx_buf.split_at(header_len);
+        let head = parse_head(head_buf)?;
+        Ok((
+            Response {
+                status: head.status,
+                reason: head.reason,
+                headers: head.headers,
+                payload: &body_buf[..body_len],
+            },
+            keep_alive,
+        ))
+    }
+
+    /// Read more data into `rx_buf` at `pos`, returning the number of bytes read.
+    async fn read_more(
+        &mut self,
+        rx_buf: &mut [u8],
+        pos: usize,
+        deadline: Deadline,
+    ) -> Result<usize, Error> {
+        if pos >= rx_buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        loop {
+            match self.socket.read(&mut rx_buf[pos..]).await {
+                // No data available yet
+                Ok(0) => {
+                    if deadline.expired() {
+                        return Err(Error::Timeout);
+                    }
+                    deadline.pause().await;
+                }
+                Ok(len) => return Ok(len),
+                Err(TcpError::SocketClosed) => return Err(Error::ConnectionClosed),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Time by which a response must be complete.
+///
+/// Without the `time` feature there is no clock: responses never time out, and the socket
+/// is polled again right away while no data is available.
+#[derive(Clone, Copy)]
+struct Deadline {
+    #[cfg(feature = "time")]
+    at: Instant,
+}
+
+#[cfg(feature = "time")]
+impl Deadline {
+    fn response() -> Self {
+        Self {
+            at: Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT_SECS),
+        }
+    }
+
+    fn expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    async fn pause(&self) {
+        Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+    }
+}
+
+#[cfg(not(feature = "time"))]
+impl Deadline {
+    fn response() -> Self {
+        Self {}
+    }
+
+    fn expired(&self) -> bool {
+        false
+    }
+
+    async fn pause(&self) {}
+}
+
+/// Coalesces small writes into socket writes of at most `WRITE_BUFFER_LEN` bytes.
+struct RequestWriter<'s, S>
+where
+    S: TcpSocket + 'static,
+{
+    socket: &'s mut S,
+    buf: [u8; WRITE_BUFFER_LEN],
+    pos: usize,
+}
+
+impl<'s, S> RequestWriter<'s, S>
+where
+    S: TcpSocket + 'static,
+{
+    fn new(socket: &'s mut S) -> Self {
+        Self {
+            socket,
+            buf: [0; WRITE_BUFFER_LEN],
+            pos: 0,
+        }
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<(), TcpError> {
+        while !data.is_empty() {
+            let len = core::cmp::min(data.len(), self.buf.len() - self.pos);
+            self.buf[self.pos..self.pos + len].copy_from_slice(&data[..len]);
+            self.pos += len;
+            data = &data[len..];
+            if self.pos == self.buf.len() {
+                self.flush().await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), TcpError> {
+        let mut wp = 0;
+        while wp < self.pos {
+            let len = self.socket.write(&self.buf[wp..self.pos]).await?;
+            if len == 0 {
+                return Err(TcpError::WriteError);
+            }
+            wp += len;
+        }
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+struct Head<'m> {
+    status: u16,
+    reason: &'m str,
+    headers: &'m str,
+    content_length: Option<usize>,
+    chunked: bool,
+    keep_alive: bool,
+}
+
+/// Parse the status line and headers, including the terminating empty line.
+fn parse_head(data: &[u8]) -> Result<Head<'_>, Error> {
+    let head = core::str::from_utf8(data).map_err(|_| Error::Codec)?;
+    let (status_line, headers) = match head.find("\r\n") {
+        Some(i) => (&head[..i], &head[i + 2..]),
+        None => return Err(Error::Codec),
+    };
+
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().ok_or(Error::Codec)?;
+    if !version.starts_with("HTTP/1.") {
+        return Err(Error::Codec);
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or(Error::Codec)?;
+    let reason = parts.next().unwrap_or("");
+
+    let content_length = match find_header(headers, "Content-Length") {
+        Some(value) => Some(value.parse::<usize>().map_err(|_| Error::Codec)?),
+        None => None,
+    };
+    let chunked = find_header(headers, "Transfer-Encoding")
+        .map(|v| v.eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+    let keep_alive = match find_header(headers, "Connection") {
+        Some(v) if v.eq_ignore_ascii_case("close") => false,
+        Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+        _ => version == "HTTP/1.1",
+    };
+
+    Ok(Head {
+        status,
+        reason,
+        headers,
+        content_length,
+        chunked,
+        keep_alive,
+    })
+}
+
+fn header_lines(headers: &str) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .split("\r\n")
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut kv = line.splitn(2, ':');
+            match (kv.next(), kv.next()) {
+                (Some(name), Some(value)) => Some((name.trim(), value.trim())),
+                _ => None,
+            }
+        })
+}
+
+pub(crate) fn find_header<'m>(headers: &'m str, name: &str) -> Option<&'m str> {
+    header_lines(headers)
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+pub(crate) fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_hex(data: &[u8]) -> Result<usize, Error> {
+    if data.is_empty() {
+        return Err(Error::Codec);
+    }
+    let mut value: usize = 0;
+    for b in data {
+        let digit = match b {
+            b'0'..=b'9' => b - b'0',
+            b'a'..=b'f' => b - b'a' + 10,
+            b'A'..=b'F' => b - b'A' + 10,
+            _ => return Err(Error::Codec),
+        };
+        value = value
+            .checked_mul(16)
+            .and_then(|v| v.checked_add(digit as usize))
+            .ok_or(Error::Codec)?;
+    }
+    Ok(value)
+}
+
+/// Parse a chunk size line, returning the chunk size and the length of the line.
+fn chunk_header(data: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    match find(data, b"\r\n") {
+        Some(end) => {
+            let size = match data[..end].iter().position(|b| *b == b';') {
+                Some(ext) => &data[..ext],
+                None => &data[..end],
+            };
+            Ok(Some((parse_hex(size)?, end + 2)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Check whether `data` holds a complete chunked body. Returns the number of raw bytes
+/// making up the body, or `None` if more data is needed.
+fn scan_chunked(data: &[u8]) -> Result<Option<usize>, Error> {
+    let mut pos = 0;
+    loop {
+        let (size, header_len) = match chunk_header(&data[pos..])? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        pos += header_len;
+        if size == 0 {
+            // Skip trailers until the final empty line
+            loop {
+                match find(&data[pos..], b"\r\n") {
+                    Some(0) => return Ok(Some(pos + 2)),
+                    Some(end) => pos += end + 2,
+                    None => return Ok(None),
+                }
+            }
+        }
+        let end = pos
+            .checked_add(size)
+            .and_then(|end| end.checked_add(2))
+            .ok_or(Error::Codec)?;
+        if data.len() < end {
+            return Ok(None);
+        }
+        if &data[end - 2..end] != b"\r\n" {
+            return Err(Error::Codec);
+        }
+        pos = end;
+    }
+}
+
+/// Decode a complete chunked body in place, returning the decoded length. The body must
+/// have been validated with `scan_chunked`.
+fn decode_chunked(data: &mut [u8]) -> usize {
+    let mut rp = 0;
+    let mut wp = 0;
+    while let Ok(Some((size, header_len))) = chunk_header(&data[rp..]) {
+        if size == 0 {
+            break;
+        }
+        rp += header_len;
+        data.copy_within(rp..rp + size, wp);
+        wp += size;
+        rp += size + 2;
+    }
+    wp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_status_and_headers() {
+        let head =
+            parse_head(b"HTTP/1.1 404 Not Found\r\nContent-Length: 12\r\nX-Foo:  bar \r\n\r\n")
+                .unwrap();
+        assert_eq!(404, head.status);
+        assert_eq!("Not Found", head.reason);
+        assert_eq!(Some(12), head.content_length);
+        assert!(!head.chunked);
+        assert!(head.keep_alive);
+        assert_eq!(Some("bar"), find_header(head.headers, "x-foo"));
+        assert_eq!(2, header_lines(head.headers).count());
+    }
+
+    #[test]
+    fn parse_connection_close() {
+        let head = parse_head(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        )
+        .unwrap();
+        assert!(head.chunked);
+        assert!(!head.keep_alive);
+
+        let head = parse_head(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+        assert!(!head.keep_alive);
+    }
+
+    #[test]
+    fn parse_invalid_head() {
+        assert!(parse_head(b"FOO 200 OK\r\n\r\n").is_err());
+        assert!(parse_head(b"HTTP/1.1 abc OK\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn chunked_decoding() {
+        let mut body = *b"4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n";
+        assert_eq!(Some(body.len()), scan_chunked(&body).unwrap());
+        let len = decode_chunked(&mut body);
+        assert_eq!(b"Wikipedia in \r\n\r\nchunks.", &body[..len]);
+    }
+
+    #[test]
+    fn chunked_incomplete() {
+        let body = b"4\r\nWiki\r\n6\r\nped";
+        assert_eq!(None, scan_chunked(&body[..]).unwrap());
+        let body = b"4\r\nWiki\r\n0\r\nTrailer: x\r\n";
+        assert_eq!(None, scan_chunked(&body[..]).unwrap());
+        let body = b"4\r\nWiki\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(Some(body.len()), scan_chunked(&body[..]).unwrap());
+    }
+
+    #[test]
+    fn chunked_invalid() {
+        assert!(scan_chunked(b"zz\r\n").is_err());
+        assert!(scan_chunked(b"2\r\nabc\r\n").is_err());
+        assert!(scan_chunked(b"ffffffffffffffff\r\nabc\r\n").is_err());
     }
 }
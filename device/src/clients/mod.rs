@@ -1 +1,8 @@
+#[cfg(feature = "time")]
+pub mod coap;
 pub mod http;
+
+#[cfg(feature = "time")]
+pub mod mqtt;
+#[cfg(feature = "time")]
+pub mod websocket;
@@ -0,0 +1,931 @@
+//! An MQTT 3.1.1 client working over any `TcpSocket`.
+//!
+//! The client supports QoS 0 and 1 for both publishing and subscribing. Incoming
+//! messages are delivered to an actor implementing `FromMqttMessage`.
+
+use crate::{
+    kernel::actor::{Actor, Address},
+    traits::{
+        ip::{IpProtocol, SocketAddress},
+        tcp::{TcpError, TcpSocket},
+    },
+};
+use embassy::time::{Duration, Instant, Timer};
+
+/// How often to poll the socket for data while waiting for a packet.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How long to wait for a server response to a request.
+const RESPONSE_TIMEOUT_SECS: u64 = 30;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const UNSUBSCRIBE: u8 = 0xA2;
+const UNSUBACK: u8 = 0xB0;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying socket reported an error.
+    Network(TcpError),
+    /// A malformed packet was received.
+    Codec,
+    /// A packet did not fit in the client buffers.
+    BufferTooSmall,
+    /// The server refused the connection with the given return code.
+    ConnectionRefused(u8),
+    /// The server rejected a subscription.
+    SubscriptionRejected,
+    /// The server did not respond in time.
+    Timeout,
+    /// The client is not connected.
+    NotConnected,
+    /// The server sent a packet not allowed by the protocol.
+    Protocol,
+}
+
+impl From<TcpError> for Error {
+    fn from(e: TcpError) -> Self {
+        Error::Network(e)
+    }
+}
+
+/// An application message received from the server.
+#[derive(Debug)]
+pub struct Message<'m> {
+    pub topic: &'m str,
+    pub payload: &'m [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Trait for actors receiving MQTT messages from an `MqttClient`.
+pub trait FromMqttMessage: Actor {
+    fn from<'m>(message: Message<'m>) -> Option<Self::Message<'m>>
+    where
+        Self: 'm;
+}
+
+/// Options for the MQTT CONNECT packet.
+pub struct ConnectOptions<'a> {
+    client_id: &'a str,
+    keep_alive: u16,
+    clean_session: bool,
+    username: Option<&'a str>,
+    password: Option<&'a [u8]>,
+}
+
+impl<'a> ConnectOptions<'a> {
+    pub fn new(client_id: &'a str) -> Self {
+        Self {
+            client_id,
+            keep_alive: 60,
+            clean_session: true,
+            username: None,
+            password: None,
+        }
+    }
+
+    /// Keep-alive interval in seconds. Zero disables keep-alive pings.
+    pub fn keep_alive(mut self, keep_alive: u16) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn credentials(mut self, username: &'a str, password: &'a [u8]) -> Self {
+        self.username.replace(username);
+        self.password.replace(password);
+        self
+    }
+}
+
+/// A summary of a processed packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Event {
+    ConnAck(u8),
+    PubAck(u16),
+    SubAck(u16, Option<QoS>),
+    UnsubAck(u16),
+    PingResp,
+    Publish,
+}
+
+/// An MQTT client using fixed-size buffers of `N` bytes for sending and receiving packets.
+#[rustfmt::skip]
+pub struct MqttClient<'a, S, A, const N: usize = 512>
+where
+    S: TcpSocket + 'static,
+    A: FromMqttMessage + 'static,
+{
+    socket: &'a mut S,
+    handler: Option<Address<'a, A>>,
+    rx_buf: [u8; N],
+    rx_pos: usize,
+    tx_buf: [u8; N],
+    packet_id: u16,
+    keep_alive: Option<Duration>,
+    last_tx: Instant,
+    ping_outstanding: bool,
+    connected: bool,
+}
+
+impl<'a, S, A, const N: usize> MqttClient<'a, S, A, N>
+where
+    S: TcpSocket + 'static,
+    A: FromMqttMessage + 'static,
+{
+    pub fn new(socket: &'a mut S) -> Self {
+        Self {
+            socket,
+            handler: None,
+            rx_buf: [0; N],
+            rx_pos: 0,
+            tx_buf: [0; N],
+            packet_id: 0,
+            keep_alive: None,
+            last_tx: Instant::now(),
+            ping_outstanding: false,
+            connected: false,
+        }
+    }
+
+    /// Deliver incoming messages to the given actor.
+    pub fn handler(mut self, handler: Address<'a, A>) -> Self {
+        self.handler.replace(handler);
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Open a connection to the broker and wait for it to be accepted.
+    pub async fn connect(
+        &mut self,
+        dst: SocketAddress,
+        options: ConnectOptions<'_>,
+    ) -> Result<(), Error> {
+        self.socket.connect(IpProtocol::Tcp, dst).await?;
+        self.rx_pos = 0;
+        self.ping_outstanding = false;
+        self.keep_alive = if options.keep_alive > 0 {
+            Some(Duration::from_secs(options.keep_alive as u64))
+        } else {
+            None
+        };
+
+        let len = encode_connect(&mut self.tx_buf, &options)?;
+        self.transmit(len).await?;
+        self.connected = true;
+        match self.next_event(Some(response_deadline())).await {
+            Ok(Event::ConnAck(0)) => {
+                info!("MQTT connection accepted");
+                Ok(())
+            }
+            Ok(Event::ConnAck(code)) => {
+                warn!("MQTT connection refused: {}", code);
+                self.connected = false;
+                Err(Error::ConnectionRefused(code))
+            }
+            Ok(_) => {
+                self.connected = false;
+                Err(Error::Protocol)
+            }
+            Err(e) => {
+                self.connected = false;
+                Err(e)
+            }
+        }
+    }
+
+    /// Publish a message. For `QoS::AtLeastOnce`, wait until the broker acknowledges it.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<(), Error> {
+        self.ensure_connected()?;
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce => Some(self.next_packet_id()),
+        };
+        let len = encode_publish(&mut self.tx_buf, topic, payload, qos, retain, packet_id)?;
+        self.transmit(len).await?;
+        if let Some(id) = packet_id {
+            let deadline = response_deadline();
+            loop {
+                if let Event::PubAck(ack) = self.next_event(Some(deadline)).await? {
+                    if ack == id {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to a topic filter, returning the QoS granted by the broker.
+    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<QoS, Error> {
+        self.ensure_connected()?;
+        let id = self.next_packet_id();
+        let len = encode_subscribe(&mut self.tx_buf, id, topic, qos)?;
+        self.transmit(len).await?;
+        let deadline = response_deadline();
+        loop {
+            if let Event::SubAck(ack, granted) = self.next_event(Some(deadline)).await? {
+                if ack == id {
+                    return granted.ok_or(Error::SubscriptionRejected);
+                }
+            }
+        }
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.ensure_connected()?;
+        let id = self.next_packet_id();
+        let len = encode_unsubscribe(&mut self.tx_buf, id, topic)?;
+        self.transmit(len).await?;
+        let deadline = response_deadline();
+        loop {
+            if let Event::UnsubAck(ack) = self.next_event(Some(deadline)).await? {
+                if ack == id {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Process any data received from the broker and send keep-alive pings when due.
+    /// Returns without waiting if no data is available.
+    pub async fn poll(&mut self) -> Result<(), Error> {
+        self.ensure_connected()?;
+        self.keep_alive().await?;
+        self.receive().await?;
+        while self.process().await?.is_some() {}
+        Ok(())
+    }
+
+    /// Process incoming messages and keep the connection alive until an error occurs.
+    pub async fn run(&mut self) -> Result<(), Error> {
+        loop {
+            self.next_event(None).await?;
+        }
+    }
+
+    pub async fn disconnect(&mut self) {
+        if self.connected {
+            self.tx_buf[0] = DISCONNECT;
+            self.tx_buf[1] = 0;
+            let _ = self.transmit(2).await;
+            self.connected = false;
+        }
+        self.socket.close().await;
+    }
+
+    fn ensure_connected(&self) -> Result<(), Error> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(Error::NotConnected)
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.packet_id = self.packet_id.wrapping_add(1);
+        if self.packet_id == 0 {
+            self.packet_id = 1;
+        }
+        self.packet_id
+    }
+
+    async fn transmit(&mut self, len: usize) -> Result<(), Error> {
+        let mut wp = 0;
+        while wp < len {
+            match self.socket.write(&self.tx_buf[wp..len]).await {
+                Ok(0) => {
+                    self.connected = false;
+                    return Err(Error::Network(TcpError::WriteError));
+                }
+                Ok(written) => wp += written,
+                Err(e) => {
+                    self.connected = false;
+                    return Err(e.into());
+                }
+            }
+        }
+        self.last_tx = Instant::now();
+        Ok(())
+    }
+
+    async fn keep_alive(&mut self) -> Result<(), Error> {
+        if let Some(keep_alive) = self.keep_alive {
+            if Instant::now() >= self.last_tx + keep_alive {
+                if self.ping_outstanding {
+                    warn!("MQTT broker did not answer ping");
+                    self.connected = false;
+                    return Err(Error::Timeout);
+                }
+                self.tx_buf[0] = PINGREQ;
+                self.tx_buf[1] = 0;
+                self.transmit(2).await?;
+                self.ping_outstanding = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read available data into the receive buffer, returning the number of bytes read.
+    async fn receive(&mut self) -> Result<usize, Error> {
+        if self.rx_pos >= N {
+            // Packets are processed as soon as they are complete, so this one never will be
+            warn!("MQTT packet larger than the receive buffer");
+            self.abort().await;
+            return Err(Error::BufferTooSmall);
+        }
+        match self.socket.read(&mut self.rx_buf[self.rx_pos..]).await {
+            Ok(len) => {
+                self.rx_pos += len;
+                Ok(len)
+            }
+            Err(e) => {
+                self.connected = false;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Wait for the next packet from the broker, keeping the connection alive while waiting.
+    async fn next_event(&mut self, deadline: Option<Instant>) -> Result<Event, Error> {
+        loop {
+            if let Some(event) = self.process().await? {
+                return Ok(event);
+            }
+            self.keep_alive().await?;
+            if self.receive().await? == 0 {
+                if let Some(deadline) = deadline {
+                    if Instant::now() >= deadline {
+                        return Err(Error::Timeout);
+                    }
+                }
+                Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+            }
+        }
+    }
+
+    /// Process the first complete packet in the receive buffer, if any.
+    async fn process(&mut self) -> Result<Option<Event>, Error> {
+        let decoded = match decode(&self.rx_buf[..self.rx_pos]) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Unable to decode MQTT packet: {:?}", e);
+                self.abort().await;
+                return Err(e);
+            }
+        };
+        let (event, len, ack) = match decoded {
+            None => return Ok(None),
+            Some((packet, len)) => match packet {
+                Packet::Publish {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    packet_id,
+                } => {
+                    trace!("Received message on {}", topic);
+                    if let Some(handler) = self.handler {
+                        if let Some(message) = <A as FromMqttMessage>::from(Message {
+                            topic,
+                            payload,
+                            qos,
+                            retain,
+                        }) {
+                            match handler.request(message) {
+                                Ok(response) => {
+                                    response.await;
+                                }
+                                Err(e) => {
+                                    warn!("Unable to deliver MQTT message: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                    (Event::Publish, len, packet_id)
+                }
+                Packet::Ack(event) => (event, len, None),
+            },
+        };
+        self.rx_buf.copy_within(len..self.rx_pos, 0);
+        self.rx_pos -= len;
+
+        if let Event::PingResp = event {
+            self.ping_outstanding = false;
+        }
+        if let Some(id) = ack {
+            encode_ack(&mut self.tx_buf, PUBACK, id);
+            self.transmit(4).await?;
+        }
+        Ok(Some(event))
+    }
+
+    /// Drop the connection after receiving data that can not be processed, as the packets
+    /// following it can not be found in the stream.
+    async fn abort(&mut self) {
+        self.rx_pos = 0;
+        self.connected = false;
+        self.socket.close().await;
+    }
+}
+
+fn response_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(RESPONSE_TIMEOUT_SECS)
+}
+
+enum Packet<'m> {
+    Publish {
+        topic: &'m str,
+        payload: &'m [u8],
+        qos: QoS,
+        retain: bool,
+        packet_id: Option<u16>,
+    },
+    Ack(Event),
+}
+
+/// Writes packet fields into a buffer, failing if the buffer is too small.
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Encoder<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> Result<(), Error> {
+        if self.pos + value.len() > self.buf.len() {
+            return Err(Error::BufferTooSmall);
+        }
+        self.buf[self.pos..self.pos + value.len()].copy_from_slice(value);
+        self.pos += value.len();
+        Ok(())
+    }
+
+    /// A length-prefixed string or binary field.
+    fn prefixed(&mut self, value: &[u8]) -> Result<(), Error> {
+        if value.len() > u16::MAX as usize {
+            return Err(Error::BufferTooSmall);
+        }
+        self.u16(value.len() as u16)?;
+        self.bytes(value)
+    }
+
+    /// Fixed header with the variable-length encoded remaining length.
+    fn header(&mut self, kind: u8, mut remaining: usize) -> Result<(), Error> {
+        self.u8(kind)?;
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            self.u8(byte)?;
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn encode_connect(buf: &mut [u8], options: &ConnectOptions<'_>) -> Result<usize, Error> {
+    let mut flags = 0;
+    let mut remaining = 10 + 2 + options.client_id.len();
+    if let Some(username) = options.username {
+        flags |= 0x80;
+        remaining += 2 + username.len();
+    }
+    if let Some(password) = options.password {
+        flags |= 0x40;
+        remaining += 2 + password.len();
+    }
+    if options.clean_session {
+        flags |= 0x02;
+    }
+
+    let mut e = Encoder::new(buf);
+    e.header(CONNECT, remaining)?;
+    e.prefixed(b"MQTT")?;
+    e.u8(4)?;
+    e.u8(flags)?;
+    e.u16(options.keep_alive)?;
+    e.prefixed(options.client_id.as_bytes())?;
+    if let Some(username) = options.username {
+        e.prefixed(username.as_bytes())?;
+    }
+    if let Some(password) = options.password {
+        e.prefixed(password)?;
+    }
+    Ok(e.pos)
+}
+
+fn encode_publish(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    packet_id: Option<u16>,
+) -> Result<usize, Error> {
+    let mut kind = PUBLISH | ((qos as u8) << 1);
+    if retain {
+        kind |= 0x01;
+    }
+    let remaining = 2 + topic.len() + packet_id.map(|_| 2).unwrap_or(0) + payload.len();
+
+    let mut e = Encoder::new(buf);
+    e.header(kind, remaining)?;
+    e.prefixed(topic.as_bytes())?;
+    if let Some(id) = packet_id {
+        e.u16(id)?;
+    }
+    e.bytes(payload)?;
+    Ok(e.pos)
+}
+
+fn encode_subscribe(buf: &mut [u8], packet_id: u16, topic: &str, qos: QoS) -> Result<usize, Error> {
+    let mut e = Encoder::new(buf);
+    e.header(SUBSCRIBE, 2 + 2 + topic.len() + 1)?;
+    e.u16(packet_id)?;
+    e.prefixed(topic.as_bytes())?;
+    e.u8(qos as u8)?;
+    Ok(e.pos)
+}
+
+fn encode_unsubscribe(buf: &mut [u8], packet_id: u16, topic: &str) -> Result<usize, Error> {
+    let mut e = Encoder::new(buf);
+    e.header(UNSUBSCRIBE, 2 + 2 + topic.len())?;
+    e.u16(packet_id)?;
+    e.prefixed(topic.as_bytes())?;
+    Ok(e.pos)
+}
+
+fn encode_ack(buf: &mut [u8], kind: u8, packet_id: u16) {
+    buf[0] = kind;
+    buf[1] = 2;
+    buf[2..4].copy_from_slice(&packet_id.to_be_bytes());
+}
+
+fn read_u16(data: &[u8]) -> Result<u16, Error> {
+    if data.len() < 2 {
+        return Err(Error::Codec);
+    }
+    Ok(u16::from_be_bytes([data[0], data[1]]))
+}
+
+/// Decode the first packet in `data`. Returns the packet and its total length,
+/// or `None` if the packet is not yet complete.
+fn decode(data: &[u8]) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let mut remaining: usize = 0;
+    let mut pos = 1;
+    loop {
+        if pos >= data.len() {
+            return Ok(None);
+        }
+        if pos > 4 {
+            return Err(Error::Codec);
+        }
+        let byte = data[pos];
+        remaining |= ((byte & 0x7F) as usize) << (7 * (pos - 1));
+        pos += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let len = pos + remaining;
+    if data.len() < len {
+        return Ok(None);
+    }
+    let kind = data[0];
+    let body = &data[pos..len];
+
+    let packet = match kind & 0xF0 {
+        CONNACK => {
+            if body.len() != 2 {
+                return Err(Error::Codec);
+            }
+            Packet::Ack(Event::ConnAck(body[1]))
+        }
+        PUBLISH => {
+            let qos = match (kind >> 1) & 0x03 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => return Err(Error::Protocol),
+            };
+            let topic_len = read_u16(body)? as usize;
+            if body.len() < 2 + topic_len {
+                return Err(Error::Codec);
+            }
+            let topic = core::str::from_utf8(&body[2..2 + topic_len]).map_err(|_| Error::Codec)?;
+            let mut payload = &body[2 + topic_len..];
+            let packet_id = if qos == QoS::AtLeastOnce {
+                let id = read_u16(payload)?;
+                payload = &payload[2..];
+                Some(id)
+            } else {
+                None
+            };
+            Packet::Publish {
+                topic,
+                payload,
+                qos,
+                retain: kind & 0x01 != 0,
+                packet_id,
+            }
+        }
+        PUBACK => Packet::Ack(Event::PubAck(read_u16(body)?)),
+        SUBACK => {
+            if body.len() < 3 {
+                return Err(Error::Codec);
+            }
+            let granted = match body[2] {
+                0 => Some(QoS::AtMostOnce),
+                1 | 2 => Some(QoS::AtLeastOnce),
+                _ => None,
+            };
+            Packet::Ack(Event::SubAck(read_u16(body)?, granted))
+        }
+        UNSUBACK => Packet::Ack(Event::UnsubAck(read_u16(body)?)),
+        PINGRESP => Packet::Ack(Event::PingResp),
+        _ => return Err(Error::Protocol),
+    };
+    Ok(Some((packet, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        kernel::util::ImmediateFuture,
+        traits::ip::{IpAddress, SocketAddress},
+    };
+    use core::{future::Future, pin::Pin};
+    use futures::{
+        executor::block_on,
+        future::{ready, Ready},
+    };
+    use std::vec::Vec;
+
+    /// A socket replaying canned broker data, at most `chunk` bytes per read.
+    struct MockSocket {
+        rx: Vec<u8>,
+        rx_pos: usize,
+        chunk: usize,
+        tx: Vec<u8>,
+        closed: bool,
+    }
+
+    impl MockSocket {
+        fn new(rx: &[u8], chunk: usize) -> Self {
+            Self {
+                rx: rx.to_vec(),
+                rx_pos: 0,
+                chunk,
+                tx: Vec::new(),
+                closed: false,
+            }
+        }
+    }
+
+    impl TcpSocket for MockSocket {
+        type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn connect<'m>(&'m mut self, _: IpProtocol, _: SocketAddress) -> Self::ConnectFuture<'m> {
+            async move { Ok(()) }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn write<'m>(&'m mut self, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                self.tx.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+
+        type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn read<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            async move {
+                let len = buf.len().min(self.chunk).min(self.rx.len() - self.rx_pos);
+                buf[..len].copy_from_slice(&self.rx[self.rx_pos..self.rx_pos + len]);
+                self.rx_pos += len;
+                Ok(len)
+            }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self) -> Self::CloseFuture<'m> {
+            async move {
+                self.closed = true;
+            }
+        }
+    }
+
+    /// Handler ignoring all messages.
+    struct Sink;
+
+    impl Actor for Sink {
+        type OnStartFuture<'m> = ImmediateFuture;
+        fn on_start(self: Pin<&'_ mut Self>) -> Self::OnStartFuture<'_> {
+            ImmediateFuture::new()
+        }
+
+        type OnMessageFuture<'m> = Ready<()>;
+        fn on_message<'m>(self: Pin<&'m mut Self>, _: ()) -> Self::OnMessageFuture<'m> {
+            ready(())
+        }
+    }
+
+    impl FromMqttMessage for Sink {
+        fn from<'m>(_: Message<'m>) -> Option<()> {
+            None
+        }
+    }
+
+    /// Connect to a broker sending `data` after accepting the connection, and poll until
+    /// polling fails.
+    fn poll_until_error(data: &[u8]) -> (Error, MockSocket) {
+        let mut rx = std::vec![0x20, 2, 0, 0];
+        rx.extend_from_slice(data);
+        let mut socket = MockSocket::new(&rx, 8);
+        let error = {
+            let mut client: MqttClient<'_, _, Sink, 16> = MqttClient::new(&mut socket);
+            block_on(async {
+                client
+                    .connect(
+                        SocketAddress::new(IpAddress::new_v4(127, 0, 0, 1), 1883),
+                        ConnectOptions::new("dev"),
+                    )
+                    .await
+                    .unwrap();
+                let error = loop {
+                    if let Err(e) = client.poll().await {
+                        break e;
+                    }
+                };
+                assert!(!client.is_connected());
+                assert_eq!(0, client.rx_pos);
+                error
+            })
+        };
+        (error, socket)
+    }
+
+    #[test]
+    fn close_on_undecodable_packet() {
+        // A PINGRESP, then a packet of a type never sent by brokers
+        let (error, socket) = poll_until_error(&[0xD0, 0, 0xF0, 0]);
+        assert!(matches!(error, Error::Protocol));
+        assert!(socket.closed);
+    }
+
+    #[test]
+    fn close_on_oversize_packet() {
+        // A PUBLISH with 32 bytes of remaining length
+        let mut data = std::vec![0x30, 32, 0, 1, b't'];
+        data.extend_from_slice(&[0; 29]);
+        let (error, socket) = poll_until_error(&data);
+        assert!(matches!(error, Error::BufferTooSmall));
+        assert!(socket.closed);
+    }
+
+    #[test]
+    fn encode_connect_with_credentials() {
+        let mut buf = [0; 64];
+        let options = ConnectOptions::new("dev")
+            .keep_alive(30)
+            .credentials("user", b"pw");
+        let len = encode_connect(&mut buf, &options).unwrap();
+        assert_eq!(
+            &[
+                0x10, 25, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xC2, 0, 30, 0, 3, b'd', b'e', b'v', 0,
+                4, b'u', b's', b'e', b'r', 0, 2, b'p', b'w'
+            ],
+            &buf[..len]
+        );
+    }
+
+    #[test]
+    fn encode_publish_qos1() {
+        let mut buf = [0; 32];
+        let len = encode_publish(&mut buf, "a/b", b"hi", QoS::AtLeastOnce, true, Some(7)).unwrap();
+        assert_eq!(
+            &[0x33, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'h', b'i'],
+            &buf[..len]
+        );
+    }
+
+    #[test]
+    fn encode_too_large() {
+        let mut buf = [0; 8];
+        assert!(
+            encode_publish(&mut buf, "topic", b"payload", QoS::AtMostOnce, false, None).is_err()
+        );
+    }
+
+    #[test]
+    fn encode_remaining_length() {
+        let mut buf = [0; 256];
+        let payload = [0; 200];
+        let len = encode_publish(&mut buf, "t", &payload, QoS::AtMostOnce, false, None).unwrap();
+        // 2 + 1 + 200 = 203 = 0xCB 0x01
+        assert_eq!(&[0x30, 0xCB, 0x01], &buf[..3]);
+        assert_eq!(206, len);
+        match decode(&buf[..len]).unwrap() {
+            Some((Packet::Publish { topic, payload, .. }, l)) => {
+                assert_eq!("t", topic);
+                assert_eq!(200, payload.len());
+                assert_eq!(len, l);
+            }
+            _ => panic!("unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn decode_publish() {
+        let data = [0x32, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'h', b'i', 0xD0];
+        match decode(&data).unwrap() {
+            Some((
+                Packet::Publish {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    packet_id,
+                },
+                len,
+            )) => {
+                assert_eq!("a/b", topic);
+                assert_eq!(b"hi", payload);
+                assert_eq!(QoS::AtLeastOnce, qos);
+                assert!(!retain);
+                assert_eq!(Some(7), packet_id);
+                assert_eq!(11, len);
+            }
+            _ => panic!("unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn decode_incomplete() {
+        assert!(decode(&[0x32]).unwrap().is_none());
+        assert!(decode(&[0x32, 9, 0, 3]).unwrap().is_none());
+        assert!(decode(&[0x30, 0x80]).unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_acks() {
+        match decode(&[0x20, 2, 0, 5]).unwrap() {
+            Some((Packet::Ack(event), 4)) => assert_eq!(Event::ConnAck(5), event),
+            _ => panic!("unexpected packet"),
+        }
+        match decode(&[0x90, 3, 0, 1, 0x80]).unwrap() {
+            Some((Packet::Ack(event), 5)) => assert_eq!(Event::SubAck(1, None), event),
+            _ => panic!("unexpected packet"),
+        }
+        match decode(&[0xD0, 0]).unwrap() {
+            Some((Packet::Ack(event), 2)) => assert_eq!(Event::PingResp, event),
+            _ => panic!("unexpected packet"),
+        }
+    }
+}
@@ -0,0 +1,780 @@
+//! A WebSocket (RFC 6455) client working over any `TcpSocket`.
+//!
+//! The opening handshake is performed with `HttpClient::upgrade`. Wrapping the socket
+//! in a `TlsSocket` gives an encrypted (wss://) connection, without authenticating the
+//! server.
+
+use crate::clients::http::{self, HttpClient, Request};
+use crate::traits::{
+    ip::IpAddress,
+    tcp::{TcpError, TcpSocket},
+};
+use core::cmp::min;
+use embassy::time::{Duration, Instant, Timer};
+
+/// How often to poll the socket for data while waiting for a frame.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// How long the server may take to send the rest of a frame once it started, or to confirm
+/// closing the connection.
+const FRAME_TIMEOUT_SECS: u64 = 30;
+
+/// GUID appended to the handshake key when computing the accept key.
+const WEBSOCKET_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest payload allowed in a control frame.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Status code sent when closing a connection normally.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying socket reported an error.
+    Network(TcpError),
+    /// The HTTP upgrade request failed.
+    Http(http::Error),
+    /// The server did not accept the upgrade to WebSocket.
+    Handshake,
+    /// The server sent a frame not allowed by the protocol.
+    Protocol,
+    /// A message did not fit in the provided buffer.
+    BufferTooSmall,
+    /// The connection has been closed.
+    Closed,
+    /// The server stopped sending in the middle of a frame, or did not confirm closing in time.
+    Timeout,
+}
+
+impl From<TcpError> for Error {
+    fn from(e: TcpError) -> Self {
+        match e {
+            TcpError::SocketClosed => Error::Closed,
+            e => Error::Network(e),
+        }
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(e: http::Error) -> Self {
+        match e {
+            http::Error::Network(e) => Error::Network(e),
+            e => Error::Http(e),
+        }
+    }
+}
+
+/// A message received from the server.
+#[derive(Debug, PartialEq)]
+pub enum Message<'m> {
+    Text(&'m str),
+    Binary(&'m [u8]),
+    /// A ping from the server. The client has already answered it with a pong.
+    Ping(&'m [u8]),
+    Pong(&'m [u8]),
+    /// The server closed the connection, with an optional status code and reason.
+    Close(Option<(u16, &'m str)>),
+}
+
+#[rustfmt::skip]
+pub struct WebSocketClient<'a, S, const N: usize = 512>
+where
+    S: TcpSocket + 'static,
+{
+    socket: &'a mut S,
+    get_random: fn() -> u32,
+    rx_buf: [u8; N],
+    rx_pos: usize,
+    rx_len: usize,
+    tx_buf: [u8; N],
+    closed: bool,
+}
+
+impl<'a, S, const N: usize> WebSocketClient<'a, S, N>
+where
+    S: TcpSocket + 'static,
+{
+    /// Create a client for a socket. `get_random` provides the handshake key and the
+    /// masking keys of outgoing frames.
+    pub fn new(socket: &'a mut S, get_random: fn() -> u32) -> Self {
+        Self {
+            socket,
+            get_random,
+            rx_buf: [0; N],
+            rx_pos: 0,
+            rx_len: 0,
+            tx_buf: [0; N],
+            closed: true,
+        }
+    }
+
+    /// Connect to the server and perform the opening handshake for `path`.
+    pub async fn connect(
+        &mut self,
+        ip: IpAddress,
+        port: u16,
+        host: &str,
+        path: &str,
+    ) -> Result<(), Error> {
+        let mut nonce = [0; 16];
+        for chunk in nonce.chunks_mut(4) {
+            chunk.copy_from_slice(&(self.get_random)().to_be_bytes());
+        }
+        let mut key = [0; 24];
+        base64::encode_config_slice(&nonce, base64::STANDARD, &mut key);
+        let key = core::str::from_utf8(&key).map_err(|_| Error::Handshake)?;
+        let expected = accept_key(key.as_bytes());
+
+        let headers = [
+            ("Upgrade", "websocket"),
+            ("Connection", "Upgrade"),
+            ("Sec-WebSocket-Key", key),
+            ("Sec-WebSocket-Version", "13"),
+        ];
+        let request = Request::get(path).headers(&headers);
+        let mut client = HttpClient::new(&mut *self.socket, ip, port).host(host);
+        let response = client.upgrade(request, &mut self.tx_buf).await?;
+
+        let upgraded = response.status() == 101
+            && response
+                .header("Upgrade")
+                .map(|v| v.eq_ignore_ascii_case("websocket"))
+                .unwrap_or(false)
+            && response.header("Sec-WebSocket-Accept") == Some(as_str(&expected));
+        if !upgraded {
+            warn!(
+                "WebSocket upgrade rejected with status {}",
+                response.status()
+            );
+            return Err(Error::Handshake);
+        }
+
+        // Frames may have arrived together with the handshake response
+        let pending = response.payload();
+        self.rx_buf[..pending.len()].copy_from_slice(pending);
+        self.rx_pos = 0;
+        self.rx_len = pending.len();
+        self.closed = false;
+        info!("WebSocket connected to {}", path);
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        !self.closed
+    }
+
+    pub async fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.send_frame(OPCODE_BINARY, data).await
+    }
+
+    pub async fn ping(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Error::BufferTooSmall);
+        }
+        self.send_frame(OPCODE_PING, data).await
+    }
+
+    pub async fn pong(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(Error::BufferTooSmall);
+        }
+        self.send_frame(OPCODE_PONG, data).await
+    }
+
+    /// Wait for the next message, reassembling fragmented messages into `buf`. Pings
+    /// are answered automatically.
+    pub async fn next<'m>(&mut self, buf: &'m mut [u8]) -> Result<Message<'m>, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        // Opcode and length of a fragmented message being reassembled
+        let mut fragmented: Option<(u8, usize)> = None;
+        loop {
+            // Only the first frame of a message may be waited for indefinitely
+            let start = fragmented.map(|_| frame_deadline());
+            let (fin, opcode, len, deadline) = self.read_header(start).await?;
+            match opcode {
+                OPCODE_TEXT | OPCODE_BINARY | OPCODE_CONTINUATION => {
+                    let (opcode, offset) = match (opcode, fragmented) {
+                        (OPCODE_CONTINUATION, Some((opcode, offset))) => (opcode, offset),
+                        (OPCODE_CONTINUATION, None) | (_, Some(_)) => return Err(Error::Protocol),
+                        (opcode, None) => (opcode, 0),
+                    };
+                    let end = match offset.checked_add(len) {
+                        Some(end) if end <= buf.len() => end,
+                        _ => return Err(Error::BufferTooSmall),
+                    };
+                    self.read_exact(&mut buf[offset..end], Some(deadline))
+                        .await?;
+                    if !fin {
+                        fragmented.replace((opcode, end));
+                        continue;
+                    }
+                    let data = &buf[..end];
+                    return if opcode == OPCODE_TEXT {
+                        let text = core::str::from_utf8(data).map_err(|_| Error::Protocol)?;
+                        Ok(Message::Text(text))
+                    } else {
+                        Ok(Message::Binary(data))
+                    };
+                }
+                OPCODE_PING | OPCODE_PONG | OPCODE_CLOSE => {
+                    if !fin || len > MAX_CONTROL_PAYLOAD {
+                        return Err(Error::Protocol);
+                    }
+                    let mut control = [0; MAX_CONTROL_PAYLOAD];
+                    self.read_exact(&mut control[..len], Some(deadline)).await?;
+                    let data = &control[..len];
+                    match opcode {
+                        OPCODE_PING => {
+                            self.send_frame(OPCODE_PONG, data).await?;
+                            // Only surface pings that do not interrupt a fragmented message
+                            if fragmented.is_none() {
+                                return copy_control(data, buf).map(Message::Ping);
+                            }
+                        }
+                        OPCODE_PONG => {
+                            if fragmented.is_none() {
+                                return copy_control(data, buf).map(Message::Pong);
+                            }
+                        }
+                        _ => {
+                            // Echo the close frame to complete the closing handshake
+                            let status = if len >= 2 { &data[..2] } else { &[][..] };
+                            let _ = self.send_frame(OPCODE_CLOSE, status).await;
+                            self.closed = true;
+                            self.socket.close().await;
+                            return decode_close(copy_control(data, buf)?);
+                        }
+                    }
+                }
+                _ => return Err(Error::Protocol),
+            }
+        }
+    }
+
+    /// Start the closing handshake and wait for the server to confirm it.
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        if self.closed {
+            return Ok(());
+        }
+        let mut payload = [0; MAX_CONTROL_PAYLOAD];
+        let reason = &reason.as_bytes()[..min(reason.len(), MAX_CONTROL_PAYLOAD - 2)];
+        payload[..2].copy_from_slice(&code.to_be_bytes());
+        payload[2..2 + reason.len()].copy_from_slice(reason);
+        let result = self
+            .send_frame(OPCODE_CLOSE, &payload[..2 + reason.len()])
+            .await;
+
+        if result.is_ok() {
+            // Discard anything the server sends before its close frame
+            let deadline = frame_deadline();
+            loop {
+                match self.read_header(Some(deadline)).await {
+                    Ok((_, opcode, len, _)) => {
+                        if self.skip(len, deadline).await.is_err() || opcode == OPCODE_CLOSE {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        self.closed = true;
+        self.socket.close().await;
+        result
+    }
+
+    /// Read a frame header, waiting for it to start until `deadline` if any. Returns the FIN
+    /// flag, opcode and payload length, and the deadline for the rest of the frame.
+    async fn read_header(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<(bool, u8, usize, Instant), Error> {
+        let mut header = [0; 2];
+        self.read_exact(&mut header[..1], deadline).await?;
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => frame_deadline(),
+        };
+        self.read_exact(&mut header[1..], Some(deadline)).await?;
+        if header[0] & 0x70 != 0 {
+            // No extensions were negotiated, so reserved bits must be zero
+            return Err(Error::Protocol);
+        }
+        if header[1] & MASKED != 0 {
+            // Servers must not mask frames
+            return Err(Error::Protocol);
+        }
+        let len = match header[1] & 0x7F {
+            126 => {
+                let mut ext = [0; 2];
+                self.read_exact(&mut ext, Some(deadline)).await?;
+                u16::from_be_bytes(ext) as usize
+            }
+            127 => {
+                let mut ext = [0; 8];
+                self.read_exact(&mut ext, Some(deadline)).await?;
+                let len = u64::from_be_bytes(ext);
+                if len & (1 << 63) != 0 {
+                    // The most significant bit of a 64-bit length must be zero
+                    return Err(Error::Protocol);
+                }
+                if len > usize::MAX as u64 {
+                    return Err(Error::BufferTooSmall);
+                }
+                len as usize
+            }
+            len => len as usize,
+        };
+        Ok((header[0] & FIN != 0, header[0] & 0x0F, len, deadline))
+    }
+
+    /// Fill `buf`, giving up at `deadline` if any.
+    async fn read_exact(&mut self, buf: &mut [u8], deadline: Option<Instant>) -> Result<(), Error> {
+        let mut pos = 0;
+        // Use data buffered during the handshake first
+        if self.rx_pos < self.rx_len {
+            let len = min(buf.len(), self.rx_len - self.rx_pos);
+            buf[..len].copy_from_slice(&self.rx_buf[self.rx_pos..self.rx_pos + len]);
+            self.rx_pos += len;
+            pos = len;
+        }
+        while pos < buf.len() {
+            match self.socket.read(&mut buf[pos..]).await {
+                // No data available yet
+                Ok(0) => {
+                    if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                        self.closed = true;
+                        return Err(Error::Timeout);
+                    }
+                    Timer::after(Duration::from_millis(POLL_INTERVAL_MS)).await;
+                }
+                Ok(len) => pos += len,
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn skip(&mut self, mut len: usize, deadline: Instant) -> Result<(), Error> {
+        let mut scratch = [0; 64];
+        while len > 0 {
+            let n = min(len, scratch.len());
+            self.read_exact(&mut scratch[..n], Some(deadline)).await?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let mask = (self.get_random)().to_be_bytes();
+        let mut pos = encode_header(&mut self.tx_buf, opcode, payload.len(), mask);
+        let mut offset = 0;
+        loop {
+            let len = min(payload.len() - offset, N - pos);
+            self.tx_buf[pos..pos + len].copy_from_slice(&payload[offset..offset + len]);
+            apply_mask(&mut self.tx_buf[pos..pos + len], mask, offset);
+            offset += len;
+            pos += len;
+            self.write_all(pos).await?;
+            if offset == payload.len() {
+                return Ok(());
+            }
+            pos = 0;
+        }
+    }
+
+    async fn write_all(&mut self, len: usize) -> Result<(), Error> {
+        let mut pos = 0;
+        while pos < len {
+            match self.socket.write(&self.tx_buf[pos..len]).await {
+                Ok(0) => return Err(Error::Network(TcpError::WriteError)),
+                Ok(n) => pos += n,
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_control<'m>(data: &[u8], buf: &'m mut [u8]) -> Result<&'m [u8], Error> {
+    if data.len() > buf.len() {
+        return Err(Error::BufferTooSmall);
+    }
+    buf[..data.len()].copy_from_slice(data);
+    Ok(&buf[..data.len()])
+}
+
+fn decode_close(payload: &[u8]) -> Result<Message<'_>, Error> {
+    match payload.len() {
+        0 => Ok(Message::Close(None)),
+        1 => Err(Error::Protocol),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            let reason = core::str::from_utf8(&payload[2..]).map_err(|_| Error::Protocol)?;
+            Ok(Message::Close(Some((code, reason))))
+        }
+    }
+}
+
+/// Write a masked, final frame header into `buf`, returning its length.
+fn encode_header(buf: &mut [u8], opcode: u8, len: usize, mask: [u8; 4]) -> usize {
+    buf[0] = FIN | opcode;
+    let pos = if len < 126 {
+        buf[1] = MASKED | len as u8;
+        2
+    } else if len <= u16::MAX as usize {
+        buf[1] = MASKED | 126;
+        buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+        4
+    } else {
+        buf[1] = MASKED | 127;
+        buf[2..10].copy_from_slice(&(len as u64).to_be_bytes());
+        10
+    };
+    buf[pos..pos + 4].copy_from_slice(&mask);
+    pos + 4
+}
+
+/// Mask `data`, which starts at `offset` within the frame payload.
+fn apply_mask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, b) in data.iter_mut().enumerate() {
+        *b ^= mask[(offset + i) % 4];
+    }
+}
+
+fn as_str(data: &[u8]) -> &str {
+    core::str::from_utf8(data).unwrap_or("")
+}
+
+/// The expected Sec-WebSocket-Accept value for a handshake key.
+fn accept_key(key: &[u8]) -> [u8; 28] {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(WEBSOCKET_GUID);
+    let mut accept = [0; 28];
+    base64::encode_config_slice(&sha1.finish(), base64::STANDARD, &mut accept);
+    accept
+}
+
+/// Minimal SHA-1, only used for the handshake accept key.
+struct Sha1 {
+    state: [u32; 5],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+impl Sha1 {
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u64;
+        while !data.is_empty() {
+            let len = min(data.len(), 64 - self.block_len);
+            self.block[self.block_len..self.block_len + len].copy_from_slice(&data[..len]);
+            self.block_len += len;
+            data = &data[len..];
+            if self.block_len == 64 {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 20] {
+        let bit_len = self.total_len * 8;
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+
+        let mut digest = [0; 20];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+}
+
+fn frame_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(FRAME_TIMEOUT_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traits::ip::{IpProtocol, SocketAddress};
+    use core::future::Future;
+    use futures::executor::block_on;
+    use std::vec::Vec;
+
+    /// A socket replaying canned server data and recording what the client writes.
+    struct MockSocket {
+        rx: Vec<u8>,
+        rx_pos: usize,
+        tx: Vec<u8>,
+    }
+
+    impl MockSocket {
+        fn new(rx: Vec<u8>) -> Self {
+            Self {
+                rx,
+                rx_pos: 0,
+                tx: Vec::new(),
+            }
+        }
+    }
+
+    impl TcpSocket for MockSocket {
+        type ConnectFuture<'m> = impl Future<Output = Result<(), TcpError>> + 'm;
+        fn connect<'m>(&'m mut self, _: IpProtocol, _: SocketAddress) -> Self::ConnectFuture<'m> {
+            async move { Ok(()) }
+        }
+
+        type WriteFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn write<'m>(&'m mut self, buf: &'m [u8]) -> Self::WriteFuture<'m> {
+            async move {
+                self.tx.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+
+        type ReadFuture<'m> = impl Future<Output = Result<usize, TcpError>> + 'm;
+        fn read<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            async move {
+                if self.rx_pos == self.rx.len() {
+                    return Err(TcpError::SocketClosed);
+                }
+                let len = min(buf.len(), self.rx.len() - self.rx_pos);
+                buf[..len].copy_from_slice(&self.rx[self.rx_pos..self.rx_pos + len]);
+                self.rx_pos += len;
+                Ok(len)
+            }
+        }
+
+        type CloseFuture<'m> = impl Future<Output = ()> + 'm;
+        fn close<'m>(&'m mut self) -> Self::CloseFuture<'m> {
+            async move {}
+        }
+    }
+
+    fn random() -> u32 {
+        0x01020304
+    }
+
+    /// Key sent by the client given `random`, and the matching accept value.
+    fn handshake_response() -> Vec<u8> {
+        let mut key = [0; 24];
+        base64::encode_config_slice(&[1u8, 2, 3, 4].repeat(4), base64::STANDARD, &mut key);
+        let accept = accept_key(&key);
+        let mut response = Vec::new();
+        response.extend_from_slice(
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: ",
+        );
+        response.extend_from_slice(&accept);
+        response.extend_from_slice(b"\r\n\r\n");
+        response
+    }
+
+    #[test]
+    fn sha1_digest() {
+        let mut sha1 = Sha1::new();
+        sha1.update(b"abc");
+        assert_eq!(
+            [
+                0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+                0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d
+            ],
+            sha1.finish()
+        );
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        assert_eq!(
+            b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+            &accept_key(b"dGhlIHNhbXBsZSBub25jZQ==")
+        );
+    }
+
+    #[test]
+    fn frame_headers() {
+        let mut buf = [0; 14];
+        let mask = [1, 2, 3, 4];
+        assert_eq!(6, encode_header(&mut buf, OPCODE_TEXT, 5, mask));
+        assert_eq!(&[0x81, 0x85, 1, 2, 3, 4], &buf[..6]);
+        assert_eq!(8, encode_header(&mut buf, OPCODE_BINARY, 300, mask));
+        assert_eq!(&[0x82, 0xFE, 0x01, 0x2C], &buf[..4]);
+        assert_eq!(14, encode_header(&mut buf, OPCODE_BINARY, 70000, mask));
+        assert_eq!(&[0x82, 0xFF, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70], &buf[..10]);
+    }
+
+    #[test]
+    fn masking_across_chunks() {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut data = *b"Hello";
+        apply_mask(&mut data[..3], mask, 0);
+        apply_mask(&mut data[3..], mask, 3);
+        assert_eq!([0x7f, 0x9f, 0x4d, 0x51, 0x58], data);
+    }
+
+    #[test]
+    fn connect_and_exchange_messages() {
+        let mut rx = handshake_response();
+        // A fragmented text message, interrupted by a ping
+        rx.extend_from_slice(&[0x01, 3, b'H', b'e', b'l']);
+        rx.extend_from_slice(&[0x89, 1, b'p']);
+        rx.extend_from_slice(&[0x80, 2, b'l', b'o']);
+        rx.extend_from_slice(&[0x82, 2, 0xCA, 0xFE]);
+        rx.extend_from_slice(&[0x88, 4, 0x03, 0xE8, b'o', b'k']);
+
+        let mut socket = MockSocket::new(rx);
+        {
+            let mut client: WebSocketClient<'_, _> = WebSocketClient::new(&mut socket, random);
+            let mut buf = [0; 64];
+            block_on(async {
+                client
+                    .connect(IpAddress::new_v4(127, 0, 0, 1), 80, "example.com", "/ws")
+                    .await
+                    .unwrap();
+                client.send_text("Hi").await.unwrap();
+                assert_eq!(Message::Text("Hello"), client.next(&mut buf).await.unwrap());
+                assert_eq!(
+                    Message::Binary(&[0xCA, 0xFE]),
+                    client.next(&mut buf).await.unwrap()
+                );
+                assert_eq!(
+                    Message::Close(Some((1000, "ok"))),
+                    client.next(&mut buf).await.unwrap()
+                );
+                assert!(!client.is_connected());
+            });
+        }
+
+        let request = core::str::from_utf8(&socket.tx).unwrap_or("");
+        assert!(request.starts_with("GET /ws HTTP/1.1\r\nHost: example.com\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: AQIDBAECAwQBAgMEAQIDBA==\r\n"));
+
+        let frames = &socket.tx[socket.tx.iter().position(|b| *b == 0x81).unwrap()..];
+        // Masked "Hi" with mask 01 02 03 04
+        assert_eq!(&[0x81, 0x82, 1, 2, 3, 4, b'H' ^ 1, b'i' ^ 2], &frames[..8]);
+        // Pong answering the ping, then the close echo
+        assert_eq!(&[0x8A, 0x81, 1, 2, 3, 4, b'p' ^ 1], &frames[8..15]);
+        assert_eq!(&[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ 1, 0xE8 ^ 2], &frames[15..]);
+    }
+
+    /// Connect and return the error reading a message from `frames`.
+    fn next_error(frames: &[u8]) -> Error {
+        let mut rx = handshake_response();
+        rx.extend_from_slice(frames);
+        let mut socket = MockSocket::new(rx);
+        let mut client: WebSocketClient<'_, _> = WebSocketClient::new(&mut socket, random);
+        let mut buf = [0; 64];
+        block_on(async {
+            client
+                .connect(IpAddress::new_v4(127, 0, 0, 1), 80, "example.com", "/ws")
+                .await
+                .unwrap();
+            match client.next(&mut buf).await {
+                Ok(_) => panic!("Unexpected message"),
+                Err(e) => e,
+            }
+        })
+    }
+
+    #[test]
+    fn oversized_frame_lengths() {
+        // A continuation extending the message by the largest valid length
+        assert!(matches!(
+            next_error(&[
+                0x01, 3, b'a', b'b', b'c', 0x80, 127, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                0xFF
+            ]),
+            Error::BufferTooSmall
+        ));
+        // A 64-bit length with the most significant bit set
+        assert!(matches!(
+            next_error(&[0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 1]),
+            Error::Protocol
+        ));
+    }
+
+    #[test]
+    fn rejected_handshake() {
+        let mut socket =
+            MockSocket::new(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_vec());
+        let mut client: WebSocketClient<'_, _> = WebSocketClient::new(&mut socket, random);
+        let result =
+            block_on(client.connect(IpAddress::new_v4(127, 0, 0, 1), 80, "example.com", "/ws"));
+        assert!(matches!(result, Err(Error::Handshake)));
+    }
+}
@@ -0,0 +1,654 @@
+//! Engine for modems driven by AT commands over a UART.
+//!
+//! The engine is split in the same way as the drivers using it:
+//!
+//! * `AtEngine` holds the state shared by the other parts, and lives in the driver.
+//! * `AtClient` is used by the controller to queue commands and await their responses.
+//! * `AtModem` owns the UART, writes queued commands and routes parsed responses.
+//!
+//! Commands are pipelined: they are written in the order they were queued, with up to `QUEUE`
+//! commands awaiting their first response at a time, and responses are matched to commands in
+//! the order the commands were written. Every command has its own timeout for its first response,
+//! and the modem is reset and re-initialized after `MAX_TIMEOUTS` consecutive timeouts. Modem
+//! specific parsing, routing and initialization is provided by an `AtDevice`.
+//!
+//! The UART is never stalled by a slow client: responses and notifications that do not fit in
+//! their queues are dropped. The notification queue holds `NOTIFICATIONS` entries, which a device
+//! sizes for the notifications it can not afford to lose.
+
+use atomic_polyfill::{AtomicU8, Ordering};
+use core::{cell::UnsafeCell, future::Future, pin::Pin, task::Poll};
+use embassy::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt},
+    time::{with_timeout, Duration, Instant, Timer},
+    util::{
+        mpsc::{self, Channel, Receiver, Sender, WithThreadModeOnly},
+        Signal,
+    },
+};
+use futures::future::{pending, poll_fn, select, Either};
+use futures::pin_mut;
+
+/// Maximum length of a command, including any data written along with it.
+pub const COMMAND_LEN: usize = 256;
+
+/// Number of consecutive timeouts after which the modem is reset.
+pub const MAX_TIMEOUTS: u8 = 3;
+
+/// Time allowed for the initialization sequence of the modem.
+pub const INITIALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time the client waits for a response beyond the timeout of its command, in case the response
+/// was dropped.
+const RESPONSE_GRACE: Duration = Duration::from_secs(1);
+
+type DriverMutex = WithThreadModeOnly;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AtError {
+    /// The modem did not respond in time.
+    Timeout,
+    /// The modem could not be initialized.
+    NotInitialized,
+    /// The command does not fit in `COMMAND_LEN` bytes.
+    CommandTooLong,
+    /// Reading from or writing to the UART failed.
+    Io,
+}
+
+/// Where a response parsed by an `AtDevice` is delivered.
+pub enum Route<R> {
+    /// A response to the command in flight, delivered to the client.
+    Response(R),
+    /// An unsolicited result, delivered to the client as a notification.
+    Notification(R),
+    /// The response was handled by the device itself.
+    Handled,
+}
+
+/// Modem specific part of the engine.
+pub trait AtDevice<UART> {
+    type Response;
+    /// Result of a successful initialization, handed to the client.
+    type Ready: Copy;
+
+    type InitializeFuture<'m>: Future<Output = Result<Self::Ready, AtError>>
+    where
+        Self: 'm;
+    /// Reset the modem and run its initialization sequence.
+    fn initialize<'m>(&'m mut self, uart: &'m mut UART) -> Self::InitializeFuture<'m>;
+
+    /// Feed a byte read from the UART, returning the response it completes, if any.
+    fn digest(&mut self, octet: u8) -> Option<Self::Response>;
+
+    /// Decide where a parsed response is delivered.
+    fn route(&mut self, response: Self::Response) -> Route<Self::Response>;
+
+    /// Number of bytes the device expects straight from the UART before parsing resumes.
+    fn raw_pending(&self) -> usize {
+        0
+    }
+
+    /// Consume data expected by `raw_pending`, returning the number of bytes consumed.
+    fn raw(&mut self, data: &[u8]) -> usize {
+        data.len()
+    }
+
+    /// Abandon the data expected by `raw_pending`, e.g. after reading from the UART failed.
+    fn abort_raw(&mut self) {}
+}
+
+struct AtCommand {
+    id: u16,
+    len: usize,
+    data: [u8; COMMAND_LEN],
+    timeout: Duration,
+}
+
+const INITIALIZING: u8 = 0;
+const READY: u8 = 1;
+const FAILED: u8 = 2;
+
+/// Outcome of the latest initialization of the modem.
+struct Readiness<T> {
+    state: AtomicU8,
+    value: UnsafeCell<Option<T>>,
+    signal: Signal<()>,
+}
+
+// Safety: the value is only written while initializing, and only read once ready.
+unsafe impl<T: Send> Sync for Readiness<T> {}
+
+impl<T: Copy> Readiness<T> {
+    fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INITIALIZING),
+            value: UnsafeCell::new(None),
+            signal: Signal::new(),
+        }
+    }
+
+    fn initializing(&self) {
+        self.state.store(INITIALIZING, Ordering::SeqCst);
+    }
+
+    fn set(&self, result: Result<T, AtError>) {
+        match result {
+            Ok(value) => {
+                unsafe { *self.value.get() = Some(value) };
+                self.state.store(READY, Ordering::SeqCst);
+            }
+            Err(_) => self.state.store(FAILED, Ordering::SeqCst),
+        }
+        self.signal.signal(());
+    }
+
+    fn is_ready(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == READY
+    }
+
+    async fn wait(&self) -> Result<T, AtError> {
+        loop {
+            match self.state.load(Ordering::SeqCst) {
+                READY => return unsafe { *self.value.get() }.ok_or(AtError::NotInitialized),
+                FAILED => return Err(AtError::NotInitialized),
+                _ => self.signal.wait().await,
+            }
+        }
+    }
+}
+
+/// State shared by the client and modem parts of the engine, with room for `QUEUE` pending
+/// commands and responses, and `NOTIFICATIONS` notifications.
+#[rustfmt::skip]
+pub struct AtEngine<R, T, const QUEUE: usize = 2, const NOTIFICATIONS: usize = 4> {
+    ready: Readiness<T>,
+    commands: Channel<DriverMutex, AtCommand, QUEUE>,
+    responses: Channel<DriverMutex, (u16, Result<R, AtError>), QUEUE>,
+    notifications: Channel<DriverMutex, R, NOTIFICATIONS>,
+}
+
+impl<R, T, const QUEUE: usize, const NOTIFICATIONS: usize> AtEngine<R, T, QUEUE, NOTIFICATIONS>
+where
+    T: Copy,
+{
+    pub fn new() -> Self {
+        Self {
+            ready: Readiness::new(),
+            commands: Channel::new(),
+            responses: Channel::new(),
+            notifications: Channel::new(),
+        }
+    }
+
+    /// Split the engine into the client used by the controller and the modem driving `uart`.
+    pub fn split<'a, UART, D>(
+        &'a mut self,
+        uart: UART,
+        device: D,
+    ) -> (
+        AtClient<'a, R, T, QUEUE, NOTIFICATIONS>,
+        AtModem<'a, UART, D, QUEUE, NOTIFICATIONS>,
+    )
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+        D: AtDevice<UART, Response = R, Ready = T>,
+    {
+        let (cp, cc) = mpsc::split(&mut self.commands);
+        let (rp, rc) = mpsc::split(&mut self.responses);
+        let (np, nc) = mpsc::split(&mut self.notifications);
+
+        let client = AtClient {
+            ready: &self.ready,
+            commands: cp,
+            responses: rc,
+            notifications: nc,
+            id: 0,
+        };
+        let modem = AtModem {
+            ready: &self.ready,
+            uart,
+            device,
+            commands: cc,
+            responses: rp,
+            notifications: np,
+            current: 0,
+            in_flight: InFlight::new(),
+            timeouts: 0,
+            dropped: 0,
+        };
+        (client, modem)
+    }
+}
+
+/// Client side of the engine, queueing commands and receiving their responses.
+#[rustfmt::skip]
+pub struct AtClient<'a, R, T, const QUEUE: usize, const NOTIFICATIONS: usize = 4> {
+    ready: &'a Readiness<T>,
+    commands: Sender<'a, DriverMutex, AtCommand, QUEUE>,
+    responses: Receiver<'a, DriverMutex, (u16, Result<R, AtError>), QUEUE>,
+    notifications: Receiver<'a, DriverMutex, R, NOTIFICATIONS>,
+    id: u16,
+}
+
+impl<'a, R, T, const QUEUE: usize, const NOTIFICATIONS: usize>
+    AtClient<'a, R, T, QUEUE, NOTIFICATIONS>
+where
+    T: Copy,
+{
+    /// Wait for the modem to be initialized, returning the result of its initialization.
+    pub async fn ready(&self) -> Result<T, AtError> {
+        self.ready.wait().await
+    }
+
+    /// Queue a command and wait for its first response, which must arrive within `timeout`.
+    ///
+    /// Responses to earlier commands that arrive late are discarded.
+    pub async fn request(&mut self, command: &[u8], timeout: Duration) -> Result<R, AtError> {
+        let id = self.send(command, timeout).await?;
+        self.receive(id, timeout).await
+    }
+
+    /// Queue a command without waiting for its response, returning the id to receive the
+    /// response with. The first response to the command must arrive within `timeout`.
+    ///
+    /// Commands sent this way are written while earlier ones are still awaiting their response,
+    /// so their responses must not include intermediate responses.
+    pub async fn send(&mut self, command: &[u8], timeout: Duration) -> Result<u16, AtError> {
+        self.id = self.id.wrapping_add(1);
+        self.queue(command, timeout).await?;
+        Ok(self.id)
+    }
+
+    /// Wait for the first response to the command `id` sent with `timeout`.
+    ///
+    /// Responses arrive in the order the commands were sent: responses to commands sent before
+    /// `id` that were not received yet are discarded.
+    pub async fn receive(&mut self, id: u16, timeout: Duration) -> Result<R, AtError> {
+        // The modem responds with a timeout if no response arrives in time, unless that
+        // response was dropped because the client did not keep up
+        match with_timeout(timeout + RESPONSE_GRACE, self.response_to(id)).await {
+            Ok(response) => response,
+            Err(_) => Err(AtError::Timeout),
+        }
+    }
+
+    /// Queue data belonging to the current command, such as a payload following a prompt, and
+    /// wait for its first response, which must arrive within `timeout`.
+    pub async fn write(&mut self, data: &[u8], timeout: Duration) -> Result<R, AtError> {
+        self.queue(data, timeout).await?;
+        self.receive(self.id, timeout).await
+    }
+
+    async fn queue(&mut self, data: &[u8], timeout: Duration) -> Result<(), AtError> {
+        if data.len() > COMMAND_LEN {
+            return Err(AtError::CommandTooLong);
+        }
+        let mut command = AtCommand {
+            id: self.id,
+            len: data.len(),
+            data: [0; COMMAND_LEN],
+            timeout,
+        };
+        command.data[..data.len()].copy_from_slice(data);
+        self.commands.send(command).await.map_err(|_| AtError::Io)
+    }
+
+    /// Wait for a further response to the current command, such as the result of a command
+    /// completing after an intermediate response.
+    pub async fn response(&mut self, timeout: Duration) -> Result<R, AtError> {
+        match with_timeout(timeout, self.response_to(self.id)).await {
+            Ok(response) => response,
+            Err(_) => Err(AtError::Timeout),
+        }
+    }
+
+    /// Take a pending notification, if any.
+    pub fn try_notification(&mut self) -> Option<R> {
+        self.notifications.try_recv().ok()
+    }
+
+    /// Wait for the next notification.
+    pub async fn notification(&mut self) -> Option<R> {
+        self.notifications.recv().await
+    }
+
+    async fn response_to(&mut self, expected: u16) -> Result<R, AtError> {
+        loop {
+            match self.responses.recv().await {
+                Some((id, response)) if id == expected => return response,
+                Some(_) => trace!("Discarding response to an earlier command"),
+                None => return Err(AtError::Io),
+            }
+        }
+    }
+}
+
+enum Event {
+    Command(Option<AtCommand>),
+    Input(Result<usize, embassy::io::Error>),
+    Timeout,
+}
+
+/// Commands written to the modem and awaiting their first response, oldest first.
+struct InFlight<const N: usize> {
+    commands: [Option<(u16, Instant)>; N],
+    len: usize,
+}
+
+impl<const N: usize> InFlight<N> {
+    fn new() -> Self {
+        Self {
+            commands: [None; N],
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    fn push(&mut self, id: u16, deadline: Instant) {
+        if !self.is_full() {
+            self.commands[self.len] = Some((id, deadline));
+            self.len += 1;
+        }
+    }
+
+    /// Remove the oldest command, which the next response belongs to.
+    fn pop(&mut self) -> Option<u16> {
+        if self.len == 0 {
+            None
+        } else {
+            self.remove(0)
+        }
+    }
+
+    /// The earliest deadline of the commands in flight.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.commands[..self.len]
+            .iter()
+            .filter_map(|c| c.map(|(_, deadline)| deadline))
+            .min()
+    }
+
+    /// Remove a command whose deadline has passed at `now`.
+    fn expire(&mut self, now: Instant) -> Option<u16> {
+        let index = self.commands[..self.len]
+            .iter()
+            .position(|c| matches!(c, Some((_, deadline)) if *deadline <= now))?;
+        self.remove(index)
+    }
+
+    fn remove(&mut self, index: usize) -> Option<u16> {
+        let removed = self.commands[index].take();
+        self.commands[index..self.len].rotate_left(1);
+        self.len -= 1;
+        removed.map(|(id, _)| id)
+    }
+}
+
+/// Modem side of the engine, driving the UART.
+#[rustfmt::skip]
+pub struct AtModem<'a, UART, D, const QUEUE: usize, const NOTIFICATIONS: usize = 4>
+where
+    UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    D: AtDevice<UART>,
+{
+    ready: &'a Readiness<D::Ready>,
+    uart: UART,
+    device: D,
+    commands: Receiver<'a, DriverMutex, AtCommand, QUEUE>,
+    responses: Sender<'a, DriverMutex, (u16, Result<D::Response, AtError>), QUEUE>,
+    notifications: Sender<'a, DriverMutex, D::Response, NOTIFICATIONS>,
+    /// Identifier of the command written last, which responses arriving while no command is in
+    /// flight belong to.
+    current: u16,
+    in_flight: InFlight<QUEUE>,
+    /// Consecutive commands that timed out.
+    timeouts: u8,
+    /// Notifications dropped because the client did not keep up.
+    dropped: u32,
+}
+
+impl<'a, UART, D, const QUEUE: usize, const NOTIFICATIONS: usize>
+    AtModem<'a, UART, D, QUEUE, NOTIFICATIONS>
+where
+    UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    D: AtDevice<UART>,
+{
+    /// Run the processing loop
+    pub async fn run(&mut self) -> ! {
+        self.initialize().await;
+        loop {
+            if self.device.raw_pending() > 0 {
+                if let Err(e) = uart_stream(&mut self.uart, &mut self.device).await {
+                    error!("Error reading from uart: {:?}", e);
+                    self.device.abort_raw();
+                }
+                continue;
+            }
+
+            let mut buf = [0; 1];
+            let event = {
+                // Commands are not written until there is room to track their response
+                let accepting = !self.in_flight.is_full();
+                let deadline = self.in_flight.next_deadline();
+                let commands = &mut self.commands;
+                let command_fut = async move {
+                    if accepting {
+                        Event::Command(commands.recv().await)
+                    } else {
+                        pending().await
+                    }
+                };
+                let timer_fut = async move {
+                    match deadline {
+                        Some(deadline) => {
+                            Timer::at(deadline).await;
+                            Event::Timeout
+                        }
+                        None => pending().await,
+                    }
+                };
+                let uart_fut = uart_read(&mut self.uart, &mut buf[..]);
+                pin_mut!(command_fut);
+                pin_mut!(timer_fut);
+                pin_mut!(uart_fut);
+                match select(uart_fut, select(command_fut, timer_fut)).await {
+                    Either::Left((r, _)) => Event::Input(r),
+                    Either::Right((Either::Left((event, _)), _)) => event,
+                    Either::Right((Either::Right((event, _)), _)) => event,
+                }
+            };
+
+            match event {
+                Event::Command(Some(command)) => self.write(command).await,
+                Event::Command(None) => {}
+                Event::Input(Ok(len)) => {
+                    for b in &buf[..len] {
+                        if let Some(response) = self.device.digest(*b) {
+                            self.dispatch(response);
+                        }
+                    }
+                }
+                Event::Input(Err(e)) => {
+                    error!("Error reading from uart: {:?}", e);
+                }
+                Event::Timeout => {
+                    while let Some(id) = self.in_flight.expire(Instant::now()) {
+                        warn!("Timeout waiting for response to command {}", id);
+                        self.respond(id, Err(AtError::Timeout));
+                        self.timeouts += 1;
+                    }
+                    if self.timeouts >= MAX_TIMEOUTS {
+                        warn!("Modem not responding, resetting");
+                        self.initialize().await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn initialize(&mut self) {
+        self.ready.initializing();
+        // Commands written before the reset will not be answered
+        while let Some(id) = self.in_flight.pop() {
+            self.respond(id, Err(AtError::Timeout));
+        }
+        self.timeouts = 0;
+        let result =
+            match with_timeout(INITIALIZE_TIMEOUT, self.device.initialize(&mut self.uart)).await {
+                Ok(result) => result,
+                Err(_) => Err(AtError::Timeout),
+            };
+        if let Err(e) = &result {
+            error!("Error initializing modem: {:?}", e);
+        }
+        self.ready.set(result);
+    }
+
+    async fn write(&mut self, command: AtCommand) {
+        // Retry a failed initialization before giving up on the command
+        if !self.ready.is_ready() {
+            self.initialize().await;
+            if !self.ready.is_ready() {
+                self.respond(command.id, Err(AtError::NotInitialized));
+                return;
+            }
+        }
+
+        self.current = command.id;
+        match uart_write(&mut self.uart, &command.data[..command.len]).await {
+            Ok(_) => {
+                self.in_flight
+                    .push(command.id, Instant::now() + command.timeout);
+            }
+            Err(e) => {
+                error!("Error writing command to uart: {:?}", e);
+                self.respond(command.id, Err(AtError::Io));
+            }
+        }
+    }
+
+    fn dispatch(&mut self, response: D::Response) {
+        match self.device.route(response) {
+            Route::Response(response) => {
+                self.timeouts = 0;
+                // Further responses to the command written last arrive with nothing in flight
+                let id = self.in_flight.pop().unwrap_or(self.current);
+                self.respond(id, Ok(response));
+            }
+            Route::Notification(response) => {
+                // Waiting for room would stall reading the responses the client is waiting for
+                if self.notifications.try_send(response).is_err() {
+                    self.dropped = self.dropped.wrapping_add(1);
+                    warn!("Notification queue full, dropped {} so far", self.dropped);
+                }
+            }
+            Route::Handled => {}
+        }
+    }
+
+    /// Number of notifications dropped because the client did not take them in time.
+    pub fn dropped_notifications(&self) -> u32 {
+        self.dropped
+    }
+
+    fn respond(&mut self, id: u16, response: Result<D::Response, AtError>) {
+        // Waiting for room would stall the UART, the client gives up on a dropped response
+        if self.responses.try_send((id, response)).is_err() {
+            warn!("Response queue full, dropped response to command {}", id);
+        }
+    }
+}
+
+pub(crate) async fn uart_read<UART>(
+    uart: &mut UART,
+    rx_buf: &mut [u8],
+) -> Result<usize, embassy::io::Error>
+where
+    UART: AsyncBufReadExt + 'static,
+{
+    let mut uart = unsafe { Pin::new_unchecked(uart) };
+    uart.read(rx_buf).await
+}
+
+pub(crate) async fn uart_write<UART>(uart: &mut UART, buf: &[u8]) -> Result<(), embassy::io::Error>
+where
+    UART: AsyncWriteExt + 'static,
+{
+    let mut uart = unsafe { Pin::new_unchecked(uart) };
+    uart.write_all(buf).await
+}
+
+/// Pass data from the UART buffer straight to the device.
+async fn uart_stream<UART, D>(uart: &mut UART, device: &mut D) -> Result<(), embassy::io::Error>
+where
+    UART: AsyncBufRead + 'static,
+    D: AtDevice<UART>,
+{
+    let mut uart = unsafe { Pin::new_unchecked(uart) };
+    poll_fn(|cx| {
+        let len = match uart.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(data)) => {
+                let max = core::cmp::min(data.len(), device.raw_pending());
+                device.raw(&data[..max])
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        };
+        uart.as_mut().consume(len);
+        Poll::Ready(Ok(()))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn readiness() {
+        let ready = Readiness::new();
+        ready.set(Ok(7));
+        assert_eq!(Ok(7), block_on(ready.wait()));
+
+        ready.initializing();
+        assert!(!ready.is_ready());
+        ready.set(Err(AtError::Timeout));
+        assert_eq!(Err(AtError::NotInitialized), block_on(ready.wait()));
+
+        ready.initializing();
+        ready.set(Ok(8));
+        assert_eq!(Ok(8), block_on(ready.wait()));
+    }
+
+    #[test]
+    fn in_flight_commands() {
+        let at = |ticks| Instant::from_ticks(ticks);
+        let mut in_flight = InFlight::<3>::new();
+        assert_eq!(None, in_flight.pop());
+        assert_eq!(None, in_flight.next_deadline());
+
+        in_flight.push(1, at(30));
+        in_flight.push(2, at(10));
+        in_flight.push(3, at(20));
+        assert!(in_flight.is_full());
+        assert_eq!(Some(at(10)), in_flight.next_deadline());
+
+        // Commands expire in the order of their deadlines, responses answer the oldest
+        assert_eq!(None, in_flight.expire(at(5)));
+        assert_eq!(Some(2), in_flight.expire(at(15)));
+        assert_eq!(None, in_flight.expire(at(15)));
+        assert_eq!(Some(1), in_flight.pop());
+        assert_eq!(Some(at(20)), in_flight.next_deadline());
+
+        in_flight.push(4, at(40));
+        assert_eq!(Some(3), in_flight.pop());
+        assert_eq!(Some(4), in_flight.pop());
+        assert_eq!(None, in_flight.pop());
+    }
+}
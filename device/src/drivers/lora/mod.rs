@@ -1,4 +1,5 @@
 #[cfg(feature = "lora+rak811")]
 pub mod rak811;
+pub mod session;
 #[cfg(feature = "lora+sx127x")]
 pub mod sx127x;
@@ -3,8 +3,11 @@ use super::protocol::Response;
 use core::str::from_utf8;
 use moveslice::Moveslice;
 
+/// Large enough for a hex encoded downlink of the maximum size, plus its header.
+const BUFFER_LEN: usize = 2 * super::RECV_BUFFER_LEN + 64;
+
 pub struct Buffer {
-    buffer: [u8; 512],
+    buffer: [u8; BUFFER_LEN],
     pos: usize,
     needs_parse: bool,
 }
@@ -12,7 +15,7 @@ pub struct Buffer {
 impl Buffer {
     pub fn new() -> Self {
         Buffer {
-            buffer: [0; 512],
+            buffer: [0; BUFFER_LEN],
             pos: 0,
             needs_parse: false,
         }
@@ -6,89 +6,60 @@ mod buffer;
 mod parser;
 mod protocol;
 use crate::{
+    drivers::at::{uart_read, AtClient, AtDevice, AtEngine, AtError, AtModem, Route},
     kernel::actor::{Actor, Address},
     traits::lora::*,
 };
 
 pub use buffer::*;
-use core::{
-    future::Future,
-    pin::Pin,
-    sync::atomic::{AtomicBool, Ordering},
-};
+use core::{future::Future, pin::Pin};
 use embassy::{
     io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt},
-    util::{
-        mpsc::{self, Channel, Receiver, Sender, WithThreadModeOnly},
-        Signal,
-    },
+    time::{with_timeout, Duration},
 };
 use embedded_hal::digital::v2::OutputPin;
-use futures::future::{select, Either};
-use futures::pin_mut;
+use heapless::{consts, spsc::Queue};
 pub use protocol::*;
 
 const RECV_BUFFER_LEN: usize = 256;
-type DriverMutex = WithThreadModeOnly;
 
-pub struct Initialized {
-    signal: Signal<Result<LoraRegion, LoraError>>,
-    initialized: AtomicBool,
-}
+/// Time allowed for the module to respond to a command.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
 
-impl Initialized {
-    pub fn new() -> Self {
-        Self {
-            signal: Signal::new(),
-            initialized: AtomicBool::new(false),
-        }
-    }
+/// Time allowed for the result of a join, including the module's own retries.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(60);
 
-    async fn wait(&self) -> Result<Option<LoraRegion>, LoraError> {
-        if self.initialized.swap(true, Ordering::SeqCst) == false {
-            let region = self.signal.wait().await?;
-            return Ok(Some(region));
-        }
-        Ok(None)
-    }
-
-    pub fn signal(&self, result: Result<LoraRegion, LoraError>) {
-        self.signal.signal(result);
-    }
-}
+/// Time allowed for the result of a send, including the receive windows.
+const SEND_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub struct Rak811Driver {
-    initialized: Initialized,
-    command_channel: Channel<DriverMutex, CommandBuffer, 2>,
-    response_channel: Channel<DriverMutex, Response, 2>,
+    engine: AtEngine<Response, LoraRegion, 2>,
 }
 
 pub struct Rak811Controller<'a> {
     config: LoraConfig,
-    initialized: &'a Initialized,
-    command_producer: Sender<'a, DriverMutex, CommandBuffer, 2>,
-    response_consumer: Receiver<'a, DriverMutex, Response, 2>,
+    at: AtClient<'a, Response, LoraRegion, 2>,
+    downlinks: Queue<LoraDownlink, consts::U4>,
+    /// Every downlink received, until taken with `take_downlink`.
+    received: Queue<LoraDownlink, consts::U4>,
 }
 
-pub struct Rak811Modem<'a, UART, RESET>
+/// Modem driving the UART, using the AT command engine.
+pub type Rak811Modem<'a, UART, RESET> = AtModem<'a, UART, Rak811Device<RESET>, 2>;
+
+/// RAK811 specific parsing and initialization for the AT command engine.
+pub struct Rak811Device<RESET>
 where
-    UART: AsyncBufRead + AsyncBufReadExt + AsyncWrite + AsyncWriteExt + 'static,
     RESET: OutputPin,
 {
-    initialized: &'a Initialized,
-    uart: UART,
     reset: RESET,
     parse_buffer: Buffer,
-    command_consumer: Receiver<'a, DriverMutex, CommandBuffer, 2>,
-    response_producer: Sender<'a, DriverMutex, Response, 2>,
 }
 
 impl Rak811Driver {
     pub fn new() -> Self {
         Self {
-            initialized: Initialized::new(),
-            command_channel: Channel::new(),
-            response_channel: Channel::new(),
+            engine: AtEngine::new(),
         }
     }
 
@@ -101,74 +72,24 @@ impl Rak811Driver {
         UART: AsyncBufRead + AsyncBufReadExt + AsyncWrite + AsyncWriteExt + 'static,
         RESET: OutputPin + 'static,
     {
-        let (cp, cc) = mpsc::split(&mut self.command_channel);
-        let (rp, rc) = mpsc::split(&mut self.response_channel);
-
-        let modem = Rak811Modem::new(&self.initialized, uart, reset, cc, rp);
-        let controller = Rak811Controller::new(&self.initialized, cp, rc);
+        let (client, modem) = self.engine.split(uart, Rak811Device::new(reset));
+        let controller = Rak811Controller::new(client);
 
         (controller, modem)
     }
 }
 
-impl<'a, UART, RESET> Rak811Modem<'a, UART, RESET>
+impl<RESET> Rak811Device<RESET>
 where
-    UART: AsyncBufRead + AsyncBufReadExt + AsyncWrite + AsyncWriteExt + 'static,
     RESET: OutputPin + 'static,
 {
-    pub fn new(
-        initialized: &'a Initialized,
-        uart: UART,
-        reset: RESET,
-        command_consumer: Receiver<'a, DriverMutex, CommandBuffer, 2>,
-        response_producer: Sender<'a, DriverMutex, Response, 2>,
-    ) -> Self {
+    pub fn new(reset: RESET) -> Self {
         Self {
-            initialized,
-            uart,
             reset,
             parse_buffer: Buffer::new(),
-            command_consumer,
-            response_producer,
-        }
-    }
-
-    async fn initialize(&mut self) -> Result<LoraRegion, LoraError> {
-        self.reset.set_high().ok();
-        self.reset.set_low().ok();
-        loop {
-            // Run processing to increase likelyhood we have something to parse.
-            self.process().await?;
-            if let Some(response) = self.parse() {
-                match response {
-                    Response::Initialized(region) => {
-                        info!("Got initialize response with region {:?}", region);
-                        return Ok(region);
-                    }
-                    e => {
-                        error!("Got unexpected repsonse: {:?}", e);
-                        return Err(LoraError::NotInitialized);
-                    }
-                }
-            }
         }
     }
 
-    async fn process(&mut self) -> Result<(), LoraError> {
-        let mut buf = [0; 1];
-        let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
-        let len = uart
-            .read(&mut buf[..])
-            .await
-            .map_err(|_| LoraError::RecvError)?;
-        if len > 0 {
-            self.parse_buffer
-                .write(buf[0])
-                .map_err(|_| LoraError::RecvError)?;
-        }
-        Ok(())
-    }
-
     fn parse(&mut self) -> Option<Response> {
         let result = self.parse_buffer.parse();
         if let Ok(response) = result {
@@ -179,53 +100,64 @@ where
         }
         None
     }
+}
 
-    async fn digest(&mut self) {
-        if let Some(response) = self.parse() {
-            let _ = self.response_producer.send(response).await;
-        }
-    }
+impl<UART, RESET> AtDevice<UART> for Rak811Device<RESET>
+where
+    UART: AsyncBufRead + AsyncBufReadExt + AsyncWrite + AsyncWriteExt + 'static,
+    RESET: OutputPin + 'static,
+{
+    type Response = Response;
+    type Ready = LoraRegion;
 
-    pub async fn run(&mut self) -> ! {
-        let result = self.initialize().await;
-        self.initialized.signal(result);
-        loop {
+    #[rustfmt::skip]
+    type InitializeFuture<'m> where Self: 'm = impl Future<Output = Result<LoraRegion, AtError>> + 'm;
+    fn initialize<'m>(&'m mut self, uart: &'m mut UART) -> Self::InitializeFuture<'m> {
+        async move {
+            self.parse_buffer = Buffer::new();
+            self.reset.set_high().ok();
+            self.reset.set_low().ok();
             let mut buf = [0; 1];
-            let (cmd, input) = {
-                let command_fut = self.command_consumer.recv();
-                let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
-                let uart_fut = uart.read(&mut buf[..]);
-                pin_mut!(uart_fut);
-
-                match select(command_fut, uart_fut).await {
-                    Either::Left((s, _)) => (Some(s), None),
-                    Either::Right((r, _)) => (None, Some(r)),
-                }
-            };
-            // We got command to write, write it
-            if let Some(Some(s)) = cmd {
-                let mut uart = unsafe { Pin::new_unchecked(&mut self.uart) };
-                if let Err(e) = uart.write_all(s.as_bytes()).await {
-                    error!("Error writing command to uart: {:?}", e);
+            loop {
+                let len = uart_read(uart, &mut buf[..])
+                    .await
+                    .map_err(|_| AtError::Io)?;
+                if len > 0 && self.parse_buffer.write(buf[0]).is_err() {
+                    return Err(AtError::Io);
                 }
-            }
-
-            // We got input, digest it
-            if let Some(input) = input {
-                match input {
-                    Ok(len) => {
-                        for b in &buf[..len] {
-                            self.parse_buffer.write(*b).unwrap();
+                if let Some(response) = self.parse() {
+                    match response {
+                        Response::Initialized(region) => {
+                            info!("Got initialize response with region {:?}", region);
+                            return Ok(region);
+                        }
+                        e => {
+                            error!("Got unexpected repsonse: {:?}", e);
+                            return Err(AtError::NotInitialized);
                         }
-                        self.digest().await;
-                    }
-                    Err(e) => {
-                        error!("Error reading from uart: {:?}", e);
                     }
                 }
             }
         }
     }
+
+    fn digest(&mut self, octet: u8) -> Option<Response> {
+        if self.parse_buffer.write(octet).is_err() {
+            warn!("Parse buffer full, discarding input");
+            self.parse_buffer = Buffer::new();
+            return None;
+        }
+        self.parse()
+    }
+
+    fn route(&mut self, response: Response) -> Route<Response> {
+        match response {
+            Response::Downlink(..) | Response::Recv(EventCode::RecvData, ..) => {
+                Route::Notification(response)
+            }
+            response => Route::Response(response),
+        }
+    }
 }
 
 /*
@@ -249,7 +181,30 @@ where
     }
 */
 
-fn log_unexpected(r: Response) -> Result<(), LoraError> {
+fn lora_error(e: AtError) -> LoraError {
+    match e {
+        AtError::Timeout => LoraError::RecvTimeout,
+        AtError::NotInitialized => LoraError::NotInitialized,
+        AtError::CommandTooLong | AtError::Io => LoraError::OtherError,
+    }
+}
+
+fn downlink(response: Response) -> Option<LoraDownlink> {
+    match response {
+        Response::Downlink(port, rssi, snr, len, data) => {
+            Some(LoraDownlink::new(port, rssi, snr, &data[..len]))
+        }
+        Response::Recv(EventCode::RecvData, port, len, Some(data)) => {
+            Some(LoraDownlink::new(port, 0, 0, &data[..len]))
+        }
+        r => {
+            warn!("Unexpected notification: {:?}", r);
+            None
+        }
+    }
+}
+
+fn log_unexpected<T>(r: Response) -> Result<T, LoraError> {
     error!("Unexpected response: {:?}", r);
     Err(LoraError::OtherError)
 }
@@ -268,7 +223,7 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
             let response = self.send_command(Command::Join(mode)).await?;
             match response {
                 Response::Ok => {
-                    let response = self.response_consumer.recv().await.unwrap();
+                    let response = self.response(JOIN_TIMEOUT).await?;
                     match response {
                         Response::Recv(EventCode::JoinedSuccess, _, _, _) => Ok(()),
                         r => log_unexpected(r),
@@ -282,23 +237,7 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
     #[rustfmt::skip]
     type SendFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
     fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
-        async move {
-            let response = self.send_command(Command::Send(qos, port, data)).await?;
-            match response {
-                Response::Ok => {
-                    let response = self.response_consumer.recv().await.unwrap();
-                    let expected_code = match qos {
-                        QoS::Unconfirmed => EventCode::TxUnconfirmed,
-                        QoS::Confirmed => EventCode::TxConfirmed,
-                    };
-                    match response {
-                        Response::Recv(c, 0, _, _) if expected_code == c => Ok(()),
-                        r => log_unexpected(r),
-                    }
-                }
-                r => log_unexpected(r),
-            }
-        }
+        async move { self.transmit(qos, port, data).await }
     }
 
     #[rustfmt::skip]
@@ -310,36 +249,289 @@ impl<'a> LoraDriver for Rak811Controller<'a> {
         data: &'m [u8],
         rx: &'m mut [u8],
     ) -> Self::SendRecvFuture<'m> {
-        async move { Ok(0) }
+        async move {
+            self.transmit(qos, port, data).await?;
+
+            // The module reports a downlink before the event completing the uplink, so
+            // anything received by now is the reply to this uplink.
+            let mut result = Ok(0);
+            let mut first = true;
+            while let Some(downlink) = self.next_notification() {
+                if !first {
+                    self.enqueue_downlink(downlink);
+                } else if downlink.payload().len() > rx.len() {
+                    self.enqueue_downlink(downlink);
+                    result = Err(LoraError::RecvBufferTooSmall);
+                } else {
+                    rx[..downlink.payload().len()].copy_from_slice(downlink.payload());
+                    result = Ok(downlink.payload().len());
+                }
+                first = false;
+            }
+            result
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkCheckFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkCheck, LoraError>> + 'm;
+    fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+        // The 2.x AT firmware has no command to request a link check
+        async move { Err(LoraError::NotImplemented) }
+    }
+
+    #[rustfmt::skip]
+    type StatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraStatus, LoraError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        async move {
+            let data_rate = match self.send_command(Command::GetDataRate).await? {
+                Response::DataRate(data_rate) => data_rate,
+                r => return log_unexpected(r),
+            };
+            let (fcnt_up, fcnt_down) = match self.send_command(Command::GetLinkCount).await? {
+                Response::LinkCount(up, down) => (up, down),
+                r => return log_unexpected(r),
+            };
+            Ok(LoraStatus {
+                data_rate,
+                tx_power: self.config.tx_power.unwrap_or(0),
+                fcnt_up: fcnt_up.saturating_sub(1),
+                fcnt_down,
+            })
+        }
+    }
+
+    #[rustfmt::skip]
+    type SessionFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraSession, LoraError>> + 'm;
+    fn session<'m>(&'m mut self) -> Self::SessionFuture<'m> {
+        async move {
+            let mut device_address = [0; 4];
+            let mut nwks_key = [0; 16];
+            let mut apps_key = [0; 16];
+            self.get_config(ConfigKey::DevAddr, &mut device_address)
+                .await?;
+            self.get_config(ConfigKey::NwksKey, &mut nwks_key).await?;
+            self.get_config(ConfigKey::AppsKey, &mut apps_key).await?;
+            // The module counts the next uplink and the last downlink
+            let (fcnt_up, fcnt_down) = match self.send_command(Command::GetLinkCount).await? {
+                Response::LinkCount(up, down) => (up, down),
+                r => return log_unexpected(r),
+            };
+            Ok(LoraSession {
+                device_address: device_address.into(),
+                nwks_key: nwks_key.into(),
+                apps_key: apps_key.into(),
+                fcnt_up,
+                fcnt_down: fcnt_down.saturating_add(1),
+                // The module does not report the JoinNonce
+                join_nonce: None,
+            })
+        }
+    }
+
+    #[rustfmt::skip]
+    type RestoreFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn restore<'m>(&'m mut self, session: &'m LoraSession) -> Self::RestoreFuture<'m> {
+        async move {
+            let config = LoraConfig::new()
+                .device_address(&session.device_address)
+                .nwks_key(&session.nwks_key)
+                .apps_key(&session.apps_key);
+            self.apply_config(&config).await?;
+            // The session is resumed as if activated by personalization, which resets the
+            // frame counters of the module.
+            self.join(ConnectMode::ABP).await?;
+            self.send_command_ok(Command::SetLinkCount(
+                session.fcnt_up,
+                session.fcnt_down.saturating_sub(1),
+            ))
+            .await
+        }
+    }
+
+    fn take_downlink(&mut self) -> Option<LoraDownlink> {
+        self.process_notifications();
+        self.received.dequeue()
+    }
+}
+
+impl<'a> LoraP2p for Rak811Controller<'a> {
+    #[rustfmt::skip]
+    type SetRfConfigFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn set_rf_config<'m>(&'m mut self, config: &'m RfConfig) -> Self::SetRfConfigFuture<'m> {
+        async move {
+            if self.config.lora_mode != Some(LoraMode::P2P) {
+                self.send_command_ok(Command::SetMode(LoraMode::P2P))
+                    .await?;
+                self.config.lora_mode.replace(LoraMode::P2P);
+            }
+            self.send_command_ok(Command::SetRfConfig(config)).await
+        }
+    }
+
+    #[rustfmt::skip]
+    type TransmitFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn transmit<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        async move {
+            match self.send_command(Command::P2pTransmit(data)).await? {
+                Response::Ok => match self.response(SEND_TIMEOUT).await? {
+                    Response::Recv(EventCode::P2PTxComplete, _, _, _) => Ok(()),
+                    r => log_unexpected(r),
+                },
+                r => log_unexpected(r),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type ReceiveFuture<'m> where 'a: 'm = impl Future<Output = Result<RxPacket, LoraError>> + 'm;
+    fn receive<'m>(&'m mut self, rx: &'m mut [u8], timeout: Duration) -> Self::ReceiveFuture<'m> {
+        async move {
+            self.send_command_ok(Command::P2pReceive).await?;
+            let at = &mut self.at;
+            let result = with_timeout(timeout, async move {
+                loop {
+                    match at.notification().await {
+                        Some(response) => {
+                            if let Some(packet) = downlink(response) {
+                                break Ok(packet);
+                            }
+                        }
+                        None => break Err(LoraError::RecvError),
+                    }
+                }
+            })
+            .await;
+            self.send_command_ok(Command::P2pStopReceive).await?;
+            let packet = result.map_err(|_| LoraError::RecvTimeout)??;
+            if packet.payload().len() > rx.len() {
+                return Err(LoraError::RecvBufferTooSmall);
+            }
+            rx[..packet.payload().len()].copy_from_slice(packet.payload());
+            Ok(RxPacket {
+                len: packet.payload().len(),
+                rssi: packet.rssi,
+                snr: packet.snr,
+            })
+        }
     }
 }
 
 impl<'a> Rak811Controller<'a> {
-    pub fn new(
-        initialized: &'a Initialized,
-        command_producer: Sender<'a, DriverMutex, CommandBuffer, 2>,
-        response_consumer: Receiver<'a, DriverMutex, Response, 2>,
-    ) -> Self {
+    pub fn new(at: AtClient<'a, Response, LoraRegion, 2>) -> Self {
         Self {
             config: LoraConfig::new(),
-            initialized,
-            command_producer,
-            response_consumer,
+            at,
+            downlinks: Queue::new(),
+            received: Queue::new(),
         }
     }
 
-    async fn send_command<'m>(&mut self, command: Command<'m>) -> Result<Response, LoraError> {
-        if let Some(region) = self.initialized.wait().await? {
-            self.config.region.replace(region);
+    /// Poll for a queued downlink on the given port and copy it to the provided buffer.
+    /// If a downlink is available, the length of the data is returned.
+    pub fn try_recv(&mut self, port: Port, rx: &mut [u8]) -> Result<usize, LoraError> {
+        self.process_notifications();
+        let mut result = None;
+        for _ in 0..self.downlinks.len() {
+            if let Some(downlink) = self.downlinks.dequeue() {
+                if result.is_none() && downlink.port == port {
+                    if downlink.payload().len() > rx.len() {
+                        result.replace(Err(LoraError::RecvBufferTooSmall));
+                    } else {
+                        rx[..downlink.payload().len()].copy_from_slice(downlink.payload());
+                        result.replace(Ok(downlink.payload().len()));
+                        continue;
+                    }
+                }
+                // Rotate through the queue to keep the order of the remaining downlinks.
+                self.downlinks.enqueue(downlink).ok();
+            }
+        }
+        result.unwrap_or(Ok(0))
+    }
+
+    fn next_notification(&mut self) -> Option<LoraDownlink> {
+        while let Some(response) = self.at.try_notification() {
+            if let Some(downlink) = downlink(response) {
+                if self.received.is_full() {
+                    warn!("Received downlinks not taken, discarding oldest downlink");
+                    self.received.dequeue();
+                }
+                self.received.enqueue(downlink).ok();
+                return Some(downlink);
+            }
+        }
+        None
+    }
+
+    fn enqueue_downlink(&mut self, downlink: LoraDownlink) {
+        if self.downlinks.is_full() {
+            warn!("Downlink queue full, discarding oldest downlink");
+            self.downlinks.dequeue();
         }
+        self.downlinks.enqueue(downlink).ok();
+    }
+
+    /// Queue downlinks received outside of a send_recv for later pickup.
+    fn process_notifications(&mut self) {
+        while let Some(downlink) = self.next_notification() {
+            self.enqueue_downlink(downlink);
+        }
+    }
+
+    async fn ready(&mut self) {
+        if let Ok(region) = self.at.ready().await {
+            if self.config.region.is_none() {
+                self.config.region.replace(region);
+            }
+        }
+    }
+
+    async fn send_command<'m>(&mut self, command: Command<'m>) -> Result<Response, LoraError> {
+        self.ready().await;
+        self.process_notifications();
         let mut s = Command::buffer();
         command.encode(&mut s);
         debug!("Sending command {}", s.as_str());
         s.push_str("\r\n").unwrap();
-        let _ = self.command_producer.send(s).await;
+        self.at
+            .request(s.as_bytes(), COMMAND_TIMEOUT)
+            .await
+            .map_err(lora_error)
+    }
 
-        let response = self.response_consumer.recv().await;
-        Ok(response.unwrap())
+    /// Wait for an event following the response to the command sent last.
+    async fn response(&mut self, timeout: Duration) -> Result<Response, LoraError> {
+        self.at.response(timeout).await.map_err(lora_error)
+    }
+
+    /// Send an uplink and wait for the module to report its completion.
+    async fn transmit(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), LoraError> {
+        let response = self.send_command(Command::Send(qos, port, data)).await?;
+        match response {
+            Response::Ok => {
+                let response = self.response(SEND_TIMEOUT).await?;
+                let expected_code = match qos {
+                    QoS::Unconfirmed => EventCode::TxUnconfirmed,
+                    QoS::Confirmed => EventCode::TxConfirmed,
+                };
+                match response {
+                    Response::Recv(c, 0, _, _) if expected_code == c => Ok(()),
+                    r => log_unexpected(r),
+                }
+            }
+            r => log_unexpected(r),
+        }
+    }
+
+    /// Read a configuration value of exactly the length of the provided buffer.
+    async fn get_config(&mut self, key: ConfigKey, value: &mut [u8]) -> Result<(), LoraError> {
+        match self.send_command(Command::GetConfig(key)).await? {
+            Response::ConfigValue(len, data) if len == value.len() => {
+                value.copy_from_slice(&data[..len]);
+                Ok(())
+            }
+            r => log_unexpected(r),
+        }
     }
 
     async fn send_command_ok<'m>(&mut self, command: Command<'m>) -> Result<(), LoraError> {
@@ -350,9 +542,7 @@ impl<'a> Rak811Controller<'a> {
     }
 
     async fn apply_config(&mut self, config: &LoraConfig) -> Result<(), LoraError> {
-        if let Some(region) = self.initialized.wait().await? {
-            self.config.region.replace(region);
-        }
+        self.ready().await;
         info!("Applying config: {:?}", config);
         if let Some(region) = config.region {
             if self.config.region != config.region {
@@ -367,6 +557,74 @@ impl<'a> Rak811Controller<'a> {
             }
         }
 
+        if let Some(adr) = config.adr {
+            if self.config.adr != config.adr {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Adr(adr)))
+                    .await?;
+                self.config.adr.replace(adr);
+            }
+        }
+
+        if let Some(tx_power) = config.tx_power {
+            if self.config.tx_power != config.tx_power {
+                self.send_command_ok(Command::SetConfig(ConfigOption::PwrLevel(tx_power)))
+                    .await?;
+                self.config.tx_power.replace(tx_power);
+            }
+        }
+
+        if let Some(rx1_delay) = config.rx1_delay {
+            if self.config.rx1_delay != config.rx1_delay {
+                self.send_command_ok(Command::SetConfig(ConfigOption::RxDelay1(rx1_delay)))
+                    .await?;
+                self.config.rx1_delay.replace(rx1_delay);
+            }
+        }
+
+        if (config.rx2_frequency.is_some() || config.rx2_data_rate.is_some())
+            && (self.config.rx2_frequency != config.rx2_frequency
+                || self.config.rx2_data_rate != config.rx2_data_rate)
+        {
+            let region = self.config.region.unwrap_or(LoraRegion::UNKNOWN);
+            let data_rate = config.rx2_data_rate.or_else(|| region.rx2_data_rate());
+            let frequency = config.rx2_frequency.or_else(|| region.rx2_frequency());
+            match (data_rate, frequency) {
+                (Some(data_rate), Some(frequency)) => {
+                    self.send_command_ok(Command::SetConfig(ConfigOption::Rx2(
+                        data_rate, frequency,
+                    )))
+                    .await?;
+                }
+                _ => {
+                    error!(
+                        "RX2 data rate and frequency must both be set for {:?}",
+                        region
+                    );
+                    return Err(LoraError::MissingConfiguration);
+                }
+            }
+            self.config.rx2_frequency = config.rx2_frequency;
+            self.config.rx2_data_rate = config.rx2_data_rate;
+        }
+
+        // The module opens its second receive window one second after the first, and handles
+        // join accept timing and clock error itself.
+        if config.rx2_delay.is_some()
+            || config.join_accept_delay1.is_some()
+            || config.join_accept_delay2.is_some()
+            || config.rx_window_margin.is_some()
+        {
+            warn!("RX2 delay, join accept delays and RX window margin are ignored by the RAK811");
+        }
+
+        if let Some(class) = config.class {
+            if self.config.class != config.class {
+                self.send_command_ok(Command::SetConfig(ConfigOption::Class(class)))
+                    .await?;
+                self.config.class.replace(class);
+            }
+        }
+
         if let Some(ref device_address) = config.device_address {
             self.send_command_ok(Command::SetConfig(ConfigOption::DevAddr(device_address)))
                 .await?;
@@ -391,6 +649,18 @@ impl<'a> Rak811Controller<'a> {
             self.config.app_key.replace(*app_key);
         }
 
+        if let Some(ref nwks_key) = config.nwks_key {
+            self.send_command_ok(Command::SetConfig(ConfigOption::NwksKey(nwks_key)))
+                .await?;
+            self.config.nwks_key.replace(*nwks_key);
+        }
+
+        if let Some(ref apps_key) = config.apps_key {
+            self.send_command_ok(Command::SetConfig(ConfigOption::AppsKey(apps_key)))
+                .await?;
+            self.config.apps_key.replace(*apps_key);
+        }
+
         debug!("Config applied");
         Ok(())
     }
@@ -401,35 +671,6 @@ pub fn send(&mut self, qos: QoS, port: Port, data: &[u8]) -> Result<(), DriverEr
 
 }
 
-/// Poll for any received data and copy it to the provided buffer. If data have been received,
-/// the length of the data is returned.
-pub fn try_recv(&mut self, port: Port, rx_buf: &mut [u8]) -> Result<usize, DriverError> {
-    self.digest()?;
-    let mut tries = self.rxq.len();
-    while tries > 0 {
-        match self.rxq.dequeue() {
-            None => return Ok(0),
-            Some(Response::Recv(EventCode::RecvData, p, len, Some(data))) if p == port => {
-                if len > rx_buf.len() {
-                    self.rxq
-                        .enqueue(Response::Recv(EventCode::RecvData, p, len, Some(data)))
-                        .map_err(|_| DriverError::ReadError)?;
-                }
-
-                rx_buf[0..len].clone_from_slice(&data);
-                return Ok(len);
-            }
-            Some(event) => {
-                self.rxq
-                    .enqueue(event)
-                    .map_err(|_| DriverError::ReadError)?;
-            }
-        }
-        tries -= 1;
-    }
-    Ok(0)
-}
-
 /// Attempt to read data from UART and store it in the parse buffer. This should
 /// be invoked whenever data should be read.
 pub fn process(&mut self) -> Result<(), DriverError> {
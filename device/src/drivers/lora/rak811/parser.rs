@@ -1,7 +1,9 @@
 use nom::alt;
+use nom::call;
 use nom::char;
 use nom::character::streaming::digit1;
 use nom::do_parse;
+use nom::error::{Error, ErrorKind};
 use nom::named;
 use nom::opt;
 use nom::tag;
@@ -64,6 +66,54 @@ fn parse_u32(input: &[u8]) -> IResult<&[u8], u32> {
     IResult::Ok((input, atoi_u32(digits).unwrap()))
 }
 
+fn parse_i16(input: &[u8]) -> IResult<&[u8], i16> {
+    let (input, sign) = nom::combinator::opt(nom::character::streaming::char('-'))(input)?;
+    let (input, value) = parse_u32(input)?;
+    let value = value as i16;
+    IResult::Ok((input, if sign.is_some() { -value } else { value }))
+}
+
+fn hex_to_nibble(character: u8) -> Option<u8> {
+    match character {
+        b'0'..=b'9' => Some(character - b'0'),
+        b'a'..=b'f' => Some(character - b'a' + 10),
+        b'A'..=b'F' => Some(character - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode `len` octets of hex encoded payload.
+fn hex_data(input: &[u8], len: usize) -> IResult<&[u8], [u8; super::RECV_BUFFER_LEN]> {
+    if len > super::RECV_BUFFER_LEN {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::TooLarge)));
+    }
+    let (remaining, hex) = nom::bytes::streaming::take(len * 2)(input)?;
+    let mut buf = [0; super::RECV_BUFFER_LEN];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        match (hex_to_nibble(pair[0]), hex_to_nibble(pair[1])) {
+            (Some(high), Some(low)) => buf[i] = (high << 4) | low,
+            _ => return Err(nom::Err::Error(Error::new(input, ErrorKind::HexDigit))),
+        }
+    }
+    IResult::Ok((remaining, buf))
+}
+
+/// Decode a hex encoded configuration value of 4 to 16 octets.
+fn hex_value(input: &[u8]) -> IResult<&[u8], (usize, [u8; 16])> {
+    let (remaining, hex) =
+        nom::bytes::streaming::take_while_m_n(8, 32, |c: u8| c.is_ascii_hexdigit())(input)?;
+    if hex.len() % 2 != 0 {
+        return Err(nom::Err::Error(Error::new(input, ErrorKind::HexDigit)));
+    }
+    let mut buf = [0; 16];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        if let (Some(high), Some(low)) = (hex_to_nibble(pair[0]), hex_to_nibble(pair[1])) {
+            buf[i] = (high << 4) | low;
+        }
+    }
+    IResult::Ok((remaining, (hex.len() / 2, buf)))
+}
+
 #[rustfmt::skip]
 named!(
     crlf,
@@ -212,6 +262,47 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub data_rate<Response>,
+    do_parse!(
+        tag!("OK") >>
+        dr: parse_u8 >>
+        crlf >>
+        (
+            Response::DataRate(dr)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub link_count<Response>,
+    do_parse!(
+        tag!("OK") >>
+        up: parse_u32 >>
+        char!(',') >>
+        down: parse_u32 >>
+        crlf >>
+        (
+            Response::LinkCount(up, down)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub config_value<Response>,
+    do_parse!(
+        tag!("OK") >>
+        value: hex_value >>
+        crlf >>
+        (
+            Response::ConfigValue(value.0, value.1)
+        )
+    )
+);
+
 #[rustfmt::skip]
 named!(
     pub welcome<Response>,
@@ -236,6 +327,27 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub recv_data<Response>,
+    do_parse!(
+        tag!("at+recv=0,") >>
+        port: parse_u8 >>
+        char!(',') >>
+        rssi: parse_i16 >>
+        char!(',') >>
+        snr: parse_i16 >>
+        char!(',') >>
+        len: parse_u32 >>
+        char!(':') >>
+        data: call!(hex_data, len as usize) >>
+        crlf >>
+        (
+            Response::Downlink(port, rssi, snr as i8, len as usize, data)
+        )
+    )
+);
+
 #[rustfmt::skip]
 named!(
     pub recv<Response>,
@@ -269,19 +381,81 @@ named!(
     alt!(
           ok
         | error
+        | config_value
         | firmware_info
         | lora_band
         | mode_info
+        | recv_data
         | recv
         | status
+        | data_rate
+        | link_count
         | welcome
     )
 );
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn parse_downlink() {
+        let (remaining, response) = parse(b"at+recv=0,2,-45,-3,4:DEADbeef\r\n").unwrap();
+        assert!(remaining.is_empty());
+        match response {
+            Response::Downlink(2, -45, -3, 4, data) => {
+                assert_eq!(&data[..4], &[0xDE, 0xAD, 0xBE, 0xEF]);
+            }
+            r => panic!("Unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_partial_downlink() {
+        assert!(matches!(
+            parse(b"at+recv=0,2,-45,3,4:DEAD"),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
+
+    #[test]
+    fn parse_link_status() {
+        assert!(matches!(parse(b"OK5\r\n"), Ok((_, Response::DataRate(5)))));
+        assert!(matches!(
+            parse(b"OK12,3\r\n"),
+            Ok((_, Response::LinkCount(12, 3)))
+        ));
+        assert!(matches!(
+            parse(b"OK2.0.3.0\r\n"),
+            Ok((_, Response::FirmwareInfo(_)))
+        ));
+    }
+
+    #[test]
+    fn parse_config_value() {
+        match parse(b"OK26011234\r\n") {
+            Ok((_, Response::ConfigValue(4, value))) => {
+                assert_eq!(&value[..4], &[0x26, 0x01, 0x12, 0x34]);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        assert!(matches!(
+            parse(b"OK000102030405060708090A0B0C0D0E0F\r\n"),
+            Ok((_, Response::ConfigValue(16, _)))
+        ));
+    }
+
+    #[test]
+    fn parse_tx_event() {
+        let (_, response) = parse(b"at+recv=1,0,0\r\n").unwrap();
+        assert!(matches!(
+            response,
+            Response::Recv(EventCode::TxConfirmed, 0, 0, None)
+        ));
+    }
 }
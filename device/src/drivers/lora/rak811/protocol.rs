@@ -28,6 +28,15 @@ pub enum Command<'a> {
     GetConfig(ConfigKey),
     Send(QoS, Port, &'a [u8]),
     GetStatus,
+    GetDataRate,
+    GetLinkCount,
+    SetLinkCount(u32, u32),
+    SetRfConfig(&'a RfConfig),
+    /// Transmit a point-to-point packet once.
+    P2pTransmit(&'a [u8]),
+    /// Start receiving point-to-point packets, reported like downlinks.
+    P2pReceive,
+    P2pStopReceive,
 }
 
 #[derive(Debug)]
@@ -40,19 +49,20 @@ pub enum ConfigOption<'a> {
     NwksKey(&'a NwksKey),
     AppsKey(&'a AppsKey),
     ChMask(u8, u16),
+    Class(LoraClass),
+    Adr(bool),
+    PwrLevel(u8),
+    RxDelay1(u32),
+    /// Data rate and frequency of the second receive window.
+    Rx2(u8, u32),
     /*
-    PwrLevel,
-    Adr,
     Dr,
     PublicNet,
-    RxDelay1,
-    Rx2,
     ChList,
     ChMask,
     MaxChs,
     JoinCnt,
     Nbtrans,
-    Class,
     Duty,*/
 }
 
@@ -66,6 +76,8 @@ pub enum Response {
     FirmwareInfo(FirmwareInfo),
     LoraBand(LoraRegion),
     Recv(EventCode, Port, usize, Option<[u8; super::RECV_BUFFER_LEN]>),
+    /// Downlink with port, RSSI, SNR, length and data.
+    Downlink(Port, i16, i8, usize, [u8; super::RECV_BUFFER_LEN]),
     Status {
         tx_ok: u8,
         tx_err: u8,
@@ -76,6 +88,11 @@ pub enum Response {
         snr: u32,
     },
     Initialized(LoraRegion),
+    DataRate(u8),
+    /// Uplink and downlink frame counters.
+    LinkCount(u32, u32),
+    /// Hex encoded configuration value, such as an address or key, with its length.
+    ConfigValue(usize, [u8; 16]),
 }
 
 #[derive(Debug, PartialEq)]
@@ -167,6 +184,52 @@ impl<'a> Command<'a> {
             Command::GetStatus => {
                 write!(s, "at+status").unwrap();
             }
+            Command::GetDataRate => {
+                write!(s, "at+dr").unwrap();
+            }
+            Command::GetLinkCount => {
+                write!(s, "at+link_cnt").unwrap();
+            }
+            Command::SetLinkCount(up, down) => {
+                write!(s, "at+link_cnt={},{}", up, down).unwrap();
+            }
+            Command::SetRfConfig(config) => {
+                write!(
+                    s,
+                    "at+rf_config={},{},{},{},8,{}",
+                    config.frequency,
+                    match config.spreading_factor {
+                        SpreadingFactor::SF7 => 7,
+                        SpreadingFactor::SF8 => 8,
+                        SpreadingFactor::SF9 => 9,
+                        SpreadingFactor::SF10 => 10,
+                        SpreadingFactor::SF11 => 11,
+                        SpreadingFactor::SF12 => 12,
+                    },
+                    match config.bandwidth {
+                        Bandwidth::KHz125 => 0,
+                        Bandwidth::KHz250 => 1,
+                        Bandwidth::KHz500 => 2,
+                    },
+                    match config.coding_rate {
+                        CodingRate::CR4_5 => 1,
+                        CodingRate::CR4_6 => 2,
+                        CodingRate::CR4_7 => 3,
+                        CodingRate::CR4_8 => 4,
+                    },
+                    config.tx_power,
+                )
+                .unwrap();
+            }
+            Command::P2pTransmit(data) => {
+                write!(s, "at+txc=1,0,{}", HexSlice(data)).unwrap();
+            }
+            Command::P2pReceive => {
+                write!(s, "at+rxc=1").unwrap();
+            }
+            Command::P2pStopReceive => {
+                write!(s, "at+rx_stop").unwrap();
+            }
         }
     }
 }
@@ -176,7 +239,7 @@ struct HexSlice<'a>(&'a [u8]);
 impl<'a> core::fmt::Display for HexSlice<'a> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
         for b in self.0.iter() {
-            write!(f, "{:x}", b)?;
+            write!(f, "{:02x}", b)?;
         }
         Ok(())
     }
@@ -237,6 +300,29 @@ impl<'a> ConfigOption<'a> {
             ConfigOption::ChMask(id, mask) => {
                 write!(s, "ch_mask:{},{:04x}", id, mask).unwrap();
             }
+            ConfigOption::Adr(adr) => {
+                write!(s, "adr:{}", if *adr { "on" } else { "off" }).unwrap();
+            }
+            ConfigOption::PwrLevel(level) => {
+                write!(s, "pwr_level:{}", level).unwrap();
+            }
+            ConfigOption::RxDelay1(delay) => {
+                write!(s, "rx_delay1:{}", delay).unwrap();
+            }
+            ConfigOption::Rx2(data_rate, frequency) => {
+                write!(s, "rx2:{},{}", data_rate, frequency).unwrap();
+            }
+            ConfigOption::Class(class) => {
+                write!(
+                    s,
+                    "class:{}",
+                    match class {
+                        LoraClass::A => 0,
+                        LoraClass::C => 2,
+                    }
+                )
+                .unwrap();
+            }
         }
     }
 }
@@ -349,8 +435,31 @@ impl EventCode {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn encode_p2p() {
+        let mut s = Command::buffer();
+        let config = RfConfig::new(868_100_000)
+            .spreading_factor(SpreadingFactor::SF12)
+            .bandwidth(Bandwidth::KHz250);
+        Command::SetRfConfig(&config).encode(&mut s);
+        assert_eq!("at+rf_config=868100000,12,1,1,8,14", s.as_str());
+
+        let mut s = Command::buffer();
+        Command::P2pTransmit(&[0x01, 0xAB]).encode(&mut s);
+        assert_eq!("at+txc=1,0,01ab", s.as_str());
+    }
+
+    #[test]
+    fn encode_rx_windows() {
+        let mut s = Command::buffer();
+        Command::SetConfig(ConfigOption::Rx2(8, 923_300_000)).encode(&mut s);
+        assert_eq!("at+set_config=rx2:8,923300000", s.as_str());
+    }
 }
@@ -0,0 +1,386 @@
+use crate::traits::lora::*;
+use core::future::Future;
+
+/// Number of uplink frame counters reserved each time the session is stored. A restored session
+/// continues after the reserved counters, so that a counter used after the last store is never
+/// used again.
+pub const FCNT_UP_RESERVE: u32 = 64;
+
+/// LoRaWAN driver keeping its session in a store, resuming it on join instead of joining again.
+///
+/// The session is stored after a join and whenever half of the reserved uplink frame counters
+/// have been used. A failure to store the session does not fail the uplink that triggered it, and
+/// storing is retried before the next uplink. Once the reserved counters are used up, uplinks fail
+/// with `LoraError::StorageError` until the session is stored, since a counter beyond them would
+/// be used again after a reset.
+pub struct PersistentLoraDriver<D, S>
+where
+    D: LoraDriver,
+    S: LoraSessionStore,
+{
+    driver: D,
+    store: S,
+    fcnt_up_limit: Option<u32>,
+    resume: bool,
+}
+
+impl<D, S> PersistentLoraDriver<D, S>
+where
+    D: LoraDriver,
+    S: LoraSessionStore,
+{
+    pub fn new(driver: D, store: S) -> Self {
+        Self {
+            driver,
+            store,
+            fcnt_up_limit: None,
+            resume: true,
+        }
+    }
+
+    /// Make the next join a fresh join, ignoring the stored session, as when the network no longer
+    /// knows the device. The stored session is replaced once the join succeeds.
+    pub fn invalidate_session(&mut self) {
+        self.resume = false;
+    }
+
+    /// Store the session, reserving uplink frame counters from the one it holds.
+    async fn persist(&mut self, session: &LoraSession) -> Result<(), LoraError> {
+        self.store.store(session).await?;
+        self.fcnt_up_limit
+            .replace(session.fcnt_up.saturating_add(FCNT_UP_RESERVE));
+        Ok(())
+    }
+
+    /// Make sure the frame counter of the next uplink is reserved by the stored session, storing it
+    /// again once half of the reserved counters are used.
+    async fn reserve(&mut self) -> Result<(), LoraError> {
+        if let Some(limit) = self.fcnt_up_limit {
+            let session = self.driver.session().await?;
+            if session.fcnt_up >= limit - FCNT_UP_RESERVE / 2 {
+                if let Err(e) = self.persist(&session).await {
+                    warn!("Unable to store LoRaWAN session: {:?}", e);
+                    if session.fcnt_up >= limit {
+                        return Err(LoraError::StorageError);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume the stored session, skipping the uplink frame counters reserved by the last store.
+    async fn resume(&mut self) -> Result<(), LoraError> {
+        let mut session = self.store.load().await.ok_or(LoraError::NotInitialized)?;
+        session.fcnt_up = session
+            .fcnt_up
+            .checked_add(FCNT_UP_RESERVE)
+            .ok_or(LoraError::NotInitialized)?;
+        self.driver.restore(&session).await?;
+        self.persist(&session).await
+    }
+}
+
+impl<D, S> LoraDriver for PersistentLoraDriver<D, S>
+where
+    D: LoraDriver,
+    S: LoraSessionStore,
+{
+    #[rustfmt::skip]
+    type ConfigureFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn configure<'m>(&'m mut self, config: &'m LoraConfig) -> Self::ConfigureFuture<'m> {
+        self.driver.configure(config)
+    }
+
+    #[rustfmt::skip]
+    type JoinFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
+        async move {
+            if self.resume {
+                match self.resume().await {
+                    Ok(_) => {
+                        info!("Resumed stored LoRaWAN session");
+                        return Ok(());
+                    }
+                    Err(e) => debug!("Unable to resume stored session: {:?}", e),
+                }
+            }
+            self.driver.join(mode).await?;
+            let session = self.driver.session().await?;
+            self.persist(&session).await?;
+            self.resume = true;
+            Ok(())
+        }
+    }
+
+    #[rustfmt::skip]
+    type SendFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn send<'m>(&'m mut self, qos: QoS, port: Port, data: &'m [u8]) -> Self::SendFuture<'m> {
+        async move {
+            self.reserve().await?;
+            self.driver.send(qos, port, data).await
+        }
+    }
+
+    #[rustfmt::skip]
+    type SendRecvFuture<'m> where Self: 'm = impl Future<Output = Result<usize, LoraError>> + 'm;
+    fn send_recv<'m>(
+        &'m mut self,
+        qos: QoS,
+        port: Port,
+        data: &'m [u8],
+        rx: &'m mut [u8],
+    ) -> Self::SendRecvFuture<'m> {
+        async move {
+            self.reserve().await?;
+            self.driver.send_recv(qos, port, data, rx).await
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkCheckFuture<'m> where Self: 'm = impl Future<Output = Result<LinkCheck, LoraError>> + 'm;
+    fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+        async move {
+            self.reserve().await?;
+            self.driver.link_check().await
+        }
+    }
+
+    #[rustfmt::skip]
+    type StatusFuture<'m> where Self: 'm = impl Future<Output = Result<LoraStatus, LoraError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        self.driver.status()
+    }
+
+    #[rustfmt::skip]
+    type SessionFuture<'m> where Self: 'm = impl Future<Output = Result<LoraSession, LoraError>> + 'm;
+    fn session<'m>(&'m mut self) -> Self::SessionFuture<'m> {
+        self.driver.session()
+    }
+
+    #[rustfmt::skip]
+    type RestoreFuture<'m> where Self: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn restore<'m>(&'m mut self, session: &'m LoraSession) -> Self::RestoreFuture<'m> {
+        async move {
+            self.driver.restore(session).await?;
+            self.persist(session).await
+        }
+    }
+
+    fn take_downlink(&mut self) -> Option<LoraDownlink> {
+        self.driver.take_downlink()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::{ready, Ready};
+
+    fn session(fcnt_up: u32) -> LoraSession {
+        LoraSession {
+            device_address: "26011234".into(),
+            nwks_key: "000102030405060708090A0B0C0D0E0F".into(),
+            apps_key: "F0E0D0C0B0A090807060504030201000".into(),
+            fcnt_up,
+            fcnt_down: 0,
+            join_nonce: Some(1),
+        }
+    }
+
+    #[derive(Default)]
+    struct MockDriver {
+        joins: usize,
+        restored: Option<LoraSession>,
+        next_fcnt_up: u32,
+    }
+
+    impl LoraDriver for MockDriver {
+        #[rustfmt::skip]
+        type ConfigureFuture<'m> where Self: 'm = Ready<Result<(), LoraError>>;
+        fn configure<'m>(&'m mut self, _: &'m LoraConfig) -> Self::ConfigureFuture<'m> {
+            ready(Ok(()))
+        }
+
+        #[rustfmt::skip]
+        type JoinFuture<'m> where Self: 'm = Ready<Result<(), LoraError>>;
+        fn join<'m>(&'m mut self, _: ConnectMode) -> Self::JoinFuture<'m> {
+            self.joins += 1;
+            self.next_fcnt_up = 0;
+            ready(Ok(()))
+        }
+
+        #[rustfmt::skip]
+        type SendFuture<'m> where Self: 'm = Ready<Result<(), LoraError>>;
+        fn send<'m>(&'m mut self, _: QoS, _: Port, _: &'m [u8]) -> Self::SendFuture<'m> {
+            self.next_fcnt_up += 1;
+            ready(Ok(()))
+        }
+
+        #[rustfmt::skip]
+        type SendRecvFuture<'m> where Self: 'm = Ready<Result<usize, LoraError>>;
+        fn send_recv<'m>(
+            &'m mut self,
+            _: QoS,
+            _: Port,
+            _: &'m [u8],
+            _: &'m mut [u8],
+        ) -> Self::SendRecvFuture<'m> {
+            self.next_fcnt_up += 1;
+            ready(Ok(0))
+        }
+
+        #[rustfmt::skip]
+        type LinkCheckFuture<'m> where Self: 'm = Ready<Result<LinkCheck, LoraError>>;
+        fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+            ready(Err(LoraError::NotImplemented))
+        }
+
+        #[rustfmt::skip]
+        type StatusFuture<'m> where Self: 'm = Ready<Result<LoraStatus, LoraError>>;
+        fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+            ready(Ok(LoraStatus {
+                data_rate: 0,
+                tx_power: 0,
+                fcnt_up: self.next_fcnt_up.saturating_sub(1),
+                fcnt_down: 0,
+            }))
+        }
+
+        #[rustfmt::skip]
+        type SessionFuture<'m> where Self: 'm = Ready<Result<LoraSession, LoraError>>;
+        fn session<'m>(&'m mut self) -> Self::SessionFuture<'m> {
+            ready(Ok(session(self.next_fcnt_up)))
+        }
+
+        #[rustfmt::skip]
+        type RestoreFuture<'m> where Self: 'm = Ready<Result<(), LoraError>>;
+        fn restore<'m>(&'m mut self, session: &'m LoraSession) -> Self::RestoreFuture<'m> {
+            self.restored.replace(*session);
+            self.next_fcnt_up = session.fcnt_up;
+            ready(Ok(()))
+        }
+
+        fn take_downlink(&mut self) -> Option<LoraDownlink> {
+            None
+        }
+    }
+
+    #[derive(Default)]
+    struct MockStore {
+        session: Option<LoraSession>,
+        stores: usize,
+        fail: bool,
+    }
+
+    impl LoraSessionStore for MockStore {
+        #[rustfmt::skip]
+        type LoadFuture<'m> where Self: 'm = Ready<Option<LoraSession>>;
+        fn load<'m>(&'m mut self) -> Self::LoadFuture<'m> {
+            ready(self.session)
+        }
+
+        #[rustfmt::skip]
+        type StoreFuture<'m> where Self: 'm = Ready<Result<(), LoraError>>;
+        fn store<'m>(&'m mut self, session: &'m LoraSession) -> Self::StoreFuture<'m> {
+            if self.fail {
+                return ready(Err(LoraError::StorageError));
+            }
+            self.stores += 1;
+            self.session.replace(*session);
+            ready(Ok(()))
+        }
+    }
+
+    fn stored(fcnt_up: u32) -> MockStore {
+        MockStore {
+            session: Some(session(fcnt_up)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resume_skips_reserved_counters() {
+        let mut driver = PersistentLoraDriver::new(MockDriver::default(), stored(10));
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+
+        assert_eq!(0, driver.driver.joins);
+        assert_eq!(Some(session(10 + FCNT_UP_RESERVE)), driver.driver.restored);
+        assert_eq!(Some(session(10 + FCNT_UP_RESERVE)), driver.store.session);
+    }
+
+    #[test]
+    fn test_join_without_stored_session() {
+        let mut driver = PersistentLoraDriver::new(MockDriver::default(), MockStore::default());
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+
+        assert_eq!(1, driver.driver.joins);
+        assert_eq!(Some(session(0)), driver.store.session);
+    }
+
+    #[test]
+    fn test_update_near_limit() {
+        let mut driver = PersistentLoraDriver::new(MockDriver::default(), MockStore::default());
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+        assert_eq!(1, driver.store.stores);
+
+        // Half of the reserved counters are used before storing again
+        for _ in 0..FCNT_UP_RESERVE / 2 {
+            block_on(driver.send(QoS::Unconfirmed, 1, &[])).unwrap();
+        }
+        assert_eq!(1, driver.store.stores);
+        block_on(driver.send(QoS::Unconfirmed, 1, &[])).unwrap();
+        assert_eq!(2, driver.store.stores);
+        assert_eq!(Some(session(FCNT_UP_RESERVE / 2)), driver.store.session);
+    }
+
+    #[test]
+    fn test_store_failure_refuses_unreserved_uplinks() {
+        let mut driver = PersistentLoraDriver::new(MockDriver::default(), MockStore::default());
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+
+        // Uplinks continue while their frame counters are reserved
+        driver.store.fail = true;
+        for _ in 0..FCNT_UP_RESERVE / 2 {
+            block_on(driver.send(QoS::Unconfirmed, 1, &[])).unwrap();
+            let mut rx = [0; 8];
+            block_on(driver.send_recv(QoS::Confirmed, 1, &[], &mut rx)).unwrap();
+        }
+        assert_eq!(1, driver.store.stores);
+        assert_eq!(FCNT_UP_RESERVE, driver.driver.next_fcnt_up);
+
+        // Counters beyond the reserved ones are not used until the session is stored
+        assert!(matches!(
+            block_on(driver.send(QoS::Unconfirmed, 1, &[])),
+            Err(LoraError::StorageError)
+        ));
+        assert!(matches!(
+            block_on(driver.link_check()),
+            Err(LoraError::StorageError)
+        ));
+        assert_eq!(FCNT_UP_RESERVE, driver.driver.next_fcnt_up);
+
+        driver.store.fail = false;
+        block_on(driver.send(QoS::Unconfirmed, 1, &[])).unwrap();
+        assert_eq!(2, driver.store.stores);
+        assert_eq!(Some(session(FCNT_UP_RESERVE)), driver.store.session);
+        assert_eq!(FCNT_UP_RESERVE + 1, driver.driver.next_fcnt_up);
+    }
+
+    #[test]
+    fn test_invalidate_session() {
+        let mut driver = PersistentLoraDriver::new(MockDriver::default(), stored(10));
+        driver.invalidate_session();
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+
+        assert_eq!(1, driver.driver.joins);
+        assert_eq!(None, driver.driver.restored);
+        assert_eq!(Some(session(0)), driver.store.session);
+
+        // Later joins resume the new session again
+        block_on(driver.join(ConnectMode::OTAA)).unwrap();
+        assert_eq!(1, driver.driver.joins);
+        assert_eq!(Some(session(FCNT_UP_RESERVE)), driver.driver.restored);
+    }
+}
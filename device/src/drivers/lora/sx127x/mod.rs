@@ -6,17 +6,47 @@ use embedded_hal::{
     blocking::spi::{Transfer, Write},
     digital::v2::OutputPin,
 };
+use heapless::{
+    consts::{U256, U33, U7},
+    Vec,
+};
 
 use lorawan_device::{
     radio, region, Device as LorawanDevice, Error as LorawanError, Event as LorawanEvent,
     Response as LorawanResponse,
 };
-use lorawan_encoding::default_crypto::DefaultFactory as Crypto;
+use lorawan_encoding::{
+    default_crypto::DefaultFactory as Crypto,
+    maccommandcreator::LinkADRAnsCreator,
+    maccommands::{MacCommand, SerializableMacCommand},
+};
 
 mod sx127x_lora;
 mod sx127x_radio;
 
-use sx127x_radio::{RadioPhyEvent, Sx127xRadio as Radio};
+pub use sx127x_radio::Sx127xRadio;
+use sx127x_radio::{RadioPhyEvent, Sx127xRadio as Radio, DEFAULT_RX_WINDOW_MARGIN};
+
+/// Command identifier of the LinkCheckReq MAC command.
+const LINK_CHECK_REQ: u8 = 0x02;
+
+/// Uplinks without a downlink after which an acknowledgement is requested while ADR is on.
+const ADR_ACK_LIMIT: u32 = 64;
+
+/// Uplinks without a downlink, after the ADR acknowledgement limit, before the data rate is
+/// lowered by one step.
+const ADR_ACK_DELAY: u32 = 32;
+
+/// Highest TX power index of a LinkADRReq the radio can apply, at 2 dBm.
+const MAX_TX_POWER_INDEX: u8 = 7;
+
+/// Data rate or TX power index of a LinkADRReq keeping the current value.
+const ADR_KEEP: u8 = 0xF;
+
+enum Keys {
+    Otaa(EUI, EUI, AppKey),
+    Abp(DevAddr, NwksKey, AppsKey),
+}
 
 enum DriverState<SPI, CS, RESET, E>
 where
@@ -26,9 +56,24 @@ where
     E: 'static,
 {
     New(Radio<SPI, CS, RESET, E>),
-    Configured(LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>),
+    Configured(Radio<SPI, CS, RESET, E>),
+    Joined(LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>),
 }
 
+/// LoRaWAN driver for SX127x radios, using the `lorawan-device` stack.
+///
+/// The pinned revision of the stack only provides the EU868, US915 and CN470 channel plans.
+/// Configuring AU915, KR920, AS923 or IN865 fails with `LoraError::UnsupportedRegion`.
+///
+/// As a Class C device, the radio receives on the RX2 frequency and data rate whenever no
+/// operation is in progress. Downlinks received that way are checked against the session keys
+/// and frame counter by the driver, and returned by `take_downlink` without waiting, so they are
+/// only noticed when it is called, for instance by a `LoraPoller`.
+///
+/// With ADR on, uplinks carry the ADR bit and the data rate and TX power requested by the network
+/// are applied. The channel plan stays the one of the region and sub-band configured, so channel
+/// masks of a LinkADRReq are acknowledged without being applied. Answers to the requests of the
+/// network are sent in the FOpts of the next uplink on a port other than 0.
 pub struct Sx127xDriver<'a, P, SPI, CS, RESET, E>
 where
     P: WaitForRisingEdge,
@@ -39,6 +84,23 @@ where
 {
     irq: P,
     state: Option<DriverState<SPI, CS, RESET, E>>,
+    config: LoraConfig,
+    downlink: Option<LoraDownlink>,
+    link_check: Option<LinkCheck>,
+    data_rate: u8,
+    tx_power: u8,
+    /// Status of the LinkADRAns answers to send with the next uplink.
+    adr_answers: Vec<u8, U7>,
+    /// Uplinks sent since the last downlink.
+    adr_ack_cnt: u32,
+    /// A confirmed downlink is to be acknowledged by the next uplink.
+    ack_pending: bool,
+    /// Frame counter of the next uplink.
+    next_fcnt_up: u32,
+    /// Lowest frame counter accepted for the next downlink.
+    next_fcnt_down: u32,
+    /// JoinNonce of the join accept establishing the session.
+    join_nonce: Option<u32>,
     get_random: fn() -> u32,
     _phantom: core::marker::PhantomData<&'a SPI>,
 }
@@ -49,8 +111,10 @@ pub enum DriverEvent {
     JoinFailed,
     SessionExpired,
     Ack,
-    AckWithData(usize, [u8; 255]),
+    AckWithData(LoraDownlink),
     AckTimeout,
+    /// A downlink with a frame counter already used was received, and dropped.
+    Replay,
     None,
 }
 
@@ -66,6 +130,17 @@ where
         Self {
             irq,
             state: Some(DriverState::New(radio)),
+            config: LoraConfig::new(),
+            downlink: None,
+            link_check: None,
+            data_rate: 0,
+            tx_power: 0,
+            adr_answers: Vec::new(),
+            adr_ack_cnt: 0,
+            ack_pending: false,
+            next_fcnt_up: 0,
+            next_fcnt_down: 0,
+            join_nonce: None,
             _phantom: core::marker::PhantomData,
             get_random,
         }
@@ -74,7 +149,7 @@ where
     fn process_event(&mut self, event: LorawanEvent<'a, Radio<SPI, CS, RESET, E>>) -> DriverEvent {
         //crate::log_stack("Process event");
         match self.state.take().unwrap() {
-            DriverState::Configured(lorawan) => {
+            DriverState::Joined(lorawan) => {
                 match &event {
                     LorawanEvent::NewSessionRequest => {
                         trace!("New Session Request");
@@ -95,19 +170,150 @@ where
                 let (mut new_state, response) = lorawan.handle_event(event);
                 trace!("Event handled");
                 let event = self.process_response(&mut new_state, response);
-                self.state.replace(DriverState::Configured(new_state));
+                self.state.replace(DriverState::Joined(new_state));
                 event
             }
             s => {
-                trace!("Not yet configured, event processing skipped");
+                trace!("Not yet joined, event processing skipped");
                 self.state.replace(s);
                 DriverEvent::None
             }
         }
     }
 
-    fn process_response(
+    /// The radio, whether owned by the driver or by the LoRaWAN stack.
+    fn radio(&mut self) -> Option<&mut Radio<SPI, CS, RESET, E>> {
+        match &mut self.state {
+            Some(DriverState::New(radio)) | Some(DriverState::Configured(radio)) => Some(radio),
+            Some(DriverState::Joined(lorawan)) => Some(lorawan.get_radio()),
+            None => None,
+        }
+    }
+
+    /// Process the MAC commands of a downlink. Answers to requests of the network are queued for
+    /// the next uplink, as the LoRaWAN stack does not answer them.
+    fn process_mac_commands<'m>(
+        &mut self,
+        lorawan: &mut LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>,
+        commands: impl Iterator<Item = MacCommand<'m>>,
+    ) {
+        for command in commands {
+            match command {
+                MacCommand::LinkCheckAns(answer) => {
+                    self.link_check.replace(LinkCheck {
+                        margin: answer.margin(),
+                        gateways: answer.gateway_count(),
+                    });
+                }
+                MacCommand::LinkADRReq(request) => {
+                    let region = self.config.region.unwrap_or(LoraRegion::EU868);
+                    let data_rate = request.data_rate();
+                    let tx_power = request.tx_power();
+                    let data_rate_ack = data_rate == ADR_KEEP
+                        || (to_dr(data_rate).is_some()
+                            && to_modulation(region, data_rate).is_some());
+                    let tx_power_ack = tx_power == ADR_KEEP || tx_power <= MAX_TX_POWER_INDEX;
+                    // Both are applied only if the whole request is acknowledged
+                    if data_rate_ack && tx_power_ack {
+                        if let Some(dr) = to_dr(data_rate) {
+                            trace!("ADR data rate {}", data_rate);
+                            lorawan.set_datarate(dr);
+                            self.data_rate = data_rate;
+                        }
+                        if tx_power != ADR_KEEP {
+                            trace!("ADR TX power {}", tx_power);
+                            self.tx_power = tx_power;
+                            lorawan.get_radio().set_tx_power(to_dbm(tx_power));
+                        }
+                    } else {
+                        warn!(
+                            "Rejecting LinkADRReq for data rate {} and TX power {}",
+                            data_rate, tx_power
+                        );
+                    }
+                    // Channel mask ACK in bit 0, data rate ACK in bit 1 and TX power ACK in bit 2
+                    let status = 0x01 | (data_rate_ack as u8) << 1 | (tx_power_ack as u8) << 2;
+                    if self.adr_answers.push(status).is_err() {
+                        warn!("Dropping LinkADRAns, too many pending");
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn set_tx_power(&mut self, tx_power: u8) {
+        self.tx_power = tx_power;
+        if let Some(radio) = self.radio() {
+            radio.set_tx_power(to_dbm(tx_power));
+        }
+    }
+
+    /// Frame for an uplink of the LoRaWAN stack with the ADR and ACK bits and the pending MAC
+    /// command answers, which the stack cannot add, or `None` if the frame of the stack will do.
+    fn build_uplink(
         &self,
+        lorawan: &LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>,
+        confirmed: bool,
+        port: Port,
+        data: &[u8],
+    ) -> Option<Vec<u8, U256>> {
+        use lorawan_encoding::{creator::DataPayloadCreator, parser::FCtrl};
+
+        let adr = self.config.adr == Some(true);
+        // MAC commands must not be sent both in the FOpts and in a port 0 FRMPayload
+        let answers: &[u8] = if port == 0 { &[] } else { &self.adr_answers };
+        if !adr && answers.is_empty() && !self.ack_pending {
+            return None;
+        }
+        let keys = lorawan.get_session_keys()?;
+        let mut dev_addr = [0; 4];
+        dev_addr.copy_from_slice(keys.devaddr().as_ref());
+        let mut fctrl = 0;
+        if adr {
+            fctrl |= 0x80;
+            if self.adr_ack_cnt >= ADR_ACK_LIMIT {
+                fctrl |= 0x40;
+            }
+        }
+        if self.ack_pending {
+            fctrl |= 0x20;
+        }
+        let creators: Vec<LinkADRAnsCreator, U7> = answers
+            .iter()
+            .map(|status| {
+                let mut answer = LinkADRAnsCreator::new();
+                answer
+                    .set_channel_mask_ack(status & 0x01 != 0)
+                    .set_data_rate_ack(status & 0x02 != 0)
+                    .set_tx_power_ack(status & 0x04 != 0);
+                answer
+            })
+            .collect();
+        let commands: Vec<&dyn SerializableMacCommand, U7> = creators
+            .iter()
+            .map(|answer| answer as &dyn SerializableMacCommand)
+            .collect();
+
+        let mut creator = DataPayloadCreator::new();
+        creator
+            .set_confirmed(confirmed)
+            .set_uplink(true)
+            .set_f_port(port)
+            .set_dev_addr(&dev_addr)
+            .set_fctrl(&FCtrl::new(fctrl, true))
+            .set_fcnt(self.next_fcnt_up);
+        match creator.build(data, &commands, keys.newskey(), keys.appskey()) {
+            Ok(frame) => Vec::from_slice(frame).ok(),
+            Err(_) => {
+                warn!("Unable to build uplink");
+                None
+            }
+        }
+    }
+
+    fn process_response(
+        &mut self,
         lorawan: &mut LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>,
         response: Result<LorawanResponse, LorawanError<Radio<SPI, CS, RESET, E>>>,
     ) -> DriverEvent {
@@ -119,41 +325,58 @@ where
                     return DriverEvent::ProcessAfter(ms);
                 }
                 LorawanResponse::JoinSuccess => {
+                    self.join_nonce = lorawan
+                        .get_radio()
+                        .take_join_accept()
+                        .and_then(|frame| join_nonce(self.config.app_key?, frame));
                     return DriverEvent::JoinSuccess;
                 }
                 LorawanResponse::ReadyToSend => {
                     trace!("RxWindow expired but no ACK expected. Ready to Send");
                 }
                 LorawanResponse::DownlinkReceived(fcnt_down) => {
+                    if fcnt_down < self.next_fcnt_down {
+                        warn!(
+                            "Dropping replayed downlink (FCntDown={}, expected at least {})",
+                            fcnt_down, self.next_fcnt_down
+                        );
+                        let _ = lorawan.take_data_downlink();
+                        return DriverEvent::Replay;
+                    }
+                    self.next_fcnt_down = fcnt_down.wrapping_add(1);
+                    self.adr_ack_cnt = 0;
                     if let Some(downlink) = lorawan.take_data_downlink() {
-                        use lorawan_encoding::parser::FRMPayload;
-
-                        if let Ok(FRMPayload::Data(data)) = downlink.frm_payload() {
-                            trace!(
-                                "Downlink received \t\t(FCntDown={}\tFRM: {:?})",
-                                fcnt_down,
-                                data,
-                            );
-                            let mut buf = [0; 255];
-                            buf[0..data.len()].copy_from_slice(&data[0..data.len()]);
-                            return DriverEvent::AckWithData(data.len(), buf);
-                        } else {
-                            trace!("Downlink received \t\t(FcntDown={})", fcnt_down);
-                            return DriverEvent::Ack;
-                        }
+                        use lorawan_encoding::parser::{DataHeader, FRMPayload};
 
-                        /*
+                        // Uplinks built by the driver must carry the acknowledgement
+                        if downlink.is_confirmed() {
+                            self.ack_pending = true;
+                        }
                         let fhdr = downlink.fhdr();
-                        let fopts = fhdr.fopts();
-                        let mut mac_commands_len = 0;
-                        for mac_command in fopts {
-                            if mac_commands_len == 0 {
-                                trace!("\tFOpts: ");
+                        self.process_mac_commands(lorawan, fhdr.fopts());
+                        match downlink.frm_payload() {
+                            Ok(FRMPayload::Data(data)) => {
+                                trace!(
+                                    "Downlink received \t\t(FCntDown={}\tFRM: {:?})",
+                                    fcnt_down,
+                                    data,
+                                );
+                                let (rssi, snr) = lorawan.get_radio().rx_quality();
+                                let port = downlink.f_port().unwrap_or(0);
+                                return DriverEvent::AckWithData(LoraDownlink::new(
+                                    port, rssi, snr, &data,
+                                ));
+                            }
+                            Ok(FRMPayload::MACCommands(commands)) => {
+                                trace!("Downlink received \t\t(FcntDown={})", fcnt_down);
+                                self.process_mac_commands(lorawan, commands.mac_commands());
+                                return DriverEvent::Ack;
+                            }
+                            _ => {
+                                trace!("Downlink received \t\t(FcntDown={})", fcnt_down);
+                                return DriverEvent::Ack;
                             }
-                            trace!("{:?},", mac_command);
-                            mac_commands_len += 1;
                         }
-                        */
                     }
                 }
                 LorawanResponse::NoAck => {
@@ -173,6 +396,7 @@ where
                 }
                 LorawanResponse::UplinkSending(fcnt_up) => {
                     trace!("Uplink with FCnt {}", fcnt_up);
+                    self.next_fcnt_up = fcnt_up.wrapping_add(1);
                 }
                 LorawanResponse::JoinRequestSending => {
                     trace!("Join Request Sending");
@@ -187,7 +411,242 @@ where
         DriverEvent::None
     }
 
-    async fn join(&mut self) -> Result<(), LoraError> {
+    /// Use the configured RX2 parameters in place of the defaults of the region the LoRaWAN stack
+    /// uses, for the second receive window after an uplink.
+    fn override_rx2(&mut self) {
+        let region = self.config.region.unwrap_or(LoraRegion::EU868);
+        let frequency = self.config.rx2_frequency;
+        let modulation = self
+            .config
+            .rx2_data_rate
+            .and_then(|data_rate| to_downlink_modulation(region, data_rate));
+        if let Some(radio) = self.radio() {
+            radio.set_rx2_override(frequency, modulation);
+        }
+    }
+
+    /// Keep receiving on the RX2 frequency and data rate until the next uplink, if configured as a
+    /// Class C device.
+    fn listen(&mut self) {
+        if self.config.class != Some(LoraClass::C) {
+            return;
+        }
+        let region = self.config.region.unwrap_or(LoraRegion::EU868);
+        let frequency = self.config.rx2_frequency.or_else(|| region.rx2_frequency());
+        let modulation = self
+            .config
+            .rx2_data_rate
+            .or_else(|| region.rx2_data_rate())
+            .and_then(|data_rate| to_downlink_modulation(region, data_rate));
+        match (frequency, modulation, &mut self.state) {
+            (
+                Some(frequency),
+                Some((spreading_factor, bandwidth)),
+                Some(DriverState::Joined(lorawan)),
+            ) => {
+                if lorawan
+                    .get_radio()
+                    .listen(frequency, spreading_factor, bandwidth)
+                    .is_err()
+                {
+                    warn!("Unable to listen for Class C downlinks");
+                }
+            }
+            _ => warn!("No RX2 parameters to listen for Class C downlinks with"),
+        }
+    }
+
+    /// Take the next downlink received while listening as a Class C device, if any.
+    fn receive(&mut self) -> Option<LoraDownlink> {
+        match self.state.take() {
+            Some(DriverState::Joined(mut lorawan)) => {
+                let downlink = match lorawan.get_radio().take_received() {
+                    Some(mut frame) => self.process_downlink(&mut lorawan, &mut frame),
+                    None => None,
+                };
+                self.state.replace(DriverState::Joined(lorawan));
+                downlink
+            }
+            other => {
+                self.state = other;
+                None
+            }
+        }
+    }
+
+    /// Check and decrypt a downlink received outside of the receive windows of the LoRaWAN stack,
+    /// which only accepts downlinks inside them.
+    fn process_downlink(
+        &mut self,
+        lorawan: &mut LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto>,
+        frame: &mut [u8],
+    ) -> Option<LoraDownlink> {
+        use lorawan_encoding::{
+            keys::AES128,
+            parser::{parse, DataHeader, DataPayload, FRMPayload, PhyPayload},
+        };
+
+        let mut dev_addr = [0; 4];
+        let (nwks_key, apps_key) = {
+            let keys = lorawan.get_session_keys()?;
+            dev_addr.copy_from_slice(keys.devaddr().as_ref());
+            (AES128(keys.newskey().0), AES128(keys.appskey().0))
+        };
+        // Unconfirmed data downlinks have a message type of 011 in the MHDR, confirmed ones 101
+        let confirmed = match frame.first().map(|mhdr| mhdr >> 5) {
+            Some(0b011) => false,
+            Some(0b101) => true,
+            _ => {
+                trace!("Ignoring a frame other than a data downlink");
+                return None;
+            }
+        };
+        let payload = match parse(frame) {
+            Ok(PhyPayload::Data(DataPayload::Encrypted(payload))) => payload,
+            _ => return None,
+        };
+        let fcnt = {
+            let fhdr = payload.fhdr();
+            if fhdr.dev_addr().as_ref() != &dev_addr[..] {
+                trace!("Ignoring a downlink for another device");
+                return None;
+            }
+            full_fcnt(self.next_fcnt_down, fhdr.fcnt())
+        };
+        if !payload.validate_mic(&nwks_key, fcnt) {
+            warn!("Dropping a downlink with an invalid MIC");
+            return None;
+        }
+        let downlink = payload
+            .decrypt(Some(&nwks_key), Some(&apps_key), fcnt)
+            .ok()?;
+        self.next_fcnt_down = fcnt.wrapping_add(1);
+        self.adr_ack_cnt = 0;
+        self.ack_pending |= confirmed;
+        trace!("Class C downlink received \t(FCntDown={})", fcnt);
+
+        self.process_mac_commands(lorawan, downlink.fhdr().fopts());
+        match downlink.frm_payload() {
+            Ok(FRMPayload::Data(data)) => {
+                let (rssi, snr) = lorawan.get_radio().rx_quality();
+                let port = downlink.f_port().unwrap_or(0);
+                Some(LoraDownlink::new(port, rssi, snr, &data))
+            }
+            Ok(FRMPayload::MACCommands(commands)) => {
+                self.process_mac_commands(lorawan, commands.mac_commands());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Create the LoRaWAN device for the given connect mode, using the keys from the configuration.
+    fn create_device(&mut self, mode: ConnectMode) -> Result<(), LoraError> {
+        let config = &self.config;
+        let missing = |what: &str| {
+            error!("Unable to join using {:?}: {} must be set", mode, what);
+            LoraError::MissingConfiguration
+        };
+        let keys = match mode {
+            ConnectMode::OTAA => Keys::Otaa(
+                config.device_eui.ok_or_else(|| missing("device EUI"))?,
+                config.app_eui.ok_or_else(|| missing("app EUI"))?,
+                config.app_key.ok_or_else(|| missing("app key"))?,
+            ),
+            ConnectMode::ABP => Keys::Abp(
+                config
+                    .device_address
+                    .ok_or_else(|| missing("device address"))?,
+                config
+                    .nwks_key
+                    .ok_or_else(|| missing("network session key"))?,
+                config.apps_key.ok_or_else(|| missing("app session key"))?,
+            ),
+        };
+        self.create_device_with_keys(keys)
+    }
+
+    fn create_device_with_keys(&mut self, keys: Keys) -> Result<(), LoraError> {
+        let config = &self.config;
+        let lora_region = config.region.unwrap_or(LoraRegion::EU868);
+        let data_rate = to_datarate(
+            lora_region,
+            config.spreading_factor.unwrap_or(SpreadingFactor::SF9),
+        );
+        let mut region = to_region(lora_region)?;
+        let rx1_delay = config.rx1_delay.unwrap_or(RECEIVE_DELAY1);
+        region.set_receive_delay1(rx1_delay);
+        region.set_receive_delay2(
+            config
+                .rx2_delay
+                .unwrap_or(rx1_delay + (RECEIVE_DELAY2 - RECEIVE_DELAY1)),
+        );
+        region.set_join_accept_delay1(config.join_accept_delay1.unwrap_or(JOIN_ACCEPT_DELAY1));
+        region.set_join_accept_delay2(config.join_accept_delay2.unwrap_or(JOIN_ACCEPT_DELAY2));
+
+        let otaa = matches!(keys, Keys::Otaa(..));
+
+        match self.state.take().unwrap() {
+            DriverState::Configured(radio) => {
+                let mut lorawan: LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto> = match keys {
+                    Keys::Otaa(dev_eui, app_eui, app_key) => LorawanDevice::new(
+                        region,
+                        radio,
+                        dev_eui.reverse().into(),
+                        app_eui.reverse().into(),
+                        app_key.into(),
+                        self.get_random,
+                    ),
+                    Keys::Abp(dev_addr, nwks_key, apps_key) => LorawanDevice::new_abp(
+                        region,
+                        radio,
+                        dev_addr.reverse().into(),
+                        nwks_key.into(),
+                        apps_key.into(),
+                        self.get_random,
+                    ),
+                };
+                if let Some(dr) = to_dr(data_rate) {
+                    lorawan.set_datarate(dr);
+                }
+                // The join accept is received with the default RX2 parameters of the region
+                if otaa {
+                    lorawan.get_radio().set_rx2_override(None, None);
+                }
+                self.data_rate = data_rate;
+                self.next_fcnt_up = 0;
+                self.next_fcnt_down = 0;
+                self.join_nonce = None;
+                self.adr_answers.clear();
+                self.adr_ack_cnt = 0;
+                self.ack_pending = false;
+                self.state.replace(DriverState::Joined(lorawan));
+                if !otaa {
+                    self.override_rx2();
+                }
+                Ok(())
+            }
+            other => {
+                self.state.replace(other);
+                Err(LoraError::NotInitialized)
+            }
+        }
+    }
+
+    async fn join(&mut self, mode: ConnectMode) -> Result<(), LoraError> {
+        if !matches!(self.state, Some(DriverState::Joined(_))) {
+            self.create_device(mode)?;
+        }
+        match mode {
+            // The session is established from the configured keys, no join procedure needed.
+            ConnectMode::ABP => {}
+            ConnectMode::OTAA => self.join_otaa().await?,
+        }
+        self.listen();
+        Ok(())
+    }
+
+    async fn join_otaa(&mut self) -> Result<(), LoraError> {
         //crate::log_stack("Driver join");
         let mut event: DriverEvent = self.process_event(LorawanEvent::NewSessionRequest);
         loop {
@@ -207,6 +666,7 @@ where
                 }
                 DriverEvent::JoinSuccess => {
                     trace!("Joined successfully");
+                    self.override_rx2();
                     return Ok(());
                 }
                 DriverEvent::JoinFailed => {
@@ -229,23 +689,51 @@ where
         port: Port,
         data: &[u8],
     ) -> Result<DriverEvent, LoraError> {
+        if self.config.adr == Some(true)
+            && self.adr_ack_cnt >= ADR_ACK_LIMIT + ADR_ACK_DELAY
+            && self.data_rate > 0
+        {
+            // No downlink answered the acknowledgement requests, so the network may not be
+            // able to receive the data rate in use
+            if let Some(DriverState::Joined(lorawan)) = &mut self.state {
+                if let Some(dr) = to_dr(self.data_rate - 1) {
+                    warn!("No ADR acknowledgement, lowering data rate");
+                    lorawan.set_datarate(dr);
+                    self.data_rate -= 1;
+                    self.adr_ack_cnt = ADR_ACK_LIMIT;
+                }
+            }
+        }
+
         match self.state.take().unwrap() {
-            DriverState::Configured(lorawan) => {
+            DriverState::Joined(mut lorawan) => {
                 let ready_to_send = lorawan.ready_to_send_data();
                 if ready_to_send {
-                    let (mut new_state, response) = lorawan.send(
-                        data,
-                        port,
-                        match qos {
-                            QoS::Confirmed => true,
-                            QoS::Unconfirmed => false,
-                        },
-                    );
+                    let confirmed = match qos {
+                        QoS::Confirmed => true,
+                        QoS::Unconfirmed => false,
+                    };
+                    let frame = self.build_uplink(&lorawan, confirmed, port, data);
+                    let built = frame.is_some();
+                    lorawan.get_radio().set_next_uplink(frame);
+                    let (mut new_state, response) = lorawan.send(data, port, confirmed);
+                    // The frame is taken by the radio if the stack transmits
+                    new_state.get_radio().set_next_uplink(None);
+                    if response.is_ok() {
+                        self.adr_ack_cnt = self.adr_ack_cnt.saturating_add(1);
+                        if built {
+                            self.ack_pending = false;
+                            // Answers are only sent in the FOpts of uplinks on other ports
+                            if port != 0 {
+                                self.adr_answers.clear();
+                            }
+                        }
+                    }
                     let event = self.process_response(&mut new_state, response);
-                    self.state.replace(DriverState::Configured(new_state));
+                    self.state.replace(DriverState::Joined(new_state));
                     Ok(event)
                 } else {
-                    self.state.replace(DriverState::Configured(lorawan));
+                    self.state.replace(DriverState::Joined(lorawan));
                     Err(LoraError::NotReady)
                 }
             }
@@ -263,6 +751,19 @@ where
         port: Port,
         data: &[u8],
         rx: Option<&mut [u8]>,
+    ) -> Result<usize, LoraError> {
+        let result = self.exchange(qos, port, data, rx).await;
+        // A Class C device listens again once the receive windows of the uplink are over
+        self.listen();
+        result
+    }
+
+    async fn exchange(
+        &mut self,
+        qos: QoS,
+        port: Port,
+        data: &[u8],
+        rx: Option<&mut [u8]>,
     ) -> Result<usize, LoraError> {
         // Await response
         let mut event = self.send_data(qos, port, data).await?;
@@ -281,12 +782,17 @@ where
                         }
                     }
                 }
-                DriverEvent::AckWithData(len, buf) => {
-                    trace!("Received {} bytes of data", len);
+                DriverEvent::AckWithData(downlink) => {
+                    let payload = downlink.payload();
+                    trace!("Received {} bytes of data", payload.len());
+                    self.downlink.replace(downlink);
                     if let Some(rx) = rx {
-                        rx[0..len].copy_from_slice(&buf[0..len]);
+                        if payload.len() > rx.len() {
+                            return Err(LoraError::RecvBufferTooSmall);
+                        }
+                        rx[0..payload.len()].copy_from_slice(payload);
                     }
-                    return Ok(len);
+                    return Ok(payload.len());
                 }
                 DriverEvent::AckTimeout => {
                     trace!("Ack timed out!");
@@ -296,6 +802,13 @@ where
                     trace!("Ack received!");
                     return Ok(0);
                 }
+                // The acknowledgement of a replayed downlink does not acknowledge this uplink
+                DriverEvent::Replay => {
+                    return match qos {
+                        QoS::Confirmed => Err(LoraError::AckTimeout),
+                        QoS::Unconfirmed => Ok(0),
+                    };
+                }
                 _ => {
                     // Wait for interrupt
                     self.irq.wait_for_rising_edge().await;
@@ -321,33 +834,30 @@ where
     fn configure<'m>(&'m mut self, config: &'m LoraConfig) -> Self::ConfigureFuture<'m> {
         async move {
             match self.state.take().unwrap() {
-                DriverState::New(mut radio) => {
+                DriverState::New(mut radio) | DriverState::Configured(mut radio) => {
                     //crate::log_stack("lora driver configure");
-                    radio.reset().await?;
-                    //info!("Configuring radio");
-                    let dev_eui = config.device_eui.as_ref().expect("device EUI must be set");
-                    let app_eui = config.app_eui.as_ref().expect("app EUI must be set");
-                    let app_key = config.app_key.as_ref().expect("app KEY must be set");
-                    //info!("Creating device");
-                    let data_rate =
-                        to_datarate(config.spreading_factor.unwrap_or(SpreadingFactor::SF9));
-                    let region = to_region(config.region.unwrap_or(LoraRegion::EU868));
-                    if let Err(e) = region {
+                    if let Err(e) = to_region(config.region.unwrap_or(LoraRegion::EU868)) {
+                        self.state.replace(DriverState::New(radio));
                         return Err(e);
                     }
-                    let mut region = region.unwrap();
-                    region.set_receive_delay1(5000);
-                    let mut lorawan: LorawanDevice<Radio<SPI, CS, RESET, E>, Crypto> =
-                        LorawanDevice::new(
-                            region,
-                            radio,
-                            dev_eui.reverse().into(),
-                            app_eui.reverse().into(),
-                            app_key.clone().into(),
-                            self.get_random,
-                        );
-                    lorawan.set_datarate(data_rate);
-                    self.state.replace(DriverState::Configured(lorawan));
+                    let region = config.region.unwrap_or(LoraRegion::EU868);
+                    if let Some(data_rate) = config.rx2_data_rate {
+                        if to_downlink_modulation(region, data_rate).is_none() {
+                            error!("RX2 data rate {} is not a LoRa data rate", data_rate);
+                            self.state.replace(DriverState::New(radio));
+                            return Err(LoraError::NotImplemented);
+                        }
+                    }
+                    if let Err(e) = radio.reset().await {
+                        self.state.replace(DriverState::New(radio));
+                        return Err(e);
+                    }
+                    radio.set_rx_window_margin(
+                        config.rx_window_margin.unwrap_or(DEFAULT_RX_WINDOW_MARGIN),
+                    );
+                    self.config = *config;
+                    self.state.replace(DriverState::Configured(radio));
+                    self.set_tx_power(config.tx_power.unwrap_or(0));
                     Ok(())
                 }
                 other => {
@@ -361,8 +871,8 @@ where
 
     #[rustfmt::skip]
     type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
-    fn join<'m>(&'m mut self, _: ConnectMode) -> Self::JoinFuture<'m> {
-        async move { self.join().await }
+    fn join<'m>(&'m mut self, mode: ConnectMode) -> Self::JoinFuture<'m> {
+        async move { self.join(mode).await }
     }
 
     #[rustfmt::skip]
@@ -382,6 +892,84 @@ where
     ) -> Self::SendRecvFuture<'m> {
         async move { self.send_recv(qos, port, data, Some(rx)).await }
     }
+
+    #[rustfmt::skip]
+    type LinkCheckFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkCheck, LoraError>> + 'm;
+    fn link_check<'m>(&'m mut self) -> Self::LinkCheckFuture<'m> {
+        async move {
+            self.link_check.take();
+            // The LoRaWAN stack cannot add MAC commands to the FOpts of an uplink, so the
+            // request is sent as the FRMPayload of a port 0 uplink instead, which is encrypted
+            // with the network session key as the specification requires for MAC commands
+            self.send_recv(QoS::Confirmed, 0, &[LINK_CHECK_REQ], None)
+                .await?;
+            self.link_check.take().ok_or(LoraError::RecvError)
+        }
+    }
+
+    #[rustfmt::skip]
+    type StatusFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraStatus, LoraError>> + 'm;
+    fn status<'m>(&'m mut self) -> Self::StatusFuture<'m> {
+        async move {
+            Ok(LoraStatus {
+                data_rate: self.data_rate,
+                tx_power: self.tx_power,
+                fcnt_up: self.next_fcnt_up.saturating_sub(1),
+                fcnt_down: self.next_fcnt_down.saturating_sub(1),
+            })
+        }
+    }
+
+    #[rustfmt::skip]
+    type SessionFuture<'m> where 'a: 'm = impl Future<Output = Result<LoraSession, LoraError>> + 'm;
+    fn session<'m>(&'m mut self) -> Self::SessionFuture<'m> {
+        async move {
+            match &self.state {
+                Some(DriverState::Joined(lorawan)) => {
+                    let keys = lorawan
+                        .get_session_keys()
+                        .ok_or(LoraError::NotInitialized)?;
+                    let mut device_address = [0; 4];
+                    device_address.copy_from_slice(keys.devaddr().as_ref());
+                    Ok(LoraSession {
+                        device_address: DevAddr::from(device_address).reverse(),
+                        nwks_key: keys.newskey().0.into(),
+                        apps_key: keys.appskey().0.into(),
+                        fcnt_up: self.next_fcnt_up,
+                        fcnt_down: self.next_fcnt_down,
+                        join_nonce: self.join_nonce,
+                    })
+                }
+                _ => Err(LoraError::NotInitialized),
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type RestoreFuture<'m> where 'a: 'm = impl Future<Output = Result<(), LoraError>> + 'm;
+    fn restore<'m>(&'m mut self, session: &'m LoraSession) -> Self::RestoreFuture<'m> {
+        async move {
+            // The session keys are used as for a device activated by personalization
+            self.create_device_with_keys(Keys::Abp(
+                session.device_address,
+                session.nwks_key,
+                session.apps_key,
+            ))?;
+            if let Some(DriverState::Joined(lorawan)) = &mut self.state {
+                lorawan.set_fcnt_up(session.fcnt_up);
+            }
+            self.next_fcnt_up = session.fcnt_up;
+            // Downlink frame counters are checked by the driver, as the stack cannot be given one
+            self.next_fcnt_down = session.fcnt_down;
+            self.join_nonce = session.join_nonce;
+            self.listen();
+            Ok(())
+        }
+    }
+
+    fn take_downlink(&mut self) -> Option<LoraDownlink> {
+        self.downlink.take().or_else(|| self.receive())
+    }
 }
 
 fn to_region(region: LoraRegion) -> Result<region::Configuration, LoraError> {
@@ -393,13 +981,240 @@ fn to_region(region: LoraRegion) -> Result<region::Configuration, LoraError> {
     }
 }
 
-fn to_datarate(spreading_factor: SpreadingFactor) -> region::DR {
-    match spreading_factor {
-        SpreadingFactor::SF7 => region::DR::_5,
-        SpreadingFactor::SF8 => region::DR::_4,
-        SpreadingFactor::SF9 => region::DR::_3,
-        SpreadingFactor::SF10 => region::DR::_2,
-        SpreadingFactor::SF11 => region::DR::_1,
-        SpreadingFactor::SF12 => region::DR::_0,
+/// Frame counter of a downlink carrying the given lower 16 bits, at least the next one expected.
+/// Replayed downlinks are thus taken to be from the next 65536 frames, and fail the MIC check.
+fn full_fcnt(next_fcnt: u32, fcnt: u16) -> u32 {
+    let full = (next_fcnt & !0xFFFF) | fcnt as u32;
+    if full < next_fcnt {
+        full.wrapping_add(0x10000)
+    } else {
+        full
+    }
+}
+
+/// JoinNonce of a join accept, decrypted with the app key it was sent for.
+fn join_nonce(app_key: AppKey, mut frame: Vec<u8, U33>) -> Option<u32> {
+    use lorawan_encoding::{
+        keys::AES128,
+        parser::{parse, JoinAcceptPayload, PhyPayload},
+    };
+
+    match parse(&mut frame[..]) {
+        Ok(PhyPayload::JoinAccept(JoinAcceptPayload::Encrypted(payload))) => {
+            let payload = payload.decrypt(&AES128(app_key.into()));
+            let nonce = payload.app_nonce();
+            let nonce = nonce.as_ref();
+            Some(u32::from_le_bytes([nonce[0], nonce[1], nonce[2], 0]))
+        }
+        _ => None,
+    }
+}
+
+fn to_datarate(region: LoraRegion, spreading_factor: SpreadingFactor) -> u8 {
+    match (region, spreading_factor) {
+        // US915 only allows SF7 to SF10 for uplinks at 125 kHz
+        (LoraRegion::US915, SpreadingFactor::SF7) => 3,
+        (LoraRegion::US915, SpreadingFactor::SF8) => 2,
+        (LoraRegion::US915, SpreadingFactor::SF9) => 1,
+        (LoraRegion::US915, _) => 0,
+        (_, SpreadingFactor::SF7) => 5,
+        (_, SpreadingFactor::SF8) => 4,
+        (_, SpreadingFactor::SF9) => 3,
+        (_, SpreadingFactor::SF10) => 2,
+        (_, SpreadingFactor::SF11) => 1,
+        (_, SpreadingFactor::SF12) => 0,
+    }
+}
+
+/// Spreading factor and bandwidth in kHz of a LoRa data rate of the region.
+fn to_modulation(region: LoraRegion, data_rate: u8) -> Option<(SpreadingFactor, u32)> {
+    match (region, data_rate) {
+        (LoraRegion::US915, 0) => Some((SpreadingFactor::SF10, 125)),
+        (LoraRegion::US915, 1) => Some((SpreadingFactor::SF9, 125)),
+        (LoraRegion::US915, 2) => Some((SpreadingFactor::SF8, 125)),
+        (LoraRegion::US915, 3) => Some((SpreadingFactor::SF7, 125)),
+        (LoraRegion::US915, 4) => Some((SpreadingFactor::SF8, 500)),
+        (LoraRegion::US915, _) => None,
+        (_, 0) => Some((SpreadingFactor::SF12, 125)),
+        (_, 1) => Some((SpreadingFactor::SF11, 125)),
+        (_, 2) => Some((SpreadingFactor::SF10, 125)),
+        (_, 3) => Some((SpreadingFactor::SF9, 125)),
+        (_, 4) => Some((SpreadingFactor::SF8, 125)),
+        (_, 5) => Some((SpreadingFactor::SF7, 125)),
+        (_, 6) => Some((SpreadingFactor::SF7, 250)),
+        // FSK
+        _ => None,
+    }
+}
+
+/// Spreading factor and bandwidth in kHz of a LoRa data rate of the region used for downlinks.
+fn to_downlink_modulation(region: LoraRegion, data_rate: u8) -> Option<(SpreadingFactor, u32)> {
+    match (region, data_rate) {
+        (LoraRegion::US915, 8) => Some((SpreadingFactor::SF12, 500)),
+        (LoraRegion::US915, 9) => Some((SpreadingFactor::SF11, 500)),
+        (LoraRegion::US915, 10) => Some((SpreadingFactor::SF10, 500)),
+        (LoraRegion::US915, 11) => Some((SpreadingFactor::SF9, 500)),
+        (LoraRegion::US915, 12) => Some((SpreadingFactor::SF8, 500)),
+        (LoraRegion::US915, 13) => Some((SpreadingFactor::SF7, 500)),
+        (LoraRegion::US915, _) => None,
+        _ => to_modulation(region, data_rate),
+    }
+}
+
+/// Output power in dBm of a TX power index, 14 dBm lowered by 2 dB per step down to 2 dBm.
+fn to_dbm(tx_power: u8) -> u8 {
+    core::cmp::max(14 - 2 * tx_power as i16, 2) as u8
+}
+
+fn to_dr(data_rate: u8) -> Option<region::DR> {
+    match data_rate {
+        0 => Some(region::DR::_0),
+        1 => Some(region::DR::_1),
+        2 => Some(region::DR::_2),
+        3 => Some(region::DR::_3),
+        4 => Some(region::DR::_4),
+        5 => Some(region::DR::_5),
+        6 => Some(region::DR::_6),
+        7 => Some(region::DR::_7),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downlink_frame_counter() {
+        assert_eq!(5, full_fcnt(0, 5));
+        assert_eq!(0x1_0002, full_fcnt(0xFFFF, 2));
+        assert_eq!(0x2_0003, full_fcnt(0x1_0004, 3));
+        assert_eq!(0x1_0004, full_fcnt(0x1_0004, 4));
+    }
+
+    #[test]
+    fn test_tx_power_index() {
+        assert_eq!(14, to_dbm(0));
+        assert_eq!(4, to_dbm(5));
+        assert_eq!(2, to_dbm(MAX_TX_POWER_INDEX));
+    }
+
+    #[test]
+    fn test_link_check_request_encryption() {
+        use lorawan_encoding::{
+            creator::DataPayloadCreator,
+            keys::AES128,
+            parser::{parse, DataPayload, FRMPayload, PhyPayload},
+        };
+
+        let nwk_skey = AES128([1; 16]);
+        let app_skey = AES128([2; 16]);
+        let mut creator = DataPayloadCreator::new();
+        creator
+            .set_confirmed(true)
+            .set_uplink(true)
+            .set_f_port(0)
+            .set_dev_addr(&[4, 3, 2, 1])
+            .set_fcnt(1);
+        let uplink = creator
+            .build(&[LINK_CHECK_REQ], &[], &nwk_skey, &app_skey)
+            .unwrap();
+        let mut data = [0; 32];
+        let len = uplink.len();
+        data[..len].copy_from_slice(uplink);
+
+        // The network decrypts MAC commands on port 0 with the network session key only
+        match parse(&mut data[..len]) {
+            Ok(PhyPayload::Data(DataPayload::Encrypted(payload))) => {
+                let payload = payload.decrypt(Some(&nwk_skey), None, 1).unwrap();
+                match payload.frm_payload() {
+                    Ok(FRMPayload::MACCommands(commands)) => assert!(matches!(
+                        commands.mac_commands().next(),
+                        Some(MacCommand::LinkCheckReq(_))
+                    )),
+                    _ => panic!("Expected MAC commands"),
+                }
+            }
+            _ => panic!("Expected an encrypted data payload"),
+        }
+    }
+
+    #[test]
+    fn test_adr_uplink() {
+        use lorawan_encoding::{
+            creator::DataPayloadCreator,
+            keys::AES128,
+            parser::{parse, DataHeader, DataPayload, FCtrl, FRMPayload, PhyPayload},
+        };
+
+        let nwk_skey = AES128([1; 16]);
+        let app_skey = AES128([2; 16]);
+        let mut answer = LinkADRAnsCreator::new();
+        answer
+            .set_channel_mask_ack(true)
+            .set_data_rate_ack(true)
+            .set_tx_power_ack(false);
+        let mut creator = DataPayloadCreator::new();
+        creator
+            .set_confirmed(false)
+            .set_uplink(true)
+            .set_f_port(1)
+            .set_dev_addr(&[4, 3, 2, 1])
+            .set_fctrl(&FCtrl::new(0x80, true))
+            .set_fcnt(1);
+        let uplink = creator
+            .build(&[42], &[&answer], &nwk_skey, &app_skey)
+            .unwrap();
+        let mut data = [0; 32];
+        let len = uplink.len();
+        data[..len].copy_from_slice(uplink);
+
+        match parse(&mut data[..len]) {
+            Ok(PhyPayload::Data(DataPayload::Encrypted(payload))) => {
+                let payload = payload
+                    .decrypt(Some(&nwk_skey), Some(&app_skey), 1)
+                    .unwrap();
+                let fhdr = payload.fhdr();
+                assert!(fhdr.fctrl().adr());
+                match fhdr.fopts().next() {
+                    Some(MacCommand::LinkADRAns(answer)) => {
+                        assert!(answer.channel_mask_ack());
+                        assert!(answer.data_rate_ack());
+                        assert!(!answer.powert_ack());
+                    }
+                    _ => panic!("Expected a LinkADRAns"),
+                }
+                assert!(matches!(payload.frm_payload(), Ok(FRMPayload::Data(&[42]))));
+            }
+            _ => panic!("Expected an encrypted data payload"),
+        }
+    }
+
+    #[test]
+    fn test_modulation_of_data_rate() {
+        assert!(matches!(
+            to_modulation(LoraRegion::US915, 0),
+            Some((SpreadingFactor::SF10, 125))
+        ));
+        assert!(to_modulation(LoraRegion::US915, 5).is_none());
+        assert!(matches!(
+            to_modulation(LoraRegion::EU868, 0),
+            Some((SpreadingFactor::SF12, 125))
+        ));
+    }
+
+    #[test]
+    fn test_downlink_modulation() {
+        // The default RX2 data rate of US915 is only used for downlinks
+        assert!(matches!(
+            to_downlink_modulation(LoraRegion::US915, 8),
+            Some((SpreadingFactor::SF12, 500))
+        ));
+        assert!(to_downlink_modulation(LoraRegion::US915, 5).is_none());
+        assert!(matches!(
+            to_downlink_modulation(LoraRegion::EU868, 3),
+            Some((SpreadingFactor::SF9, 125))
+        ));
+        assert!(to_downlink_modulation(LoraRegion::EU868, 7).is_none());
     }
 }
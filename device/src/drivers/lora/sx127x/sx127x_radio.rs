@@ -1,7 +1,12 @@
-use crate::traits::lora::LoraError as DriverError;
+use crate::traits::lora::{self, LoraError as DriverError, LoraP2p, RfConfig, RxPacket};
+use core::future::Future;
+use embassy::time::{with_timeout, Duration, Timer};
 use embedded_hal::blocking::spi::{Transfer, Write};
 use embedded_hal::digital::v2::OutputPin;
-use heapless::{consts::U256, Vec};
+use heapless::{
+    consts::{U256, U33},
+    Vec,
+};
 use lorawan_device::{
     radio::{
         Bandwidth, Error as LoraError, Event as LoraEvent, PhyRxTx, PhyRxTxBuf,
@@ -10,7 +15,20 @@ use lorawan_device::{
     Timings,
 };
 
-use super::sx127x_lora::{LoRa, RadioMode, IRQ};
+use super::sx127x_lora::{Error as RadioError, LoRa, RadioMode, IRQ};
+
+/// Time in milliseconds a receive window stays open after its nominal start, long enough to
+/// detect the preamble of a downlink at SF12.
+const RX_WINDOW_LENGTH: u32 = 300;
+
+/// Default time in milliseconds a receive window is opened early, to compensate for clock error.
+pub const DEFAULT_RX_WINDOW_MARGIN: u32 = 500;
+
+/// Interval between reads of the IRQ flags while waiting for a point-to-point transfer.
+const P2P_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Time allowed for a point-to-point transmission, longer than the time on air of any packet.
+const P2P_TX_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Sx127xRadio<SPI, CS, RESET, E>
 where
@@ -21,6 +39,26 @@ where
     radio: LoRa<SPI, CS, RESET>,
     radio_state: State,
     buffer: RadioBuffer,
+    rf_config: Option<RfConfig>,
+    rx_window_margin: u32,
+    /// Receive windows opened since the last uplink.
+    rx_windows: u8,
+    /// The last join accept received, kept as received since the LoRaWAN stack does not pass
+    /// on its contents.
+    join_accept: Option<Vec<u8, U33>>,
+    /// Output power in dBm used for transmitting.
+    tx_power: u8,
+    /// RSSI and SNR of the last packet received, which the LoRaWAN stack does not pass on.
+    rx_quality: (i16, i8),
+    /// Frequency in Hz of the second receive window after an uplink, in place of the default
+    /// of the region, the only one the LoRaWAN stack knows.
+    rx2_frequency: Option<u32>,
+    /// Spreading factor and bandwidth in Hz of the second receive window after an uplink.
+    rx2_modulation: Option<(u8, i64)>,
+    /// Frame transmitted in place of the next uplink of the LoRaWAN stack.
+    next_uplink: Option<Vec<u8, U256>>,
+    /// Receiving continuously outside of the windows of the LoRaWAN stack.
+    listening: bool,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -42,6 +80,17 @@ fn spreading_factor_to_u8(sf: SpreadingFactor) -> u8 {
     }
 }
 
+fn lora_spreading_factor_to_u8(sf: lora::SpreadingFactor) -> u8 {
+    match sf {
+        lora::SpreadingFactor::SF7 => 7,
+        lora::SpreadingFactor::SF8 => 8,
+        lora::SpreadingFactor::SF9 => 9,
+        lora::SpreadingFactor::SF10 => 10,
+        lora::SpreadingFactor::SF11 => 11,
+        lora::SpreadingFactor::SF12 => 12,
+    }
+}
+
 fn bandwidth_to_i64(bw: Bandwidth) -> i64 {
     match bw {
         Bandwidth::_125KHz => 125_000,
@@ -61,7 +110,115 @@ where
             radio_state: State::Idle,
             radio: LoRa::new(spi, cs, reset),
             buffer: RadioBuffer { packet: Vec::new() },
+            rf_config: None,
+            rx_window_margin: DEFAULT_RX_WINDOW_MARGIN,
+            rx_windows: 0,
+            join_accept: None,
+            tx_power: 14,
+            rx_quality: (0, 0),
+            rx2_frequency: None,
+            rx2_modulation: None,
+            next_uplink: None,
+            listening: false,
+        }
+    }
+
+    /// Set the output power in dBm used for transmitting.
+    pub fn set_tx_power(&mut self, dbm: u8) {
+        self.tx_power = dbm;
+    }
+
+    /// RSSI and SNR of the last packet received.
+    pub fn rx_quality(&self) -> (i16, i8) {
+        self.rx_quality
+    }
+
+    /// Override the frequency in Hz and the spreading factor and bandwidth in kHz of the second
+    /// receive window after an uplink, using the ones requested by the LoRaWAN stack if `None`.
+    pub fn set_rx2_override(
+        &mut self,
+        frequency: Option<u32>,
+        modulation: Option<(lora::SpreadingFactor, u32)>,
+    ) {
+        self.rx2_frequency = frequency;
+        self.rx2_modulation = modulation.map(|(spreading_factor, bandwidth)| {
+            (
+                lora_spreading_factor_to_u8(spreading_factor),
+                bandwidth as i64 * 1000,
+            )
+        });
+    }
+
+    /// Receive downlinks on the given frequency in Hz, with the given spreading factor and
+    /// bandwidth in kHz, until the LoRaWAN stack uses the radio again. Packets received are taken
+    /// with `take_received`.
+    pub fn listen(
+        &mut self,
+        frequency: u32,
+        spreading_factor: lora::SpreadingFactor,
+        bandwidth: u32,
+    ) -> Result<(), DriverError> {
+        if !matches!(self.radio_state, State::Idle) {
+            return Err(DriverError::NotReady);
+        }
+        (|| {
+            self.radio.set_mode(RadioMode::Stdby)?;
+            self.radio.reset_payload_length()?;
+            self.radio.set_frequency(frequency)?;
+            // TODO: Modify radio to support other coding rates
+            self.radio.set_coding_rate_4(5)?;
+            self.radio.set_signal_bandwidth(bandwidth as i64 * 1000)?;
+            self.radio
+                .set_spreading_factor(lora_spreading_factor_to_u8(spreading_factor))?;
+            self.radio.set_preamble_length(8)?;
+            self.radio.set_lora_sync_word()?;
+            self.radio.set_invert_iq(true)?;
+            self.radio.set_crc(true)?;
+            self.radio.set_dio0_rx_done()?;
+            self.radio.set_mode(RadioMode::RxContinuous)
+        })()
+        .map_err(|_| DriverError::RecvError)?;
+        self.listening = true;
+        Ok(())
+    }
+
+    /// Take the packet received while listening, if any, without waiting. The radio keeps
+    /// listening afterwards.
+    pub fn take_received(&mut self) -> Option<Vec<u8, U256>> {
+        if !self.listening {
+            return None;
+        }
+        let irq = self.radio.irq_flags().ok()?;
+        if irq & IRQ::IrqRxDoneMask.addr() == 0 {
+            return None;
         }
+        self.radio.clear_irq().ok()?;
+        if irq & IRQ::IrqPayloadCrcErrorMask.addr() != 0 {
+            return None;
+        }
+        let rssi = self.radio.get_packet_rssi().unwrap_or(0) as i16;
+        let snr = self.radio.get_packet_snr().unwrap_or(0.0) as i8;
+        self.rx_quality = (rssi, snr);
+        let size = self.radio.read_packet_size().ok()?;
+        let packet = self.radio.read_packet().ok()?;
+        Vec::from_slice(&packet[..size]).ok()
+    }
+
+    /// Transmit the given frame in place of the next uplink of the LoRaWAN stack, or clear a
+    /// frame not transmitted yet if `None`. The frame must use the frame counter the stack uses.
+    pub fn set_next_uplink(&mut self, frame: Option<Vec<u8, U256>>) {
+        self.next_uplink = frame;
+    }
+
+    /// Open receive windows the given time in milliseconds early, keeping them open for as long
+    /// after their nominal start.
+    pub fn set_rx_window_margin(&mut self, margin: u32) {
+        self.rx_window_margin = margin;
+    }
+
+    /// Take the last join accept received, still encrypted.
+    pub fn take_join_accept(&mut self) -> Option<Vec<u8, U33>> {
+        self.join_accept.take()
     }
 
     pub async fn reset(&mut self) -> Result<(), DriverError> {
@@ -75,11 +232,24 @@ where
         &mut self,
         event: LoraEvent<Self>,
     ) -> (State, Result<LoraResponse<Self>, LoraError<Self>>) {
+        // The LoRaWAN stack takes over the radio
+        self.listening = false;
         match event {
             LoraEvent::TxRequest(config, buf) => {
                 //trace!("Set config: {:?}", config);
+                self.rx_windows = 0;
+                let frame = self.next_uplink.take();
+                let packet = match &frame {
+                    Some(frame) => &frame[..],
+                    None => &buf.packet[..],
+                };
+                let len = packet.len();
+                assert!(len < 255);
+                let mut payload = [0; 255];
+                payload[..len].copy_from_slice(packet);
+                let tx_power = self.tx_power;
                 let result = (move || {
-                    self.radio.set_tx_power(14, 0)?;
+                    self.radio.set_tx_power(tx_power as i32, 0)?;
                     self.radio.set_frequency(config.rf.frequency)?;
                     // TODO: Modify radio to support other coding rates
                     self.radio.set_coding_rate_4(5)?;
@@ -94,10 +264,6 @@ where
                     self.radio.set_invert_iq(false)?;
                     self.radio.set_crc(true)?;
 
-                    let len = buf.packet.len();
-                    assert!(len < 255);
-                    let mut payload = [0; 255];
-                    payload[..len].copy_from_slice(&buf.packet[..len]);
                     self.radio.set_dio0_tx_done()?;
                     self.radio.transmit_payload(payload, len)
                 })();
@@ -108,15 +274,25 @@ where
             }
             LoraEvent::RxRequest(config) => {
                 // trace!("Set RX config: {:?}", config);
+                self.rx_windows = self.rx_windows.saturating_add(1);
+                let mut frequency = config.frequency;
+                let mut spreading_factor = spreading_factor_to_u8(config.spreading_factor);
+                let mut bandwidth = bandwidth_to_i64(config.bandwidth);
+                // The second window after an uplink uses the parameters configured for the network
+                if self.rx_windows == 2 {
+                    frequency = self.rx2_frequency.unwrap_or(frequency);
+                    if let Some((sf, bw)) = self.rx2_modulation {
+                        spreading_factor = sf;
+                        bandwidth = bw;
+                    }
+                }
                 let result = (move || {
                     self.radio.reset_payload_length()?;
-                    self.radio.set_frequency(config.frequency)?;
+                    self.radio.set_frequency(frequency)?;
                     // TODO: Modify radio to support other coding rates
                     self.radio.set_coding_rate_4(5)?;
-                    self.radio
-                        .set_signal_bandwidth(bandwidth_to_i64(config.bandwidth))?;
-                    self.radio
-                        .set_spreading_factor(spreading_factor_to_u8(config.spreading_factor))?;
+                    self.radio.set_signal_bandwidth(bandwidth)?;
+                    self.radio.set_spreading_factor(spreading_factor)?;
 
                     self.radio.set_preamble_length(8)?;
                     self.radio.set_lora_sync_word()?;
@@ -181,8 +357,13 @@ where
                     if (irq & IRQ::IrqRxDoneMask.addr()) != 0 {
                         let rssi = self.radio.get_packet_rssi().unwrap_or(0) as i16;
                         let snr = self.radio.get_packet_snr().unwrap_or(0.0) as i8;
+                        self.rx_quality = (rssi, snr);
                         if let Ok(size) = self.radio.read_packet_size() {
                             if let Ok(packet) = self.radio.read_packet() {
+                                // Join accepts have a message type of 001 in the MHDR
+                                if size > 0 && packet[0] & 0xE0 == 0x20 {
+                                    self.join_accept = Vec::from_slice(&packet[..size]).ok();
+                                }
                                 self.buffer.packet.clear();
                                 self.buffer
                                     .packet
@@ -211,6 +392,145 @@ where
     }
 }
 
+impl<SPI, CS, RESET, E> Sx127xRadio<SPI, CS, RESET, E>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+    RESET: OutputPin,
+{
+    fn apply_rf_config(
+        &mut self,
+        config: &RfConfig,
+    ) -> Result<(), RadioError<E, CS::Error, RESET::Error>> {
+        self.radio.set_frequency(config.frequency)?;
+        self.radio.set_coding_rate_4(match config.coding_rate {
+            lora::CodingRate::CR4_5 => 5,
+            lora::CodingRate::CR4_6 => 6,
+            lora::CodingRate::CR4_7 => 7,
+            lora::CodingRate::CR4_8 => 8,
+        })?;
+        self.radio.set_signal_bandwidth(match config.bandwidth {
+            lora::Bandwidth::KHz125 => 125_000,
+            lora::Bandwidth::KHz250 => 250_000,
+            lora::Bandwidth::KHz500 => 500_000,
+        })?;
+        self.radio
+            .set_spreading_factor(lora_spreading_factor_to_u8(config.spreading_factor))?;
+        self.radio.set_preamble_length(8)?;
+        self.radio.set_lora_sync_word()?;
+        // Both ends of a point-to-point link use the same IQ polarity
+        self.radio.set_invert_iq(false)?;
+        self.radio.set_crc(true)
+    }
+
+    /// Wait until any of the given IRQ flags is raised, returning all flags raised. The radio
+    /// does not own the interrupt pin, so the flags are polled.
+    async fn wait_for_irq(&mut self, mask: u8) -> Result<u8, DriverError> {
+        loop {
+            let irq = self
+                .radio
+                .irq_flags()
+                .map_err(|_| DriverError::OtherError)?;
+            if irq & mask != 0 {
+                self.radio
+                    .clear_irq()
+                    .map_err(|_| DriverError::OtherError)?;
+                return Ok(irq);
+            }
+            Timer::after(P2P_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn p2p_transmit(&mut self, data: &[u8]) -> Result<(), DriverError> {
+        let config = self.rf_config.ok_or(DriverError::NotInitialized)?;
+        if data.len() > 255 {
+            return Err(DriverError::SendError);
+        }
+        let mut payload = [0; 255];
+        payload[..data.len()].copy_from_slice(data);
+        (|| {
+            self.apply_rf_config(&config)?;
+            self.radio.set_tx_power(config.tx_power as i32, 0)?;
+            self.radio.set_lora_pa_ramp()?;
+            self.radio.set_dio0_tx_done()?;
+            self.radio.transmit_payload(payload, data.len())
+        })()
+        .map_err(|_| DriverError::SendError)?;
+
+        let result =
+            with_timeout(P2P_TX_TIMEOUT, self.wait_for_irq(IRQ::IrqTxDoneMask.addr())).await;
+        self.radio.set_mode(RadioMode::Sleep).ok();
+        match result {
+            Ok(irq) => irq.map(|_| ()),
+            Err(_) => Err(DriverError::SendError),
+        }
+    }
+
+    async fn p2p_receive(
+        &mut self,
+        rx: &mut [u8],
+        timeout: Duration,
+    ) -> Result<RxPacket, DriverError> {
+        let config = self.rf_config.ok_or(DriverError::NotInitialized)?;
+        (|| {
+            self.apply_rf_config(&config)?;
+            self.radio.reset_payload_length()?;
+            self.radio.set_dio0_rx_done()?;
+            self.radio.set_mode(RadioMode::RxContinuous)
+        })()
+        .map_err(|_| DriverError::RecvError)?;
+
+        let result = with_timeout(timeout, self.wait_for_irq(IRQ::IrqRxDoneMask.addr())).await;
+        let packet = match result {
+            Ok(Ok(irq)) if irq & IRQ::IrqPayloadCrcErrorMask.addr() == 0 => {
+                let rssi = self.radio.get_packet_rssi().unwrap_or(0) as i16;
+                let snr = self.radio.get_packet_snr().unwrap_or(0.0) as i8;
+                match (self.radio.read_packet_size(), self.radio.read_packet()) {
+                    (Ok(len), Ok(_)) if len > rx.len() => Err(DriverError::RecvBufferTooSmall),
+                    (Ok(len), Ok(packet)) => {
+                        rx[..len].copy_from_slice(&packet[..len]);
+                        Ok(RxPacket { len, rssi, snr })
+                    }
+                    _ => Err(DriverError::RecvError),
+                }
+            }
+            Ok(Ok(_)) => Err(DriverError::RecvError),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(DriverError::RecvTimeout),
+        };
+        self.radio.set_mode(RadioMode::Sleep).ok();
+        packet
+    }
+}
+
+impl<SPI, CS, RESET, E> LoraP2p for Sx127xRadio<SPI, CS, RESET, E>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+    RESET: OutputPin,
+{
+    #[rustfmt::skip]
+    type SetRfConfigFuture<'m> where Self: 'm = impl Future<Output = Result<(), DriverError>> + 'm;
+    fn set_rf_config<'m>(&'m mut self, config: &'m RfConfig) -> Self::SetRfConfigFuture<'m> {
+        async move {
+            self.rf_config.replace(*config);
+            Ok(())
+        }
+    }
+
+    #[rustfmt::skip]
+    type TransmitFuture<'m> where Self: 'm = impl Future<Output = Result<(), DriverError>> + 'm;
+    fn transmit<'m>(&'m mut self, data: &'m [u8]) -> Self::TransmitFuture<'m> {
+        self.p2p_transmit(data)
+    }
+
+    #[rustfmt::skip]
+    type ReceiveFuture<'m> where Self: 'm = impl Future<Output = Result<RxPacket, DriverError>> + 'm;
+    fn receive<'m>(&'m mut self, rx: &'m mut [u8], timeout: Duration) -> Self::ReceiveFuture<'m> {
+        self.p2p_receive(rx, timeout)
+    }
+}
+
 impl<SPI, CS, RESET, E> Timings for Sx127xRadio<SPI, CS, RESET, E>
 where
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
@@ -218,10 +538,10 @@ where
     RESET: OutputPin,
 {
     fn get_rx_window_offset_ms(&self) -> i32 {
-        -500
+        -(self.rx_window_margin as i32)
     }
     fn get_rx_window_duration_ms(&self) -> u32 {
-        800
+        self.rx_window_margin + RX_WINDOW_LENGTH
     }
 }
 
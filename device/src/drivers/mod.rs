@@ -1,3 +1,5 @@
+#[cfg(any(feature = "wifi+esp8266", feature = "lora+rak811"))]
+pub mod at;
 pub mod led;
 pub mod lora;
 pub mod wifi;
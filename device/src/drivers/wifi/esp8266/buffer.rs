@@ -29,6 +29,17 @@ impl Buffer {
         }
     }
 
+    /// Remove up to `max` bytes from the start of the buffer, passing them to `f`.
+    pub fn take<F: FnOnce(&[u8])>(&mut self, max: usize, f: F) -> usize {
+        let len = core::cmp::min(max, self.pos);
+        f(&self.buffer[..len]);
+        if len < self.pos {
+            (&mut self.buffer[..]).moveslice(len..self.pos, 0);
+        }
+        self.pos -= len;
+        len
+    }
+
     pub fn parse(&mut self) -> Result<Response, ()> {
         if self.pos == 0 {
             return Ok(Response::None);
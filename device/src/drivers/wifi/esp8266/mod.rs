@@ -9,30 +9,51 @@ mod parser;
 mod protocol;
 mod socket_pool;
 
+pub use protocol::{Firmware, FirmwareFeatures, FirmwareInfo};
 use socket_pool::SocketPool;
 
+use crate::drivers::at::{
+    uart_read, uart_write, AtClient, AtDevice, AtEngine, AtError, AtModem, Route,
+};
 use crate::traits::{
     ip::{IpAddress, IpProtocol, SocketAddress},
-    tcp::{TcpError, TcpStack},
-    wifi::{Join, JoinError, WifiSupplicant},
+    tcp::{TcpError, TcpServer, TcpStack},
+    wifi::{
+        AccessPoint, AccessPointConfig, AccessPointError, Join, JoinError, JoinOptions, LinkInfo,
+        LinkInfoError, LinkState, ScanError, StaticIp, WifiAccessPoint, WifiSupplicant,
+    },
 };
-use atomic_polyfill::{AtomicBool, Ordering};
+use atomic_polyfill::{AtomicBool, AtomicU32, AtomicU8, Ordering};
 use buffer::Buffer;
-use core::{future::Future, pin::Pin};
+use core::{
+    cell::RefCell,
+    future::Future,
+    marker::PhantomData,
+    sync::atomic::{AtomicPtr, AtomicUsize},
+};
 use embassy::{
     io::{AsyncBufReadExt, AsyncWriteExt},
-    util::{
-        mpsc::{self, Channel, Receiver, Sender, WithThreadModeOnly},
-        Signal,
-    },
+    time::{Duration, Timer},
 };
 use embedded_hal::digital::v2::OutputPin;
-use futures::future::{select, Either};
-use futures::pin_mut;
-use protocol::{Command, ConnectionType, Response as AtResponse};
+use heapless::{consts, spsc::Queue};
+use protocol::{
+    Command, ConnectionType, ResolverAddresses, Response as AtResponse, WiFiMode,
+    WifiConnectionFailure,
+};
 
-pub const BUFFER_LEN: usize = 512;
-type DriverMutex = WithThreadModeOnly;
+/// Time allowed for further responses to a command, e.g. the result of a send.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest read the firmware serves with a single `AT+CIPRECVDATA`.
+const MAX_RECEIVE_LEN: usize = 2048;
+
+/// Number of links the firmware multiplexes over one connection.
+pub const MAX_SOCKETS: usize = 5;
+
+/// Depth of the notification queue, with room for a connect and a close of every link, as
+/// sockets are only freed by their close notification when closed by the peer.
+const NOTIFICATIONS: usize = 2 * MAX_SOCKETS;
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -48,71 +69,247 @@ pub enum DriverError {
     OperationNotSupported,
 }
 
-type CommandBuffer = (usize, [u8; 256]);
+pub struct LinkStatus {
+    state: AtomicU8,
+}
+
+impl LinkStatus {
+    pub fn new() -> Self {
+        Self {
+            state: AtomicU8::new(LinkState::Down as u8),
+        }
+    }
 
-pub struct Initialized {
-    signal: Signal<Result<(), DriverError>>,
-    initialized: AtomicBool,
+    fn set(&self, state: LinkState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    fn get(&self) -> LinkState {
+        match self.state.load(Ordering::SeqCst) {
+            s if s == LinkState::Up as u8 => LinkState::Up,
+            s if s == LinkState::Associated as u8 => LinkState::Associated,
+            _ => LinkState::Down,
+        }
+    }
 }
 
-impl Initialized {
+/// Firmware detected by the modem during initialization, shared with the controller.
+pub struct FirmwareStatus {
+    detected: AtomicBool,
+    version: AtomicU32,
+}
+
+impl FirmwareStatus {
     pub fn new() -> Self {
         Self {
-            signal: Signal::new(),
-            initialized: AtomicBool::new(false),
+            detected: AtomicBool::new(false),
+            version: AtomicU32::new(0),
         }
     }
 
-    async fn wait(&self) -> Result<(), DriverError> {
-        if self.initialized.swap(true, Ordering::SeqCst) == false {
-            self.signal.wait().await?;
+    fn set(&self, version: FirmwareInfo) {
+        let packed =
+            u32::from_be_bytes([version.major, version.minor, version.patch, version.build]);
+        self.version.store(packed, Ordering::SeqCst);
+        self.detected.store(true, Ordering::SeqCst);
+    }
+
+    fn get(&self) -> Option<Firmware> {
+        if !self.detected.load(Ordering::SeqCst) {
+            return None;
+        }
+        let [major, minor, patch, build] = self.version.load(Ordering::SeqCst).to_be_bytes();
+        Some(Firmware::new(FirmwareInfo {
+            major,
+            minor,
+            patch,
+            build,
+        }))
+    }
+}
+
+/// Destination of data read from a socket, shared with the modem.
+///
+/// The controller lends the caller's buffer for the duration of a read, and the modem
+/// copies received data into it straight from the UART.
+///
+/// The controller and the modem must run on the same thread-mode executor, never from an
+/// interrupt. The lent buffer is then only touched by one of them at a time, and only
+/// between `lend` and the drop of the returned lease.
+pub struct ReceiveBuffer {
+    target: AtomicPtr<u8>,
+    capacity: AtomicUsize,
+    written: AtomicUsize,
+    datagram: RefCell<Datagram>,
+}
+
+/// The last datagram received on a UDP link, kept until read. UDP data is pushed by the
+/// firmware as it arrives, so a datagram not read before the next one arrives is lost.
+struct Datagram {
+    /// Link of the datagram, once received completely.
+    link_id: Option<u8>,
+    len: usize,
+    data: [u8; MAX_RECEIVE_LEN],
+}
+
+impl ReceiveBuffer {
+    pub fn new() -> Self {
+        Self {
+            target: AtomicPtr::new(core::ptr::null_mut()),
+            capacity: AtomicUsize::new(0),
+            written: AtomicUsize::new(0),
+            datagram: RefCell::new(Datagram {
+                link_id: None,
+                len: 0,
+                data: [0; MAX_RECEIVE_LEN],
+            }),
+        }
+    }
+
+    /// Start receiving a datagram, replacing any datagram not read yet.
+    fn begin_datagram(&self) {
+        let mut datagram = self.datagram.borrow_mut();
+        if let Some(link_id) = datagram.link_id.take() {
+            warn!("Discarding unread datagram of link {}", link_id);
+        }
+        datagram.len = 0;
+    }
+
+    /// Append `data` to the datagram being received, truncating it to `MAX_RECEIVE_LEN`.
+    fn write_datagram(&self, data: &[u8]) {
+        let mut datagram = self.datagram.borrow_mut();
+        let start = datagram.len;
+        let len = core::cmp::min(data.len(), MAX_RECEIVE_LEN - start);
+        datagram.data[start..start + len].copy_from_slice(&data[..len]);
+        datagram.len += len;
+    }
+
+    /// Make the datagram received available to reads of `link_id`.
+    fn end_datagram(&self, link_id: u8) {
+        self.datagram.borrow_mut().link_id.replace(link_id);
+    }
+
+    /// Copy the datagram received on `link_id` into `buf`, if any. Like any UDP read, the
+    /// datagram is truncated to the length of `buf`.
+    fn read_datagram(&self, link_id: u8, buf: &mut [u8]) -> Option<usize> {
+        let mut datagram = self.datagram.borrow_mut();
+        if datagram.link_id != Some(link_id) {
+            return None;
+        }
+        datagram.link_id = None;
+        let len = core::cmp::min(datagram.len, buf.len());
+        buf[..len].copy_from_slice(&datagram.data[..len]);
+        Some(len)
+    }
+
+    /// Lend `buf` to the modem until the returned lease is dropped.
+    ///
+    /// The lease borrows `buf` mutably, so the caller cannot touch it while the modem may
+    /// write to it. Dropping the lease, including when a read is cancelled, withdraws it.
+    fn lend<'b>(&'b self, buf: &'b mut [u8]) -> ReceiveLease<'b> {
+        self.written.store(0, Ordering::SeqCst);
+        self.capacity.store(buf.len(), Ordering::SeqCst);
+        self.target.store(buf.as_mut_ptr(), Ordering::SeqCst);
+        ReceiveLease {
+            receive: self,
+            _buf: PhantomData,
         }
-        Ok(())
     }
 
-    pub fn signal(&self, result: Result<(), DriverError>) {
-        self.signal.signal(result);
+    /// Copy `data` into the lent buffer. Data is discarded if no buffer is lent or
+    /// it is full.
+    fn write(&self, data: &[u8]) {
+        let target = self.target.load(Ordering::SeqCst);
+        if target.is_null() {
+            warn!("Discarding {} bytes of unexpected socket data", data.len());
+            return;
+        }
+        let written = self.written.load(Ordering::SeqCst);
+        let len = core::cmp::min(data.len(), self.capacity.load(Ordering::SeqCst) - written);
+        // SAFETY: a non-null target is only set by `lend` and cleared when its lease drops, so
+        // it points to a live buffer of `capacity` bytes, mutably borrowed by the lease.
+        // `written + len` never exceeds `capacity`, keeping the copy in bounds. The modem and
+        // controller share one thread-mode executor, so the lease holder cannot access the
+        // buffer while this copy runs.
+        unsafe {
+            core::slice::from_raw_parts_mut(target.add(written), len).copy_from_slice(&data[..len]);
+        }
+        self.written.store(written + len, Ordering::SeqCst);
     }
 }
 
-pub struct Esp8266Controller<'a> {
-    initialized: &'a Initialized,
-    socket_pool: SocketPool,
-    command_producer: Sender<'a, DriverMutex, CommandBuffer, 2>,
-    response_consumer: Receiver<'a, DriverMutex, AtResponse, 2>,
-    notification_consumer: Receiver<'a, DriverMutex, AtResponse, 2>,
+/// A buffer lent to the modem. The buffer is withdrawn when the lease is dropped, which must
+/// happen before the buffer is used again; the lease is never leaked with `mem::forget`.
+struct ReceiveLease<'b> {
+    receive: &'b ReceiveBuffer,
+    _buf: PhantomData<&'b mut [u8]>,
 }
 
-pub struct Esp8266Modem<'a, UART, ENABLE, RESET>
+impl<'b> ReceiveLease<'b> {
+    fn written(&self) -> usize {
+        self.receive.written.load(Ordering::SeqCst)
+    }
+}
+
+impl<'b> Drop for ReceiveLease<'b> {
+    fn drop(&mut self) {
+        self.receive
+            .target
+            .store(core::ptr::null_mut(), Ordering::SeqCst);
+        self.receive.capacity.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Controller for the modem, with `SOCKETS` sockets (at most `MAX_SOCKETS`).
+#[rustfmt::skip]
+pub struct Esp8266Controller<'a, const SOCKETS: usize = 5> {
+    at: AtClient<'a, AtResponse, (), 2, NOTIFICATIONS>,
+    link: &'a LinkStatus,
+    firmware: &'a FirmwareStatus,
+    receive: &'a ReceiveBuffer,
+    socket_pool: SocketPool<SOCKETS>,
+    accepted: Queue<u8, consts::U4>,
+    /// Links connected with UDP, whose data is received as datagrams.
+    udp: [bool; MAX_SOCKETS],
+}
+
+/// Modem driving the UART, using the AT command engine.
+pub type Esp8266Modem<'a, UART, ENABLE, RESET> =
+    AtModem<'a, UART, Esp8266Device<'a, ENABLE, RESET>, 2, NOTIFICATIONS>;
+
+/// ESP8266 specific parsing, routing and initialization for the AT command engine.
+pub struct Esp8266Device<'a, ENABLE, RESET>
 where
-    UART: AsyncBufReadExt + AsyncWriteExt + 'static,
     ENABLE: OutputPin + 'static,
     RESET: OutputPin + 'static,
 {
-    initialized: &'a Initialized,
-    uart: UART,
+    link: &'a LinkStatus,
+    firmware: &'a FirmwareStatus,
+    receive: &'a ReceiveBuffer,
     enable: ENABLE,
     reset: RESET,
     parse_buffer: Buffer,
-    command_consumer: Receiver<'a, DriverMutex, CommandBuffer, 2>,
-    response_producer: Sender<'a, DriverMutex, AtResponse, 2>,
-    notification_producer: Sender<'a, DriverMutex, AtResponse, 2>,
+    /// Bytes of socket data still to be streamed from the UART.
+    receiving: usize,
+    /// Link of the datagram being streamed from the UART, if the data is not that of a read.
+    datagram: Option<u8>,
 }
 
-pub struct Esp8266Driver {
-    initialized: Initialized,
-    command_channel: Channel<DriverMutex, CommandBuffer, 2>,
-    response_channel: Channel<DriverMutex, AtResponse, 2>,
-    notification_channel: Channel<DriverMutex, AtResponse, 2>,
+#[rustfmt::skip]
+pub struct Esp8266Driver<const SOCKETS: usize = 5> {
+    engine: AtEngine<AtResponse, (), 2, NOTIFICATIONS>,
+    link: LinkStatus,
+    firmware: FirmwareStatus,
+    receive: ReceiveBuffer,
 }
 
-impl Esp8266Driver {
+impl<const SOCKETS: usize> Esp8266Driver<SOCKETS> {
     pub fn new() -> Self {
         Self {
-            initialized: Initialized::new(),
-            command_channel: Channel::new(),
-            response_channel: Channel::new(),
-            notification_channel: Channel::new(),
+            engine: AtEngine::new(),
+            link: LinkStatus::new(),
+            firmware: FirmwareStatus::new(),
+            receive: ReceiveBuffer::new(),
         }
     }
 
@@ -121,51 +318,56 @@ impl Esp8266Driver {
         uart: UART,
         enable: ENABLE,
         reset: RESET,
-    ) -> (Esp8266Controller<'a>, Esp8266Modem<'a, UART, ENABLE, RESET>)
+    ) -> (
+        Esp8266Controller<'a, SOCKETS>,
+        Esp8266Modem<'a, UART, ENABLE, RESET>,
+    )
     where
         UART: AsyncBufReadExt + AsyncWriteExt + 'static,
         ENABLE: OutputPin + 'static,
         RESET: OutputPin + 'static,
     {
-        let (cp, cc) = mpsc::split(&mut self.command_channel);
-        let (rp, rc) = mpsc::split(&mut self.response_channel);
-        let (np, nc) = mpsc::split(&mut self.notification_channel);
-
-        let modem = Esp8266Modem::new(&self.initialized, uart, enable, reset, cc, rp, np);
-        let controller = Esp8266Controller::new(&self.initialized, cp, rc, nc);
+        let device = Esp8266Device::new(&self.link, &self.firmware, &self.receive, enable, reset);
+        let (client, modem) = self.engine.split(uart, device);
+        let controller = Esp8266Controller::new(client, &self.link, &self.firmware, &self.receive);
 
         (controller, modem)
     }
+
+    /// The firmware detected on the modem, once initialized.
+    pub fn firmware(&self) -> Option<Firmware> {
+        self.firmware.get()
+    }
 }
 
-impl<'a, UART, ENABLE, RESET> Esp8266Modem<'a, UART, ENABLE, RESET>
+impl<'a, ENABLE, RESET> Esp8266Device<'a, ENABLE, RESET>
 where
-    UART: AsyncBufReadExt + AsyncWriteExt + 'static,
     ENABLE: OutputPin + 'static,
     RESET: OutputPin + 'static,
 {
     pub fn new(
-        initialized: &'a Initialized,
-        uart: UART,
+        link: &'a LinkStatus,
+        firmware: &'a FirmwareStatus,
+        receive: &'a ReceiveBuffer,
         enable: ENABLE,
         reset: RESET,
-        command_consumer: Receiver<'a, DriverMutex, CommandBuffer, 2>,
-        response_producer: Sender<'a, DriverMutex, AtResponse, 2>,
-        notification_producer: Sender<'a, DriverMutex, AtResponse, 2>,
     ) -> Self {
         Self {
-            initialized,
-            uart,
+            link,
+            firmware,
+            receive,
             enable,
             reset,
             parse_buffer: Buffer::new(),
-            command_consumer,
-            response_producer,
-            notification_producer,
+            receiving: 0,
+            datagram: None,
         }
     }
 
-    async fn initialize(&mut self) -> Result<(), DriverError> {
+    async fn setup<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
         let mut buffer: [u8; 1024] = [0; 1024];
         let mut pos = 0;
 
@@ -173,25 +375,39 @@ where
 
         info!("Initializing ESP8266");
 
+        self.parse_buffer = Buffer::new();
+        self.receiving = 0;
+        self.datagram = None;
+        self.link.set(LinkState::Down);
+
+        self.reset.set_low().ok().unwrap();
+        Timer::after(Duration::from_millis(10)).await;
         self.enable.set_high().ok().unwrap();
         self.reset.set_high().ok().unwrap();
 
         let mut rx_buf = [0; 1];
         loop {
-            let result = uart_read(&mut self.uart, &mut rx_buf[..]).await;
+            let result = uart_read(uart, &mut rx_buf[..]).await;
             match result {
                 Ok(c) => {
                     if c > 0 {
                         buffer[pos] = rx_buf[0];
                         pos += 1;
                         if pos >= READY.len() && buffer[pos - READY.len()..pos] == READY {
-                            self.disable_echo().await?;
+                            self.disable_echo(uart).await?;
                             trace!("Echo disabled");
-                            self.enable_mux().await?;
+                            let firmware = self.query_firmware(uart).await?;
+                            self.firmware.set(firmware.version);
+                            info!("ESP8266 firmware {:?}", firmware.version);
+                            if !firmware.is_supported() {
+                                error!("Unsupported ESP8266 firmware: {:?}", firmware.features);
+                                return Err(DriverError::OperationNotSupported);
+                            }
+                            self.enable_mux(uart).await?;
                             trace!("Mux enabled");
-                            self.set_recv_mode().await?;
+                            self.set_recv_mode(uart).await?;
                             trace!("Recv mode configured");
-                            self.set_mode().await?;
+                            self.set_mode(uart).await?;
                             info!("ESP8266 initialized");
                             return Ok(());
                         }
@@ -205,52 +421,98 @@ where
         }
     }
 
-    async fn disable_echo(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"ATE0\r\n")
+    async fn disable_echo<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
+        uart_write(uart, b"ATE0\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
-            .wait_for_ok()
+            .wait_for_ok(uart)
             .await
             .map_err(|_| DriverError::UnableToInitialize)?)
     }
 
-    async fn enable_mux(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CIPMUX=1\r\n")
+    async fn query_firmware<UART>(&mut self, uart: &mut UART) -> Result<Firmware, DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
+        uart_write(uart, b"AT+GMR\r\n")
+            .await
+            .map_err(|_| DriverError::UnableToInitialize)?;
+
+        let mut buf: [u8; 256] = [0; 256];
+        let mut pos = 0;
+        while pos < buf.len() {
+            uart_read(uart, &mut buf[pos..pos + 1])
+                .await
+                .map_err(|_| DriverError::ReadError)?;
+            pos += 1;
+            if buf[0..pos].ends_with(b"OK\r\n") {
+                break;
+            } else if buf[0..pos].ends_with(b"ERROR\r\n") {
+                return Err(DriverError::UnableToInitialize);
+            }
+        }
+
+        match parser::firmware_info(&buf[0..pos]) {
+            Ok((_, AtResponse::FirmwareInfo(version))) => Ok(Firmware::new(version)),
+            _ => {
+                error!("Unable to detect ESP8266 firmware version");
+                Err(DriverError::UnableToInitialize)
+            }
+        }
+    }
+
+    async fn enable_mux<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
+        uart_write(uart, b"AT+CIPMUX=1\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
-            .wait_for_ok()
+            .wait_for_ok(uart)
             .await
             .map_err(|_| DriverError::UnableToInitialize)?)
     }
 
-    async fn set_recv_mode(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CIPRECVMODE=1\r\n")
+    async fn set_recv_mode<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
+        uart_write(uart, b"AT+CIPRECVMODE=1\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
-            .wait_for_ok()
+            .wait_for_ok(uart)
             .await
             .map_err(|_| DriverError::UnableToInitialize)?)
     }
 
-    async fn set_mode(&mut self) -> Result<(), DriverError> {
-        uart_write(&mut self.uart, b"AT+CWMODE_CUR=1\r\n")
+    async fn set_mode<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
+        uart_write(uart, b"AT+CWMODE_CUR=1\r\n")
             .await
             .map_err(|_| DriverError::UnableToInitialize)?;
         Ok(self
-            .wait_for_ok()
+            .wait_for_ok(uart)
             .await
             .map_err(|_| DriverError::UnableToInitialize)?)
     }
 
-    async fn wait_for_ok(&mut self) -> Result<(), DriverError> {
+    async fn wait_for_ok<UART>(&mut self, uart: &mut UART) -> Result<(), DriverError>
+    where
+        UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    {
         let mut buf: [u8; 64] = [0; 64];
         let mut pos = 0;
 
         loop {
-            uart_read(&mut self.uart, &mut buf[pos..pos + 1])
+            uart_read(uart, &mut buf[pos..pos + 1])
                 .await
                 .map_err(|_| DriverError::ReadError)?;
             pos += 1;
@@ -261,120 +523,154 @@ where
             }
         }
     }
+}
 
-    /// Run the processing loop until an error is encountered
-    pub async fn run(&mut self) -> ! {
-        // Result<(), DriverError> where Self: 'a {
-        let result = self.initialize().await;
-        self.initialized.signal(result);
-        loop {
-            let mut buf = [0; 1];
-            let (cmd, input) = {
-                let command_fut = self.command_consumer.recv();
-                let uart_fut = uart_read(&mut self.uart, &mut buf[..]);
-                pin_mut!(uart_fut);
-
-                match select(command_fut, uart_fut).await {
-                    Either::Left((s, _)) => (Some(s), None),
-                    Either::Right((r, _)) => (None, Some(r)),
-                }
-            };
-            // We got command to write, write it
-            if let Some(Some((len, buf))) = cmd {
-                if let Err(e) = uart_write(&mut self.uart, &buf[0..len]).await {
-                    error!("Error writing command to uart: {:?}", e);
-                }
+impl<'a, UART, ENABLE, RESET> AtDevice<UART> for Esp8266Device<'a, ENABLE, RESET>
+where
+    UART: AsyncBufReadExt + AsyncWriteExt + 'static,
+    ENABLE: OutputPin + 'static,
+    RESET: OutputPin + 'static,
+{
+    type Response = AtResponse;
+    type Ready = ();
+
+    #[rustfmt::skip]
+    type InitializeFuture<'m> where Self: 'm = impl Future<Output = Result<(), AtError>> + 'm;
+    fn initialize<'m>(&'m mut self, uart: &'m mut UART) -> Self::InitializeFuture<'m> {
+        async move { self.setup(uart).await.map_err(|_| AtError::NotInitialized) }
+    }
+
+    fn digest(&mut self, octet: u8) -> Option<AtResponse> {
+        if self.parse_buffer.write(octet).is_err() {
+            warn!("Parse buffer full, discarding input");
+            self.parse_buffer = Buffer::new();
+            return None;
+        }
+        match self.parse_buffer.parse() {
+            Ok(AtResponse::None) | Err(_) => None,
+            Ok(response) => {
+                trace!("--> {:?}", response);
+                Some(response)
             }
+        }
+    }
 
-            // We got input, digest it
-            if let Some(input) = input {
-                match input {
-                    Ok(len) => {
-                        for b in &buf[..len] {
-                            self.parse_buffer.write(*b).unwrap();
-                        }
-                        if let Err(e) = self.digest().await {
-                            error!("Error digesting modem input: {:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error reading from uart: {:?}", e);
-                    }
+    fn route(&mut self, response: AtResponse) -> Route<AtResponse> {
+        match response {
+            AtResponse::None => Route::Handled,
+            AtResponse::Ok
+            | AtResponse::Error
+            | AtResponse::FirmwareInfo(..)
+            | AtResponse::Connect(..)
+            | AtResponse::ReadyForData
+            | AtResponse::ReceivedDataToSend(..)
+            | AtResponse::SendOk
+            | AtResponse::SendFail
+            | AtResponse::WifiConnectionFailure(..)
+            | AtResponse::IpAddress(..)
+            | AtResponse::Resolvers(..)
+            | AtResponse::DnsFail
+            | AtResponse::UnlinkFail
+            | AtResponse::IpAddresses(..)
+            | AtResponse::AccessPoint(..)
+            | AtResponse::CurrentAccessPoint(..)
+            | AtResponse::MacAddress(..) => Route::Response(response),
+            AtResponse::Closed(..)
+            | AtResponse::Accepted(..)
+            | AtResponse::DataAvailable { .. } => Route::Notification(response),
+            AtResponse::ReceivingData(len) => {
+                let receive = self.receive;
+                let buffered = self.parse_buffer.take(len, |data| receive.write(data));
+                self.receiving = len - buffered;
+                Route::Handled
+            }
+            AtResponse::ReceivingDatagram { link_id, len } => {
+                let receive = self.receive;
+                receive.begin_datagram();
+                let buffered = self
+                    .parse_buffer
+                    .take(len, |data| receive.write_datagram(data));
+                self.receiving = len - buffered;
+                if self.receiving == 0 {
+                    receive.end_datagram(link_id as u8);
+                } else {
+                    self.datagram.replace(link_id as u8);
                 }
+                Route::Handled
+            }
+            AtResponse::WifiConnected => {
+                debug!("wifi connected");
+                self.link.set(LinkState::Associated);
+                Route::Handled
+            }
+            AtResponse::WifiDisconnect => {
+                debug!("wifi disconnect");
+                self.link.set(LinkState::Down);
+                Route::Handled
+            }
+            AtResponse::GotIp => {
+                debug!("wifi got ip");
+                self.link.set(LinkState::Up);
+                Route::Handled
             }
         }
     }
 
-    async fn digest(&mut self) -> Result<(), DriverError> {
-        let result = self.parse_buffer.parse();
+    // Socket data is copied straight from the UART, bypassing the parser
+    fn raw_pending(&self) -> usize {
+        self.receiving
+    }
 
-        if let Ok(response) = result {
-            if !matches!(response, AtResponse::None) {
-                trace!("--> {:?}", response);
-            }
-            match response {
-                AtResponse::None => {}
-                AtResponse::Ok
-                | AtResponse::Error
-                | AtResponse::FirmwareInfo(..)
-                | AtResponse::Connect(..)
-                | AtResponse::ReadyForData
-                | AtResponse::ReceivedDataToSend(..)
-                | AtResponse::DataReceived(..)
-                | AtResponse::SendOk
-                | AtResponse::SendFail
-                | AtResponse::WifiConnectionFailure(..)
-                | AtResponse::IpAddress(..)
-                | AtResponse::Resolvers(..)
-                | AtResponse::DnsFail
-                | AtResponse::UnlinkFail
-                | AtResponse::IpAddresses(..) => {
-                    self.response_producer
-                        .send(response)
-                        .await
-                        .map_err(|_| DriverError::WriteError)?;
-                }
-                AtResponse::Closed(..) | AtResponse::DataAvailable { .. } => {
-                    self.notification_producer
-                        .send(response)
-                        .await
-                        .map_err(|_| DriverError::WriteError)?;
-                }
-                AtResponse::WifiConnected => {
-                    debug!("wifi connected");
-                }
-                AtResponse::WifiDisconnect => {
-                    debug!("wifi disconnect");
-                }
-                AtResponse::GotIp => {
-                    debug!("wifi got ip");
+    fn raw(&mut self, data: &[u8]) -> usize {
+        self.receiving -= data.len();
+        match self.datagram {
+            Some(link_id) => {
+                self.receive.write_datagram(data);
+                if self.receiving == 0 {
+                    self.receive.end_datagram(link_id);
+                    self.datagram = None;
                 }
             }
+            None => self.receive.write(data),
         }
-        Ok(())
+        data.len()
+    }
+
+    fn abort_raw(&mut self) {
+        self.receiving = 0;
+        self.datagram = None;
     }
 }
 
-impl<'a> Esp8266Controller<'a> {
+impl<'a, const SOCKETS: usize> Esp8266Controller<'a, SOCKETS> {
+    /// Fails to compile if `SOCKETS` exceeds `MAX_SOCKETS`.
+    const SOCKETS_CHECK: usize = MAX_SOCKETS - SOCKETS;
+
     pub fn new(
-        initialized: &'a Initialized,
-        command_producer: Sender<'a, DriverMutex, CommandBuffer, 2>,
-        response_consumer: Receiver<'a, DriverMutex, AtResponse, 2>,
-        notification_consumer: Receiver<'a, DriverMutex, AtResponse, 2>,
+        at: AtClient<'a, AtResponse, (), 2, NOTIFICATIONS>,
+        link: &'a LinkStatus,
+        firmware: &'a FirmwareStatus,
+        receive: &'a ReceiveBuffer,
     ) -> Self {
+        let _ = Self::SOCKETS_CHECK;
         Self {
-            initialized,
+            at,
+            link,
+            firmware,
+            receive,
             socket_pool: SocketPool::new(),
-            command_producer,
-            response_consumer,
-            notification_consumer,
+            accepted: Queue::new(),
+            udp: [false; MAX_SOCKETS],
         }
     }
 
+    /// The firmware detected on the modem, waiting for it to be initialized.
+    pub async fn firmware(&mut self) -> Result<Firmware, DriverError> {
+        let _ = self.at.ready().await;
+        self.firmware.get().ok_or(DriverError::UnableToInitialize)
+    }
+
     async fn send<'c>(&mut self, command: Command<'c>) -> Result<AtResponse, DriverError> {
-        trace!("Sending command");
-        self.initialized.wait().await?;
-        trace!("Confirmed initialized");
         let mut bytes = command.as_bytes();
         trace!(
             "writing command {}",
@@ -382,14 +678,29 @@ impl<'a> Esp8266Controller<'a> {
         );
 
         bytes.push_str("\r\n").unwrap();
-        let bs = bytes.as_bytes();
-        let mut data = [0; 256];
-        data[0..bs.len()].copy_from_slice(&bs[0..bs.len()]);
-        self.command_producer
-            .send((bs.len(), data))
+        self.at
+            .request(bytes.as_bytes(), command.timeout())
             .await
-            .map_err(|_| DriverError::WriteError)?;
-        Ok(self.response_consumer.recv().await.unwrap())
+            .map_err(|e| self.driver_error(e))
+    }
+
+    /// Wait for a further response to the command sent last.
+    async fn response(&mut self) -> Result<AtResponse, DriverError> {
+        self.at
+            .response(RESPONSE_TIMEOUT)
+            .await
+            .map_err(|e| self.driver_error(e))
+    }
+
+    fn driver_error(&self, e: AtError) -> DriverError {
+        match e {
+            AtError::Timeout => DriverError::Timeout,
+            AtError::NotInitialized => match self.firmware.get() {
+                Some(firmware) if !firmware.is_supported() => DriverError::OperationNotSupported,
+                _ => DriverError::UnableToInitialize,
+            },
+            AtError::CommandTooLong | AtError::Io => DriverError::WriteError,
+        }
     }
 
     /*
@@ -402,13 +713,30 @@ impl<'a> Esp8266Controller<'a> {
     }
     */
 
-    async fn join_wep(&mut self, ssid: &str, password: &str) -> Result<IpAddress, JoinError> {
-        let command = Command::JoinAp { ssid, password };
+    async fn join_wpa(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        options: JoinOptions,
+    ) -> Result<IpAddress, JoinError> {
+        self.configure_ip(options.static_ip).await?;
+
+        // The firmware probes for the SSID when joining, so hidden networks need no extra step
+        let command = Command::JoinAp {
+            ssid,
+            password,
+            bssid: options.bssid,
+        };
         match self.send(command).await {
             Ok(AtResponse::Ok) => self.get_ip_address().await.map_err(|_| JoinError::Unknown),
             Ok(AtResponse::WifiConnectionFailure(reason)) => {
                 warn!("Error connecting to wifi: {:?}", reason);
-                Err(JoinError::Unknown)
+                Err(match reason {
+                    WifiConnectionFailure::Timeout => JoinError::Timeout,
+                    WifiConnectionFailure::WrongPassword => JoinError::WrongPassword,
+                    WifiConnectionFailure::CannotFindTargetAp => JoinError::NetworkNotFound,
+                    WifiConnectionFailure::ConnectionFailed => JoinError::ConnectionFailed,
+                })
             }
             Ok(r) => {
                 error!("Unexpected response: {:?}", r);
@@ -421,6 +749,46 @@ impl<'a> Esp8266Controller<'a> {
         }
     }
 
+    async fn configure_ip(&mut self, static_ip: Option<StaticIp>) -> Result<(), JoinError> {
+        match static_ip {
+            Some(config) => {
+                let command = Command::SetStaticIp {
+                    address: config.address,
+                    gateway: config.gateway,
+                    netmask: config.netmask,
+                };
+                if !matches!(self.send(command).await, Ok(AtResponse::Ok)) {
+                    return Err(JoinError::Unknown);
+                }
+                if let Some(IpAddress::V4(dns)) = config.dns {
+                    if !self.features().dns {
+                        return Err(JoinError::NotSupported);
+                    }
+                    let command = Command::SetDnsResolvers(ResolverAddresses {
+                        resolver1: dns,
+                        resolver2: None,
+                    });
+                    if !matches!(self.send(command).await, Ok(AtResponse::Ok)) {
+                        return Err(JoinError::Unknown);
+                    }
+                }
+            }
+            None => {
+                if !matches!(self.send(Command::EnableDhcp).await, Ok(AtResponse::Ok)) {
+                    return Err(JoinError::Unknown);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn features(&self) -> FirmwareFeatures {
+        self.firmware
+            .get()
+            .map(|firmware| firmware.features)
+            .unwrap_or_default()
+    }
+
     async fn get_ip_address(&mut self) -> Result<IpAddress, ()> {
         let command = Command::QueryIpAddress;
 
@@ -431,42 +799,233 @@ impl<'a> Esp8266Controller<'a> {
         Err(())
     }
 
-    fn process_notifications(&mut self) {
-        while let Ok(response) = self.notification_consumer.try_recv() {
+    async fn query_link_info(&mut self) -> Result<LinkInfo, LinkInfoError> {
+        let ap = match self.send(Command::QueryCurrentAccessPoint).await {
+            Ok(AtResponse::CurrentAccessPoint(Some(ap))) => ap,
+            Ok(AtResponse::CurrentAccessPoint(None)) => return Err(LinkInfoError::NotConnected),
+            _ => return Err(LinkInfoError::Unknown),
+        };
+        let addresses = match self.send(Command::QueryIpAddress).await {
+            Ok(AtResponse::IpAddresses(addresses)) => addresses,
+            _ => return Err(LinkInfoError::Unknown),
+        };
+        let mac = match self.send(Command::QueryMacAddress).await {
+            Ok(AtResponse::MacAddress(mac)) => mac,
+            _ => return Err(LinkInfoError::Unknown),
+        };
+        let dns = if !self.features().dns {
+            [None, None]
+        } else {
+            match self.send(Command::QueryDnsResolvers).await {
+                Ok(AtResponse::Resolvers(resolvers)) => [
+                    Some(IpAddress::V4(resolvers.resolver1)),
+                    resolvers.resolver2.map(IpAddress::V4),
+                ],
+                _ => [None, None],
+            }
+        };
+        Ok(LinkInfo {
+            bssid: ap.bssid,
+            rssi: ap.rssi,
+            channel: ap.channel,
+            mac,
+            ip: IpAddress::V4(addresses.ip),
+            gateway: IpAddress::V4(addresses.gateway),
+            netmask: IpAddress::V4(addresses.netmask),
+            dns,
+        })
+    }
+
+    async fn list_access_points(
+        &mut self,
+        results: &mut [AccessPoint],
+    ) -> Result<usize, ScanError> {
+        match self.send(Command::SetScanOptions).await {
+            Ok(AtResponse::Ok) => {}
+            Ok(r) => {
+                warn!("Unexpected response: {:?}", r);
+                return Err(ScanError::Unknown);
+            }
+            Err(_) => return Err(ScanError::Unknown),
+        }
+
+        let mut response = self
+            .send(Command::ListAccessPoints)
+            .await
+            .map_err(|_| ScanError::Unknown)?;
+        let mut found = 0;
+        loop {
             match response {
-                AtResponse::DataAvailable { .. } => {
-                    //  shared.socket_pool // [link_id].available += len;
+                AtResponse::AccessPoint(ap) => {
+                    if let Some(slot) = results.get_mut(found) {
+                        *slot = ap;
+                        found += 1;
+                    }
                 }
-                AtResponse::Connect(_) => {}
-                AtResponse::Closed(link_id) => {
-                    self.socket_pool.close(link_id as u8);
+                AtResponse::Ok => return Ok(found),
+                AtResponse::Error => return Err(ScanError::NotSupported),
+                r => {
+                    warn!("Unexpected response: {:?}", r);
+                    return Err(ScanError::Unknown);
+                }
+            }
+            response = self.response().await.map_err(|_| ScanError::Unknown)?;
+        }
+    }
+
+    async fn start_ap(
+        &mut self,
+        config: AccessPointConfig<'_>,
+    ) -> Result<IpAddress, AccessPointError> {
+        if config.ssid.is_empty() || config.ssid.len() > 32 {
+            return Err(AccessPointError::InvalidSsid);
+        }
+        if !config.password.is_empty() && !(8..=64).contains(&config.password.len()) {
+            return Err(AccessPointError::InvalidPassword);
+        }
+        if !(1..=13).contains(&config.channel) {
+            return Err(AccessPointError::InvalidChannel);
+        }
+
+        // Keep the station running, so networks can still be scanned and joined
+        let command = Command::SetMode(WiFiMode::SoftAccessPointAndStation);
+        if !matches!(self.send(command).await, Ok(AtResponse::Ok)) {
+            return Err(AccessPointError::Unknown);
+        }
+
+        let command = Command::SetAccessPoint {
+            ssid: config.ssid,
+            password: config.password,
+            channel: config.channel,
+        };
+        match self.send(command).await {
+            Ok(AtResponse::Ok) => {}
+            Ok(r) => {
+                warn!("Unexpected response: {:?}", r);
+                return Err(AccessPointError::Unknown);
+            }
+            Err(_) => return Err(AccessPointError::Unknown),
+        }
+
+        match self.send(Command::QueryAccessPointIpAddress).await {
+            Ok(AtResponse::IpAddresses(addresses)) => Ok(IpAddress::V4(addresses.ip)),
+            _ => Err(AccessPointError::Unknown),
+        }
+    }
+
+    async fn accept_link(&mut self) -> Option<u8> {
+        self.process_notifications();
+        while let Some(link_id) = self.accepted.dequeue() {
+            if self.socket_pool.accept(link_id) {
+                self.udp[link_id as usize] = false;
+                return Some(link_id);
+            }
+            warn!("No socket available for link {}, closing", link_id);
+            let _ = self.send(Command::CloseConnection(link_id as usize)).await;
+        }
+        None
+    }
+
+    fn process_notifications(&mut self) {
+        while let Some(response) = self.at.try_notification() {
+            self.handle_notification(response);
+        }
+    }
+
+    fn handle_notification(&mut self, response: AtResponse) {
+        match response {
+            AtResponse::DataAvailable { .. } => {
+                //  shared.socket_pool // [link_id].available += len;
+            }
+            AtResponse::Connect(_) => {}
+            AtResponse::Accepted(link_id) => {
+                if self.accepted.enqueue(link_id as u8).is_err() {
+                    warn!("Too many pending connections, dropping link {}", link_id);
                 }
-                _ => { /* ignore */ }
             }
+            AtResponse::Closed(link_id) => {
+                self.socket_pool.close(link_id as u8);
+            }
+            _ => { /* ignore */ }
         }
     }
 }
 
-impl<'a> WifiSupplicant for Esp8266Controller<'a> {
+impl<'a, const SOCKETS: usize> WifiSupplicant for Esp8266Controller<'a, SOCKETS> {
     #[rustfmt::skip]
     type JoinFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, JoinError>> + 'm;
-    fn join<'m>(&'m mut self, join_info: Join<'m>) -> Self::JoinFuture<'m> {
+    fn join_with_options<'m>(
+        &'m mut self,
+        join_info: Join<'m>,
+        options: JoinOptions,
+    ) -> Self::JoinFuture<'m> {
         async move {
             match join_info {
                 Join::Open => Err(JoinError::Unknown),
-                Join::Wpa { ssid, password } => self.join_wep(ssid, password).await,
+                Join::Wpa { ssid, password } => self.join_wpa(ssid, password, options).await,
+                Join::Wpa2Enterprise { .. } | Join::Wpa3Enterprise { .. } => {
+                    warn!("Enterprise networks are not supported by the ESP8266 AT firmware");
+                    Err(JoinError::NotSupported)
+                }
+            }
+        }
+    }
+
+    #[rustfmt::skip]
+    type LinkStateFuture<'m> where 'a: 'm = impl Future<Output = LinkState> + 'm;
+    fn link_state<'m>(&'m mut self) -> Self::LinkStateFuture<'m> {
+        async move { self.link.get() }
+    }
+
+    #[rustfmt::skip]
+    type LinkInfoFuture<'m> where 'a: 'm = impl Future<Output = Result<LinkInfo, LinkInfoError>> + 'm;
+    fn link_info<'m>(&'m mut self) -> Self::LinkInfoFuture<'m> {
+        async move { self.query_link_info().await }
+    }
+
+    #[rustfmt::skip]
+    type ScanFuture<'m> where 'a: 'm = impl Future<Output = Result<usize, ScanError>> + 'm;
+    fn scan<'m>(&'m mut self, results: &'m mut [AccessPoint]) -> Self::ScanFuture<'m> {
+        async move { self.list_access_points(results).await }
+    }
+}
+
+impl<'a, const SOCKETS: usize> WifiAccessPoint for Esp8266Controller<'a, SOCKETS> {
+    #[rustfmt::skip]
+    type StartAccessPointFuture<'m> where 'a: 'm = impl Future<Output = Result<IpAddress, AccessPointError>> + 'm;
+    fn start_access_point<'m>(
+        &'m mut self,
+        config: AccessPointConfig<'m>,
+    ) -> Self::StartAccessPointFuture<'m> {
+        async move { self.start_ap(config).await }
+    }
+
+    #[rustfmt::skip]
+    type StopAccessPointFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn stop_access_point<'m>(&'m mut self) -> Self::StopAccessPointFuture<'m> {
+        async move {
+            if let Err(e) = self.send(Command::SetMode(WiFiMode::Station)).await {
+                warn!("Error stopping access point: {:?}", e);
             }
         }
     }
 }
 
-impl<'a> TcpStack for Esp8266Controller<'a> {
+impl<'a, const SOCKETS: usize> TcpStack for Esp8266Controller<'a, SOCKETS> {
     type SocketHandle = u8;
 
     #[rustfmt::skip]
-    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Self::SocketHandle> + 'm;
+    type OpenFuture<'m> where 'a: 'm = impl Future<Output = Result<Self::SocketHandle, TcpError>> + 'm;
     fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
-        async move { self.socket_pool.open().await }
+        async move {
+            // Apply pending CLOSED notifications first, so that one for a previous use of a
+            // socket can not close it again once it is reused
+            self.process_notifications();
+            // Sockets are only freed by requests queued behind this one, so never wait
+            self.socket_pool
+                .try_open()
+                .map_err(|_| TcpError::NoAvailableSockets)
+        }
     }
 
     #[rustfmt::skip]
@@ -474,12 +1033,17 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
     fn connect<'m>(
         &'m mut self,
         handle: Self::SocketHandle,
-        _: IpProtocol,
+        proto: IpProtocol,
         dst: SocketAddress,
     ) -> Self::ConnectFuture<'m> {
         async move {
-            let command = Command::StartConnection(handle as usize, ConnectionType::TCP, dst);
+            let connection_type = match proto {
+                IpProtocol::Tcp => ConnectionType::TCP,
+                IpProtocol::Udp => ConnectionType::UDP,
+            };
+            let command = Command::StartConnection(handle as usize, connection_type, dst);
             if let Ok(AtResponse::Connect(..)) = self.send(command).await {
+                self.udp[handle as usize] = matches!(proto, IpProtocol::Udp);
                 Ok(())
             } else {
                 Err(TcpError::ConnectError)
@@ -501,35 +1065,33 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
             };
 
             let result = match self.send(command).await {
-                Ok(AtResponse::Ok) => {
-                    match self.response_consumer.recv().await.unwrap() {
-                        AtResponse::ReadyForData => {
-                            let mut data = [0; 256];
-                            data[0..buf.len()].copy_from_slice(&buf[0..buf.len()]);
-                            self.command_producer
-                                .send((buf.len(), data))
-                                .await
-                                .map_err(|_| TcpError::WriteError)?;
-                            let mut data_sent: Option<usize> = None;
-                            loop {
-                                match self.response_consumer.recv().await.unwrap() {
-                                    AtResponse::ReceivedDataToSend(len) => {
-                                        data_sent.replace(len);
-                                    }
-                                    AtResponse::SendOk => break Ok(data_sent.unwrap_or_default()),
-                                    _ => {
-                                        break Err(TcpError::WriteError);
-                                        // unknown response
-                                    }
+                Ok(AtResponse::Ok) => match self.response().await {
+                    Ok(AtResponse::ReadyForData) => {
+                        let mut response = self
+                            .at
+                            .write(buf, RESPONSE_TIMEOUT)
+                            .await
+                            .map_err(|_| TcpError::WriteError)?;
+                        let mut data_sent: Option<usize> = None;
+                        loop {
+                            match response {
+                                AtResponse::ReceivedDataToSend(len) => {
+                                    data_sent.replace(len);
+                                }
+                                AtResponse::SendOk => break Ok(data_sent.unwrap_or_default()),
+                                r => {
+                                    warn!("Unexpected response: {:?}", r);
+                                    break Err(TcpError::WriteError);
                                 }
                             }
-                        }
-                        r => {
-                            warn!("Unexpected response: {:?}", r);
-                            Err(TcpError::WriteError)
+                            response = self.response().await.map_err(|_| TcpError::WriteError)?;
                         }
                     }
-                }
+                    r => {
+                        warn!("Unexpected response: {:?}", r);
+                        Err(TcpError::WriteError)
+                    }
+                },
                 Ok(r) => {
                     warn!("Unexpected response: {:?}", r);
                     Err(TcpError::WriteError)
@@ -548,62 +1110,36 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
         buf: &'m mut [u8],
     ) -> Self::ReadFuture<'m> {
         async move {
-            const BLOCK_SIZE: usize = 512;
-            let mut rp = 0;
-            let mut remaining = buf.len();
-            while remaining > 0 {
-                let result = async {
-                    self.process_notifications();
-                    if self.socket_pool.is_closed(handle) {
-                        return Err(TcpError::SocketClosed);
-                    }
+            self.process_notifications();
+            if self.socket_pool.is_closed(handle) {
+                return Err(TcpError::SocketClosed);
+            }
+            if let Some(len) = self.receive.read_datagram(handle, buf) {
+                return Ok(len);
+            }
+            if self.udp[handle as usize] {
+                // Datagrams are pushed by the firmware, not read on request
+                return Ok(0);
+            }
 
-                    let recv_len = core::cmp::min(remaining, BLOCK_SIZE);
-                    let command = Command::Receive {
-                        link_id: handle as usize,
-                        len: recv_len,
-                    };
-                    //                   info!("Awaiting {} bytes from adapter", recv_len);
-
-                    match self.send(command).await {
-                        Ok(AtResponse::DataReceived(inbound, len)) => {
-                            for (i, b) in inbound[0..len].iter().enumerate() {
-                                buf[rp + i] = *b;
-                            }
-                            //                            info!("Received {} bytes from adapter", len);
-                            Ok(len)
-                        }
-                        Ok(AtResponse::Ok) => Ok(0),
-                        Ok(r) => {
-                            warn!("Unexpected response: {:?}", r);
-                            Err(TcpError::ReadError)
-                        }
-                        Err(e) => {
-                            warn!("Unexpected error: {:?}", e);
-                            Err(TcpError::ReadError)
-                        }
-                    }
+            let len = core::cmp::min(buf.len(), MAX_RECEIVE_LEN);
+            let receive = self.receive;
+            let lease = receive.lend(&mut buf[..len]);
+            let command = Command::Receive {
+                link_id: handle as usize,
+                len,
+            };
+            match self.send(command).await {
+                Ok(AtResponse::Ok) => Ok(lease.written()),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    Err(TcpError::ReadError)
                 }
-                .await;
-
-                match result {
-                    Ok(len) => {
-                        rp += len;
-                        remaining -= len;
-                        if len == 0 {
-                            return Ok(rp);
-                        }
-                    }
-                    Err(e) => {
-                        if rp == 0 {
-                            return Err(e);
-                        } else {
-                            return Ok(rp);
-                        }
-                    }
+                Err(e) => {
+                    warn!("Unexpected error: {:?}", e);
+                    Err(TcpError::ReadError)
                 }
             }
-            Ok(rp)
         }
     }
 
@@ -614,7 +1150,8 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
             let command = Command::CloseConnection(handle as usize);
             match self.send(command).await {
                 Ok(AtResponse::Ok) | Ok(AtResponse::UnlinkFail) => {
-                    self.socket_pool.close(handle);
+                    // The link is gone, so don't rely on its CLOSED notification to free it
+                    self.socket_pool.release(handle);
                 }
                 _ => {}
             }
@@ -622,18 +1159,35 @@ impl<'a> TcpStack for Esp8266Controller<'a> {
     }
 }
 
-async fn uart_read<UART>(uart: &mut UART, rx_buf: &mut [u8]) -> Result<usize, embassy::io::Error>
-where
-    UART: AsyncBufReadExt + 'static,
-{
-    let mut uart = unsafe { Pin::new_unchecked(uart) };
-    uart.read(rx_buf).await
-}
+impl<'a, const SOCKETS: usize> TcpServer for Esp8266Controller<'a, SOCKETS> {
+    #[rustfmt::skip]
+    type ListenFuture<'m> where 'a: 'm = impl Future<Output = Result<(), TcpError>> + 'm;
+    fn listen<'m>(&'m mut self, port: u16) -> Self::ListenFuture<'m> {
+        async move {
+            match self.send(Command::StartServer(port)).await {
+                Ok(AtResponse::Ok) => Ok(()),
+                Ok(r) => {
+                    warn!("Unexpected response: {:?}", r);
+                    Err(TcpError::IoError)
+                }
+                Err(_) => Err(TcpError::IoError),
+            }
+        }
+    }
 
-async fn uart_write<UART>(uart: &mut UART, buf: &[u8]) -> Result<(), embassy::io::Error>
-where
-    UART: AsyncWriteExt + 'static,
-{
-    let mut uart = unsafe { Pin::new_unchecked(uart) };
-    uart.write_all(buf).await
+    #[rustfmt::skip]
+    type AcceptFuture<'m> where 'a: 'm = impl Future<Output = Result<Option<Self::SocketHandle>, TcpError>> + 'm;
+    fn accept<'m>(&'m mut self) -> Self::AcceptFuture<'m> {
+        async move { Ok(self.accept_link().await) }
+    }
+
+    #[rustfmt::skip]
+    type StopListeningFuture<'m> where 'a: 'm = impl Future<Output = ()> + 'm;
+    fn stop_listening<'m>(&'m mut self) -> Self::StopListeningFuture<'m> {
+        async move {
+            if let Err(e) = self.send(Command::StopServer).await {
+                warn!("Error stopping server: {:?}", e);
+            }
+        }
+    }
 }
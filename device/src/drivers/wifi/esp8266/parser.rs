@@ -1,4 +1,5 @@
 use nom::alt;
+use nom::bytes::streaming::take_while_m_n;
 use nom::char;
 use nom::character::streaming::digit1;
 use nom::do_parse;
@@ -10,12 +11,14 @@ use nom::take_until;
 use nom::tuple;
 use nom::IResult;
 
-use crate::traits::ip::{IpAddress, IpAddressV4};
+use crate::traits::{
+    ip::{IpAddress, IpAddressV4},
+    wifi::{AccessPoint, Security},
+};
 
 use super::{
     num::{atoi_u8, atoi_usize},
     protocol::{FirmwareInfo, IpAddresses, ResolverAddresses, Response, WifiConnectionFailure},
-    BUFFER_LEN,
 };
 
 fn parse_u8(input: &[u8]) -> IResult<&[u8], u8> {
@@ -29,6 +32,20 @@ fn parse_usize(input: &[u8]) -> IResult<&[u8], usize> {
     IResult::Ok((input, num))
 }
 
+fn parse_i8(input: &[u8]) -> IResult<&[u8], i8> {
+    let (input, sign) = opt!(input, char!('-'))?;
+    let (input, value) = parse_u8(input)?;
+    let value = value as i16;
+    let value = if sign.is_some() { -value } else { value };
+    IResult::Ok((input, value.max(i8::MIN as i16) as i8))
+}
+
+fn parse_hex_u8(input: &[u8]) -> IResult<&[u8], u8> {
+    let (input, digits) = take_while_m_n(2, 2, |c: u8| c.is_ascii_hexdigit())(input)?;
+    let value = u8::from_str_radix(core::str::from_utf8(digits).unwrap(), 16).unwrap();
+    IResult::Ok((input, value))
+}
+
 #[rustfmt::skip]
 named!(
     crlf,
@@ -205,6 +222,159 @@ named!(
     )
 );
 
+#[rustfmt::skip]
+named!(
+    pub ap_ip_addresses<Response>,
+    do_parse!(
+        tag!("+CIPAP_CUR:ip:\"") >>
+        ip: ip_addr >>
+        tag!("\"") >>
+        crlf >>
+        tag!("+CIPAP_CUR:gateway:\"") >>
+        gateway: ip_addr >>
+        tag!("\"") >>
+        crlf >>
+        tag!("+CIPAP_CUR:netmask:\"") >>
+        netmask: ip_addr >>
+        tag!("\"") >>
+        crlf >>
+        crlf >>
+        ok >>
+        (
+            Response::IpAddresses(
+                IpAddresses {
+                    ip,
+                    gateway,
+                    netmask,
+                }
+            )
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    mac_addr<[u8; 6]>,
+    do_parse!(
+        a: parse_hex_u8 >>
+        char!(':') >>
+        b: parse_hex_u8 >>
+        char!(':') >>
+        c: parse_hex_u8 >>
+        char!(':') >>
+        d: parse_hex_u8 >>
+        char!(':') >>
+        e: parse_hex_u8 >>
+        char!(':') >>
+        f: parse_hex_u8 >>
+        (
+            [a, b, c, d, e, f]
+        )
+    )
+);
+
+fn security(ecn: u8) -> Security {
+    match ecn {
+        0 => Security::Open,
+        1 => Security::Wep,
+        2 => Security::WpaPsk,
+        3 => Security::Wpa2Psk,
+        4 => Security::WpaWpa2Psk,
+        5 => Security::Wpa2Enterprise,
+        6 => Security::Wpa3Psk,
+        7 => Security::Wpa2Wpa3Psk,
+        _ => Security::Unknown,
+    }
+}
+
+fn access_point_info(ecn: u8, ssid: &[u8], rssi: i8, bssid: [u8; 6], channel: u8) -> AccessPoint {
+    let mut ap = AccessPoint {
+        bssid,
+        rssi,
+        channel,
+        security: security(ecn),
+        ..Default::default()
+    };
+    if let Ok(ssid) = core::str::from_utf8(ssid) {
+        let _ = ap.ssid.push_str(ssid);
+    }
+    ap
+}
+
+// Trailing fields depend on the firmware and AT+CWLAPOPT, and are skipped.
+#[rustfmt::skip]
+named!(
+    pub access_point<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        tag!("+CWLAP:(") >>
+        ecn: parse_u8 >>
+        tag!(",\"") >>
+        ssid: take_until!("\",") >>
+        tag!("\",") >>
+        rssi: parse_i8 >>
+        tag!(",\"") >>
+        bssid: mac_addr >>
+        tag!("\",") >>
+        channel: parse_u8 >>
+        take_until!(")") >>
+        tag!(")") >>
+        crlf >>
+        (
+            Response::AccessPoint(access_point_info(ecn, ssid, rssi, bssid, channel))
+        )
+    )
+);
+
+// Trailing fields depend on the firmware, and are skipped.
+#[rustfmt::skip]
+named!(
+    pub current_access_point<Response>,
+    do_parse!(
+        tag!("+CWJAP_CUR:\"") >>
+        ssid: take_until!("\",\"") >>
+        tag!("\",\"") >>
+        bssid: mac_addr >>
+        tag!("\",") >>
+        channel: parse_u8 >>
+        char!(',') >>
+        rssi: parse_i8 >>
+        take_until!("\r\n") >>
+        ok >>
+        (
+            Response::CurrentAccessPoint(Some(access_point_info(u8::MAX, ssid, rssi, bssid, channel)))
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub no_access_point<Response>,
+    do_parse!(
+        tag!("No AP") >>
+        crlf >>
+        ok >>
+        (
+            Response::CurrentAccessPoint(None)
+        )
+    )
+);
+
+#[rustfmt::skip]
+named!(
+    pub mac_address<Response>,
+    do_parse!(
+        tag!("+CIPSTAMAC_CUR:\"") >>
+        mac: mac_addr >>
+        tag!("\"") >>
+        crlf >>
+        ok >>
+        (
+            Response::MacAddress(mac)
+        )
+    )
+);
+
 #[rustfmt::skip]
 named!(
     pub connect<Response>,
@@ -219,6 +389,22 @@ named!(
     )
 );
 
+// An inbound connection on a server link. Unlike `connect`, this is not followed by OK,
+// so it is only matched once `connect` has failed on the input that follows.
+#[rustfmt::skip]
+named!(
+    pub accepted<Response>,
+    do_parse!(
+        opt!(crlf) >>
+        link_id: parse_u8 >>
+        tag!(",CONNECT") >>
+        crlf >>
+        (
+            Response::Accepted(link_id as usize)
+        )
+    )
+);
+
 named!(
     pub ready_for_data<Response>,
     do_parse!(
@@ -296,25 +482,31 @@ named!(
 );
 
 named!(
-    pub data_received<Response>,
+    pub receiving_data<Response>,
     do_parse!(
         opt!(tag!("\r")) >>
         opt!(tag!("\n")) >>
         tag!("+CIPRECVDATA,") >>
         len: parse_usize >>
         char!(':') >>
-        data: take!(len) >>
-        crlf >>
-        ok >>
-        ( {
-            let mut buf = [0; BUFFER_LEN];
-            for (i, b) in data.iter().enumerate() {
-                //log::info!( "copy {} @ {}", *b as char, i);
-                buf[i] = *b;
-            }
-            //log::info!("------------> onwards {:?}", buf);
-            Response::DataReceived(buf, len)
-        } )
+        (
+            Response::ReceivingData(len)
+        )
+    )
+);
+
+named!(
+    pub receiving_datagram<Response>,
+    do_parse!(
+        opt!( crlf ) >>
+        tag!( "+IPD,") >>
+        link_id: parse_usize >>
+        char!(',') >>
+        len: parse_usize >>
+        char!(':') >>
+        (
+            Response::ReceivingDatagram { link_id, len }
+        )
     )
 );
 
@@ -393,17 +585,107 @@ named!(
         | wifi_connection_failure
         | got_ip
         | ip_addresses
+        | ap_ip_addresses
+        | access_point
+        | current_access_point
+        | no_access_point
+        | mac_address
         | connect
+        | accepted
         | closed
         | ready_for_data
         | received_data_to_send
         | send_ok
         | send_fail
         | data_available
-        | data_received
+        | receiving_data
+        | receiving_datagram
         | dns_resolvers
         | dns_lookup
         | dns_fail
         | unlink_fail
     )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_access_point() {
+        let input = b"+CWLAP:(3,\"drogue\",-67,\"a4:2b:b0:c1:0e:9f\",6,-8,0)\r\n";
+        match parse(input) {
+            Ok((remaining, Response::AccessPoint(ap))) => {
+                assert!(remaining.is_empty());
+                assert_eq!("drogue", ap.ssid.as_str());
+                assert_eq!([0xa4, 0x2b, 0xb0, 0xc1, 0x0e, 0x9f], ap.bssid);
+                assert_eq!(-67, ap.rssi);
+                assert_eq!(6, ap.channel);
+                assert_eq!(Security::Wpa2Psk, ap.security);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_current_access_point() {
+        let input = b"+CWJAP_CUR:\"drogue\",\"a4:2b:b0:c1:0e:9f\",11,-58\r\n\r\nOK\r\n";
+        match parse(input) {
+            Ok((_, Response::CurrentAccessPoint(Some(ap)))) => {
+                assert_eq!("drogue", ap.ssid.as_str());
+                assert_eq!(11, ap.channel);
+                assert_eq!(-58, ap.rssi);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match parse(b"No AP\r\n\r\nOK\r\n") {
+            Ok((_, Response::CurrentAccessPoint(None))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_accepted() {
+        match parse(b"0,CONNECT\r\n+IPD,0,312\r\n") {
+            Ok((remaining, Response::Accepted(0))) => {
+                assert_eq!(b"+IPD,0,312\r\n", remaining);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match parse(b"1,CONNECT\r\n\r\nOK\r\n") {
+            Ok((_, Response::Connect(1))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_receiving_data() {
+        match parse(b"\r\n+CIPRECVDATA,5:hello\r\nOK\r\n") {
+            Ok((remaining, Response::ReceivingData(5))) => {
+                assert_eq!(b"hello\r\nOK\r\n", remaining);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn parse_receiving_datagram() {
+        match parse(b"\r\n+IPD,3,5:hello\r\n") {
+            Ok((remaining, Response::ReceivingDatagram { link_id: 3, len: 5 })) => {
+                assert_eq!(b"hello\r\n", remaining);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // Data of TCP links in passive receive mode is only announced
+        match parse(b"+IPD,0,312\r\n") {
+            Ok((
+                _,
+                Response::DataAvailable {
+                    link_id: 0,
+                    len: 312,
+                },
+            )) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use super::BUFFER_LEN;
-use crate::traits::ip::{IpAddress, IpAddressV4, SocketAddress};
+use crate::traits::{
+    ip::{IpAddress, IpAddressV4, SocketAddress},
+    wifi::AccessPoint,
+};
 use core::fmt;
 use core::fmt::{Debug, Write};
+use embassy::time::Duration;
 use heapless::{consts::U256, String};
 
 #[derive(Debug)]
@@ -37,35 +40,124 @@ pub enum WiFiMode {
 pub enum Command<'a> {
     QueryFirmwareInfo,
     SetMode(WiFiMode),
-    JoinAp { ssid: &'a str, password: &'a str },
+    JoinAp {
+        ssid: &'a str,
+        password: &'a str,
+        bssid: Option<[u8; 6]>,
+    },
+    SetStaticIp {
+        address: IpAddress,
+        gateway: IpAddress,
+        netmask: IpAddress,
+    },
+    EnableDhcp,
+    SetScanOptions,
+    ListAccessPoints,
+    SetAccessPoint {
+        ssid: &'a str,
+        password: &'a str,
+        channel: u8,
+    },
+    QueryAccessPointIpAddress,
+    StartServer(u16),
+    StopServer,
     QueryIpAddress,
+    QueryCurrentAccessPoint,
+    QueryMacAddress,
     StartConnection(usize, ConnectionType, SocketAddress),
     CloseConnection(usize),
-    Send { link_id: usize, len: usize },
-    Receive { link_id: usize, len: usize },
+    Send {
+        link_id: usize,
+        len: usize,
+    },
+    Receive {
+        link_id: usize,
+        len: usize,
+    },
     QueryDnsResolvers,
     SetDnsResolvers(ResolverAddresses),
-    GetHostByName { hostname: &'a str },
+    GetHostByName {
+        hostname: &'a str,
+    },
 }
 
 impl<'a> Command<'a> {
+    /// Time allowed for the first response to the command.
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Command::JoinAp { .. } => Duration::from_secs(20),
+            Command::ListAccessPoints
+            | Command::StartConnection(..)
+            | Command::GetHostByName { .. } => Duration::from_secs(10),
+            _ => Duration::from_secs(5),
+        }
+    }
+
     pub fn as_bytes(&self) -> String<U256> {
         match self {
             Command::QueryFirmwareInfo => String::from("AT+GMR"),
             Command::QueryIpAddress => String::from("AT+CIPSTA_CUR?"),
+            Command::QueryCurrentAccessPoint => String::from("AT+CWJAP_CUR?"),
+            Command::QueryMacAddress => String::from("AT+CIPSTAMAC_CUR?"),
             Command::SetMode(mode) => match mode {
                 WiFiMode::Station => String::from("AT+CWMODE_CUR=1"),
                 WiFiMode::SoftAccessPoint => String::from("AT+CWMODE_CUR=2"),
                 WiFiMode::SoftAccessPointAndStation => String::from("AT+CWMODE_CUR=3"),
             },
-            Command::JoinAp { ssid, password } => {
-                let mut s = String::from("AT+CWJAP_CUR=\"");
-                s.push_str(ssid).unwrap();
-                s.push_str("\",\"").unwrap();
-                s.push_str(password).unwrap();
-                s.push_str("\"").unwrap();
+            Command::JoinAp {
+                ssid,
+                password,
+                bssid,
+            } => {
+                let mut s = String::from("AT+CWJAP_CUR=");
+                push_quoted(&mut s, ssid);
+                s.push(',').unwrap();
+                push_quoted(&mut s, password);
+                if let Some(b) = bssid {
+                    write!(
+                        s,
+                        ",\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\"",
+                        b[0], b[1], b[2], b[3], b[4], b[5]
+                    )
+                    .unwrap();
+                }
+                s
+            }
+            Command::SetStaticIp {
+                address,
+                gateway,
+                netmask,
+            } => {
+                let mut s = String::from("AT+CIPSTA_CUR=");
+                write!(s, "\"{}\",\"{}\",\"{}\"", address, gateway, netmask).unwrap();
                 s
             }
+            // DHCP on for station mode
+            Command::EnableDhcp => String::from("AT+CWDHCP_CUR=1,1"),
+            // Sort by RSSI and report ecn, ssid, rssi, mac and channel only
+            Command::SetScanOptions => String::from("AT+CWLAPOPT=1,31"),
+            Command::ListAccessPoints => String::from("AT+CWLAP"),
+            Command::SetAccessPoint {
+                ssid,
+                password,
+                channel,
+            } => {
+                // Open network without a password, WPA2-PSK otherwise
+                let ecn = if password.is_empty() { 0 } else { 3 };
+                let mut s = String::from("AT+CWSAP_CUR=");
+                push_quoted(&mut s, ssid);
+                s.push(',').unwrap();
+                push_quoted(&mut s, password);
+                write!(s, ",{},{}", channel, ecn).unwrap();
+                s
+            }
+            Command::QueryAccessPointIpAddress => String::from("AT+CIPAP_CUR?"),
+            Command::StartServer(port) => {
+                let mut s = String::from("AT+CIPSERVER=1,");
+                write!(s, "{}", port).unwrap();
+                s
+            }
+            Command::StopServer => String::from("AT+CIPSERVER=0"),
             Command::StartConnection(link_id, connection_type, socket_addr) => {
                 let mut s = String::from("AT+CIPSTART=");
                 write!(s, "{},", link_id).unwrap();
@@ -129,14 +221,29 @@ pub enum Response {
     ReceivedDataToSend(usize),
     SendOk,
     SendFail,
-    DataAvailable { link_id: usize, len: usize },
-    DataReceived([u8; BUFFER_LEN], usize),
+    DataAvailable {
+        link_id: usize,
+        len: usize,
+    },
+    /// Header of data read from a socket, followed by `len` bytes of data.
+    ReceivingData(usize),
+    /// Header of a datagram received on a UDP link, followed by `len` bytes of data. UDP links
+    /// are not affected by the passive receive mode.
+    ReceivingDatagram {
+        link_id: usize,
+        len: usize,
+    },
     WifiConnected,
     WifiConnectionFailure(WifiConnectionFailure),
     WifiDisconnect,
     GotIp,
     IpAddresses(IpAddresses),
+    AccessPoint(AccessPoint),
+    /// The access point joined, if any.
+    CurrentAccessPoint(Option<AccessPoint>),
+    MacAddress([u8; 6]),
     Connect(usize),
+    Accepted(usize),
     Closed(usize),
     Resolvers(ResolverAddresses),
     IpAddress(IpAddress),
@@ -161,14 +268,20 @@ impl defmt::Format for Response {
             Response::DataAvailable { link_id, len } => {
                 defmt::write!(f, "DataAvailable link_id({}), len({})", link_id, len)
             }
-            //Response::DataReceived(d, l) => dump_data("DataReceived", d, *l, f),
-            Response::DataReceived(_, _) => defmt::write!(f, "DataReceived"),
+            Response::ReceivingData(len) => defmt::write!(f, "ReceivingData len({})", len),
+            Response::ReceivingDatagram { link_id, len } => {
+                defmt::write!(f, "ReceivingDatagram link_id({}), len({})", link_id, len)
+            }
             Response::WifiConnected => defmt::write!(f, "WifiConnected"),
             Response::WifiConnectionFailure(v) => defmt::write!(f, "WifiConnectionFailure {}", v),
             Response::WifiDisconnect => defmt::write!(f, "WifiDisconnect"),
             Response::GotIp => defmt::write!(f, "GotIp"),
             Response::IpAddresses(v) => defmt::write!(f, "IpAddresses: {}", v),
+            Response::AccessPoint(v) => defmt::write!(f, "AccessPoint: {}", v),
+            Response::CurrentAccessPoint(v) => defmt::write!(f, "CurrentAccessPoint: {}", v),
+            Response::MacAddress(v) => defmt::write!(f, "MacAddress: {}", v),
             Response::Connect(v) => defmt::write!(f, "Connect {}", v),
+            Response::Accepted(v) => defmt::write!(f, "Accepted {}", v),
             Response::Closed(v) => defmt::write!(f, "Closed {}", v),
             Response::IpAddress(v) => defmt::write!(f, "IpAddress {}", v),
             Response::Resolvers(v) => defmt::write!(f, "Resolvers {}", v),
@@ -196,8 +309,12 @@ impl Debug for Response {
                 .field("link_id", link_id)
                 .field("len", len)
                 .finish(),
-            //Response::DataReceived(d, l) => dump_data("DataReceived", d, *l, f),
-            Response::DataReceived(_, _) => f.write_str("DataReceived"),
+            Response::ReceivingData(len) => f.debug_tuple("ReceivingData").field(len).finish(),
+            Response::ReceivingDatagram { link_id, len } => f
+                .debug_struct("ReceivingDatagram")
+                .field("link_id", link_id)
+                .field("len", len)
+                .finish(),
             Response::WifiConnected => f.write_str("WifiConnected"),
             Response::WifiConnectionFailure(v) => {
                 f.debug_tuple("WifiConnectionFailure").field(v).finish()
@@ -205,7 +322,13 @@ impl Debug for Response {
             Response::WifiDisconnect => f.write_str("WifiDisconnect"),
             Response::GotIp => f.write_str("GotIp"),
             Response::IpAddresses(v) => f.debug_tuple("IpAddresses").field(v).finish(),
+            Response::AccessPoint(v) => f.debug_tuple("AccessPoint").field(v).finish(),
+            Response::CurrentAccessPoint(v) => {
+                f.debug_tuple("CurrentAccessPoint").field(v).finish()
+            }
+            Response::MacAddress(v) => f.debug_tuple("MacAddress").field(v).finish(),
             Response::Connect(v) => f.debug_tuple("Connect").field(v).finish(),
+            Response::Accepted(v) => f.debug_tuple("Accepted").field(v).finish(),
             Response::Closed(v) => f.debug_tuple("Closed").field(v).finish(),
             Response::IpAddress(v) => f.debug_tuple("IpAddress").field(v).finish(),
             Response::Resolvers(v) => f.debug_tuple("Resolvers").field(v).finish(),
@@ -225,7 +348,7 @@ pub struct IpAddresses {
 }
 
 /// Version information for the ESP board.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct FirmwareInfo {
     pub major: u8,
@@ -234,6 +357,54 @@ pub struct FirmwareInfo {
     pub build: u8,
 }
 
+impl FirmwareInfo {
+    fn at_least(&self, major: u8, minor: u8) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+
+/// Features supported by the AT firmware, derived from its version.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareFeatures {
+    /// `_CUR` variants of configuration commands, removed in the 2.x firmware.
+    pub cur_commands: bool,
+    /// Passive receive mode (`AT+CIPRECVMODE` and `AT+CIPRECVDATA`).
+    pub passive_receive: bool,
+    /// SSL connections.
+    pub ssl: bool,
+    /// Configurable DNS resolvers (`AT+CIPDNS_CUR`).
+    pub dns: bool,
+}
+
+/// AT firmware detected on the modem.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Firmware {
+    pub version: FirmwareInfo,
+    pub features: FirmwareFeatures,
+}
+
+impl Firmware {
+    pub fn new(version: FirmwareInfo) -> Self {
+        let cur_commands = version.at_least(1, 0) && version.major < 2;
+        Self {
+            version,
+            features: FirmwareFeatures {
+                cur_commands,
+                passive_receive: version.at_least(1, 4),
+                ssl: version.at_least(1, 0),
+                dns: cur_commands && version.at_least(1, 3),
+            },
+        }
+    }
+
+    /// Whether the driver can run on this firmware.
+    pub fn is_supported(&self) -> bool {
+        self.features.cur_commands && self.features.passive_receive
+    }
+}
+
 /// Reasons for Wifi access-point join failures.
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -285,6 +456,19 @@ fn dump_data(name: &str, data: &[u8], len: usize, f: &mut fmt::Formatter<'_>) ->
     Ok(())
 }
 
+/// Append `value` as a quoted string parameter, escaping the characters that would otherwise
+/// end the parameter.
+fn push_quoted(s: &mut String<U256>, value: &str) {
+    s.push('"').unwrap();
+    for c in value.chars() {
+        if matches!(c, '"' | ',' | '\\') {
+            s.push('\\').unwrap();
+        }
+        s.push(c).unwrap();
+    }
+    s.push('"').unwrap();
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -299,6 +483,32 @@ mod test {
         assert_eq!(&buf, "Ok");
     }
 
+    #[test]
+    fn join_with_bssid() {
+        let command = Command::JoinAp {
+            ssid: "drogue",
+            password: "secret",
+            bssid: Some([0xa4, 0x2b, 0xb0, 0xc1, 0x0e, 0x9f]),
+        };
+        assert_eq!(
+            "AT+CWJAP_CUR=\"drogue\",\"secret\",\"a4:2b:b0:c1:0e:9f\"",
+            command.as_bytes().as_str()
+        );
+    }
+
+    #[test]
+    fn join_escapes_parameters() {
+        let command = Command::JoinAp {
+            ssid: "a\",b",
+            password: "c\\d",
+            bssid: None,
+        };
+        assert_eq!(
+            "AT+CWJAP_CUR=\"a\\\"\\,b\",\"c\\\\d\"",
+            command.as_bytes().as_str()
+        );
+    }
+
     #[test]
     fn test_debug_simple_value() {
         let mut buf = ArrayString::<20>::new();
@@ -307,19 +517,33 @@ mod test {
         assert_eq!(&buf, "Connect(1)");
     }
 
-    fn test_debug_data() {
-        let mut buf = ArrayString::<256>::new();
-        let data = b"FOO\0BAR";
+    #[test]
+    fn firmware_features() {
+        let firmware = Firmware::new(FirmwareInfo {
+            major: 1,
+            minor: 7,
+            patch: 4,
+            build: 0,
+        });
+        assert!(firmware.is_supported());
+        assert!(firmware.features.dns);
 
-        let mut array = [0u8; super::BUFFER_LEN];
-        for (&x, p) in data.iter().zip(array.iter_mut()) {
-            *p = x;
-        }
+        let firmware = Firmware::new(FirmwareInfo {
+            major: 1,
+            minor: 3,
+            patch: 0,
+            build: 0,
+        });
+        assert!(!firmware.features.passive_receive);
+        assert!(!firmware.is_supported());
 
-        write!(&mut buf, "{:?}", Response::DataReceived(array, data.len())).expect("Can't write");
-        assert_eq!(
-            &buf,
-            "DataReceived(7; 'FOO\\0BAR'; [46, 4F, 4F, 0, 42, 41, 52])"
-        );
+        let firmware = Firmware::new(FirmwareInfo {
+            major: 2,
+            minor: 2,
+            patch: 0,
+            build: 0,
+        });
+        assert!(!firmware.features.cur_commands);
+        assert!(!firmware.is_supported());
     }
 }
@@ -1,11 +1,8 @@
 use core::cell::RefCell;
-use core::future::Future;
-use core::pin::Pin;
-use core::task::{Context, Poll, Waker};
 
-use heapless::{consts::U8, spsc::Queue};
+use super::DriverError;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 enum SocketState {
     HalfClosed,
     Closed,
@@ -19,106 +16,137 @@ impl Default for SocketState {
     }
 }
 
-pub(crate) struct SocketPool {
-    sockets: RefCell<[SocketState; 4]>,
-    waiters: RefCell<Queue<Waker, U8>>,
+/// Tracks the state of `N` sockets.
+///
+/// Sockets are only freed by requests to the adapter, so a socket is never waited for.
+pub(crate) struct SocketPool<const N: usize> {
+    sockets: RefCell<[SocketState; N]>,
 }
 
-impl SocketPool {
+impl<const N: usize> SocketPool<N> {
     pub(crate) fn new() -> Self {
         Self {
-            sockets: Default::default(),
-            waiters: RefCell::new(Queue::new()),
+            sockets: RefCell::new([SocketState::Closed; N]),
         }
     }
 
-    pub(crate) async fn open<'a>(&'a self) -> u8 {
-        OpenFuture::new(self).await
+    /// Take a free socket, failing if all sockets are taken.
+    pub(crate) fn try_open<'a>(&'a self) -> Result<u8, DriverError> {
+        let mut sockets = self.sockets.borrow_mut();
+        match sockets.iter().position(|s| *s == SocketState::Closed) {
+            Some(index) => {
+                sockets[index] = SocketState::Open;
+                Ok(index as u8)
+            }
+            None => Err(DriverError::NoAvailableSockets),
+        }
     }
 
+    /// Advance the closing of a socket. Ids without a socket in the pool are ignored.
     pub(crate) fn close<'a>(&'a self, socket: u8) {
         let mut sockets = self.sockets.borrow_mut();
-        let index = socket as usize;
-        match sockets[index] {
-            SocketState::HalfClosed => {
-                sockets[index] = SocketState::Closed;
-            }
-            SocketState::Open | SocketState::Connected => {
-                sockets[index] = SocketState::HalfClosed;
-            }
-            SocketState::Closed => {
-                // nothing
+        if let Some(state) = sockets.get_mut(socket as usize) {
+            match *state {
+                SocketState::HalfClosed => {
+                    *state = SocketState::Closed;
+                }
+                SocketState::Open | SocketState::Connected => {
+                    *state = SocketState::HalfClosed;
+                }
+                SocketState::Closed => {
+                    // nothing
+                }
             }
         }
     }
 
-    pub(crate) fn is_closed<'a>(&'a self, socket: u8) -> bool {
-        let sockets = self.sockets.borrow();
-        let index = socket as usize;
-        sockets[index] == SocketState::Closed || sockets[index] == SocketState::HalfClosed
+    /// Free a socket whose link is known to be closed, such as after a successful close
+    /// command. Ids without a socket in the pool are ignored.
+    pub(crate) fn release<'a>(&'a self, socket: u8) {
+        let mut sockets = self.sockets.borrow_mut();
+        if let Some(state) = sockets.get_mut(socket as usize) {
+            *state = SocketState::Closed;
+        }
     }
 
-    fn poll_open(&self, waker: &Waker, waiting: bool) -> Poll<u8> {
+    /// Take a socket for a link opened by a remote peer, if the link is not in use.
+    pub(crate) fn accept<'a>(&'a self, socket: u8) -> bool {
         let mut sockets = self.sockets.borrow_mut();
-        let available = sockets
-            .iter()
-            .enumerate()
-            .filter(|e| matches!(e, (_, SocketState::Closed)))
-            .next();
-
-        if let Some((index, _)) = available {
-            sockets[index] = SocketState::Open;
-            Poll::Ready(index as u8)
-        } else {
-            if !waiting {
-                self.waiters.borrow_mut().enqueue(waker.clone()).unwrap();
+        match sockets.get_mut(socket as usize) {
+            Some(state) if *state == SocketState::Closed => {
+                *state = SocketState::Connected;
+                true
             }
-            Poll::Pending
+            _ => false,
         }
     }
-}
-
-pub(crate) struct OpenFuture<'a> {
-    pool: &'a SocketPool,
-    waiting: bool,
-}
 
-impl<'a> OpenFuture<'a> {
-    fn new(pool: &'a SocketPool) -> Self {
-        Self {
-            pool,
-            waiting: false,
-        }
-    }
-}
-impl<'a> Future for OpenFuture<'a> {
-    type Output = u8;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let result = self.pool.poll_open(cx.waker(), self.waiting);
-        if result.is_pending() {
-            self.waiting = true;
+    /// Ids without a socket in the pool are always closed.
+    pub(crate) fn is_closed<'a>(&'a self, socket: u8) -> bool {
+        let sockets = self.sockets.borrow();
+        match sockets.get(socket as usize) {
+            Some(state) => *state == SocketState::Closed || *state == SocketState::HalfClosed,
+            None => true,
         }
-        result
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::executor::block_on;
 
     #[test]
     fn max_simultaneous_sockets() {
-        let pool = SocketPool::new();
+        let pool = SocketPool::<4>::new();
         for i in 0..100 {
             let expected = i % 4;
             if !pool.is_closed(expected) {
                 pool.close(expected);
                 pool.close(expected); // account for HalfClosed state
             }
-            let actual = block_on(pool.open());
+            let actual = pool.try_open().unwrap();
             assert_eq!(expected, actual);
         }
     }
+
+    #[test]
+    fn accept_only_unused_sockets() {
+        let pool = SocketPool::<4>::new();
+        assert_eq!(0, pool.try_open().unwrap());
+        assert!(!pool.accept(0));
+        assert!(pool.accept(1));
+        assert!(!pool.is_closed(1));
+        assert!(!pool.accept(4));
+        assert_eq!(2, pool.try_open().unwrap());
+    }
+
+    #[test]
+    fn open_fails_when_exhausted() {
+        let pool = SocketPool::<5>::new();
+        for expected in 0..5 {
+            assert_eq!(expected, pool.try_open().unwrap());
+        }
+        assert!(matches!(
+            pool.try_open(),
+            Err(DriverError::NoAvailableSockets)
+        ));
+
+        pool.close(3);
+        pool.close(3);
+        assert_eq!(3, pool.try_open().unwrap());
+    }
+
+    #[test]
+    fn release_and_out_of_range_ids() {
+        let pool = SocketPool::<2>::new();
+        assert_eq!(0, pool.try_open().unwrap());
+        pool.release(0);
+        assert!(pool.is_closed(0));
+        assert_eq!(0, pool.try_open().unwrap());
+
+        // Link ids the module supports beyond the pool size
+        pool.close(4);
+        pool.release(4);
+        assert!(pool.is_closed(4));
+    }
 }
@@ -98,6 +98,13 @@ impl FromButtonEvent<TestMessage> for TestHandler {
     }
 }
 
+#[cfg(feature = "time")]
+impl crate::actors::wifi::manager::FromLinkState<TestMessage> for TestHandler {
+    fn from(state: crate::traits::wifi::LinkState) -> Option<TestMessage> {
+        Some(TestMessage(state as u32))
+    }
+}
+
 /// A dummy actor that does nothing
 #[derive(Default)]
 pub struct DummyActor {}
@@ -33,6 +33,50 @@ pub trait LoraDriver {
         data: &'a [u8],
         rx: &'a mut [u8],
     ) -> Self::SendRecvFuture<'a>;
+
+    type LinkCheckFuture<'a>: Future<Output = Result<LinkCheck, LoraError>>
+    where
+        Self: 'a;
+    /// Ask the network for the quality of the link, using an uplink.
+    fn link_check<'a>(&'a mut self) -> Self::LinkCheckFuture<'a>;
+
+    type StatusFuture<'a>: Future<Output = Result<LoraStatus, LoraError>>
+    where
+        Self: 'a;
+    /// Query the data rate, TX power and frame counters currently in use.
+    fn status<'a>(&'a mut self) -> Self::StatusFuture<'a>;
+
+    type SessionFuture<'a>: Future<Output = Result<LoraSession, LoraError>>
+    where
+        Self: 'a;
+    /// Export the state of the current session, for restoring it after a reset.
+    fn session<'a>(&'a mut self) -> Self::SessionFuture<'a>;
+
+    type RestoreFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Resume a previously exported session instead of joining. Frame counters are taken as is,
+    /// so the uplink counter must not have been used already.
+    fn restore<'a>(&'a mut self, session: &'a LoraSession) -> Self::RestoreFuture<'a>;
+
+    /// Take the next downlink received by any operation, or received outside of an uplink by a
+    /// Class C device, in the order received. Never waits for a downlink.
+    fn take_downlink(&mut self) -> Option<LoraDownlink>;
+}
+
+/// Non-volatile storage for a LoRaWAN session, such as a flash page or EEPROM.
+pub trait LoraSessionStore {
+    type LoadFuture<'a>: Future<Output = Option<LoraSession>>
+    where
+        Self: 'a;
+    /// Load the stored session, if any.
+    fn load<'a>(&'a mut self) -> Self::LoadFuture<'a>;
+
+    type StoreFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Replace the stored session.
+    fn store<'a>(&'a mut self, session: &'a LoraSession) -> Self::StoreFuture<'a>;
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -48,5 +92,7 @@ pub enum LoraError {
     NotInitialized,
     NotImplemented,
     UnsupportedRegion,
+    MissingConfiguration,
+    StorageError,
     OtherError,
 }
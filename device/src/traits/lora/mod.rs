@@ -1,5 +1,7 @@
 mod api;
+mod p2p;
 mod types;
 
 pub use api::*;
+pub use p2p::*;
 pub use types::*;
@@ -0,0 +1,97 @@
+use super::{LoraError, SpreadingFactor};
+use core::future::Future;
+use embassy::time::Duration;
+
+/// API for point-to-point communication between LoRa radios, without a LoRaWAN network.
+pub trait LoraP2p {
+    type SetRfConfigFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Apply the radio settings used for transmitting and receiving.
+    fn set_rf_config<'a>(&'a mut self, config: &'a RfConfig) -> Self::SetRfConfigFuture<'a>;
+
+    type TransmitFuture<'a>: Future<Output = Result<(), LoraError>>
+    where
+        Self: 'a;
+    /// Transmit a packet, returning once it has been sent.
+    fn transmit<'a>(&'a mut self, data: &'a [u8]) -> Self::TransmitFuture<'a>;
+
+    type ReceiveFuture<'a>: Future<Output = Result<RxPacket, LoraError>>
+    where
+        Self: 'a;
+    /// Wait for a packet until the timeout expires, writing it into the provided buffer.
+    fn receive<'a>(&'a mut self, rx: &'a mut [u8], timeout: Duration) -> Self::ReceiveFuture<'a>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Bandwidth {
+    KHz125,
+    KHz250,
+    KHz500,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodingRate {
+    CR4_5,
+    CR4_6,
+    CR4_7,
+    CR4_8,
+}
+
+/// Radio settings for point-to-point communication.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RfConfig {
+    /// Frequency in Hz.
+    pub frequency: u32,
+    pub spreading_factor: SpreadingFactor,
+    pub bandwidth: Bandwidth,
+    pub coding_rate: CodingRate,
+    /// Output power in dBm.
+    pub tx_power: u8,
+}
+
+impl RfConfig {
+    /// Settings for the given frequency, using SF7 at 125 kHz, coding rate 4/5 and 14 dBm.
+    pub fn new(frequency: u32) -> Self {
+        Self {
+            frequency,
+            spreading_factor: SpreadingFactor::SF7,
+            bandwidth: Bandwidth::KHz125,
+            coding_rate: CodingRate::CR4_5,
+            tx_power: 14,
+        }
+    }
+
+    pub fn spreading_factor(mut self, spreading_factor: SpreadingFactor) -> Self {
+        self.spreading_factor = spreading_factor;
+        self
+    }
+
+    pub fn bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    pub fn coding_rate(mut self, coding_rate: CodingRate) -> Self {
+        self.coding_rate = coding_rate;
+        self
+    }
+
+    pub fn tx_power(mut self, tx_power: u8) -> Self {
+        self.tx_power = tx_power;
+        self
+    }
+}
+
+/// Packet received by a point-to-point receive, along with the quality of its signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RxPacket {
+    /// Length of the packet written into the receive buffer.
+    pub len: usize,
+    pub rssi: i16,
+    pub snr: i8,
+}
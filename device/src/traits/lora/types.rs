@@ -27,6 +27,20 @@ pub enum LoraMode {
     P2P = 1,
 }
 
+/// LoRaWAN device class, determining when the device listens for downlinks.
+///
+/// Class B is deliberately not offered: it needs beacon tracking and ping slot timing, which
+/// neither the LoRaWAN stack of the SX127x driver nor the RAK811 firmware provide.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LoraClass {
+    /// Downlinks are only received in the windows following an uplink.
+    A,
+    /// Downlinks are received whenever the device is not transmitting, and taken with
+    /// `LoraDriver::take_downlink`.
+    C,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum LoraRegion {
@@ -40,23 +54,174 @@ pub enum LoraRegion {
     UNKNOWN,
 }
 
+impl LoraRegion {
+    /// Default frequency in Hz of the second receive window in the region.
+    pub fn rx2_frequency(&self) -> Option<u32> {
+        match self {
+            LoraRegion::EU868 => Some(869_525_000),
+            LoraRegion::US915 | LoraRegion::AU915 => Some(923_300_000),
+            LoraRegion::KR920 => Some(921_900_000),
+            LoraRegion::AS923 => Some(923_200_000),
+            LoraRegion::IN865 => Some(866_550_000),
+            LoraRegion::CN470 => Some(505_300_000),
+            LoraRegion::UNKNOWN => None,
+        }
+    }
+
+    /// Default data rate of the second receive window in the region.
+    pub fn rx2_data_rate(&self) -> Option<u8> {
+        match self {
+            LoraRegion::US915 | LoraRegion::AU915 => Some(8),
+            LoraRegion::AS923 | LoraRegion::IN865 => Some(2),
+            LoraRegion::EU868 | LoraRegion::KR920 | LoraRegion::CN470 => Some(0),
+            LoraRegion::UNKNOWN => None,
+        }
+    }
+}
+
 pub type Port = u8;
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DevAddr([u8; 4]);
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct EUI([u8; 8]);
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AppKey([u8; 16]);
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct NwksKey([u8; 16]);
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AppsKey([u8; 16]);
 
+/// Result of a link check, as reported by the network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkCheck {
+    /// Link margin in dB of the last uplink, relative to the demodulation floor.
+    pub margin: u8,
+    /// Number of gateways that received the last uplink.
+    pub gateways: u8,
+}
+
+/// Current state of the LoRaWAN link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoraStatus {
+    pub data_rate: u8,
+    pub tx_power: u8,
+    /// Frame counter of the last uplink sent.
+    pub fcnt_up: u32,
+    /// Frame counter of the last downlink received.
+    pub fcnt_down: u32,
+}
+
+/// Length of a serialized `LoraSession`.
+pub const SESSION_LEN: usize = 49;
+
+/// Version of the serialization format of `LoraSession`.
+const SESSION_VERSION: u8 = 3;
+
+/// Serialized value of a missing JoinNonce, which is out of range for the 24 bit JoinNonce.
+const NO_JOIN_NONCE: u32 = u32::MAX;
+
+/// State of an activated LoRaWAN session, allowing a device to resume it without joining again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoraSession {
+    pub device_address: DevAddr,
+    pub nwks_key: NwksKey,
+    pub apps_key: AppsKey,
+    /// Frame counter of the next uplink to send.
+    pub fcnt_up: u32,
+    /// Lowest frame counter accepted for the next downlink. Downlinks with lower counters are
+    /// rejected as replays.
+    pub fcnt_down: u32,
+    /// JoinNonce of the join accept establishing the session, or `None` for sessions activated
+    /// by personalization and drivers unable to read it.
+    pub join_nonce: Option<u32>,
+}
+
+impl LoraSession {
+    /// Serialize the session for storage, with the frame counters in little endian.
+    pub fn to_bytes(&self) -> [u8; SESSION_LEN] {
+        let mut buf = [0; SESSION_LEN];
+        buf[0] = SESSION_VERSION;
+        buf[1..5].copy_from_slice(&self.device_address.0);
+        buf[5..21].copy_from_slice(&self.nwks_key.0);
+        buf[21..37].copy_from_slice(&self.apps_key.0);
+        buf[37..41].copy_from_slice(&self.fcnt_up.to_le_bytes());
+        buf[41..45].copy_from_slice(&self.fcnt_down.to_le_bytes());
+        buf[45..49].copy_from_slice(&self.join_nonce.unwrap_or(NO_JOIN_NONCE).to_le_bytes());
+        buf
+    }
+
+    /// Deserialize a session written by `to_bytes`, returning `None` if the data is not a
+    /// session, such as erased storage.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < SESSION_LEN || buf[0] != SESSION_VERSION {
+            return None;
+        }
+        let mut device_address = [0; 4];
+        let mut nwks_key = [0; 16];
+        let mut apps_key = [0; 16];
+        let mut counters = [[0; 4]; 3];
+        device_address.copy_from_slice(&buf[1..5]);
+        nwks_key.copy_from_slice(&buf[5..21]);
+        apps_key.copy_from_slice(&buf[21..37]);
+        for (i, counter) in counters.iter_mut().enumerate() {
+            counter.copy_from_slice(&buf[37 + i * 4..41 + i * 4]);
+        }
+        Some(Self {
+            device_address: DevAddr(device_address),
+            nwks_key: NwksKey(nwks_key),
+            apps_key: AppsKey(apps_key),
+            fcnt_up: u32::from_le_bytes(counters[0]),
+            fcnt_down: u32::from_le_bytes(counters[1]),
+            join_nonce: match u32::from_le_bytes(counters[2]) {
+                NO_JOIN_NONCE => None,
+                join_nonce => Some(join_nonce),
+            },
+        })
+    }
+}
+
+/// Maximum size of a downlink payload.
+pub const DOWNLINK_LEN: usize = 255;
+
+/// Downlink received from the network, along with the quality of its signal.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LoraDownlink {
+    pub port: Port,
+    pub rssi: i16,
+    pub snr: i8,
+    len: usize,
+    data: [u8; DOWNLINK_LEN],
+}
+
+impl LoraDownlink {
+    /// Create a downlink, truncating the payload to `DOWNLINK_LEN` octets.
+    pub fn new(port: Port, rssi: i16, snr: i8, payload: &[u8]) -> Self {
+        let len = core::cmp::min(payload.len(), DOWNLINK_LEN);
+        let mut data = [0; DOWNLINK_LEN];
+        data[..len].copy_from_slice(&payload[..len]);
+        Self {
+            port,
+            rssi,
+            snr,
+            len,
+            data,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SpreadingFactor {
@@ -68,28 +233,61 @@ pub enum SpreadingFactor {
     SF12,
 }
 
+/// Default delay in milliseconds from the end of an uplink to the first receive window.
+pub const RECEIVE_DELAY1: u32 = 1000;
+/// Default delay in milliseconds from the end of an uplink to the second receive window.
+pub const RECEIVE_DELAY2: u32 = RECEIVE_DELAY1 + 1000;
+/// Default delay in milliseconds from the end of a join request to the first join accept window.
+pub const JOIN_ACCEPT_DELAY1: u32 = 5000;
+/// Default delay in milliseconds from the end of a join request to the second join accept window.
+pub const JOIN_ACCEPT_DELAY2: u32 = 6000;
+
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct LoraConfig {
     pub spreading_factor: Option<SpreadingFactor>,
+    pub adr: Option<bool>,
+    pub tx_power: Option<u8>,
     pub region: Option<LoraRegion>,
     pub lora_mode: Option<LoraMode>,
+    pub class: Option<LoraClass>,
     pub device_address: Option<DevAddr>,
     pub device_eui: Option<EUI>,
     pub app_eui: Option<EUI>,
     pub app_key: Option<AppKey>,
+    pub nwks_key: Option<NwksKey>,
+    pub apps_key: Option<AppsKey>,
+    pub rx1_delay: Option<u32>,
+    pub rx2_delay: Option<u32>,
+    pub rx2_frequency: Option<u32>,
+    pub rx2_data_rate: Option<u8>,
+    pub join_accept_delay1: Option<u32>,
+    pub join_accept_delay2: Option<u32>,
+    pub rx_window_margin: Option<u32>,
 }
 
 impl LoraConfig {
     pub fn new() -> Self {
         Self {
             spreading_factor: None,
+            adr: None,
+            tx_power: None,
             region: None,
             lora_mode: None,
+            class: None,
             device_address: None,
             device_eui: None,
             app_eui: None,
             app_key: None,
+            nwks_key: None,
+            apps_key: None,
+            rx1_delay: None,
+            rx2_delay: None,
+            rx2_frequency: None,
+            rx2_data_rate: None,
+            join_accept_delay1: None,
+            join_accept_delay2: None,
+            rx_window_margin: None,
         }
     }
 
@@ -98,6 +296,20 @@ impl LoraConfig {
         self
     }
 
+    /// Let the network adapt the data rate and TX power of the device. Drivers unable to
+    /// answer the requests of the network reject it with `LoraError::NotImplemented`.
+    pub fn adr(mut self, adr: bool) -> Self {
+        self.adr.replace(adr);
+        self
+    }
+
+    /// TX power index from the regional parameters, 0 being the maximum and each step lowering
+    /// the power by 2 dB.
+    pub fn tx_power(mut self, tx_power: u8) -> Self {
+        self.tx_power.replace(tx_power);
+        self
+    }
+
     pub fn region(mut self, region: LoraRegion) -> Self {
         self.region.replace(region);
         self
@@ -108,6 +320,11 @@ impl LoraConfig {
         self
     }
 
+    pub fn class(mut self, class: LoraClass) -> Self {
+        self.class.replace(class);
+        self
+    }
+
     pub fn device_address(mut self, device_address: &DevAddr) -> Self {
         self.device_address.replace(device_address.clone());
         self
@@ -127,6 +344,59 @@ impl LoraConfig {
         self.app_key.replace(app_key.clone());
         self
     }
+
+    pub fn nwks_key(mut self, nwks_key: &NwksKey) -> Self {
+        self.nwks_key.replace(nwks_key.clone());
+        self
+    }
+
+    pub fn apps_key(mut self, apps_key: &AppsKey) -> Self {
+        self.apps_key.replace(apps_key.clone());
+        self
+    }
+
+    /// Delay in milliseconds from the end of an uplink to the first receive window, as assigned by
+    /// the network. Defaults to `RECEIVE_DELAY1`.
+    pub fn rx1_delay(mut self, rx1_delay: u32) -> Self {
+        self.rx1_delay.replace(rx1_delay);
+        self
+    }
+
+    /// Delay in milliseconds from the end of an uplink to the second receive window. Defaults to
+    /// one second after the first receive window.
+    pub fn rx2_delay(mut self, rx2_delay: u32) -> Self {
+        self.rx2_delay.replace(rx2_delay);
+        self
+    }
+
+    /// Frequency in Hz of the second receive window. Defaults to the one of the region.
+    pub fn rx2_frequency(mut self, rx2_frequency: u32) -> Self {
+        self.rx2_frequency.replace(rx2_frequency);
+        self
+    }
+
+    /// Data rate of the second receive window. Defaults to the one of the region.
+    pub fn rx2_data_rate(mut self, rx2_data_rate: u8) -> Self {
+        self.rx2_data_rate.replace(rx2_data_rate);
+        self
+    }
+
+    pub fn join_accept_delay1(mut self, join_accept_delay1: u32) -> Self {
+        self.join_accept_delay1.replace(join_accept_delay1);
+        self
+    }
+
+    pub fn join_accept_delay2(mut self, join_accept_delay2: u32) -> Self {
+        self.join_accept_delay2.replace(join_accept_delay2);
+        self
+    }
+
+    /// Time in milliseconds a receive window is opened early, to compensate for the clock error
+    /// of the device.
+    pub fn rx_window_margin(mut self, rx_window_margin: u32) -> Self {
+        self.rx_window_margin.replace(rx_window_margin);
+        self
+    }
 }
 
 impl EUI {
@@ -396,4 +666,25 @@ mod tests {
         assert_eq!(0xBB, reversed[6]);
         assert_eq!(0xAA, reversed[7]);
     }
+
+    #[test]
+    fn test_session_bytes() {
+        let session = LoraSession {
+            device_address: "26011234".into(),
+            nwks_key: "000102030405060708090A0B0C0D0E0F".into(),
+            apps_key: "F0E0D0C0B0A090807060504030201000".into(),
+            fcnt_up: 70000,
+            fcnt_down: 12,
+            join_nonce: Some(0x123456),
+        };
+        let data = session.to_bytes();
+        assert_eq!(Some(session), LoraSession::from_bytes(&data));
+        let abp = LoraSession {
+            join_nonce: None,
+            ..session
+        };
+        assert_eq!(Some(abp), LoraSession::from_bytes(&abp.to_bytes()));
+        assert_eq!(None, LoraSession::from_bytes(&[0xFF; SESSION_LEN]));
+        assert_eq!(None, LoraSession::from_bytes(&data[..SESSION_LEN - 1]));
+    }
 }
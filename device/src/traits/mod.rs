@@ -1,4 +1,5 @@
 pub mod ip;
 pub mod lora;
 pub mod tcp;
+pub mod udp;
 pub mod wifi;
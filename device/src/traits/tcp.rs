@@ -10,6 +10,8 @@ pub enum TcpError {
     CloseError,
     IoError,
     SocketClosed,
+    /// All sockets of the stack are in use.
+    NoAvailableSockets,
 }
 
 pub trait TcpSocket {
@@ -37,7 +39,7 @@ pub trait TcpSocket {
 pub trait TcpStack {
     type SocketHandle: Copy;
 
-    type OpenFuture<'m>: Future<Output = Self::SocketHandle>
+    type OpenFuture<'m>: Future<Output = Result<Self::SocketHandle, TcpError>>
     where
         Self: 'm;
     fn open<'m>(&'m mut self) -> Self::OpenFuture<'m>;
@@ -71,3 +73,24 @@ pub trait TcpStack {
         Self: 'm;
     fn close<'m>(&'m mut self, handle: Self::SocketHandle) -> Self::CloseFuture<'m>;
 }
+
+/// Accepting inbound connections on a `TcpStack`.
+pub trait TcpServer: TcpStack {
+    type ListenFuture<'m>: Future<Output = Result<(), TcpError>>
+    where
+        Self: 'm;
+    fn listen<'m>(&'m mut self, port: u16) -> Self::ListenFuture<'m>;
+
+    /// Take a pending inbound connection without waiting for one, returning a connected
+    /// socket handle, or `None` if no connection is pending.
+    type AcceptFuture<'m>: Future<Output = Result<Option<Self::SocketHandle>, TcpError>>
+    where
+        Self: 'm;
+    fn accept<'m>(&'m mut self) -> Self::AcceptFuture<'m>;
+
+    /// Stop accepting connections. Accepted sockets stay open.
+    type StopListeningFuture<'m>: Future<Output = ()>
+    where
+        Self: 'm;
+    fn stop_listening<'m>(&'m mut self) -> Self::StopListeningFuture<'m>;
+}
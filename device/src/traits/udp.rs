@@ -0,0 +1,25 @@
+use core::future::Future;
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UdpError {
+    SendError,
+    RecvError,
+    SocketClosed,
+}
+
+/// A datagram socket connected to a single remote endpoint.
+///
+/// `recv` returns `Ok(0)` when no datagram is available yet, so that protocols can
+/// implement their own timeouts and retransmissions on top.
+pub trait UdpSocket {
+    type SendFuture<'m>: Future<Output = Result<(), UdpError>>
+    where
+        Self: 'm;
+    fn send<'m>(&'m mut self, buf: &'m [u8]) -> Self::SendFuture<'m>;
+
+    type RecvFuture<'m>: Future<Output = Result<usize, UdpError>>
+    where
+        Self: 'm;
+    fn recv<'m>(&'m mut self, buf: &'m mut [u8]) -> Self::RecvFuture<'m>;
+}
@@ -1,11 +1,79 @@
 use super::ip::IpAddress;
 use core::future::Future;
+use heapless::{consts, String};
 
 #[derive(Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Join<'a> {
     Open,
-    Wpa { ssid: &'a str, password: &'a str },
+    Wpa {
+        ssid: &'a str,
+        password: &'a str,
+    },
+    /// WPA2-Enterprise (802.1X) with an outer identity and inner username and password.
+    ///
+    /// Adapters without 802.1X support, such as the ESP8266, fail with `JoinError::NotSupported`.
+    Wpa2Enterprise {
+        ssid: &'a str,
+        identity: &'a str,
+        username: &'a str,
+        password: &'a str,
+    },
+    /// WPA3-Enterprise (802.1X) with an outer identity and inner username and password.
+    ///
+    /// Adapters without 802.1X support, such as the ESP8266, fail with `JoinError::NotSupported`.
+    Wpa3Enterprise {
+        ssid: &'a str,
+        identity: &'a str,
+        username: &'a str,
+        password: &'a str,
+    },
+}
+
+/// Static IP configuration, used instead of DHCP.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaticIp {
+    pub address: IpAddress,
+    pub gateway: IpAddress,
+    pub netmask: IpAddress,
+    pub dns: Option<IpAddress>,
+}
+
+/// Options for joining a network, beyond its credentials.
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JoinOptions {
+    /// Only join the access point with this BSSID.
+    pub bssid: Option<[u8; 6]>,
+    /// The network does not broadcast its SSID.
+    ///
+    /// This is a hint for adapters that must probe for hidden networks explicitly. Adapters
+    /// always probing for the SSID when joining, such as the ESP8266, ignore it.
+    pub hidden: bool,
+    /// Use a static IP configuration rather than DHCP.
+    pub static_ip: Option<StaticIp>,
+}
+
+impl JoinOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bssid(mut self, bssid: [u8; 6]) -> Self {
+        self.bssid.replace(bssid);
+        self
+    }
+
+    pub fn hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    pub fn static_ip(mut self, static_ip: StaticIp) -> Self {
+        self.static_ip.replace(static_ip);
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -15,11 +83,181 @@ pub enum JoinError {
     InvalidSsid,
     InvalidPassword,
     UnableToAssociate,
+    /// No response from the access point in time.
+    Timeout,
+    /// The access point rejected the password.
+    WrongPassword,
+    /// No access point with the SSID (or BSSID) was found.
+    NetworkNotFound,
+    /// The access point was found, but the connection failed.
+    ConnectionFailed,
+    /// The security or options requested are not supported by the adapter.
+    NotSupported,
+}
+
+/// State of the link to the access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkState {
+    /// Not associated with an access point.
+    Down,
+    /// Associated, but no IP address assigned yet.
+    Associated,
+    /// Associated and an IP address is assigned.
+    Up,
+}
+
+/// Security used by an access point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Security {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+    WpaWpa2Psk,
+    Wpa2Enterprise,
+    Wpa3Psk,
+    Wpa2Wpa3Psk,
+    Unknown,
+}
+
+/// An access point found when scanning.
+#[derive(Debug, Clone)]
+pub struct AccessPoint {
+    pub ssid: String<consts::U32>,
+    pub bssid: [u8; 6],
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+    pub security: Security,
+}
+
+impl Default for AccessPoint {
+    fn default() -> Self {
+        Self {
+            ssid: String::new(),
+            bssid: [0; 6],
+            rssi: 0,
+            channel: 0,
+            security: Security::Unknown,
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AccessPoint {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "AccessPoint(ssid: {}, bssid: {}, rssi: {}, channel: {}, security: {})",
+            self.ssid.as_str(),
+            self.bssid,
+            self.rssi,
+            self.channel,
+            self.security
+        )
+    }
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanError {
+    Unknown,
+    /// The adapter is unable to scan in its current mode.
+    NotSupported,
+}
+
+/// Configuration for running the adapter as an access point.
+///
+/// An empty password starts an open network, otherwise WPA2-PSK is used.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AccessPointConfig<'a> {
+    pub ssid: &'a str,
+    pub password: &'a str,
+    pub channel: u8,
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AccessPointError {
+    Unknown,
+    InvalidSsid,
+    InvalidPassword,
+    InvalidChannel,
+}
+
+/// Information about the current link to an access point.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LinkInfo {
+    pub bssid: [u8; 6],
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+    /// MAC address of the adapter.
+    pub mac: [u8; 6],
+    pub ip: IpAddress,
+    pub gateway: IpAddress,
+    pub netmask: IpAddress,
+    pub dns: [Option<IpAddress>; 2],
+}
+
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkInfoError {
+    Unknown,
+    /// Not joined to an access point.
+    NotConnected,
 }
 
 pub trait WifiSupplicant {
     type JoinFuture<'m>: Future<Output = Result<IpAddress, JoinError>>
     where
         Self: 'm;
-    fn join<'m>(&'m mut self, join: Join<'m>) -> Self::JoinFuture<'m>;
+    fn join<'m>(&'m mut self, join: Join<'m>) -> Self::JoinFuture<'m> {
+        self.join_with_options(join, JoinOptions::default())
+    }
+
+    fn join_with_options<'m>(
+        &'m mut self,
+        join: Join<'m>,
+        options: JoinOptions,
+    ) -> Self::JoinFuture<'m>;
+
+    type LinkStateFuture<'m>: Future<Output = LinkState>
+    where
+        Self: 'm;
+    fn link_state<'m>(&'m mut self) -> Self::LinkStateFuture<'m>;
+
+    type LinkInfoFuture<'m>: Future<Output = Result<LinkInfo, LinkInfoError>>
+    where
+        Self: 'm;
+    fn link_info<'m>(&'m mut self) -> Self::LinkInfoFuture<'m>;
+
+    /// Scan for access points, filling `results` and returning the number found.
+    ///
+    /// Access points that do not fit into `results` are dropped.
+    type ScanFuture<'m>: Future<Output = Result<usize, ScanError>>
+    where
+        Self: 'm;
+    fn scan<'m>(&'m mut self, results: &'m mut [AccessPoint]) -> Self::ScanFuture<'m>;
+}
+
+/// Access point mode, where the adapter runs its own network for other stations to join.
+pub trait WifiAccessPoint {
+    /// Start the access point, returning the IP address of the adapter on that network.
+    type StartAccessPointFuture<'m>: Future<Output = Result<IpAddress, AccessPointError>>
+    where
+        Self: 'm;
+    fn start_access_point<'m>(
+        &'m mut self,
+        config: AccessPointConfig<'m>,
+    ) -> Self::StartAccessPointFuture<'m>;
+
+    type StopAccessPointFuture<'m>: Future<Output = ()>
+    where
+        Self: 'm;
+    fn stop_access_point<'m>(&'m mut self) -> Self::StopAccessPointFuture<'m>;
 }
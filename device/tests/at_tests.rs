@@ -0,0 +1,307 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(all(
+    feature = "std",
+    any(feature = "wifi+esp8266", feature = "lora+rak811")
+))]
+mod tests {
+    extern crate std;
+    use core::cell::{Cell, RefCell};
+    use core::future::Future;
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+    use drogue_device::{drivers::at::*, testutil::*};
+    use drogue_device_macros::test as drogue_test;
+    use embassy::executor::Spawner;
+    use embassy::io::{AsyncBufRead, AsyncWrite};
+    use embassy::time::{Duration, Timer};
+    use futures::future::select;
+    use futures::pin_mut;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    type Line = Vec<u8>;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    /// Bytes exchanged with the mock UART.
+    #[derive(Default)]
+    struct Serial {
+        input: VecDeque<u8>,
+        written: Vec<Line>,
+        replies: VecDeque<&'static [u8]>,
+        waker: Option<Waker>,
+    }
+
+    #[derive(Clone, Default)]
+    struct SerialHandle(Rc<RefCell<Serial>>);
+
+    impl SerialHandle {
+        /// Make data available for the modem to read.
+        fn input(&self, data: &[u8]) {
+            let mut serial = self.0.borrow_mut();
+            serial.input.extend(data);
+            if let Some(waker) = serial.waker.take() {
+                waker.wake();
+            }
+        }
+
+        /// Queue the data input in reply to the next command written.
+        fn reply(&self, data: &'static [u8]) {
+            self.0.borrow_mut().replies.push_back(data);
+        }
+
+        fn written(&self) -> Vec<Line> {
+            self.0.borrow().written.clone()
+        }
+    }
+
+    struct MockUart {
+        serial: SerialHandle,
+        buf: Vec<u8>,
+    }
+
+    impl AsyncBufRead for MockUart {
+        fn poll_fill_buf(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Result<&[u8], embassy::io::Error>> {
+            let this = self.get_mut();
+            if this.buf.is_empty() {
+                let mut serial = this.serial.0.borrow_mut();
+                if serial.input.is_empty() {
+                    serial.waker.replace(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                this.buf.extend(serial.input.drain(..));
+            }
+            Poll::Ready(Ok(&this.buf[..]))
+        }
+
+        fn consume(self: Pin<&mut Self>, amt: usize) {
+            self.get_mut().buf.drain(..amt);
+        }
+    }
+
+    impl AsyncWrite for MockUart {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, embassy::io::Error>> {
+            let reply = {
+                let mut serial = self.serial.0.borrow_mut();
+                serial.written.push(buf.to_vec());
+                serial.replies.pop_front()
+            };
+            if let Some(reply) = reply {
+                self.serial.input(reply);
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+    }
+
+    /// Line based device, routing lines starting with '+' as notifications.
+    struct MockDevice {
+        line: Line,
+        initialized: Rc<Cell<u32>>,
+    }
+
+    impl AtDevice<MockUart> for MockDevice {
+        type Response = Line;
+        type Ready = u32;
+
+        #[rustfmt::skip]
+        type InitializeFuture<'m> where Self: 'm = impl Future<Output = Result<u32, AtError>> + 'm;
+        fn initialize<'m>(&'m mut self, _: &'m mut MockUart) -> Self::InitializeFuture<'m> {
+            async move {
+                self.line.clear();
+                self.initialized.set(self.initialized.get() + 1);
+                Ok(self.initialized.get())
+            }
+        }
+
+        fn digest(&mut self, octet: u8) -> Option<Line> {
+            if octet == b'\n' {
+                Some(core::mem::take(&mut self.line))
+            } else {
+                self.line.push(octet);
+                None
+            }
+        }
+
+        fn route(&mut self, response: Line) -> Route<Line> {
+            if response.starts_with(b"+") {
+                Route::Notification(response)
+            } else {
+                Route::Response(response)
+            }
+        }
+    }
+
+    fn setup() -> (SerialHandle, MockUart, MockDevice, Rc<Cell<u32>>) {
+        let serial = SerialHandle::default();
+        let initialized = Rc::new(Cell::new(0));
+        let uart = MockUart {
+            serial: serial.clone(),
+            buf: Vec::new(),
+        };
+        let device = MockDevice {
+            line: Vec::new(),
+            initialized: initialized.clone(),
+        };
+        (serial, uart, device, initialized)
+    }
+
+    struct AtTestDevice;
+
+    #[drogue_test]
+    async fn test_request_timeout(_spawner: Spawner, _context: TestContext<AtTestDevice>) {
+        let (serial, uart, device, _) = setup();
+        let mut engine: AtEngine<Line, u32> = AtEngine::new();
+        let (mut client, mut modem) = engine.split(uart, device);
+
+        let run = modem.run();
+        let test = async {
+            assert_eq!(Ok(1), client.ready().await);
+
+            // No reply to the first command
+            serial.reply(b"");
+            assert_eq!(
+                Err(AtError::Timeout),
+                client.request(b"AT+SLOW\r\n", TIMEOUT).await
+            );
+
+            // The late response to the first command must not answer the second
+            serial.input(b"LATE\n");
+            Timer::after(Duration::from_millis(10)).await;
+            serial.reply(b"OK\n");
+            assert_eq!(Ok(b"OK".to_vec()), client.request(b"AT\r\n", TIMEOUT).await);
+
+            assert_eq!(
+                std::vec![b"AT+SLOW\r\n".to_vec(), b"AT\r\n".to_vec()],
+                serial.written()
+            );
+        };
+        pin_mut!(run);
+        pin_mut!(test);
+        select(run, test).await;
+    }
+
+    #[drogue_test]
+    async fn test_pipelined_commands(_spawner: Spawner, _context: TestContext<AtTestDevice>) {
+        let (serial, uart, device, _) = setup();
+        let mut engine: AtEngine<Line, u32> = AtEngine::new();
+        let (mut client, mut modem) = engine.split(uart, device);
+
+        let run = modem.run();
+        let test = async {
+            assert_eq!(Ok(1), client.ready().await);
+
+            // Both commands are written before the first is answered
+            let first = client.send(b"AT+A\r\n", TIMEOUT).await.unwrap();
+            let second = client.send(b"AT+B\r\n", TIMEOUT).await.unwrap();
+            Timer::after(Duration::from_millis(10)).await;
+            assert_eq!(
+                std::vec![b"AT+A\r\n".to_vec(), b"AT+B\r\n".to_vec()],
+                serial.written()
+            );
+            serial.input(b"A\nB\n");
+            assert_eq!(Ok(b"A".to_vec()), client.receive(first, TIMEOUT).await);
+            assert_eq!(Ok(b"B".to_vec()), client.receive(second, TIMEOUT).await);
+
+            // Every command has its own timeout
+            let slow = client
+                .send(b"AT+SLOW\r\n", Duration::from_millis(200))
+                .await
+                .unwrap();
+            let fast = client.send(b"AT+FAST\r\n", TIMEOUT).await.unwrap();
+            assert_eq!(Err(AtError::Timeout), client.receive(fast, TIMEOUT).await);
+            serial.input(b"SLOW\n");
+            assert_eq!(Ok(b"SLOW".to_vec()), client.receive(slow, TIMEOUT).await);
+        };
+        pin_mut!(run);
+        pin_mut!(test);
+        select(run, test).await;
+    }
+
+    #[drogue_test]
+    async fn test_notifications(_spawner: Spawner, _context: TestContext<AtTestDevice>) {
+        let (serial, uart, device, _) = setup();
+        let mut engine: AtEngine<Line, u32, 2, 2> = AtEngine::new();
+        let (mut client, mut modem) = engine.split(uart, device);
+
+        {
+            let run = modem.run();
+            let test = async {
+                assert_eq!(Ok(1), client.ready().await);
+
+                serial.input(b"+EVENT\n");
+                assert_eq!(Some(b"+EVENT".to_vec()), client.notification().await);
+
+                // Notifications interleaved with a response are routed apart from it
+                serial.reply(b"+FIRST\nOK\n+SECOND\n");
+                assert_eq!(Ok(b"OK".to_vec()), client.request(b"AT\r\n", TIMEOUT).await);
+                Timer::after(Duration::from_millis(10)).await;
+                assert_eq!(Some(b"+FIRST".to_vec()), client.try_notification());
+                assert_eq!(Some(b"+SECOND".to_vec()), client.try_notification());
+                assert_eq!(None, client.try_notification());
+
+                // Notifications are dropped instead of stalling the modem once the queue is full
+                serial.input(b"+1\n+2\n+3\n");
+                Timer::after(Duration::from_millis(10)).await;
+                serial.reply(b"OK\n");
+                assert_eq!(Ok(b"OK".to_vec()), client.request(b"AT\r\n", TIMEOUT).await);
+                assert_eq!(Some(b"+1".to_vec()), client.try_notification());
+                assert_eq!(Some(b"+2".to_vec()), client.try_notification());
+                assert_eq!(None, client.try_notification());
+            };
+            pin_mut!(run);
+            pin_mut!(test);
+            select(run, test).await;
+        }
+        assert_eq!(1, modem.dropped_notifications());
+    }
+
+    #[drogue_test]
+    async fn test_reset_after_timeouts(_spawner: Spawner, _context: TestContext<AtTestDevice>) {
+        let (serial, uart, device, initialized) = setup();
+        let mut engine: AtEngine<Line, u32> = AtEngine::new();
+        let (mut client, mut modem) = engine.split(uart, device);
+
+        let run = modem.run();
+        let test = async {
+            assert_eq!(Ok(1), client.ready().await);
+
+            for _ in 0..MAX_TIMEOUTS - 1 {
+                assert_eq!(
+                    Err(AtError::Timeout),
+                    client.request(b"AT\r\n", TIMEOUT).await
+                );
+            }
+            // A response in between resets the count
+            serial.reply(b"OK\n");
+            assert_eq!(Ok(b"OK".to_vec()), client.request(b"AT\r\n", TIMEOUT).await);
+            assert_eq!(1, initialized.get());
+
+            for _ in 0..MAX_TIMEOUTS {
+                assert_eq!(
+                    Err(AtError::Timeout),
+                    client.request(b"AT\r\n", TIMEOUT).await
+                );
+            }
+            assert_eq!(Ok(2), client.ready().await);
+            assert_eq!(2, initialized.get());
+
+            serial.reply(b"OK\n");
+            assert_eq!(Ok(b"OK".to_vec()), client.request(b"AT\r\n", TIMEOUT).await);
+        };
+        pin_mut!(run);
+        pin_mut!(test);
+        select(run, test).await;
+    }
+}
@@ -0,0 +1,150 @@
+#![macro_use]
+#![allow(incomplete_features)]
+#![feature(generic_associated_types)]
+#![feature(type_alias_impl_trait)]
+
+#[cfg(all(feature = "std", feature = "time"))]
+mod tests {
+    extern crate std;
+    use drogue_device::{
+        actors::wifi::{manager::*, Adapter, AdapterActor},
+        testutil::*,
+        traits::{ip::*, tcp::*, wifi::*},
+        *,
+    };
+    use drogue_device_macros::test as drogue_test;
+    use embassy::executor::Spawner;
+    use futures::future::{ready, Ready};
+
+    /// Adapter failing the first join, and succeeding afterwards.
+    #[derive(Default)]
+    struct MockAdapter {
+        joins: u32,
+        joined: bool,
+    }
+
+    impl WifiSupplicant for MockAdapter {
+        #[rustfmt::skip]
+        type JoinFuture<'m> where Self: 'm = Ready<Result<IpAddress, JoinError>>;
+        fn join_with_options<'m>(
+            &'m mut self,
+            _: Join<'m>,
+            _: JoinOptions,
+        ) -> Self::JoinFuture<'m> {
+            self.joins += 1;
+            self.joined = self.joins > 1;
+            ready(if self.joined {
+                Ok(IpAddress::new_v4(192, 168, 1, 2))
+            } else {
+                Err(JoinError::Timeout)
+            })
+        }
+
+        #[rustfmt::skip]
+        type LinkStateFuture<'m> where Self: 'm = Ready<LinkState>;
+        fn link_state<'m>(&'m mut self) -> Self::LinkStateFuture<'m> {
+            ready(if self.joined {
+                LinkState::Up
+            } else {
+                LinkState::Down
+            })
+        }
+
+        #[rustfmt::skip]
+        type LinkInfoFuture<'m> where Self: 'm = Ready<Result<LinkInfo, LinkInfoError>>;
+        fn link_info<'m>(&'m mut self) -> Self::LinkInfoFuture<'m> {
+            ready(Err(LinkInfoError::NotConnected))
+        }
+
+        #[rustfmt::skip]
+        type ScanFuture<'m> where Self: 'm = Ready<Result<usize, ScanError>>;
+        fn scan<'m>(&'m mut self, _: &'m mut [AccessPoint]) -> Self::ScanFuture<'m> {
+            ready(Ok(0))
+        }
+    }
+
+    impl TcpStack for MockAdapter {
+        type SocketHandle = u8;
+
+        #[rustfmt::skip]
+        type OpenFuture<'m> where Self: 'm = Ready<Result<u8, TcpError>>;
+        fn open<'m>(&'m mut self) -> Self::OpenFuture<'m> {
+            ready(Err(TcpError::NoAvailableSockets))
+        }
+
+        #[rustfmt::skip]
+        type ConnectFuture<'m> where Self: 'm = Ready<Result<(), TcpError>>;
+        fn connect<'m>(
+            &'m mut self,
+            _: u8,
+            _: IpProtocol,
+            _: SocketAddress,
+        ) -> Self::ConnectFuture<'m> {
+            ready(Err(TcpError::ConnectError))
+        }
+
+        #[rustfmt::skip]
+        type WriteFuture<'m> where Self: 'm = Ready<Result<usize, TcpError>>;
+        fn write<'m>(&'m mut self, _: u8, _: &'m [u8]) -> Self::WriteFuture<'m> {
+            ready(Err(TcpError::WriteError))
+        }
+
+        #[rustfmt::skip]
+        type ReadFuture<'m> where Self: 'm = Ready<Result<usize, TcpError>>;
+        fn read<'m>(&'m mut self, _: u8, _: &'m mut [u8]) -> Self::ReadFuture<'m> {
+            ready(Err(TcpError::ReadError))
+        }
+
+        #[rustfmt::skip]
+        type CloseFuture<'m> where Self: 'm = Ready<()>;
+        fn close<'m>(&'m mut self, _: u8) -> Self::CloseFuture<'m> {
+            ready(())
+        }
+    }
+
+    impl Adapter for MockAdapter {}
+
+    struct ManagerDevice {
+        handler: ActorContext<'static, TestHandler>,
+        adapter: ActorContext<'static, AdapterActor<MockAdapter>>,
+        manager: ActorContext<'static, ConnectionManager<'static, MockAdapter, TestHandler>>,
+        supervisor: ActorContext<'static, ConnectionSupervisor<'static, MockAdapter, TestHandler>>,
+    }
+
+    #[drogue_test]
+    async fn test_supervise(spawner: Spawner, mut context: TestContext<ManagerDevice>) {
+        let notified = context.signal();
+        context.configure(ManagerDevice {
+            handler: ActorContext::new(TestHandler::new(notified)),
+            adapter: ActorContext::new(AdapterActor::new()),
+            manager: ActorContext::new(ConnectionManager::new(Some(
+                Credentials::wpa("drogue", "secret").unwrap(),
+            ))),
+            supervisor: ActorContext::new(ConnectionSupervisor::new()),
+        });
+
+        let manager = context
+            .mount(|device| async move {
+                let handler = device.handler.mount((), spawner);
+                let adapter = device.adapter.mount(MockAdapter::default(), spawner);
+                let manager = device.manager.mount(adapter, spawner);
+                manager
+                    .notify(ConnectionManagerRequest::Subscribe(handler))
+                    .unwrap();
+                device.supervisor.mount(manager, spawner);
+                manager
+            })
+            .await;
+
+        // The first join fails, and is retried after backing off
+        manager.wait_link_up().await;
+        notified.wait_signaled().await;
+        assert_eq!(LinkState::Up as u32, notified.message().unwrap().0);
+
+        // Supervising more often than the queue allows must neither panic nor block requests
+        for _ in 0..4 {
+            let _ = manager.notify(ConnectionManagerRequest::Supervise);
+        }
+        assert_eq!(LinkState::Up, manager.link_state().await);
+    }
+}
@@ -85,11 +85,11 @@ where
                     log::info!("Sending data");
                     let this = unsafe { self.get_unchecked_mut() };
                     let socket = this.socket.as_mut().unwrap();
-                    let mut client =
-                        HttpClient::new(socket, this.ip, this.port, this.username, this.password);
+                    let mut client = HttpClient::new(socket, this.ip, this.port)
+                        .basic_auth(this.username, this.password);
 
                     let mut rx_buf = [0; 1024];
-                    let response_len = client
+                    let response = client
                         .post(
                             "/v1/foo",
                             b"Hello from Drogue",
@@ -97,11 +97,17 @@ where
                             &mut rx_buf[..],
                         )
                         .await;
-                    if let Ok(response_len) = response_len {
-                        log::info!(
-                            "Response: {}",
-                            core::str::from_utf8(&rx_buf[..response_len]).unwrap()
-                        );
+                    match response {
+                        Ok(response) => {
+                            log::info!(
+                                "Response status {}: {}",
+                                response.status(),
+                                core::str::from_utf8(response.payload()).unwrap()
+                            );
+                        }
+                        Err(e) => {
+                            log::warn!("Error sending request: {:?}", e);
+                        }
                     }
                 }
             }
@@ -1,7 +1,12 @@
 use core::fmt::Write;
 use core::future::Future;
 use core::pin::Pin;
-use drogue_device::{actors::button::*, drivers::led::*, traits::lora::*, *};
+use drogue_device::{
+    actors::{button::*, lora::FromLoraDownlink},
+    drivers::led::*,
+    traits::lora::*,
+    *,
+};
 use embedded_hal::digital::v2::{StatefulOutputPin, ToggleableOutputPin};
 use heapless::String;
 
@@ -10,6 +15,7 @@ pub enum Command {
     Tick,
     Send,
     TickAndSend,
+    Downlink(LoraDownlink),
 }
 
 impl<D, L1, L2, L3, L4> FromButtonEvent<Command> for App<D, L1, L2, L3, L4>
@@ -28,6 +34,19 @@ where
     }
 }
 
+impl<D, L1, L2, L3, L4> FromLoraDownlink<Command> for App<D, L1, L2, L3, L4>
+where
+    D: LoraDriver,
+    L1: StatefulOutputPin + ToggleableOutputPin + 'static,
+    L2: StatefulOutputPin + ToggleableOutputPin + 'static,
+    L3: StatefulOutputPin + ToggleableOutputPin + 'static,
+    L4: StatefulOutputPin + ToggleableOutputPin + 'static,
+{
+    fn from(downlink: LoraDownlink) -> Option<Command> {
+        Some(Command::Downlink(downlink))
+    }
+}
+
 pub struct AppConfig<D>
 where
     D: LoraDriver + 'static,
@@ -93,34 +112,12 @@ where
             log::info!("Message: {}", &tx);
             let tx = tx.into_bytes();
 
-            let mut rx = [0; 64];
-            let result = cfg.lora.send_recv(QoS::Confirmed, 1, &tx, &mut rx).await;
+            // Any reply is delivered as a downlink
+            let result = cfg.lora.send(QoS::Confirmed, 1, &tx).await;
 
             match result {
-                Ok(rx_len) => {
+                Ok(_) => {
                     log::info!("Message sent!");
-                    if rx_len > 0 {
-                        let response = &rx[0..rx_len];
-                        match core::str::from_utf8(response) {
-                            Ok(str) => {
-                                log::info!("Received {} bytes from uplink:\n{}", rx_len, str)
-                            }
-                            Err(_) => log::info!(
-                                "Received {} bytes from uplink: {:x?}",
-                                rx_len,
-                                &rx[0..rx_len]
-                            ),
-                        }
-                        match response {
-                            b"led:on" => {
-                                self.config.user_led.on().ok();
-                            }
-                            b"led:off" => {
-                                self.config.user_led.off().ok();
-                            }
-                            _ => {}
-                        }
-                    }
                 }
                 Err(e) => {
                     log::error!("Error sending message: {:?}", e);
@@ -130,6 +127,37 @@ where
 
         self.config.tx_led.off().ok();
     }
+
+    fn on_downlink(&mut self, downlink: LoraDownlink) {
+        let response = downlink.payload();
+        match core::str::from_utf8(response) {
+            Ok(str) => log::info!(
+                "Received {} bytes on port {} (RSSI {}, SNR {}):\n{}",
+                response.len(),
+                downlink.port,
+                downlink.rssi,
+                downlink.snr,
+                str
+            ),
+            Err(_) => log::info!(
+                "Received {} bytes on port {} (RSSI {}, SNR {}): {:x?}",
+                response.len(),
+                downlink.port,
+                downlink.rssi,
+                downlink.snr,
+                response
+            ),
+        }
+        match response {
+            b"led:on" => {
+                self.config.user_led.on().ok();
+            }
+            b"led:off" => {
+                self.config.user_led.off().ok();
+            }
+            _ => {}
+        }
+    }
 }
 
 impl<D, L1, L2, L3, L4> Unpin for App<D, L1, L2, L3, L4>
@@ -198,6 +226,9 @@ where
                     self.tick();
                     self.send().await;
                 }
+                Command::Downlink(downlink) => {
+                    self.on_downlink(downlink);
+                }
             }
         }
     }
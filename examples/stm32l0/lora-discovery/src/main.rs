@@ -155,7 +155,7 @@ async fn main(spawner: embassy::executor::Spawner, mut p: Peripherals) {
 
     DEVICE
         .mount(|device| async move {
-            let lora = device.lora.mount((), spawner);
+            let lora = device.lora.mount(Some(&device.app), spawner);
             let app = device.app.mount(AppConfig { lora }, spawner);
             device.button.mount(app, spawner);
         })